@@ -0,0 +1,295 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use chat_ui::ChatEvent;
+use futures::stream::{AbortHandle, AbortRegistration};
+use serde::Serialize;
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+/// 生成结束后保留多久，用于断线重连后补发
+const FINISHED_TTL: Duration = Duration::from_secs(300);
+/// 取消后等待任务保存已生成内容的时间，超时后强制终止
+const CANCEL_GRACE: Duration = Duration::from_secs(10);
+
+#[derive(Serialize)]
+pub struct StreamPacket {
+    pub chat_id: Uuid,
+    pub request_id: Uuid,
+    /// 同一个request内从1开始单调递增
+    pub seq: u64,
+    #[serde(flatten)]
+    pub event: ChatEvent,
+}
+
+/// 一个在后台运行的生成任务，不属于任何WebSocket连接
+/// 所有事件都会被缓存，直到结束后`FINISHED_TTL`被清理
+pub struct Generation {
+    chat_id: Uuid,
+    request_id: Uuid,
+    /// 第i个元素的seq为i+1
+    buffer: Mutex<Vec<String>>,
+    /// (最新的seq, 是否已经结束)
+    notify: watch::Sender<(u64, bool)>,
+    token: CancellationToken,
+    abort: AbortHandle,
+}
+
+impl Generation {
+    pub fn chat_id(&self) -> Uuid {
+        self.chat_id
+    }
+
+    fn is_running(&self) -> bool {
+        !self.notify.borrow().1
+    }
+
+    pub fn push(&self, event: ChatEvent) {
+        let mut buffer = self.buffer.lock().unwrap();
+        let seq = buffer.len() as u64 + 1;
+        let packet = StreamPacket {
+            chat_id: self.chat_id,
+            request_id: self.request_id,
+            seq,
+            event,
+        };
+        match serde_json::to_string(&packet) {
+            Ok(json) => buffer.push(json),
+            Err(e) => {
+                tracing::error!("Failed to serialize packet of {}: {}", self.request_id, e);
+                return;
+            }
+        }
+        self.notify.send_modify(|s| s.0 = seq);
+    }
+
+    /// 先通知任务自行结束，让它有机会保存已经生成的部分
+    pub fn cancel(&self) {
+        self.token.cancel();
+        let abort = self.abort.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(CANCEL_GRACE).await;
+            abort.abort();
+        });
+    }
+
+    /// 依次把`last_seq`之后的事件交给`send`，然后继续等待新的事件
+    /// 生成结束或者`send`返回false时退出
+    pub async fn replay(&self, last_seq: u64, mut send: impl FnMut(String) -> bool) {
+        let mut rx = self.notify.subscribe();
+        let mut next = last_seq as usize;
+        loop {
+            // 先确认是否结束再取数据，结束之后buffer不会再变化
+            let (pending, finished) = {
+                let buffer = self.buffer.lock().unwrap();
+                let finished = rx.borrow_and_update().1;
+                (buffer.get(next..).unwrap_or_default().to_vec(), finished)
+            };
+            next += pending.len();
+            for json in pending {
+                if !send(json) {
+                    return;
+                }
+            }
+            if finished || rx.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// 以`request_id`为键管理所有的生成任务
+/// WebSocket断开时任务继续运行，重新连接后可以通过`ClientRequest::Resume`继续接收
+#[derive(Default)]
+pub struct GenerationRegistry {
+    tasks: Mutex<HashMap<Uuid, Arc<Generation>>>,
+}
+
+impl GenerationRegistry {
+    /// 注册一个新的生成任务，`chat_id`已经有还没结束的任务时返回空
+    /// 返回的token和AbortRegistration交给实际运行的任务
+    pub fn register(
+        &self,
+        chat_id: Uuid,
+        request_id: Uuid,
+    ) -> Option<(Arc<Generation>, CancellationToken, AbortRegistration)> {
+        let mut tasks = self.tasks.lock().unwrap();
+        // 检查和插入在同一把锁内，两个连接不会同时在一个会话上开始生成
+        if tasks
+            .values()
+            .any(|g| g.chat_id == chat_id && g.is_running())
+        {
+            return None;
+        }
+        let token = CancellationToken::new();
+        let (abort, abort_reg) = AbortHandle::new_pair();
+        let generation = Arc::new(Generation {
+            chat_id,
+            request_id,
+            buffer: Mutex::new(Vec::new()),
+            notify: watch::channel((0, false)).0,
+            token: token.clone(),
+            abort,
+        });
+        if let Some(old) = tasks.insert(request_id, generation.clone()) {
+            tracing::warn!("Duplicated request {}, cancel the old one", request_id);
+            old.cancel();
+        }
+        Some((generation, token, abort_reg))
+    }
+
+    pub fn get(&self, request_id: Uuid) -> Option<Arc<Generation>> {
+        self.tasks.lock().unwrap().get(&request_id).cloned()
+    }
+
+    pub fn cancel(&self, request_id: Uuid) -> bool {
+        match self.get(request_id) {
+            Some(generation) => {
+                generation.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// `chat_id`是否有还没结束的生成任务
+    pub fn is_running(&self, chat_id: Uuid) -> bool {
+        self.tasks
+            .lock()
+            .unwrap()
+            .values()
+            .any(|g| g.chat_id == chat_id && g.is_running())
+    }
+
+    /// 是否有任何还没结束的生成任务
+    pub fn any_running(&self) -> bool {
+        self.tasks.lock().unwrap().values().any(|g| g.is_running())
+    }
+
+    /// 标记任务结束，`FINISHED_TTL`之后从表中移除
+    pub fn finish(self: &Arc<Self>, generation: &Arc<Generation>) {
+        generation.notify.send_modify(|s| s.1 = true);
+        let registry = self.clone();
+        let generation = generation.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(FINISHED_TTL).await;
+            let mut tasks = registry.tasks.lock().unwrap();
+            // 同一个request_id可能已经被新的任务替换
+            if tasks
+                .get(&generation.request_id)
+                .is_some_and(|g| Arc::ptr_eq(g, &generation))
+            {
+                tasks.remove(&generation.request_id);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::stream::Abortable;
+
+    fn seqs(packets: &[String]) -> Vec<u64> {
+        packets
+            .iter()
+            .map(|json| {
+                let packet: serde_json::Value = serde_json::from_str(json).unwrap();
+                packet["seq"].as_u64().unwrap()
+            })
+            .collect()
+    }
+
+    async fn replay_all(generation: &Generation, last_seq: u64) -> Vec<u64> {
+        let mut packets = vec![];
+        generation
+            .replay(last_seq, |json| {
+                packets.push(json);
+                true
+            })
+            .await;
+        seqs(&packets)
+    }
+
+    #[tokio::test]
+    async fn replay_resumes_after_last_seq() {
+        let registry = Arc::new(GenerationRegistry::default());
+        let (chat_id, request_id) = (Uuid::new_v4(), Uuid::new_v4());
+        let (generation, _, _) = registry.register(chat_id, request_id).unwrap();
+        generation.push(ChatEvent::Error("a".into()));
+        generation.push(ChatEvent::Error("b".into()));
+
+        // 读到第一个事件后断开
+        let mut first = vec![];
+        generation
+            .replay(0, |json| {
+                first.push(json);
+                false
+            })
+            .await;
+        assert_eq!(seqs(&first), [1]);
+
+        // 重连后从断开的位置继续，并接收之后的新事件
+        let resumed = tokio::spawn({
+            let generation = generation.clone();
+            async move { replay_all(&generation, 1).await }
+        });
+        tokio::task::yield_now().await;
+        generation.push(ChatEvent::StreamEnd {});
+        registry.finish(&generation);
+        assert_eq!(resumed.await.unwrap(), [2, 3]);
+
+        // 结束后仍然可以补发
+        assert_eq!(replay_all(&generation, 0).await, [1, 2, 3]);
+        assert_eq!(replay_all(&generation, 2).await, [3]);
+        assert!(replay_all(&generation, 3).await.is_empty());
+        assert!(registry.get(request_id).is_some());
+    }
+
+    #[tokio::test]
+    async fn one_running_generation_per_chat() {
+        let registry = Arc::new(GenerationRegistry::default());
+        let chat_id = Uuid::new_v4();
+        let (generation, _, _) = registry.register(chat_id, Uuid::new_v4()).unwrap();
+        assert!(registry.is_running(chat_id));
+        assert!(registry.register(chat_id, Uuid::new_v4()).is_none());
+        assert!(registry.register(Uuid::new_v4(), Uuid::new_v4()).is_some());
+
+        registry.finish(&generation);
+        assert!(!registry.is_running(chat_id));
+        assert!(registry.register(chat_id, Uuid::new_v4()).is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn finished_generation_expires() {
+        let registry = Arc::new(GenerationRegistry::default());
+        let request_id = Uuid::new_v4();
+        let (generation, _, _) = registry.register(Uuid::new_v4(), request_id).unwrap();
+        registry.finish(&generation);
+
+        tokio::time::sleep(FINISHED_TTL - Duration::from_secs(1)).await;
+        assert!(registry.get(request_id).is_some());
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert!(registry.get(request_id).is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cancel_aborts_after_grace() {
+        let registry = GenerationRegistry::default();
+        let request_id = Uuid::new_v4();
+        let (_, token, abort_reg) = registry.register(Uuid::new_v4(), request_id).unwrap();
+        let task = tokio::spawn(Abortable::new(futures::future::pending::<()>(), abort_reg));
+
+        assert!(registry.cancel(request_id));
+        assert!(token.is_cancelled());
+        tokio::time::sleep(CANCEL_GRACE - Duration::from_secs(1)).await;
+        assert!(!task.is_finished());
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert!(task.await.unwrap().is_err());
+        assert!(!registry.cancel(Uuid::new_v4()));
+    }
+}
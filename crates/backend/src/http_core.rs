@@ -3,30 +3,33 @@ use std::{collections::HashMap, sync::Arc};
 use anyhow::{Error, Result};
 use axum::{
     Json,
+    body::Body,
     extract::{
         Multipart, Path, Query, State, WebSocketUpgrade,
         ws::{Message, WebSocket},
     },
-    http::{HeaderMap, StatusCode, header::CONTENT_TYPE},
+    http::{
+        HeaderMap, HeaderValue, StatusCode,
+        header::{CONTENT_DISPOSITION, CONTENT_LENGTH, CONTENT_TYPE},
+    },
     response::{IntoResponse, Response},
 };
 use chat_ui::*;
 use futures::{
-    SinkExt, Stream, StreamExt,
+    SinkExt, Stream, StreamExt, TryStreamExt,
     stream::{AbortHandle, AbortRegistration, Abortable},
 };
 use serde::{Deserialize, Serialize};
 use tokio::sync::mpsc::{self, UnboundedSender};
-use tokio_util::sync::CancellationToken;
+use tokio_util::io::{ReaderStream, StreamReader, SyncIoBridge};
 
 use uuid::Uuid;
 
-use crate::AppState;
+use crate::{
+    AppState,
+    generation::{Generation, StreamPacket},
+};
 
-struct TaskControl {
-    abort: AbortHandle,
-    token: CancellationToken,
-}
 #[derive(Deserialize)]
 pub struct ToolCallRequest {
     args: String,
@@ -45,6 +48,7 @@ pub async fn call_tool_handler(
         use_id: Uuid::new_v4(),
         function_name: name,
         args: payload.args,
+        call_id: None,
     };
     let result_message = state.llm.call_tool(tool_use).await;
     Json(result_message).into_response()
@@ -77,29 +81,28 @@ pub enum ClientRequest {
         new_content: Vec<MessageContent>, // 用户修改后的新内容
         config: Option<LLMConfig>,
     },
+    /// 从被中断的assistant消息继续生成
+    Continue {
+        request_id: Uuid,
+        chat_id: Uuid,
+        config: Option<LLMConfig>,
+    },
     /// 终止生成
     Abort { request_id: Uuid, chat_id: Uuid },
-}
-
-#[derive(serde::Serialize)]
-struct StreamPacket {
-    chat_id: Uuid,
-    request_id: Uuid,
-    #[serde(flatten)]
-    event: ChatEvent,
+    /// 断线重连后，补发`last_seq`之后的事件并继续接收
+    Resume { request_id: Uuid, last_seq: u64 },
 }
 
 // 内部循环事件
 enum LoopEvent {
-    // Worker 线程生成好 JSON 字符串发给主线程
+    // 转发任务把 JSON 字符串发给主线程
     InternalMsg(String),
-    // 任务完成/失败信号，用于清理 Map
+    // 转发结束信号，用于清理 Map
     TaskFinished(Uuid),
 }
+
 async fn handle_stream(
-    chat_id: Uuid,
-    request_id: Uuid,
-    tx: UnboundedSender<LoopEvent>,
+    generation: &Generation,
     stream: Result<impl Stream<Item = Result<ChatEvent, Error>>, Error>,
     abort_reg: AbortRegistration,
 ) {
@@ -110,54 +113,87 @@ async fn handle_stream(
 
             while let Some(event_result) = stream.next().await {
                 match event_result {
-                    Ok(event) => {
-                        let packet = StreamPacket {
-                            chat_id,
-                            request_id,
-                            event,
-                        };
-                        if let Ok(json) = serde_json::to_string(&packet) {
-                            // 发送给主循环，如果通道已关(主循环挂了)则退出
-                            if tx.send(LoopEvent::InternalMsg(json)).is_err() {
-                                break;
-                            }
-                        }
-                    }
+                    Ok(event) => generation.push(event),
                     Err(e) => {
-                        tracing::error!("Stream error in task {}: {}", request_id, e);
-                        let error_packet = StreamPacket {
-                            chat_id,
-                            request_id,
-                            event: ChatEvent::Error(e.to_string()),
-                        };
-                        if let Ok(json) = serde_json::to_string(&error_packet) {
-                            let _ = tx.send(LoopEvent::InternalMsg(json));
-                        }
+                        tracing::error!("Stream error in chat {}: {}", generation.chat_id(), e);
+                        generation.push(ChatEvent::Error(e.to_string()));
                         break;
                     }
                 }
             }
 
             // 发送结束包 (StreamEnd)
-            let end_packet = StreamPacket {
-                chat_id,
-                request_id,
-                event: ChatEvent::StreamEnd {},
-            };
-            if let Ok(json) = serde_json::to_string(&end_packet) {
-                let _ = tx.send(LoopEvent::InternalMsg(json));
-            }
+            generation.push(ChatEvent::StreamEnd {});
         }
         Err(e) => {
-            tracing::error!("Failed to initialize stream for {}: {}", request_id, e);
-            let error_packet = StreamPacket {
-                chat_id,
-                request_id,
-                event: ChatEvent::Error(e.to_string()),
-            };
-            if let Ok(json) = serde_json::to_string(&error_packet) {
-                let _ = tx.send(LoopEvent::InternalMsg(json));
-            }
+            tracing::error!(
+                "Failed to initialize stream for chat {}: {}",
+                generation.chat_id(),
+                e
+            );
+            generation.push(ChatEvent::Error(e.to_string()));
+        }
+    }
+}
+
+/// 把生成任务中`last_seq`之后的事件转发到当前连接
+/// 返回的AbortHandle只停止转发，不影响生成本身
+fn forward_generation(
+    generation: Arc<Generation>,
+    request_id: Uuid,
+    last_seq: u64,
+    tx: UnboundedSender<LoopEvent>,
+) -> AbortHandle {
+    let (abort_handle, abort_reg) = AbortHandle::new_pair();
+    tokio::spawn(Abortable::new(
+        async move {
+            generation
+                .replay(last_seq, |json| {
+                    tx.send(LoopEvent::InternalMsg(json)).is_ok()
+                })
+                .await;
+            let _ = tx.send(LoopEvent::TaskFinished(request_id));
+        },
+        abort_reg,
+    ));
+    abort_handle
+}
+
+/// 找不到要恢复的任务 (已经结束并过期，或者服务器重启)
+fn resume_not_found(request_id: Uuid, tx: &UnboundedSender<LoopEvent>) {
+    for event in [
+        ChatEvent::Error(format!("Generation {} not found or expired", request_id)),
+        ChatEvent::StreamEnd {},
+    ] {
+        let packet = StreamPacket {
+            chat_id: Uuid::nil(),
+            request_id,
+            seq: 0,
+            event,
+        };
+        if let Ok(json) = serde_json::to_string(&packet) {
+            let _ = tx.send(LoopEvent::InternalMsg(json));
+        }
+    }
+}
+
+/// 会话上已经有还没结束的生成任务，需要先终止它
+fn generation_busy(request_id: Uuid, chat_id: Uuid, tx: &UnboundedSender<LoopEvent>) {
+    for event in [
+        ChatEvent::Error(format!(
+            "Chat {} already has a running generation, abort it first",
+            chat_id
+        )),
+        ChatEvent::StreamEnd {},
+    ] {
+        let packet = StreamPacket {
+            chat_id,
+            request_id,
+            seq: 0,
+            event,
+        };
+        if let Ok(json) = serde_json::to_string(&packet) {
+            let _ = tx.send(LoopEvent::InternalMsg(json));
         }
     }
 }
@@ -169,7 +205,8 @@ async fn handle_socket(socket: WebSocket, state: Arc<AppState>) {
 
     let (tx, mut rx) = mpsc::unbounded_channel::<LoopEvent>();
 
-    let mut tasks: HashMap<Uuid, TaskControl> = HashMap::new();
+    // 当前连接上的转发任务，生成任务本身由 state.generations 管理
+    let mut forwards: HashMap<Uuid, AbortHandle> = HashMap::new();
 
     loop {
         tokio::select! {
@@ -188,9 +225,17 @@ async fn handle_socket(socket: WebSocket, state: Arc<AppState>) {
                         match req {
                             ClientRequest::Abort { request_id, chat_id } => {
                                 tracing::info!("Abort request received for req: {}, chat: {}", request_id, chat_id);
-                                if let Some(control) = tasks.remove(&request_id) {
-                                    control.token.cancel();
-                                    control.abort.abort();
+                                state.generations.cancel(request_id);
+                            }
+                            ClientRequest::Resume { request_id, last_seq } => {
+                                tracing::info!("Resume request: {}, after seq {}", request_id, last_seq);
+                                match state.generations.get(request_id) {
+                                    Some(generation) => {
+                                        if let Some(old) = forwards.insert(request_id, forward_generation(generation, request_id, last_seq, tx.clone())) {
+                                            old.abort();
+                                        }
+                                    }
+                                    None => resume_not_found(request_id, &tx),
                                 }
                             }
                             ClientRequest::Chat { request_id, chat_id, content, config } => {
@@ -198,30 +243,31 @@ async fn handle_socket(socket: WebSocket, state: Arc<AppState>) {
 
 
                                 let state = state.clone();
-                                let tx = tx.clone();
-                                let token = CancellationToken::new();
-
                                 let llm_config = config.map(|x| x.merge_in(state.config.clone())).unwrap_or(state.config.clone());
 
-                                let (abort_handle, abort_reg) = AbortHandle::new_pair();
-                                tasks.insert(request_id, TaskControl { abort: abort_handle, token: token.clone() });
+                                let Some((generation, token, abort_reg)) = state.generations.register(chat_id, request_id) else {
+                                    generation_busy(request_id, chat_id, &tx);
+                                    continue;
+                                };
+                                forwards.insert(request_id, forward_generation(generation.clone(), request_id, 0, tx.clone()));
 
                                 tokio::spawn(async move {
                                     let stream_result = state.llm.send_chat_message(chat_id, content, llm_config, token).await;
-                                    handle_stream(chat_id, request_id, tx.clone(), stream_result, abort_reg).await;
-                                    let _ = tx.send(LoopEvent::TaskFinished(request_id));
+                                    handle_stream(&generation, stream_result, abort_reg).await;
+                                    state.generations.finish(&generation);
                                 });
                             }
                             ClientRequest::Regenerate { request_id, chat_id, message_id, config } => {
                                 tracing::info!("Regenerate request: {}, msg: {}", request_id, message_id);
 
                                 let state = state.clone();
-                                let tx = tx.clone();
 
-                                let token = CancellationToken::new();
-                                let (abort_handle, abort_reg) = AbortHandle::new_pair();
+                                let Some((generation, token, abort_reg)) = state.generations.register(chat_id, request_id) else {
+                                    generation_busy(request_id, chat_id, &tx);
+                                    continue;
+                                };
                                 let llm_config = config.map(|x| x.merge_in(state.config.clone())).unwrap_or(state.config.clone());
-                                tasks.insert(request_id, TaskControl { abort: abort_handle, token: token.clone() });
+                                forwards.insert(request_id, forward_generation(generation.clone(), request_id, 0, tx.clone()));
                                 tokio::spawn(async move {
                                         let stream_result = state.llm.regenerate_at(
                                             chat_id,
@@ -229,18 +275,35 @@ async fn handle_socket(socket: WebSocket, state: Arc<AppState>) {
                                             llm_config,
                                             token
                                         ).await;
-                                        handle_stream(chat_id, request_id, tx.clone(), stream_result, abort_reg).await;
-                                        let _ = tx.send(LoopEvent::TaskFinished(request_id));
+                                        handle_stream(&generation, stream_result, abort_reg).await;
+                                        state.generations.finish(&generation);
+                                });
+                            }
+                            ClientRequest::Continue { request_id, chat_id, config } => {
+                                tracing::info!("Continue request: {}, chat: {}", request_id, chat_id);
+                                let state = state.clone();
+
+                                let Some((generation, token, abort_reg)) = state.generations.register(chat_id, request_id) else {
+                                    generation_busy(request_id, chat_id, &tx);
+                                    continue;
+                                };
+                                let llm_config = config.map(|x| x.merge_in(state.config.clone())).unwrap_or(state.config.clone());
+                                forwards.insert(request_id, forward_generation(generation.clone(), request_id, 0, tx.clone()));
+                                tokio::spawn(async move {
+                                    let stream_result = state.llm.continue_generation(chat_id, llm_config, token).await;
+                                    handle_stream(&generation, stream_result, abort_reg).await;
+                                    state.generations.finish(&generation);
                                 });
                             }
                                 ClientRequest::Edit { request_id, chat_id, message_id, new_content, config } => {
                                         tracing::info!("Edit request: {}", message_id);
                                         let state = state.clone();
-                                        let tx = tx.clone();
 
-                                        let token = CancellationToken::new();
-                                        let (abort_handle, abort_reg) = AbortHandle::new_pair();
-                                        tasks.insert(request_id, TaskControl { abort: abort_handle, token: token.clone() });
+                                        let Some((generation, token, abort_reg)) = state.generations.register(chat_id, request_id) else {
+                                            generation_busy(request_id, chat_id, &tx);
+                                            continue;
+                                        };
+                                        forwards.insert(request_id, forward_generation(generation.clone(), request_id, 0, tx.clone()));
                                         let llm_config = config.map(|x| x.merge_in(state.config.clone())).unwrap_or(state.config.clone());
 
                                         tokio::spawn(async move {
@@ -252,8 +315,8 @@ async fn handle_socket(socket: WebSocket, state: Arc<AppState>) {
                                                 token
                                             ).await;
 
-                                            handle_stream(chat_id, request_id, tx.clone(), stream_result, abort_reg).await;
-                                            let _ = tx.send(LoopEvent::TaskFinished(request_id));
+                                            handle_stream(&generation, stream_result, abort_reg).await;
+                                            state.generations.finish(&generation);
                                         });
                             }
                         }
@@ -280,8 +343,8 @@ async fn handle_socket(socket: WebSocket, state: Arc<AppState>) {
                         }
                     }
                     Some(LoopEvent::TaskFinished(req_id)) => {
-                        // 任务自然结束或出错结束，清理 Map
-                        tasks.remove(&req_id);
+                        // 转发结束，清理 Map
+                        forwards.remove(&req_id);
                     }
                     None => {
                         break;
@@ -291,11 +354,11 @@ async fn handle_socket(socket: WebSocket, state: Arc<AppState>) {
         }
     }
 
-    // 循环退出（连接断开），强制终止所有还在运行的任务
-    if !tasks.is_empty() {
-        tracing::info!("Cleaning up {} active tasks", tasks.len());
-        for (_, control) in tasks {
-            control.abort.abort();
+    // 循环退出（连接断开），只停止转发，生成任务继续在后台运行
+    if !forwards.is_empty() {
+        tracing::info!("Detaching {} active generations", forwards.len());
+        for (_, forward) in forwards {
+            forward.abort();
         }
     }
 }
@@ -343,6 +406,27 @@ pub async fn get_history_handler(
     }
 }
 
+#[derive(Debug, Deserialize)]
+pub struct SearchParams {
+    pub q: String,
+    pub limit: Option<usize>,
+    pub offset: Option<usize>,
+}
+
+pub async fn search_history_handler(
+    State(state): State<Arc<AppState>>,
+    Query(param): Query<SearchParams>,
+) -> Response {
+    match state.llm.search_history(&param.q, param.limit, param.offset) {
+        Ok(results) => Json(results).into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Database error {}", e),
+        )
+            .into_response(),
+    }
+}
+
 pub async fn delete_chat_handler(
     State(state): State<Arc<AppState>>,
     Path(uuid): Path<Uuid>,
@@ -375,6 +459,195 @@ pub async fn get_chat_handler(
     }
 }
 
+/// 列出与`message_id`同一个父消息下的所有分支
+pub async fn list_branches_handler(
+    State(state): State<Arc<AppState>>,
+    Path((chat_id, message_id)): Path<(Uuid, Uuid)>,
+) -> Response {
+    match state.llm.list_branches(chat_id, message_id) {
+        Ok(branches) => Json(branches).into_response(),
+        Err(e) => {
+            tracing::warn!("Failed to list branches: {}", e);
+            (StatusCode::NOT_FOUND, format!("{}", e)).into_response()
+        }
+    }
+}
+
+/// 切换到`message_id`所在的分支，返回切换后的对话
+pub async fn switch_branch_handler(
+    State(state): State<Arc<AppState>>,
+    Path((chat_id, message_id)): Path<(Uuid, Uuid)>,
+) -> Response {
+    if state.generations.is_running(chat_id) {
+        return (StatusCode::CONFLICT, "Chat is generating").into_response();
+    }
+    match state.llm.switch_branch(chat_id, message_id) {
+        Ok(chat) => Json(chat).into_response(),
+        Err(e) => {
+            tracing::warn!("Failed to switch branch: {}", e);
+            (StatusCode::BAD_REQUEST, format!("{}", e)).into_response()
+        }
+    }
+}
+
+/// 删除一个不活跃的分支，返回删除后的对话
+pub async fn prune_branch_handler(
+    State(state): State<Arc<AppState>>,
+    Path((chat_id, message_id)): Path<(Uuid, Uuid)>,
+) -> Response {
+    if state.generations.is_running(chat_id) {
+        return (StatusCode::CONFLICT, "Chat is generating").into_response();
+    }
+    match state.llm.prune_branch(chat_id, message_id) {
+        Ok(chat) => Json(chat).into_response(),
+        Err(e) => {
+            tracing::warn!("Failed to prune branch: {}", e);
+            (StatusCode::BAD_REQUEST, format!("{}", e)).into_response()
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExportParams {
+    /// 逗号分隔的会话id，为空时导出所有会话
+    pub ids: Option<String>,
+}
+
+/// 把会话和它们引用的数据打包为一个归档文件下载
+pub async fn export_chats_handler(
+    State(state): State<Arc<AppState>>,
+    Query(param): Query<ExportParams>,
+) -> Response {
+    let ids = match param
+        .ids
+        .as_deref()
+        .unwrap_or_default()
+        .split(',')
+        .filter(|s| !s.is_empty())
+        .map(Uuid::parse_str)
+        .collect::<Result<Vec<_>, _>>()
+    {
+        Ok(ids) => ids,
+        Err(e) => {
+            return (StatusCode::BAD_REQUEST, format!("Invalid chat id: {}", e)).into_response();
+        }
+    };
+    // 开始写出之后就无法再返回错误状态，先检查会话是否存在
+    for id in ids.iter() {
+        match state.llm.get_chat(*id) {
+            Ok(Some(_)) => {}
+            Ok(None) => {
+                return (StatusCode::NOT_FOUND, format!("Chat {} not found", id)).into_response();
+            }
+            Err(e) => {
+                tracing::error!("Failed to get chat entry: {}", e);
+                return (StatusCode::INTERNAL_SERVER_ERROR, "Database error").into_response();
+            }
+        }
+    }
+
+    let (writer, reader) = tokio::io::duplex(64 * 1024);
+    let mut writer = SyncIoBridge::new(writer);
+    let llm = state.llm.clone();
+    tokio::task::spawn_blocking(move || {
+        // 出错时归档被截断，导入时会校验失败
+        if let Err(e) = llm.export_chats(&ids, &mut writer) {
+            tracing::error!("Failed to export chats: {}", e);
+        }
+    });
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("application/octet-stream"),
+    );
+    headers.insert(CONTENT_DISPOSITION, content_disposition("chats.qlens"));
+    (headers, Body::from_stream(ReaderStream::new(reader))).into_response()
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReportParams {
+    pub format: ReportFormat,
+    /// 默认不包含思考过程
+    pub reasoning: Option<bool>,
+}
+
+/// 把会话导出为Markdown压缩包或单个HTML文件
+pub async fn export_report_handler(
+    State(state): State<Arc<AppState>>,
+    Path(uuid): Path<Uuid>,
+    Query(param): Query<ReportParams>,
+) -> Response {
+    let options = ReportOptions {
+        reasoning: param.reasoning.unwrap_or(false),
+    };
+    let llm = state.llm.clone();
+    match tokio::task::spawn_blocking(move || llm.export_report(uuid, param.format, &options)).await
+    {
+        Ok(Ok(Some(report))) => {
+            let mut headers = HeaderMap::new();
+            headers.insert(CONTENT_TYPE, HeaderValue::from_static(report.mime));
+            headers.insert(CONTENT_DISPOSITION, content_disposition(&report.filename));
+            (headers, report.data).into_response()
+        }
+        Ok(Ok(None)) => (StatusCode::NOT_FOUND, "Chat not found").into_response(),
+        Ok(Err(e)) => {
+            tracing::error!("Failed to export chat {}: {}", uuid, e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Export failed: {}", e),
+            )
+                .into_response()
+        }
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("{}", e)).into_response(),
+    }
+}
+
+/// 导入`export_chats_handler`导出的归档，请求体为归档文件本身
+pub async fn import_chats_handler(State(state): State<Arc<AppState>>, body: Body) -> Response {
+    let reader = StreamReader::new(body.into_data_stream().map_err(std::io::Error::other));
+    let mut reader = SyncIoBridge::new(reader);
+    let llm = state.llm.clone();
+    match tokio::task::spawn_blocking(move || llm.import_chats(&mut reader)).await {
+        Ok(Ok(report)) => Json(report).into_response(),
+        Ok(Err(e)) => {
+            tracing::warn!("Failed to import chats: {}", e);
+            (StatusCode::BAD_REQUEST, format!("Import failed: {}", e)).into_response()
+        }
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("{}", e)).into_response(),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FsckParams {
+    /// 默认只检查不修复
+    pub dry_run: Option<bool>,
+}
+
+/// 检查图片和附件的引用计数，`dry_run=false`时修复
+pub async fn fsck_handler(
+    State(state): State<Arc<AppState>>,
+    Query(param): Query<FsckParams>,
+) -> Response {
+    let dry_run = param.dry_run.unwrap_or(true);
+    // 生成中保存的图片还没有写入消息，修复会把它们当作没有引用而删除
+    if !dry_run && state.generations.any_running() {
+        return (StatusCode::CONFLICT, "Generation in progress").into_response();
+    }
+    let llm = state.llm.clone();
+    match tokio::task::spawn_blocking(move || llm.fsck(dry_run)).await {
+        Ok(Ok(report)) => Json(report).into_response(),
+        Ok(Err(e)) => {
+            tracing::error!("Failed to check blob storages: {}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Database error {}", e),
+            )
+                .into_response()
+        }
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("{}", e)).into_response(),
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct UploadResponse {
     file: String,
@@ -385,17 +658,12 @@ pub async fn download_image(
     State(state): State<Arc<AppState>>,
     Path(uuid): Path<AssetId>,
 ) -> impl IntoResponse {
-    match state.llm.get_image(uuid) {
-        Ok(Some(bytes)) => {
+    match state.llm.open_image_with_mime(uuid) {
+        Ok(Some((blob, mime))) => {
             let mut headers = HeaderMap::new();
-            headers.insert(
-                CONTENT_TYPE,
-                guess_content_type(&bytes)
-                    .unwrap_or("image/jpeg")
-                    .parse()
-                    .unwrap(),
-            );
-            (headers, bytes).into_response()
+            headers.insert(CONTENT_TYPE, HeaderValue::from_static(mime));
+            headers.insert(CONTENT_LENGTH, blob.len.into());
+            (headers, Body::from_stream(ReaderStream::new(blob.reader))).into_response()
         }
         Ok(None) => (StatusCode::NOT_FOUND, "Image not found").into_response(),
         Err(e) => {
@@ -409,11 +677,22 @@ pub async fn download_asset_handler(
     State(state): State<Arc<AppState>>,
     Path(uuid): Path<AssetId>,
 ) -> impl IntoResponse {
-    match state.llm.get_asset(uuid) {
-        Ok(Some(bytes)) => {
+    match state.llm.open_asset(uuid) {
+        Ok(Some((blob, meta))) => {
             let mut headers = HeaderMap::new();
-            headers.insert(CONTENT_TYPE, "application/octet-stream".parse().unwrap());
-            (headers, bytes).into_response()
+            let mime = meta
+                .as_ref()
+                .and_then(|m| m.mime.as_deref())
+                .and_then(|m| HeaderValue::from_str(m).ok())
+                .unwrap_or(HeaderValue::from_static("application/octet-stream"));
+            let name = match meta {
+                Some(ref meta) => meta.download_name(uuid),
+                None => uuid.to_string(),
+            };
+            headers.insert(CONTENT_TYPE, mime);
+            headers.insert(CONTENT_LENGTH, blob.len.into());
+            headers.insert(CONTENT_DISPOSITION, content_disposition(&name));
+            (headers, Body::from_stream(ReaderStream::new(blob.reader))).into_response()
         }
         Ok(None) => (StatusCode::NOT_FOUND, "Asset not found").into_response(),
         Err(e) => {
@@ -423,6 +702,47 @@ pub async fn download_asset_handler(
     }
 }
 
+/// 附件的MIME类型、原始文件名、尺寸和来源
+pub async fn asset_meta_handler(
+    State(state): State<Arc<AppState>>,
+    Path(uuid): Path<AssetId>,
+) -> Response {
+    match state.llm.asset_meta(uuid) {
+        Ok(Some(meta)) => Json(meta).into_response(),
+        Ok(None) => (StatusCode::NOT_FOUND, "Asset metadata not found").into_response(),
+        Err(e) => {
+            tracing::error!("Failed to retrieve metadata of asset {}: {}", uuid, e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Database error").into_response()
+        }
+    }
+}
+
+/// `attachment`，非ASCII的文件名用RFC 5987编码放在`filename*`中
+fn content_disposition(name: &str) -> HeaderValue {
+    let fallback: String = name
+        .chars()
+        .map(|c| match c {
+            '"' | '\\' => '_',
+            c if c.is_ascii() && !c.is_ascii_control() => c,
+            _ => '_',
+        })
+        .collect();
+    let encoded: String = name
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect();
+    HeaderValue::from_str(&format!(
+        "attachment; filename=\"{}\"; filename*=UTF-8''{}",
+        fallback, encoded
+    ))
+    .unwrap_or(HeaderValue::from_static("attachment"))
+}
+
 pub async fn upload_asset_handler(
     State(state): State<Arc<AppState>>,
     mut multipart: Multipart,
@@ -438,19 +758,22 @@ pub async fn upload_asset_handler(
 
                 tracing::debug!("开始接收文件: {}", file_name);
 
-                let data = match field.bytes().await {
-                    Ok(data) => data,
-                    Err(e) => {
-                        tracing::warn!("Failed to read stream {}: {}", file_name, e);
-                        let error_msg = format!("Failed to read data for {}: {}", file_name, e);
-                        return (StatusCode::BAD_REQUEST, error_msg).into_response();
-                    }
-                };
-                let uuid = match state.llm.save_asset(&data) {
+                let mut meta = BlobMeta::uploaded();
+                if let Some(name) = field.file_name() {
+                    meta = meta.with_filename(name);
+                }
+                if let Some(mime) = field.content_type() {
+                    meta = meta.with_mime(mime);
+                }
+
+                // 边接收边写入存储，客户端断开时同样在这里报错
+                let reader = StreamReader::new(field.map_err(std::io::Error::other));
+                tokio::pin!(reader);
+                let uuid = match state.llm.save_asset_stream(&mut reader, meta).await {
                     Ok(uuid) => uuid,
                     Err(e) => {
                         tracing::error!("Unable save {} to database: {}", file_name, e);
-                        let error_msg = "Failed to save asset to database".to_string();
+                        let error_msg = format!("Failed to save {}: {}", file_name, e);
                         return (StatusCode::INTERNAL_SERVER_ERROR, error_msg).into_response();
                     }
                 };
@@ -478,11 +801,6 @@ pub async fn upload_asset_handler(
     Json(responses).into_response()
 }
 
-fn guess_content_type(input_data: &[u8]) -> Result<&str, anyhow::Error> {
-    let format = image::guess_format(&input_data)?;
-    Ok(format.to_mime_type())
-}
-
 pub async fn upload_image(
     State(state): State<Arc<AppState>>,
     mut multipart: Multipart,
@@ -498,6 +816,10 @@ pub async fn upload_image(
 
                 tracing::debug!("开始接收文件: {}", file_name);
 
+                let mut meta = BlobMeta::uploaded();
+                if let Some(name) = field.file_name() {
+                    meta = meta.with_filename(name);
+                }
                 let data = match field.bytes().await {
                     Ok(data) => data,
                     Err(e) => {
@@ -506,14 +828,7 @@ pub async fn upload_image(
                         return (StatusCode::BAD_REQUEST, error_msg).into_response();
                     }
                 };
-                let data = match chat_ui::convert_to_png(data.to_vec()) {
-                    Ok(png_bytes) => png_bytes.to_vec(),
-                    Err(e) => {
-                        tracing::warn!("Failed to convert image to PNG: {}, keeping original", e);
-                        data.to_vec()
-                    }
-                };
-                let uuid = match state.llm.save_image(&data) {
+                let uuid = match state.llm.ingest_image(data.to_vec(), meta) {
                     Ok(uuid) => uuid,
                     Err(e) => {
                         tracing::error!("Unable save {} to database: {}", file_name, e);
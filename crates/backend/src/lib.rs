@@ -1,4 +1,6 @@
+mod generation;
 mod http_core;
+use generation::GenerationRegistry;
 use http_core::*;
 use std::sync::Arc;
 
@@ -14,13 +16,41 @@ use tower_http::{
     cors::{Any, CorsLayer},
     limit::RequestBodyLimitLayer,
 };
+/// 普通请求的大小上限
+const REQUEST_BODY_LIMIT: usize = 20 * 1000 * 1000;
+/// 流式上传的大小上限
+/// 附件只有在文件存储和SQLite中才不经过内存，sled和redb仍然使用`REQUEST_BODY_LIMIT`
+/// 导入的归档逐条读取，内存占用只取决于单个数据的大小
+const UPLOAD_BODY_LIMIT: usize = 4 * 1000 * 1000 * 1000;
+
 struct AppState {
     llm: LLMProvider<OpenAIConfig>,
     config: LLMConfig,
+    generations: Arc<GenerationRegistry>,
 }
 
 pub fn get_http_router(llm: LLMProvider<OpenAIConfig>, config: LLMConfig) -> Router {
-    let llm = AppState { llm, config };
+    let asset_limit = if llm.streams_assets() {
+        UPLOAD_BODY_LIMIT
+    } else {
+        REQUEST_BODY_LIMIT
+    };
+    let state = Arc::new(AppState {
+        llm,
+        config,
+        generations: Arc::new(GenerationRegistry::default()),
+    });
+
+    let uploads = Router::new()
+        .route(
+            "/api/asset",
+            post(upload_asset_handler).layer(RequestBodyLimitLayer::new(asset_limit)),
+        )
+        .route(
+            "/api/history/import",
+            post(import_chats_handler).layer(RequestBodyLimitLayer::new(UPLOAD_BODY_LIMIT)),
+        )
+        .with_state(state.clone());
 
     Router::new()
         .route("/api/tools", get(list_tools_handler))
@@ -29,15 +59,27 @@ pub fn get_http_router(llm: LLMProvider<OpenAIConfig>, config: LLMConfig) -> Rou
         .route("/api/chat", get(chat_handler))
         .route("/api/chat/new", post(new_chat_handler))
         .route("/api/history", get(get_history_handler))
+        .route("/api/history/search", get(search_history_handler))
+        .route("/api/history/export", get(export_chats_handler))
         .route(
             "/api/history/{id}",
             get(get_chat_handler).delete(delete_chat_handler),
         )
+        .route("/api/history/{id}/export", get(export_report_handler))
+        .route(
+            "/api/history/{id}/branches/{message_id}",
+            get(list_branches_handler)
+                .post(switch_branch_handler)
+                .delete(prune_branch_handler),
+        )
+        .route("/api/admin/fsck", post(fsck_handler))
         .route("/api/asset/{id}", get(download_asset_handler))
-        .route("/api/asset", post(upload_asset_handler))
+        .route("/api/asset/{id}/meta", get(asset_meta_handler))
         .route("/api/image/{id}", get(download_image))
         .route("/api/image", post(upload_image))
-        .with_state(Arc::new(llm))
+        .layer(RequestBodyLimitLayer::new(REQUEST_BODY_LIMIT))
+        .with_state(state)
+        .merge(uploads)
         .layer(
             CorsLayer::new()
                 .allow_origin(Any)
@@ -46,5 +88,4 @@ pub fn get_http_router(llm: LLMProvider<OpenAIConfig>, config: LLMConfig) -> Rou
         )
         .layer(CompressionLayer::new())
         .layer(DefaultBodyLimit::disable())
-        .layer(RequestBodyLimitLayer::new(20 * 1000 * 1000))
 }
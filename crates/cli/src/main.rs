@@ -2,7 +2,10 @@ use std::{io::Read, net::{IpAddr, Ipv4Addr, SocketAddr}, str::FromStr};
 
 use async_openai::{Client, config::OpenAIConfig};
 use axum::{http::{StatusCode, Uri, header}, response::{Html, IntoResponse, Response}};
-use chat_ui::{LLMConfig, LLMProvider, StorageKind, ToolKind};
+use chat_ui::{
+    BlobStoreKind, FsBlobOptions, ImageFormatPolicy, JsLimits, JsSessionOptions, LLMConfig, LLMProvider, ProviderOptions, StorageKind, ToolCallMode,
+    ToolFormatKind, ToolKind,
+};
 use clap::Parser;
 use serde::{Deserialize, Serialize};
 use tracing::Level;
@@ -73,6 +76,56 @@ struct Arguments {
     )]
     parallel_function_call: bool,
 
+    #[clap(
+        long,
+        default_value_t = ToolCallMode::Text,
+        help = "How tools are exposed to LLM: `text` (✿FUNCTION✿ markers in prompt) or `native` (OpenAI tool_calls)"
+    )]
+    #[serde(default)]
+    tool_call_mode: ToolCallMode,
+
+    #[clap(
+        long,
+        help = "Tool call markers in `text` mode: qwen, hermes or llama3. Guessed from model name if not set"
+    )]
+    tool_call_format: Option<ToolFormatKind>,
+
+    #[arg(
+        long,
+        help = "Max rounds of tool calls in one reply, then the model is asked to answer directly. Unlimited if not set"
+    )]
+    max_tool_rounds: Option<u32>,
+    #[arg(long, help = "Max number of tool invocations in one reply")]
+    max_tool_calls: Option<u32>,
+    #[arg(long, help = "Max seconds spent on one reply before tools are disabled")]
+    max_duration_secs: Option<u64>,
+    #[arg(
+        long,
+        help = "Max seconds one tool call may run before it is aborted. No deadline if not set"
+    )]
+    tool_timeout_secs: Option<u64>,
+
+    #[arg(
+        long,
+        help = "Token budget of the prompt, should be less than `--ctx-size` of the LLM server. Old tool images and turns are dropped when exceeded"
+    )]
+    context_token_budget: Option<usize>,
+    #[arg(
+        long,
+        default_value = "false",
+        help = "Ask LLM to summarize turns dropped by `--context-token-budget`"
+    )]
+    #[serde(default)]
+    context_summarize: bool,
+
+    #[arg(
+        long,
+        help = "Downscale images sent to LLM to at most this many pixels (rounded to multiples of 32). Images in database are untouched"
+    )]
+    image_max_pixels: Option<u64>,
+    #[arg(long, help = "Lower pixel bound used together with `--image-max-pixels`")]
+    image_min_pixels: Option<u64>,
+
     #[clap(
             long,
             value_delimiter = ',',
@@ -85,6 +138,71 @@ struct Arguments {
     #[clap(long,default_value_t = StorageKind::Sled, help = "Backend Storage")]
     backend: StorageKind,
 
+    #[clap(
+        long,
+        default_value_t = ImageFormatPolicy::Keep,
+        help = "Format of saved images: `keep` (JPEG/PNG as is, others to PNG), `png` or `jpeg`"
+    )]
+    #[serde(default)]
+    image_format: ImageFormatPolicy,
+
+    #[clap(
+        long,
+        value_delimiter = ',',
+        help = "Stores that keep large blobs as files instead of in the database: image, asset, memo"
+    )]
+    #[serde(default)]
+    fs_blob_stores: Vec<BlobStoreKind>,
+    #[arg(
+        long,
+        default_value = "65536",
+        help = "Blobs up to this many bytes stay in the database when `--fs-blob-stores` is set"
+    )]
+    #[serde(default = "default_fs_blob_threshold")]
+    fs_blob_threshold: usize,
+    #[arg(long, help = "Folder of blob files, defaults to `<database-path>_blobs`")]
+    fs_blob_dir: Option<std::path::PathBuf>,
+
+    #[arg(
+        long,
+        alias = "js-timeout-secs",
+        default_value = "30",
+        help = "Max wall-clock seconds a js_interpreter script may run before it is terminated, time spent awaiting included"
+    )]
+    #[serde(default = "default_js_wall_timeout_secs", alias = "js_timeout_secs")]
+    js_wall_timeout_secs: u64,
+    #[arg(long, default_value = "512", help = "V8 heap limit of js_interpreter in MB")]
+    #[serde(default = "default_js_max_heap_mb")]
+    js_max_heap_mb: usize,
+    #[arg(
+        long,
+        default_value = "false",
+        help = "Keep one js_interpreter environment per chat so globals and files persist between calls"
+    )]
+    #[serde(default)]
+    js_sessions: bool,
+    #[arg(
+        long,
+        default_value = "600",
+        help = "Close a chat's js_interpreter environment after this many idle seconds"
+    )]
+    #[serde(default = "default_js_session_ttl_secs")]
+    js_session_ttl_secs: u64,
+    #[arg(
+        long,
+        default_value = "8",
+        help = "Max js_interpreter environments kept at once, the least recently used is closed first"
+    )]
+    #[serde(default = "default_js_max_sessions")]
+    js_max_sessions: usize,
+    #[arg(
+        long,
+        default_value = "2048",
+        help = "Max total V8 heap of all kept js_interpreter environments in MB, limits how many are kept"
+    )]
+    #[serde(default = "default_js_max_total_heap_mb")]
+    js_max_total_heap_mb: usize,
+
     #[clap(long, value_enum, default_value_t = PromptLanguage::English)]
     system_prompt_language: PromptLanguage,
 
@@ -95,6 +213,69 @@ struct Arguments {
     )]
     #[serde(skip)]
     dump_config: bool,
+
+    #[command(subcommand)]
+    #[serde(skip)]
+    command: Option<Command>,
+}
+
+fn default_fs_blob_threshold() -> usize {
+    FsBlobOptions::default().inline_threshold
+}
+
+fn default_js_wall_timeout_secs() -> u64 {
+    JsLimits::default().wall_timeout_secs
+}
+
+fn default_js_max_heap_mb() -> usize {
+    JsLimits::default().max_heap_mb
+}
+
+fn default_js_session_ttl_secs() -> u64 {
+    JsSessionOptions::default().idle_ttl_secs
+}
+
+fn default_js_max_sessions() -> usize {
+    JsSessionOptions::default().max_sessions
+}
+
+fn default_js_max_total_heap_mb() -> usize {
+    JsSessionOptions::default().max_total_heap_mb
+}
+
+#[derive(clap::Subcommand, Debug, Clone)]
+enum Command {
+    /// Check reference counts of images and assets against chat history, then exit. Stop the server first
+    Fsck {
+        #[arg(
+            long,
+            default_value_t = false,
+            help = "Rewrite wrong reference counts and delete unreferenced blobs instead of only reporting"
+        )]
+        repair: bool,
+    },
+    /// Copy chats, images, assets and memos into a database of another backend, then exit. Stop the server first
+    Migrate {
+        #[arg(long, help = "Backend of the new database")]
+        to_backend: StorageKind,
+        #[arg(long, help = "Path of the new database, must be empty or not exist")]
+        to_path: String,
+    },
+    /// Export chats with their images and assets into a single archive file, then exit
+    Export {
+        #[arg(long, help = "Path of the archive file to write")]
+        out: String,
+        #[arg(
+            long = "chat",
+            help = "Chat to export, repeat for more. Exports all chats if omitted"
+        )]
+        chats: Vec<uuid::Uuid>,
+    },
+    /// Import chats from an archive file written by `export`, then exit. Stop the server first
+    Import {
+        #[arg(help = "Path of the archive file")]
+        path: String,
+    },
 }
 
 #[derive(clap::ValueEnum, Debug, Clone, Deserialize, Serialize)]
@@ -118,6 +299,16 @@ impl PromptLanguage {
     }
 }
 
+impl Arguments {
+    fn fs_blob_options(&self) -> FsBlobOptions {
+        FsBlobOptions {
+            stores: self.fs_blob_stores.clone(),
+            inline_threshold: self.fs_blob_threshold,
+            dir: self.fs_blob_dir.clone(),
+        }
+    }
+}
+
 impl Into<LLMConfig> for Arguments {
     fn into(self) -> LLMConfig {
         LLMConfig {
@@ -133,6 +324,16 @@ impl Into<LLMConfig> for Arguments {
             parallel_function_call: Some(self.parallel_function_call),
             system_prompt_lang: self.system_prompt_language.to_lang(),
             custom_system_prompt: None,
+            tool_call_mode: Some(self.tool_call_mode),
+            tool_call_format: self.tool_call_format,
+            max_tool_rounds: self.max_tool_rounds,
+            max_tool_calls: self.max_tool_calls,
+            max_duration_secs: self.max_duration_secs,
+            tool_timeout_secs: self.tool_timeout_secs,
+            context_token_budget: self.context_token_budget,
+            context_summarize: Some(self.context_summarize),
+            image_min_pixels: self.image_min_pixels,
+            image_max_pixels: self.image_max_pixels,
         }
     }
 }
@@ -179,7 +380,27 @@ fn initialize_provider(arg: &Arguments) -> Result<LLMProvider<OpenAIConfig>, any
         .with_api_key(&arg.api_key);
     let client = Client::with_config(config);
     tracing::info!("Created openai client.");
-    let llm = LLMProvider::new(client, &arg.database_path, arg.backend, &arg.tools)?;
+    let options = ProviderOptions {
+        image_format: arg.image_format,
+        fs_blob: arg.fs_blob_options(),
+        js_limits: JsLimits {
+            wall_timeout_secs: arg.js_wall_timeout_secs,
+            max_heap_mb: arg.js_max_heap_mb,
+        },
+        js_sessions: JsSessionOptions {
+            enabled: arg.js_sessions,
+            idle_ttl_secs: arg.js_session_ttl_secs,
+            max_sessions: arg.js_max_sessions,
+            max_total_heap_mb: arg.js_max_total_heap_mb,
+        },
+    };
+    let llm = LLMProvider::new_with_options(
+        client,
+        &arg.database_path,
+        arg.backend,
+        &arg.tools,
+        options,
+    )?;
     tracing::info!("LLMProvider created.");
     Ok(llm)
 }
@@ -196,6 +417,7 @@ async fn main() -> Result<(), anyhow::Error> {
         eprintln!("{}", serde_json::to_string_pretty(&args)?);
         return Ok(());
     }
+    let command = args.command.clone();
     let args = if let Some(ref p) = args.config_file {
         tracing::info!("Load config from file {}.", p.to_string_lossy());
         let mut v = Vec::new();
@@ -205,6 +427,61 @@ async fn main() -> Result<(), anyhow::Error> {
     } else {
         args
     };
+    match command {
+        Some(Command::Fsck { repair }) => {
+            let llm = initialize_provider(&args)?;
+            let report = llm.fsck(!repair)?;
+            println!("{}", serde_json::to_string_pretty(&report)?);
+            if !report.is_clean() && !repair {
+                tracing::warn!("Inconsistent blob storages, run again with `fsck --repair` to fix.");
+            }
+            return Ok(());
+        }
+        Some(Command::Export { out, chats }) => {
+            let llm = initialize_provider(&args)?;
+            let mut writer = std::io::BufWriter::new(std::fs::File::create(&out)?);
+            llm.export_chats(&chats, &mut writer)?;
+            tracing::info!("Exported chats to {}.", out);
+            return Ok(());
+        }
+        Some(Command::Import { path }) => {
+            let llm = initialize_provider(&args)?;
+            let mut reader = std::io::BufReader::new(std::fs::File::open(&path)?);
+            let report = llm.import_chats(&mut reader)?;
+            println!("{}", serde_json::to_string_pretty(&report)?);
+            return Ok(());
+        }
+        Some(Command::Migrate {
+            to_backend,
+            to_path,
+        }) => {
+            if std::path::Path::new(&to_path) == std::path::Path::new(&args.database_path) {
+                anyhow::bail!("`--to-path` must differ from `--database-path`");
+            }
+            let from_fs = args.fs_blob_options();
+            // 新数据库的文件放在`<to-path>_blobs`，不与旧数据库共用目录
+            let to_fs = FsBlobOptions {
+                dir: None,
+                ..from_fs.clone()
+            };
+            let from = args
+                .backend
+                .create_storages_with(&args.database_path, &from_fs)?;
+            let to = to_backend.create_storages_with(&to_path, &to_fs)?;
+            let report = from.migrate_to(&to)?;
+            println!("{}", serde_json::to_string_pretty(&report)?);
+            tracing::info!(
+                "Migrated {} to {} at {}, start with `--backend {} --database-path {}`.",
+                args.database_path,
+                to_backend,
+                to_path,
+                to_backend,
+                to_path
+            );
+            return Ok(());
+        }
+        None => {}
+    }
     let llm = initialize_provider(&args)?;
     let app = backend::get_http_router(llm, args.clone().into()).fallback(static_handler);
     let addr = SocketAddr::new(
@@ -0,0 +1,35 @@
+//! js_interpreter每次调用都新建V8环境，对比从build.rs生成的快照启动，
+//! 和快照缺失或不匹配时`new_runtime(None, ..)`加上`load_prelude`的耗时
+//!
+//! cargo bench -p chat_ui --bench js_cold_start
+
+use std::{sync::Arc, time::Instant};
+
+use chat_ui::{BlobStorage, JsInterpreter, SledBlobStorage, Tool, ToolContext};
+
+const SAMPLES: usize = 20;
+
+fn bench(name: &str, tool: &JsInterpreter, rt: &tokio::runtime::Runtime) {
+    let ctx = ToolContext::default();
+    // 第一次调用会检查快照，不计入
+    rt.block_on(tool.call(&ctx, "return 1;")).unwrap();
+    let started = Instant::now();
+    for _ in 0..SAMPLES {
+        rt.block_on(tool.call(&ctx, "return 1;")).unwrap();
+    }
+    let per_call = started.elapsed() / SAMPLES as u32;
+    println!("{:<10}: {:>10.2?} per call", name, per_call);
+}
+
+fn main() {
+    let db = sled::Config::new().temporary(true).open().unwrap();
+    let blob: Arc<dyn BlobStorage> = Arc::new(SledBlobStorage::new_from_db(&db, "bench").unwrap());
+    let rt = tokio::runtime::Runtime::new().unwrap();
+
+    bench(
+        "prelude",
+        &JsInterpreter::new(blob.clone(), blob.clone()).with_snapshot(false),
+        &rt,
+    );
+    bench("snapshot", &JsInterpreter::new(blob.clone(), blob), &rt);
+}
@@ -0,0 +1,72 @@
+//! 追加一条消息的耗时应该与会话长度无关
+//!
+//! cargo bench -p chat_ui --bench session_append
+
+use std::{sync::Arc, time::Instant};
+
+use chat_ui::{PushedMessage, RedbSessionStore, SessionStorage, SledSessionStore};
+use redb::{Database, backends::InMemoryBackend};
+
+const LENGTHS: [usize; 4] = [10, 100, 1000, 5000];
+const SAMPLES: usize = 200;
+
+fn message(i: usize) -> Vec<u8> {
+    format!(
+        r#"{{"id":"{}","owner":{{"role":"tool","tool_call_id":"{}"}},"reasoning":[],"content":[{{"Text":"{}"}}],"tool_use":[]}}"#,
+        uuid::Uuid::new_v4(),
+        uuid::Uuid::new_v4(),
+        "zoom result ".repeat(40 + i % 7)
+    )
+    .into_bytes()
+}
+
+fn push(store: &dyn SessionStorage, id: uuid::Uuid, i: usize) {
+    let msg = message(i);
+    store
+        .push_message(
+            id,
+            Box::new(move |meta, _| {
+                Ok(PushedMessage {
+                    meta: meta.unwrap_or_default(),
+                    data: None,
+                    message: msg.clone(),
+                    replace_last: false,
+                })
+            }),
+        )
+        .unwrap();
+}
+
+fn bench(name: &str, store: &dyn SessionStorage) {
+    for len in LENGTHS {
+        let id = store.append(b"{}", b"{}").unwrap();
+        for i in 0..len {
+            push(store, id, i);
+        }
+        let started = Instant::now();
+        for i in 0..SAMPLES {
+            push(store, id, len + i);
+        }
+        let per_append = started.elapsed() / SAMPLES as u32;
+        println!(
+            "{:<5} length {:>5}: {:>10.2?} per append",
+            name, len, per_append
+        );
+    }
+}
+
+fn main() {
+    let sled_db = sled::Config::new().temporary(true).open().unwrap();
+    bench(
+        "sled",
+        &SledSessionStore::new_from_db(&sled_db, "bench").unwrap(),
+    );
+
+    let redb_db = Database::builder()
+        .create_with_backend(InMemoryBackend::new())
+        .unwrap();
+    bench(
+        "redb",
+        &RedbSessionStore::new(Arc::new(redb_db), "bench").unwrap(),
+    );
+}
@@ -0,0 +1,162 @@
+//! 生成js_interpreter加载好所有库的V8快照
+//!
+//! 真正的ops依赖本crate的存储和`ToolContext`，这里注册名字和签名相同、什么都不做的ops。
+//! 快照和`snapshot_marker`写入OUT_DIR，运行时marker不一致或快照为空时改为每次加载库
+
+use deno_core::{JsRuntimeForSnapshot, OpState, RuntimeOptions, extension, op2};
+use deno_error::JsErrorBox;
+use std::path::PathBuf;
+
+#[allow(dead_code)]
+#[path = "src/tools/js_prelude.rs"]
+mod js_prelude;
+
+fn unavailable<T>() -> Result<T, JsErrorBox> {
+    Err(JsErrorBox::generic(
+        "Not available while creating the snapshot",
+    ))
+}
+
+#[op2(fast)]
+fn console_op_print(_state: &mut OpState, #[string] _msg: String, _is_err: bool) {}
+
+#[op2]
+#[string]
+fn op_save_svg(_state: &mut OpState, #[string] _svg_data: &str) -> Result<String, JsErrorBox> {
+    unavailable()
+}
+
+#[op2]
+#[string]
+fn op_save_blob(
+    _state: &mut OpState,
+    #[string] _schema: String,
+    #[buffer] _img: &[u8],
+) -> Result<String, JsErrorBox> {
+    unavailable()
+}
+
+#[op2]
+#[buffer]
+fn op_convert_to_png(_: &mut OpState, #[buffer] _img: &[u8]) -> Result<Vec<u8>, JsErrorBox> {
+    unavailable()
+}
+
+#[op2]
+#[string]
+fn op_qrcode_decode(#[buffer] _data: &[u8]) -> Result<String, JsErrorBox> {
+    unavailable()
+}
+
+#[op2]
+#[buffer]
+fn op_load_blob(
+    _state: &mut OpState,
+    #[string] _schema: String,
+    #[string] _uuid_str: String,
+) -> Result<Vec<u8>, JsErrorBox> {
+    unavailable()
+}
+
+#[op2(fast)]
+fn op_contain_blob(
+    _state: &mut OpState,
+    #[string] _schema: String,
+    #[string] _uuid_str: String,
+) -> Result<bool, JsErrorBox> {
+    unavailable()
+}
+
+#[op2]
+#[buffer]
+fn op_qrcode_png(#[string] _text: String) -> Result<Vec<u8>, JsErrorBox> {
+    unavailable()
+}
+
+#[op2]
+#[string]
+fn op_qrcode_svg(#[string] _text: String) -> Result<String, JsErrorBox> {
+    unavailable()
+}
+
+#[op2]
+#[buffer]
+fn op_text_encode(#[string] _text: String) -> Vec<u8> {
+    Vec::new()
+}
+
+#[op2]
+#[string]
+fn op_text_decode(#[buffer] _bytes: &[u8]) -> String {
+    String::new()
+}
+
+#[op2]
+#[string]
+fn op_base64_encode(#[buffer] _data: &[u8]) -> String {
+    String::new()
+}
+
+#[op2]
+#[buffer]
+fn op_base64_decode(#[string] _data: String) -> Result<Vec<u8>, JsErrorBox> {
+    unavailable()
+}
+
+#[op2(fast)]
+fn op_report_progress(_state: &mut OpState, #[string] _msg: String, _fraction: f64) {}
+
+#[op2(fast)]
+fn op_performance_now(_state: &mut OpState) -> f64 {
+    0.0
+}
+
+// 顺序必须和code_interpreter.rs中的`sandbox_ext`一致
+extension!(
+    sandbox_ext,
+    ops = [
+        console_op_print,
+        op_load_blob,
+        op_save_blob,
+        op_save_svg,
+        op_contain_blob,
+        op_convert_to_png,
+        op_text_encode,
+        op_text_decode,
+        op_base64_decode,
+        op_base64_encode,
+        op_performance_now,
+        op_report_progress,
+        op_qrcode_png,
+        op_qrcode_svg,
+        op_qrcode_decode,
+    ],
+);
+
+fn create_snapshot() -> Result<Box<[u8]>, anyhow::Error> {
+    let mut runtime = JsRuntimeForSnapshot::new(RuntimeOptions {
+        extensions: vec![sandbox_ext::init()],
+        ..Default::default()
+    });
+    js_prelude::load_prelude(&mut runtime)?;
+    Ok(runtime.snapshot())
+}
+
+fn main() {
+    println!("cargo::rerun-if-changed=build.rs");
+    println!("cargo::rerun-if-changed=src/tools/js_prelude.rs");
+    println!("cargo::rerun-if-changed=src/tools/prelude");
+
+    let out_dir = PathBuf::from(std::env::var_os("OUT_DIR").unwrap());
+    let (snapshot, marker) = match create_snapshot() {
+        Ok(snapshot) => (snapshot, js_prelude::snapshot_marker(&sandbox_ext::init())),
+        Err(e) => {
+            println!(
+                "cargo::warning=Failed to create JS snapshot, libraries will be loaded on every run: {e}"
+            );
+            (Box::default(), String::new())
+        }
+    };
+    std::fs::write(out_dir.join("prelude.snap"), snapshot).unwrap();
+    std::fs::write(out_dir.join("prelude.marker"), marker).unwrap();
+}
@@ -0,0 +1,400 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    io::{Read, Write},
+};
+
+use anyhow::{Error, anyhow, bail};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    AssetId, BlobMeta, BlobReferences, BlobStorage, BlobStoreKind, ChatEntry, ChatMeta, Storages,
+    chat_handler::{encode_chat, release_with_meta},
+    tools::{memo_key, memo_layer_images},
+};
+
+/// 归档文件的布局:
+/// 8字节标识 | u32 版本 | u64 清单长度 | 清单JSON | 按清单顺序排列的数据
+/// 整数都是小端序，数据不压缩，图片本身已经是压缩格式
+const ARCHIVE_MAGIC: &[u8; 8] = b"QLENSARC";
+const ARCHIVE_VERSION: u32 = 1;
+
+/// 归档中的一条数据
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ArchivedBlob {
+    pub store: BlobStoreKind,
+    pub id: AssetId,
+    /// 数据的字节数
+    pub len: u64,
+    #[serde(default)]
+    pub meta: Option<BlobMeta>,
+}
+
+/// 会话的画布，图层引用的图片和消息中的图片一起计数
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ArchivedMemo {
+    pub chat_id: Uuid,
+    pub state: serde_json::Value,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ArchiveManifest {
+    pub version: u32,
+    pub exported_at: DateTime<Utc>,
+    pub chats: Vec<ChatEntry>,
+    pub blobs: Vec<ArchivedBlob>,
+    #[serde(default)]
+    pub memos: Vec<ArchivedMemo>,
+}
+
+/// 与已有会话冲突而换了id的会话
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct RemappedChat {
+    pub from: Uuid,
+    pub to: Uuid,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ImportReport {
+    /// 导入后的会话id
+    pub chats: Vec<Uuid>,
+    pub remapped: Vec<RemappedChat>,
+    /// 新写入的数据数
+    pub blobs_created: usize,
+    /// 已经存在、只增加了引用计数的数据数
+    pub blobs_deduplicated: usize,
+    /// 被消息引用但归档和存储中都没有的数据
+    pub missing: Vec<AssetId>,
+}
+
+/// 会话中所有消息和画布对图片和附件的引用次数
+fn referenced_blobs(
+    chats: &[ChatEntry],
+    memos: &[ArchivedMemo],
+) -> Result<BTreeMap<(BlobStoreKind, AssetId), u64>, Error> {
+    let mut refs = BlobReferences::default();
+    for chat in chats {
+        for msg in chat.messages.iter().chain(chat.inactive.iter()) {
+            refs.add_message(msg);
+        }
+    }
+    for memo in memos {
+        for id in memo_layer_images(&serde_json::to_vec(&memo.state)?)? {
+            *refs.image.entry(id).or_default() += 1;
+        }
+    }
+    Ok(refs
+        .image
+        .into_iter()
+        .map(|(id, n)| ((BlobStoreKind::Image, id), n))
+        .chain(
+            refs.asset
+                .into_iter()
+                .map(|(id, n)| ((BlobStoreKind::Asset, id), n)),
+        )
+        .collect())
+}
+
+fn read_u32(reader: &mut dyn Read) -> Result<u32, Error> {
+    let mut buf = [0u8; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64(reader: &mut dyn Read) -> Result<u64, Error> {
+    let mut buf = [0u8; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+/// 读取`len`个字节，数据不足时报错
+fn read_exact_vec(reader: &mut dyn Read, len: u64) -> Result<Vec<u8>, Error> {
+    let mut data = Vec::new();
+    reader.take(len).read_to_end(&mut data)?;
+    if data.len() as u64 != len {
+        bail!("Archive is truncated");
+    }
+    Ok(data)
+}
+
+impl Storages {
+    fn blob_store(&self, kind: BlobStoreKind) -> &dyn BlobStorage {
+        match kind {
+            BlobStoreKind::Image => self.image.as_ref(),
+            BlobStoreKind::Asset => self.asset.as_ref(),
+            BlobStoreKind::Memo => self.memo.as_ref(),
+        }
+    }
+
+    /// 把`chats`、它们的画布和引用的图片、附件写入`writer`
+    /// 已经不存在的数据不写入，导入时沿用目标存储中的同名数据
+    pub fn export_chats(&self, chats: Vec<ChatEntry>, writer: &mut dyn Write) -> Result<(), Error> {
+        let mut memos = vec![];
+        for chat in chats.iter() {
+            if let Some(data) = self.memo.get_raw(&memo_key(chat.id))? {
+                memos.push(ArchivedMemo {
+                    chat_id: chat.id,
+                    state: serde_json::from_slice(&data)?,
+                });
+            }
+        }
+        let mut blobs = vec![];
+        for (store, id) in referenced_blobs(&chats, &memos)?.into_keys() {
+            let db = self.blob_store(store);
+            let Some((_, len)) = db.peek(id, 0)? else {
+                tracing::warn!("Exported chats refer to missing {} {}, skipped", store, id);
+                continue;
+            };
+            blobs.push(ArchivedBlob {
+                store,
+                id,
+                len: len as u64,
+                meta: db.get_meta(id)?,
+            });
+        }
+        let manifest = ArchiveManifest {
+            version: ARCHIVE_VERSION,
+            exported_at: Utc::now(),
+            chats,
+            blobs,
+            memos,
+        };
+        let json = serde_json::to_vec(&manifest)?;
+        writer.write_all(ARCHIVE_MAGIC)?;
+        writer.write_all(&ARCHIVE_VERSION.to_le_bytes())?;
+        writer.write_all(&(json.len() as u64).to_le_bytes())?;
+        writer.write_all(&json)?;
+        for blob in manifest.blobs.iter() {
+            let data = self.blob_store(blob.store).get(blob.id)?.ok_or(anyhow!(
+                "{} {} was deleted during export",
+                blob.store,
+                blob.id
+            ))?;
+            if data.len() as u64 != blob.len {
+                bail!("{} {} changed during export", blob.store, blob.id);
+            }
+            writer.write_all(&data)?;
+        }
+        writer.flush()?;
+        tracing::info!(
+            "Exported {} chats with {} blobs.",
+            manifest.chats.len(),
+            manifest.blobs.len()
+        );
+        Ok(())
+    }
+
+    /// 导入`export_chats`写出的归档
+    /// 数据按哈希去重，每个引用增加一次引用计数；与已有会话id冲突的会话换用新的id
+    /// 出错时撤销已经增加的引用和写入的会话
+    pub fn import_archive(&self, reader: &mut dyn Read) -> Result<ImportReport, Error> {
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != ARCHIVE_MAGIC {
+            bail!("Not a chat archive");
+        }
+        let version = read_u32(reader)?;
+        if version != ARCHIVE_VERSION {
+            bail!("Unsupported archive version {}", version);
+        }
+        let len = read_u64(reader)?;
+        let manifest: ArchiveManifest = serde_json::from_slice(&read_exact_vec(reader, len)?)?;
+
+        let mut report = ImportReport::default();
+        let mut chats = manifest.chats;
+        let mut memos = manifest.memos;
+        let mut taken = HashSet::new();
+        let mut remap = HashMap::new();
+        for chat in chats.iter_mut() {
+            if taken.contains(&chat.id) || self.history.get_meta(chat.id)?.is_some() {
+                let id = Uuid::now_v7();
+                remap.insert(chat.id, id);
+                report.remapped.push(RemappedChat {
+                    from: chat.id,
+                    to: id,
+                });
+                chat.id = id;
+            }
+            taken.insert(chat.id);
+        }
+        // 只导入归档中会话的画布
+        memos.retain(|m| remap.contains_key(&m.chat_id) || taken.contains(&m.chat_id));
+        for memo in memos.iter_mut() {
+            if let Some(to) = remap.get(&memo.chat_id) {
+                memo.chat_id = *to;
+            }
+        }
+
+        let mut retained = vec![];
+        let mut written = vec![];
+        let result = self
+            .import_blobs(
+                reader,
+                &manifest.blobs,
+                &chats,
+                &memos,
+                &remap,
+                &mut retained,
+                &mut report,
+            )
+            .and_then(|_| {
+                for chat in chats.iter_mut() {
+                    let (data, messages) = encode_chat(chat)?;
+                    let meta = serde_json::to_vec(&ChatMeta::clone_from(chat))?;
+                    self.history.update(chat.id, &meta, &data, &messages)?;
+                    written.push(chat.id);
+                }
+                for memo in memos.iter() {
+                    let data = serde_json::to_vec(&memo.state)?;
+                    self.memo.put_raw(&memo_key(memo.chat_id), &data)?;
+                }
+                Ok(())
+            });
+        if let Err(e) = result {
+            for memo in memos.iter() {
+                if let Err(e) = self.memo.delete_raw(&memo_key(memo.chat_id)) {
+                    tracing::error!("Failed to remove memo of chat {}: {}", memo.chat_id, e);
+                }
+            }
+            for chat_id in written {
+                if let Err(e) = self.history.delete(chat_id) {
+                    tracing::error!("Failed to remove imported chat {}: {}", chat_id, e);
+                }
+            }
+            for (store, id) in retained {
+                if let Err(e) = release_with_meta(self.blob_store(store), id) {
+                    tracing::error!("Failed to release imported {} {}: {}", store, id, e);
+                }
+            }
+            return Err(e);
+        }
+        report.chats = written;
+        Ok(report)
+    }
+
+    /// 保存归档中被引用的数据，每增加一次引用就在`retained`中记录一次
+    fn import_blobs(
+        &self,
+        reader: &mut dyn Read,
+        blobs: &[ArchivedBlob],
+        chats: &[ChatEntry],
+        memos: &[ArchivedMemo],
+        remap: &HashMap<Uuid, Uuid>,
+        retained: &mut Vec<(BlobStoreKind, AssetId)>,
+        report: &mut ImportReport,
+    ) -> Result<(), Error> {
+        let refs = referenced_blobs(chats, memos)?;
+        let mut archived = HashSet::new();
+        for blob in blobs {
+            let data = read_exact_vec(reader, blob.len)?;
+            if AssetId::from_data(&data) != blob.id {
+                bail!("Checksum mismatch of {} {}", blob.store, blob.id);
+            }
+            if !archived.insert((blob.store, blob.id)) {
+                continue;
+            }
+            let Some(&count) = refs.get(&(blob.store, blob.id)) else {
+                continue;
+            };
+            let db = self.blob_store(blob.store);
+            if db.peek(blob.id, 0)?.is_some() {
+                report.blobs_deduplicated += 1;
+            } else {
+                report.blobs_created += 1;
+            }
+            let mut meta = blob.meta.clone().unwrap_or_else(BlobMeta::uploaded);
+            if let Some(to) = meta.chat_id.and_then(|id| remap.get(&id)) {
+                meta.chat_id = Some(*to);
+            }
+            db.save_with_meta(&data, meta)?;
+            retained.push((blob.store, blob.id));
+            for _ in 1..count {
+                db.retain(blob.id)?;
+                retained.push((blob.store, blob.id));
+            }
+        }
+
+        // 归档中没有数据的引用只能沿用已有的数据
+        for (&(store, id), &count) in refs.iter() {
+            if archived.contains(&(store, id)) {
+                continue;
+            }
+            let db = self.blob_store(store);
+            if db.peek(id, 0)?.is_none() {
+                tracing::warn!("Imported chats refer to missing {} {}", store, id);
+                report.missing.push(id);
+                continue;
+            }
+            report.blobs_deduplicated += 1;
+            for _ in 0..count {
+                db.retain(id)?;
+                retained.push((store, id));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Message, MessageContent, Role};
+
+    fn chat_with_image(id: AssetId) -> ChatEntry {
+        let message = Message {
+            id: Uuid::new_v4(),
+            owner: Role::User,
+            reasoning: vec![],
+            content: vec![
+                MessageContent::ImageRef(id, "a".into()),
+                MessageContent::ImageRef(id, "b".into()),
+            ],
+            tool_use: vec![],
+            interrupted: false,
+            parent: None,
+        };
+        ChatEntry {
+            messages: vec![message],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn export_and_import() {
+        let source = Storages::temporary();
+        let image = source
+            .image
+            .save_with_meta(b"image", BlobMeta::uploaded().with_filename("a.png"))
+            .unwrap();
+        source.image.retain(image).unwrap();
+        let chat = chat_with_image(image);
+        let mut archive = vec![];
+        source
+            .export_chats(vec![chat.clone()], &mut archive)
+            .unwrap();
+
+        let target = Storages::temporary();
+        let report = target.import_archive(&mut archive.as_slice()).unwrap();
+        assert_eq!(report.chats, [chat.id]);
+        assert_eq!(report.blobs_created, 1);
+        let meta = target.image.get_meta(image).unwrap().unwrap();
+        assert_eq!(meta.filename.as_deref(), Some("a.png"));
+
+        // 再次导入时换用新的id，数据只增加引用计数
+        let report = target.import_archive(&mut archive.as_slice()).unwrap();
+        assert_eq!(report.remapped.len(), 1);
+        assert_ne!(report.chats, [chat.id]);
+        assert_eq!(report.blobs_deduplicated, 1);
+        for _ in 0..3 {
+            assert!(!target.image.release(image).unwrap());
+        }
+        assert!(target.image.release(image).unwrap());
+
+        let mut corrupted = archive.clone();
+        *corrupted.last_mut().unwrap() ^= 0xff;
+        assert!(target.import_archive(&mut corrupted.as_slice()).is_err());
+        assert_eq!(target.history.list(None, None).unwrap().len(), 2);
+        assert!(target.image.get(image).unwrap().is_none());
+    }
+}
@@ -0,0 +1,207 @@
+use anyhow::{Error, bail};
+use serde::Serialize;
+
+use crate::{AssetId, BlobStorage, Storages};
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct StoreMigration {
+    pub store: String,
+    /// 复制的键数，包括`put_raw`写入的
+    pub keys: usize,
+    pub ref_counts: usize,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct MigrationReport {
+    pub chats: usize,
+    pub messages: usize,
+    pub stores: Vec<StoreMigration>,
+}
+
+impl Storages {
+    fn blob_stores(&self) -> [(&'static str, &dyn BlobStorage); 3] {
+        [
+            ("image", self.image.as_ref()),
+            ("asset", self.asset.as_ref()),
+            ("memo", self.memo.as_ref()),
+        ]
+    }
+
+    fn is_empty(&self) -> Result<bool, Error> {
+        if !self.history.list(Some(1), None)?.is_empty() {
+            return Ok(false);
+        }
+        for (_, store) in self.blob_stores() {
+            if !store.raw_keys()?.is_empty() {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// 把所有会话、三个存储的数据和引用计数原样复制到`target`，然后逐条校验
+    /// `target`必须是空的，搜索索引不复制，在`target`第一次启动时重建
+    pub fn migrate_to(&self, target: &Storages) -> Result<MigrationReport, Error> {
+        if !target.is_empty()? {
+            bail!("Target storage is not empty");
+        }
+        let mut report = MigrationReport::default();
+
+        let chats = self.history.list(None, None)?;
+        for (chat_id, meta) in chats.iter() {
+            let Some((data, messages)) = self.history.get_data(*chat_id)? else {
+                tracing::warn!("Chat {} has meta but no data, skipped", chat_id);
+                continue;
+            };
+            target.history.update(*chat_id, meta, &data, &messages)?;
+            report.chats += 1;
+            report.messages += messages.len();
+        }
+
+        for ((name, from), (_, to)) in self.blob_stores().into_iter().zip(target.blob_stores()) {
+            let mut migrated = StoreMigration {
+                store: name.to_string(),
+                ..Default::default()
+            };
+            for key in from.raw_keys()? {
+                if let Some(value) = from.get_raw(&key)? {
+                    to.put_raw(&key, &value)?;
+                    migrated.keys += 1;
+                }
+            }
+            for entry in from.entries()? {
+                if let Some(rc) = entry.ref_count.filter(|rc| *rc > 0) {
+                    to.set_ref_count(entry.id, rc)?;
+                    migrated.ref_counts += 1;
+                }
+            }
+            report.stores.push(migrated);
+        }
+
+        self.verify(target, &report)?;
+        Ok(report)
+    }
+
+    fn verify(&self, target: &Storages, report: &MigrationReport) -> Result<(), Error> {
+        let mut errors = vec![];
+
+        let chats = target.history.list(None, None)?;
+        if chats.len() != report.chats {
+            errors.push(format!(
+                "history: expected {} chats, found {}",
+                report.chats,
+                chats.len()
+            ));
+        }
+        for (chat_id, meta) in chats {
+            if self.history.get_meta(chat_id)?.as_ref() != Some(&meta)
+                || self.history.get_data(chat_id)? != target.history.get_data(chat_id)?
+            {
+                errors.push(format!("history: chat {} differs", chat_id));
+            }
+        }
+
+        for (((name, from), (_, to)), migrated) in self
+            .blob_stores()
+            .into_iter()
+            .zip(target.blob_stores())
+            .zip(report.stores.iter())
+        {
+            let keys = to.raw_keys()?;
+            if keys.len() != migrated.keys {
+                errors.push(format!(
+                    "{}: expected {} keys, found {}",
+                    name,
+                    migrated.keys,
+                    keys.len()
+                ));
+            }
+            for key in keys {
+                let Some(value) = to.get_raw(&key)? else {
+                    continue;
+                };
+                // 以AssetId为键的数据用内容的哈希校验，其余的键逐字节比较
+                match AssetId::from_slice(&key) {
+                    Some(id) if AssetId::from_data(&value) == id => {}
+                    Some(id) if from.get_raw(&key)?.is_some_and(|v| v == value) => {
+                        tracing::warn!("{}: checksum of {} was already wrong in source", name, id)
+                    }
+                    Some(id) => errors.push(format!("{}: checksum mismatch of {}", name, id)),
+                    None if from.get_raw(&key)?.as_ref() == Some(&value) => {}
+                    None => errors.push(format!(
+                        "{}: value of key {} differs",
+                        name,
+                        String::from_utf8_lossy(&key)
+                    )),
+                }
+            }
+            let from_entries = from.entries()?;
+            let to_entries = to.entries()?;
+            if from_entries != to_entries {
+                errors.push(format!("{}: reference counts differ", name));
+            }
+        }
+
+        if !errors.is_empty() {
+            for e in errors.iter() {
+                tracing::error!("Migration check failed: {}", e);
+            }
+            bail!(
+                "Migration check failed with {} errors, first: {}",
+                errors.len(),
+                errors[0]
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{RedbBlobStorage, RedbSearchIndex, RedbSessionStore};
+    use redb::{Database, backends::InMemoryBackend};
+    use std::sync::Arc;
+
+    fn redb_storages() -> Storages {
+        let db = Arc::new(
+            Database::builder()
+                .create_with_backend(InMemoryBackend::new())
+                .unwrap(),
+        );
+        Storages {
+            history: Arc::new(RedbSessionStore::new(db.clone(), "history").unwrap()),
+            search: Arc::new(RedbSearchIndex::new(db.clone(), "history").unwrap()),
+            image: Arc::new(RedbBlobStorage::new(db.clone(), "image").unwrap()),
+            asset: Arc::new(RedbBlobStorage::new(db.clone(), "asset").unwrap()),
+            memo: Arc::new(RedbBlobStorage::new(db, "memo").unwrap()),
+        }
+    }
+
+    #[test]
+    fn sled_to_redb_and_back() {
+        let sled = Storages::temporary();
+        let id = sled.history.append(b"meta", b"data").unwrap();
+        sled.history
+            .update(id, b"meta", b"data", &[b"a".to_vec(), b"b".to_vec()])
+            .unwrap();
+        let image = sled.image.save(b"image").unwrap();
+        sled.image.retain(image).unwrap();
+        sled.asset.save(b"asset").unwrap();
+        sled.memo.put_raw(b"current", b"{}").unwrap();
+
+        let redb = redb_storages();
+        let report = sled.migrate_to(&redb).unwrap();
+        assert_eq!((report.chats, report.messages), (1, 2));
+        assert_eq!(redb.history.get_data(id).unwrap().unwrap().1.len(), 2);
+        assert!(!redb.image.release(image).unwrap());
+        assert!(redb.image.release(image).unwrap());
+        assert_eq!(redb.memo.get_raw(b"current").unwrap().unwrap(), b"{}");
+        assert!(sled.migrate_to(&redb).is_err());
+
+        let back = Storages::temporary();
+        redb.migrate_to(&back).unwrap();
+        assert!(back.image.get(image).unwrap().is_none());
+        assert_eq!(back.history.get_data(id).unwrap().unwrap().0, b"data");
+    }
+}
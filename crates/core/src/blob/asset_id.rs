@@ -18,13 +18,22 @@ pub struct AssetId([u8; 20]);
 impl AssetId {
     /// 从二进制数据生成 ID (Content-Addressable)
     pub fn from_data(data: &[u8]) -> Self {
-        let hash = blake3::hash(data);
+        Self::from_hash(&blake3::hash(data))
+    }
+
+    /// 从增量计算的哈希生成 ID，与`from_data`结果相同
+    pub fn from_hash(hash: &blake3::Hash) -> Self {
         let mut bytes = [0u8; 20];
         // 截取前 20 字节
         bytes.copy_from_slice(&hash.as_bytes()[0..20]);
         Self(bytes)
     }
 
+    /// 从数据库的键还原，长度不是 20 字节时返回 None
+    pub fn from_slice(bytes: &[u8]) -> Option<Self> {
+        Some(Self(bytes.try_into().ok()?))
+    }
+
     /// 获取原始字节
     pub fn as_bytes(&self) -> &[u8; 20] {
         &self.0
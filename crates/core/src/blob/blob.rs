@@ -1,5 +1,11 @@
+use std::{io::Cursor, pin::Pin};
+
 use thiserror::Error;
-use crate::AssetId;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use crate::{AssetId, BlobMeta, blob::meta_key};
+
+/// 流式读写时每块的字节数
+pub(crate) const STREAM_CHUNK_SIZE: usize = 64 * 1024;
 
 #[derive(Debug, Error)]
 pub enum BlobStorageError {
@@ -7,6 +13,12 @@ pub enum BlobStorageError {
     StorageError(#[from] sled::Error),
     #[error("Sled transaction error: {0}")]
     StorageTransactionError(String),
+    #[error("SQLite error: {0}")]
+    SqliteError(#[from] rusqlite::Error),
+    #[error("IO error: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("Invalid blob metadata: {0}")]
+    MetaError(#[from] serde_json::Error),
     #[error("Data corruption: Invalid reference count bytes")]
     InvalidRefCountData,
     #[error("UUID generation failed after multiple retries")]
@@ -22,15 +34,132 @@ where
     }
 }
 
+/// 存储中的一条数据及其引用计数
+#[derive(Clone, Debug, PartialEq)]
+pub struct BlobEntry {
+    pub id: AssetId,
+    /// 没有引用计数记录时为 None
+    pub ref_count: Option<u64>,
+    pub has_data: bool,
+}
+
+/// 合并数据表和引用计数表的键，只保留 20 字节的 AssetId
+pub(crate) fn merge_entries(
+    data_keys: impl Iterator<Item = Vec<u8>>,
+    ref_counts: impl Iterator<Item = (Vec<u8>, u64)>,
+) -> Vec<BlobEntry> {
+    let mut entries = std::collections::BTreeMap::new();
+    for id in data_keys.filter_map(|k| AssetId::from_slice(&k)) {
+        entries.insert(
+            id,
+            BlobEntry {
+                id,
+                ref_count: None,
+                has_data: true,
+            },
+        );
+    }
+    for (id, rc) in ref_counts.filter_map(|(k, rc)| Some((AssetId::from_slice(&k)?, rc))) {
+        entries
+            .entry(id)
+            .or_insert(BlobEntry {
+                id,
+                ref_count: None,
+                has_data: false,
+            })
+            .ref_count = Some(rc);
+    }
+    entries.into_values().collect()
+}
+
+/// `open_read`返回的数据流
+pub struct BlobReader {
+    /// 数据的总字节数
+    pub len: u64,
+    pub reader: Pin<Box<dyn AsyncRead + Send>>,
+}
+
+impl BlobReader {
+    /// 已经读入内存的数据
+    pub fn from_vec(data: Vec<u8>) -> Self {
+        Self {
+            len: data.len() as u64,
+            reader: Box::pin(Cursor::new(data)),
+        }
+    }
+}
+
+#[async_trait::async_trait]
 pub trait BlobStorage: Send + Sync {
     /// 保存新数据，返回新生成的 UUID。引用计数初始化为 1。
-    fn save(&self, data: &[u8]) -> Result<AssetId, BlobStorageError>;
+    fn save(&self, data: &[u8]) -> Result<AssetId, BlobStorageError> {
+        self.save_with_id(AssetId::from_data(data), data)
+    }
+
+    /// 用已经计算好的`uuid`保存数据，`uuid`必须等于`AssetId::from_data(data)`
+    fn save_with_id(&self, uuid: AssetId, data: &[u8]) -> Result<AssetId, BlobStorageError>;
+
+    /// `save_stream`是否直接写入存储，而不是先把整个数据读入内存
+    fn streams_to_storage(&self) -> bool {
+        false
+    }
+
+    /// 从`reader`分块读取并保存，读取的同时计算 AssetId
+    /// sled和redb的值只能整条写入，默认实现读完后一次保存
+    async fn save_stream(
+        &self,
+        reader: &mut (dyn AsyncRead + Unpin + Send),
+    ) -> Result<AssetId, BlobStorageError> {
+        let mut hasher = blake3::Hasher::new();
+        let mut data = Vec::new();
+        let mut buf = vec![0u8; STREAM_CHUNK_SIZE];
+        loop {
+            let n = reader.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+            data.extend_from_slice(&buf[..n]);
+        }
+        self.save_with_id(AssetId::from_hash(&hasher.finalize()), &data)
+    }
 
     /// 获取数据
     fn get(&self, uuid: AssetId) -> Result<Option<Vec<u8>>, BlobStorageError> {
         self.get_raw(uuid.as_bytes())
     }
 
+    /// 保存数据，第一次保存时写入元数据，MIME类型和图片尺寸从数据中补全
+    fn save_with_meta(&self, data: &[u8], meta: BlobMeta) -> Result<AssetId, BlobStorageError> {
+        let id = self.save(data)?;
+        if self.get_meta(id)?.is_none() {
+            self.put_meta(id, &meta.sniff(data))?;
+        }
+        Ok(id)
+    }
+
+    fn get_meta(&self, uuid: AssetId) -> Result<Option<BlobMeta>, BlobStorageError> {
+        match self.get_raw(&meta_key(uuid))? {
+            Some(v) => Ok(Some(serde_json::from_slice(&v)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn put_meta(&self, uuid: AssetId, meta: &BlobMeta) -> Result<(), BlobStorageError> {
+        self.put_raw(&meta_key(uuid), &serde_json::to_vec(meta)?)
+    }
+
+    /// 数据被删除后调用，删除它的元数据
+    fn delete_meta(&self, uuid: AssetId) -> Result<(), BlobStorageError> {
+        self.delete_raw(&meta_key(uuid))
+    }
+
+    /// 以流的方式读取数据，默认实现先把整条数据读入内存
+    /// sled和redb只能整条读出值，使用默认实现
+    fn open_read(&self, uuid: AssetId) -> Result<Option<BlobReader>, BlobStorageError> {
+        Ok(self.get(uuid)?.map(BlobReader::from_vec))
+    }
+
     /// 增加引用计数 (复用uuid)
     fn retain(&self, uuid: AssetId) -> Result<(), BlobStorageError>;
 
@@ -39,6 +168,16 @@ pub trait BlobStorage: Send + Sync {
     /// 返回值: true 表示数据已被物理删除，false 表示仅减少了计数
     fn release(&self, uuid: AssetId) -> Result<bool, BlobStorageError>;
 
+    /// 列出所有数据和引用计数，`put_raw`写入的其他键不包括在内
+    fn entries(&self) -> Result<Vec<BlobEntry>, BlobStorageError>;
+
+    /// 数据表中的所有键，包括`put_raw`写入的
+    fn raw_keys(&self) -> Result<Vec<Vec<u8>>, BlobStorageError>;
+
+    /// 直接设置引用计数，只用于一致性修复
+    /// 设为 0 时删除数据
+    fn set_ref_count(&self, uuid: AssetId, count: u64) -> Result<(), BlobStorageError>;
+
     fn peek(&self, uuid: AssetId, n: usize) -> Result<Option<(Vec<u8>, usize)>, BlobStorageError> {
         self.peek_raw(uuid.as_bytes(), n)
     }
@@ -50,3 +189,128 @@ pub trait BlobStorage: Send + Sync {
     fn get_raw(&self, key: &[u8]) -> Result<Option<Vec<u8>>, BlobStorageError>;
     fn delete_raw(&self, key: &[u8]) -> Result<(), BlobStorageError>;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FsBlobStorage, RedbBlobStorage, SledBlobStorage, SqliteBlobStorage};
+    use redb::{Database, backends::InMemoryBackend};
+    use std::sync::{Arc, Mutex};
+
+    fn check_refcount(store: &dyn BlobStorage) {
+        let id = store.save(b"hello world").unwrap();
+        assert_eq!(store.save(b"hello world").unwrap(), id);
+        store.retain(id).unwrap();
+        assert_eq!(store.get(id).unwrap().unwrap(), b"hello world");
+        assert_eq!(
+            store.peek(id, 5).unwrap().unwrap(),
+            (b"hello".to_vec(), 11)
+        );
+        assert_eq!(store.peek(id, 100).unwrap().unwrap().0.len(), 11);
+        assert!(!store.release(id).unwrap());
+        assert!(!store.release(id).unwrap());
+        assert!(store.release(id).unwrap());
+        assert!(store.get(id).unwrap().is_none());
+        assert!(store.peek(id, 5).unwrap().is_none());
+
+        store.put_raw(b"current", b"a").unwrap();
+        store.put_raw(b"current", b"b").unwrap();
+        assert_eq!(store.get_raw(b"current").unwrap().unwrap(), b"b");
+        assert_eq!(store.raw_keys().unwrap(), [b"current".to_vec()]);
+        assert!(store.entries().unwrap().is_empty());
+        store.delete_raw(b"current").unwrap();
+        assert!(store.get_raw(b"current").unwrap().is_none());
+    }
+
+    async fn check_stream(store: &dyn BlobStorage) {
+        let data = (0..200_000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let id = store.save_stream(&mut data.as_slice()).await.unwrap();
+        assert_eq!(id, AssetId::from_data(&data));
+        assert_eq!(store.save(&data).unwrap(), id);
+        let mut blob = store.open_read(id).unwrap().unwrap();
+        assert_eq!(blob.len, data.len() as u64);
+        let mut read = vec![];
+        blob.reader.read_to_end(&mut read).await.unwrap();
+        assert_eq!(read, data);
+        assert!(!store.release(id).unwrap());
+        assert!(store.release(id).unwrap());
+        assert!(store.open_read(id).unwrap().is_none());
+    }
+
+    #[test]
+    fn sled_refcount() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        check_refcount(&SledBlobStorage::new_from_db(&db, "t").unwrap());
+    }
+
+    #[test]
+    fn redb_refcount() {
+        let db = Database::builder()
+            .create_with_backend(InMemoryBackend::new())
+            .unwrap();
+        check_refcount(&RedbBlobStorage::new(Arc::new(db), "t").unwrap());
+    }
+
+    #[test]
+    fn sqlite_refcount() {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        check_refcount(&SqliteBlobStorage::new(Arc::new(Mutex::new(conn)), "t").unwrap());
+    }
+
+    #[test]
+    fn fs_refcount() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let inner = Arc::new(SledBlobStorage::new_from_db(&db, "t").unwrap());
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        // 阈值小于测试数据，数据都保存为文件
+        check_refcount(&FsBlobStorage::new(inner, dir.clone(), 4).unwrap());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn sled_stream() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        check_stream(&SledBlobStorage::new_from_db(&db, "t").unwrap()).await;
+    }
+
+    #[tokio::test]
+    async fn redb_stream() {
+        let db = Database::builder()
+            .create_with_backend(InMemoryBackend::new())
+            .unwrap();
+        check_stream(&RedbBlobStorage::new(Arc::new(db), "t").unwrap()).await;
+    }
+
+    #[tokio::test]
+    async fn sqlite_stream() {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        check_stream(&SqliteBlobStorage::new(Arc::new(Mutex::new(conn)), "t").unwrap()).await;
+    }
+
+    #[tokio::test]
+    async fn sqlite_read_stops_when_row_is_reused() {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        let store = SqliteBlobStorage::new(Arc::new(Mutex::new(conn)), "t").unwrap();
+        let data = vec![1u8; STREAM_CHUNK_SIZE * 2];
+        let id = store.save(&data).unwrap();
+        let mut blob = store.open_read(id).unwrap().unwrap();
+        let mut head = [0u8; 16];
+        blob.reader.read_exact(&mut head).await.unwrap();
+
+        // 删除后同一个rowid被新数据复用
+        assert!(store.release(id).unwrap());
+        store.save(&vec![2u8; STREAM_CHUNK_SIZE * 2]).unwrap();
+        let mut rest = vec![];
+        assert!(blob.reader.read_to_end(&mut rest).await.is_err());
+        assert!(rest.iter().all(|&b| b == 1));
+    }
+
+    #[tokio::test]
+    async fn fs_stream() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let inner = Arc::new(SledBlobStorage::new_from_db(&db, "t").unwrap());
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        check_stream(&FsBlobStorage::new(inner, dir.clone(), 1024).unwrap()).await;
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,122 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{AssetId, ToolKind, image_dimensions};
+
+/// 元数据保存在同一个存储中，键为前缀加 AssetId
+/// 长度不是 20 字节，不会被`entries`当作数据
+const META_PREFIX: &[u8] = b"meta/";
+
+/// 补全元数据时读取的字节数，足够读到常见图片格式的尺寸
+pub(crate) const META_SNIFF_LEN: usize = 64 * 1024;
+
+pub(crate) fn meta_key(id: AssetId) -> Vec<u8> {
+    [META_PREFIX, id.as_bytes().as_slice()].concat()
+}
+
+/// `meta_key`的反向操作，不是元数据的键返回 None
+pub(crate) fn meta_key_id(key: &[u8]) -> Option<AssetId> {
+    AssetId::from_slice(key.strip_prefix(META_PREFIX)?)
+}
+
+/// 每个 AssetId 的元数据，同样的数据保存多次时保留第一次的
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BlobMeta {
+    pub mime: Option<String>,
+    /// 上传或下载时的原始文件名
+    pub filename: Option<String>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub created_at: DateTime<Utc>,
+    /// 产生数据的工具，用户上传时为空
+    pub tool: Option<String>,
+    /// 第一次出现在哪个会话中
+    pub chat_id: Option<Uuid>,
+}
+
+impl BlobMeta {
+    /// 用户上传的数据
+    pub fn uploaded() -> Self {
+        Self {
+            mime: None,
+            filename: None,
+            width: None,
+            height: None,
+            created_at: Utc::now(),
+            tool: None,
+            chat_id: None,
+        }
+    }
+
+    /// 工具产生的数据
+    pub fn from_tool(tool: ToolKind) -> Self {
+        Self {
+            tool: Some(tool.to_string()),
+            ..Self::uploaded()
+        }
+    }
+
+    pub fn with_filename(mut self, filename: impl Into<String>) -> Self {
+        self.filename = Some(filename.into());
+        self
+    }
+
+    pub fn with_mime(mut self, mime: impl Into<String>) -> Self {
+        self.mime = Some(mime.into());
+        self
+    }
+
+    /// 用数据开头的字节补全MIME类型和图片尺寸
+    pub fn sniff(mut self, head: &[u8]) -> Self {
+        if self
+            .mime
+            .as_deref()
+            .is_none_or(|m| m == mime::APPLICATION_OCTET_STREAM.as_ref())
+        {
+            if let Some(kind) = infer::get(head) {
+                self.mime = Some(kind.mime_type().to_string());
+            }
+        }
+        if let Some((width, height)) = image_dimensions(head) {
+            self.width = Some(width);
+            self.height = Some(height);
+        }
+        self
+    }
+
+    /// 下载时使用的文件名，没有原始文件名时用 AssetId
+    pub fn download_name(&self, id: AssetId) -> String {
+        self.filename.clone().unwrap_or_else(|| id.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BlobStorage, SledBlobStorage};
+
+    #[test]
+    fn save_keeps_first_meta() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let store = SledBlobStorage::new_from_db(&db, "t").unwrap();
+        let mut png = vec![];
+        image::RgbImage::new(3, 2)
+            .write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)
+            .unwrap();
+
+        let id = store
+            .save_with_meta(&png, BlobMeta::uploaded().with_filename("a.png"))
+            .unwrap();
+        store
+            .save_with_meta(&png, BlobMeta::from_tool(ToolKind::ZoomIn))
+            .unwrap();
+        let meta = store.get_meta(id).unwrap().unwrap();
+        assert_eq!(meta.filename.as_deref(), Some("a.png"));
+        assert_eq!(meta.mime.as_deref(), Some("image/png"));
+        assert_eq!((meta.width, meta.height), (Some(3), Some(2)));
+        assert!(meta.tool.is_none());
+        assert_eq!(meta_key_id(&meta_key(id)), Some(id));
+        assert!(store.entries().unwrap().iter().all(|e| e.id == id));
+    }
+}
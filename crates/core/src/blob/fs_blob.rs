@@ -0,0 +1,395 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{ErrorKind, Read},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use serde::{Deserialize, Serialize};
+use strum::{Display, EnumIter, EnumString};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+
+use crate::blob::{
+    AssetId, BlobEntry, BlobReader, BlobStorage, BlobStorageError, STREAM_CHUNK_SIZE,
+};
+
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Hash,
+    PartialOrd,
+    Ord,
+    EnumString,
+    Display,
+    EnumIter,
+    Serialize,
+    Deserialize,
+)]
+#[strum(serialize_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum BlobStoreKind {
+    Image,
+    Asset,
+    Memo,
+}
+
+/// 哪些存储把大数据保存为文件
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FsBlobOptions {
+    pub stores: Vec<BlobStoreKind>,
+    /// 不超过这个字节数的数据仍然保存在数据库中
+    pub inline_threshold: usize,
+    /// 为空时使用数据库旁边的`<database>_blobs`
+    pub dir: Option<PathBuf>,
+}
+
+impl Default for FsBlobOptions {
+    fn default() -> Self {
+        Self {
+            stores: vec![],
+            inline_threshold: 64 * 1024,
+            dir: None,
+        }
+    }
+}
+
+impl FsBlobOptions {
+    /// `kind`在`stores`中时用`FsBlobStorage`包装`inner`，否则原样返回
+    pub fn wrap(
+        &self,
+        kind: BlobStoreKind,
+        inner: Arc<dyn BlobStorage>,
+        db_path: &Path,
+    ) -> Result<Arc<dyn BlobStorage>, BlobStorageError> {
+        if !self.stores.contains(&kind) {
+            return Ok(inner);
+        }
+        let root = match self.dir {
+            Some(ref dir) => dir.clone(),
+            None => {
+                let mut name = db_path.as_os_str().to_os_string();
+                name.push("_blobs");
+                PathBuf::from(name)
+            }
+        };
+        let dir = root.join(kind.to_string());
+        tracing::info!(
+            "Save {} larger than {} bytes to {}.",
+            kind,
+            self.inline_threshold,
+            dir.display()
+        );
+        Ok(Arc::new(FsBlobStorage::new(
+            inner,
+            dir,
+            self.inline_threshold,
+        )?))
+    }
+}
+
+/// 大于`inline_threshold`的数据以AssetId为文件名保存在分片目录中
+/// 引用计数和小数据仍由`inner`保存，`inner`中没有数据时再查找文件
+pub struct FsBlobStorage {
+    inner: Arc<dyn BlobStorage>,
+    dir: PathBuf,
+    inline_threshold: usize,
+    /// 文件的创建和删除与引用计数不在同一个事务中，用锁避免保存和释放交错
+    lock: Mutex<()>,
+}
+
+impl FsBlobStorage {
+    pub fn new(
+        inner: Arc<dyn BlobStorage>,
+        dir: PathBuf,
+        inline_threshold: usize,
+    ) -> Result<Self, BlobStorageError> {
+        fs::create_dir_all(&dir)?;
+        Ok(Self {
+            inner,
+            dir,
+            inline_threshold,
+            lock: Mutex::new(()),
+        })
+    }
+
+    /// `ab/cd/abcd...`，两级目录避免单个目录中文件过多
+    fn path(&self, id: &AssetId) -> PathBuf {
+        let name = hex(id.as_bytes());
+        self.dir.join(&name[0..2]).join(&name[2..4]).join(name)
+    }
+
+    fn read_file(&self, id: &AssetId) -> Result<Option<Vec<u8>>, BlobStorageError> {
+        match fs::read(self.path(id)) {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// 先写入临时文件再改名，中途退出不会留下不完整的数据
+    fn write_file(&self, id: &AssetId, data: &[u8]) -> Result<(), BlobStorageError> {
+        let path = self.path(id);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let tmp = path.with_extension(format!("tmp-{}", uuid::Uuid::new_v4()));
+        fs::write(&tmp, data)?;
+        fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+
+    /// 把`save_stream`写好的临时文件移动到`id`对应的位置
+    fn commit_file(&self, id: &AssetId, tmp: &Path) -> Result<(), BlobStorageError> {
+        let path = self.path(id);
+        let _guard = self.lock.lock().unwrap();
+        if path.exists() {
+            fs::remove_file(tmp)?;
+        } else {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::rename(tmp, &path)?;
+            tracing::debug!("Asset created on disk: {}", id);
+        }
+        self.inner.retain(*id)
+    }
+
+    fn remove_file(&self, id: &AssetId) -> Result<(), BlobStorageError> {
+        match fs::remove_file(self.path(id)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// 目录中的所有数据
+    fn file_ids(&self) -> Result<Vec<AssetId>, BlobStorageError> {
+        let mut ids = vec![];
+        for shard in subdirs(&self.dir)? {
+            for sub in subdirs(&shard)? {
+                for file in fs::read_dir(sub)? {
+                    let name = file?.file_name();
+                    // 跳过写入中的临时文件
+                    if let Some(id) = name
+                        .to_str()
+                        .and_then(unhex)
+                        .and_then(|b| AssetId::from_slice(&b))
+                    {
+                        ids.push(id);
+                    }
+                }
+            }
+        }
+        Ok(ids)
+    }
+}
+
+fn subdirs(dir: &Path) -> Result<Vec<PathBuf>, BlobStorageError> {
+    let mut dirs = vec![];
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            dirs.push(entry.path());
+        }
+    }
+    Ok(dirs)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[async_trait::async_trait]
+impl BlobStorage for FsBlobStorage {
+    fn save_with_id(&self, id: AssetId, data: &[u8]) -> Result<AssetId, BlobStorageError> {
+        if data.len() <= self.inline_threshold {
+            return self.inner.save_with_id(id, data);
+        }
+        let _guard = self.lock.lock().unwrap();
+        if !self.path(&id).exists() {
+            self.write_file(&id, data)?;
+            tracing::debug!("Asset created on disk: {}", id);
+        }
+        self.inner.retain(id)?;
+        Ok(id)
+    }
+
+    fn streams_to_storage(&self) -> bool {
+        true
+    }
+
+    /// 不超过阈值的数据留在内存中保存到`inner`，超过后写入临时文件
+    async fn save_stream(
+        &self,
+        reader: &mut (dyn AsyncRead + Unpin + Send),
+    ) -> Result<AssetId, BlobStorageError> {
+        let mut hasher = blake3::Hasher::new();
+        let mut head = Vec::new();
+        let mut buf = vec![0u8; STREAM_CHUNK_SIZE];
+        while head.len() <= self.inline_threshold {
+            let n = reader.read(&mut buf).await?;
+            if n == 0 {
+                return self
+                    .inner
+                    .save_with_id(AssetId::from_hash(&hasher.finalize()), &head);
+            }
+            hasher.update(&buf[..n]);
+            head.extend_from_slice(&buf[..n]);
+        }
+
+        // 临时文件放在分片目录外，`file_ids`不会把它当作数据
+        let tmp = self.dir.join(format!("tmp-{}", uuid::Uuid::new_v4()));
+        let written: Result<(), std::io::Error> = async {
+            let mut file = tokio::fs::File::create(&tmp).await?;
+            file.write_all(&head).await?;
+            loop {
+                let n = reader.read(&mut buf).await?;
+                if n == 0 {
+                    break;
+                }
+                hasher.update(&buf[..n]);
+                file.write_all(&buf[..n]).await?;
+            }
+            file.flush().await
+        }
+        .await;
+        let id = AssetId::from_hash(&hasher.finalize());
+        if let Err(e) = written
+            .map_err(BlobStorageError::from)
+            .and_then(|_| self.commit_file(&id, &tmp))
+        {
+            let _ = fs::remove_file(&tmp);
+            return Err(e);
+        }
+        Ok(id)
+    }
+
+    fn get(&self, id: AssetId) -> Result<Option<Vec<u8>>, BlobStorageError> {
+        match self.inner.get(id)? {
+            Some(data) => Ok(Some(data)),
+            None => self.read_file(&id),
+        }
+    }
+
+    fn open_read(&self, id: AssetId) -> Result<Option<BlobReader>, BlobStorageError> {
+        if let Some(reader) = self.inner.open_read(id)? {
+            return Ok(Some(reader));
+        }
+        let file = match fs::File::open(self.path(&id)) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Some(BlobReader {
+            len: file.metadata()?.len(),
+            reader: Box::pin(tokio::fs::File::from_std(file)),
+        }))
+    }
+
+    fn retain(&self, id: AssetId) -> Result<(), BlobStorageError> {
+        self.inner.retain(id)
+    }
+
+    fn release(&self, id: AssetId) -> Result<bool, BlobStorageError> {
+        let _guard = self.lock.lock().unwrap();
+        let deleted = self.inner.release(id)?;
+        if deleted {
+            self.remove_file(&id)?;
+        }
+        Ok(deleted)
+    }
+
+    fn entries(&self) -> Result<Vec<BlobEntry>, BlobStorageError> {
+        let mut entries = self
+            .inner
+            .entries()?
+            .into_iter()
+            .map(|e| (e.id, e))
+            .collect::<BTreeMap<_, _>>();
+        for id in self.file_ids()? {
+            entries
+                .entry(id)
+                .or_insert(BlobEntry {
+                    id,
+                    ref_count: None,
+                    has_data: true,
+                })
+                .has_data = true;
+        }
+        Ok(entries.into_values().collect())
+    }
+
+    fn raw_keys(&self) -> Result<Vec<Vec<u8>>, BlobStorageError> {
+        let mut keys = self.inner.raw_keys()?;
+        keys.extend(self.file_ids()?.iter().map(|id| id.as_bytes().to_vec()));
+        keys.sort();
+        keys.dedup();
+        Ok(keys)
+    }
+
+    fn set_ref_count(&self, id: AssetId, count: u64) -> Result<(), BlobStorageError> {
+        let _guard = self.lock.lock().unwrap();
+        self.inner.set_ref_count(id, count)?;
+        if count == 0 {
+            self.remove_file(&id)?;
+        }
+        Ok(())
+    }
+
+    fn peek_raw(&self, key: &[u8], n: usize) -> Result<Option<(Vec<u8>, usize)>, BlobStorageError> {
+        if let Some(peek) = self.inner.peek_raw(key, n)? {
+            return Ok(Some(peek));
+        }
+        let Some(id) = AssetId::from_slice(key) else {
+            return Ok(None);
+        };
+        let file = match fs::File::open(self.path(&id)) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let total = file.metadata()?.len() as usize;
+        let mut preview = Vec::with_capacity(n.min(total));
+        file.take(n as u64).read_to_end(&mut preview)?;
+        Ok(Some((preview, total)))
+    }
+
+    fn put_raw(&self, key: &[u8], value: &[u8]) -> Result<(), BlobStorageError> {
+        match AssetId::from_slice(key) {
+            Some(id) if value.len() > self.inline_threshold => self.write_file(&id, value),
+            _ => self.inner.put_raw(key, value),
+        }
+    }
+
+    fn get_raw(&self, key: &[u8]) -> Result<Option<Vec<u8>>, BlobStorageError> {
+        if let Some(data) = self.inner.get_raw(key)? {
+            return Ok(Some(data));
+        }
+        match AssetId::from_slice(key) {
+            Some(id) => self.read_file(&id),
+            None => Ok(None),
+        }
+    }
+
+    fn delete_raw(&self, key: &[u8]) -> Result<(), BlobStorageError> {
+        self.inner.delete_raw(key)?;
+        if let Some(id) = AssetId::from_slice(key) {
+            self.remove_file(&id)?;
+        }
+        Ok(())
+    }
+}
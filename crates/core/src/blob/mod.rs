@@ -1,9 +1,16 @@
 mod asset_id;
 mod blob;
+mod blob_meta;
+mod fs_blob;
 mod sled_blob;
 mod redb_blob;
+mod sqlite_blob;
 
 pub use asset_id::*;
 pub use blob::*;
+pub use blob_meta::BlobMeta;
+pub(crate) use blob_meta::{META_SNIFF_LEN, meta_key, meta_key_id};
+pub use fs_blob::*;
 pub use sled_blob::*;
 pub use redb_blob::*;
+pub use sqlite_blob::*;
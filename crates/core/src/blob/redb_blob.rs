@@ -1,6 +1,6 @@
 use std::sync::Arc;
 
-use crate::blob::{AssetId, BlobStorage, BlobStorageError};
+use crate::blob::{AssetId, BlobEntry, BlobStorage, BlobStorageError, merge_entries};
 use redb::{Database, ReadableDatabase, ReadableTable, TableDefinition};
 
 // Data 表: Key = 20字节 Hash, Value = 原始数据
@@ -34,9 +34,9 @@ impl RedbBlobStorage {
     }
 }
 
+// redb的值只能整条读写，`save_stream`和`open_read`使用先读入内存的默认实现
 impl BlobStorage for RedbBlobStorage {
-    fn save(&self, data: &[u8]) -> Result<AssetId, BlobStorageError> {
-        let id = AssetId::from_data(data);
+    fn save_with_id(&self, id: AssetId, data: &[u8]) -> Result<AssetId, BlobStorageError> {
         let key: &[u8] = id.as_bytes().as_slice();
 
         let write_txn = self
@@ -187,6 +187,90 @@ impl BlobStorage for RedbBlobStorage {
         Ok(())
     }
 
+    fn raw_keys(&self) -> Result<Vec<Vec<u8>>, BlobStorageError> {
+        let read_txn = self
+            .db
+            .begin_read()
+            .map_err(|e| BlobStorageError::StorageTransactionError(e.to_string()))?;
+        let table_blobs = read_txn
+            .open_table(TableBlob::new(&self.blob_name))
+            .map_err(|e| BlobStorageError::StorageTransactionError(e.to_string()))?;
+        let mut keys = Vec::new();
+        for item in table_blobs
+            .iter()
+            .map_err(|e| BlobStorageError::StorageTransactionError(e.to_string()))?
+        {
+            let (k, _) = item.map_err(|e| BlobStorageError::StorageTransactionError(e.to_string()))?;
+            keys.push(k.value().to_vec());
+        }
+        Ok(keys)
+    }
+
+    fn entries(&self) -> Result<Vec<BlobEntry>, BlobStorageError> {
+        let read_txn = self
+            .db
+            .begin_read()
+            .map_err(|e| BlobStorageError::StorageTransactionError(e.to_string()))?;
+        let table_blobs = read_txn
+            .open_table(TableBlob::new(&self.blob_name))
+            .map_err(|e| BlobStorageError::StorageTransactionError(e.to_string()))?;
+        let table_rc = read_txn
+            .open_table(TableRc::new(&self.rc_name))
+            .map_err(|e| BlobStorageError::StorageTransactionError(e.to_string()))?;
+
+        let mut data_keys = Vec::new();
+        for item in table_blobs
+            .iter()
+            .map_err(|e| BlobStorageError::StorageTransactionError(e.to_string()))?
+        {
+            let (k, _) =
+                item.map_err(|e| BlobStorageError::StorageTransactionError(e.to_string()))?;
+            data_keys.push(k.value().to_vec());
+        }
+        let mut ref_counts = Vec::new();
+        for item in table_rc
+            .iter()
+            .map_err(|e| BlobStorageError::StorageTransactionError(e.to_string()))?
+        {
+            let (k, v) =
+                item.map_err(|e| BlobStorageError::StorageTransactionError(e.to_string()))?;
+            ref_counts.push((k.value().to_vec(), v.value()));
+        }
+        Ok(merge_entries(data_keys.into_iter(), ref_counts.into_iter()))
+    }
+
+    fn set_ref_count(&self, id: AssetId, count: u64) -> Result<(), BlobStorageError> {
+        let key = id.as_bytes().as_slice();
+        let write_txn = self
+            .db
+            .begin_write()
+            .map_err(|e| BlobStorageError::StorageTransactionError(e.to_string()))?;
+        {
+            let mut table_blobs = write_txn
+                .open_table(TableBlob::new(&self.blob_name))
+                .map_err(|e| BlobStorageError::StorageTransactionError(e.to_string()))?;
+            let mut table_rc = write_txn
+                .open_table(TableRc::new(&self.rc_name))
+                .map_err(|e| BlobStorageError::StorageTransactionError(e.to_string()))?;
+            if count == 0 {
+                table_rc
+                    .remove(key)
+                    .map_err(|e| BlobStorageError::StorageTransactionError(e.to_string()))?;
+                table_blobs
+                    .remove(key)
+                    .map_err(|e| BlobStorageError::StorageTransactionError(e.to_string()))?;
+            } else {
+                table_rc
+                    .insert(key, count)
+                    .map_err(|e| BlobStorageError::StorageTransactionError(e.to_string()))?;
+            }
+        }
+        write_txn
+            .commit()
+            .map_err(|e| BlobStorageError::StorageTransactionError(e.to_string()))?;
+        Ok(())
+    }
+
     fn get_raw(&self, key: &[u8]) -> Result<Option<Vec<u8>>, BlobStorageError> {
         let read_txn = self
             .db
@@ -1,6 +1,6 @@
-use crate::{BlobStorage, BlobStorageError};
-use sled::{Transactional, transaction::TransactionError};
 use crate::AssetId;
+use crate::{BlobEntry, BlobStorage, BlobStorageError, merge_entries};
+use sled::{Transactional, transaction::TransactionError};
 
 #[derive(Clone)]
 pub struct SledBlobStorage {
@@ -23,9 +23,9 @@ impl SledBlobStorage {
     }
 }
 
+// sled的值只能整条读写，`save_stream`和`open_read`使用先读入内存的默认实现
 impl BlobStorage for SledBlobStorage {
-    fn save(&self, data: &[u8]) -> Result<AssetId, BlobStorageError> {
-        let uuid = AssetId::from_data(data);
+    fn save_with_id(&self, uuid: AssetId, data: &[u8]) -> Result<AssetId, BlobStorageError> {
         let key = uuid.as_bytes();
         let tx_result: Result<(), TransactionError<sled::Error>> = (&self.data_tree, &self.rc_tree)
             .transaction(|(d_tree, r_tree)| {
@@ -100,6 +100,47 @@ impl BlobStorage for SledBlobStorage {
         Ok(tx_result?)
     }
 
+    fn entries(&self) -> Result<Vec<BlobEntry>, BlobStorageError> {
+        let data_keys = self.raw_keys()?;
+        let ref_counts = self
+            .rc_tree
+            .iter()
+            .map(|item| {
+                let (k, v) = item?;
+                let bytes: [u8; 8] = v
+                    .as_ref()
+                    .try_into()
+                    .map_err(|_| BlobStorageError::InvalidRefCountData)?;
+                Ok((k.to_vec(), u64::from_be_bytes(bytes)))
+            })
+            .collect::<Result<Vec<_>, BlobStorageError>>()?;
+        Ok(merge_entries(data_keys.into_iter(), ref_counts.into_iter()))
+    }
+
+    fn raw_keys(&self) -> Result<Vec<Vec<u8>>, BlobStorageError> {
+        Ok(self
+            .data_tree
+            .iter()
+            .keys()
+            .map(|k| k.map(|k| k.to_vec()))
+            .collect::<Result<Vec<_>, _>>()?)
+    }
+
+    fn set_ref_count(&self, uuid: AssetId, count: u64) -> Result<(), BlobStorageError> {
+        let key = uuid.as_bytes();
+        let tx_result: Result<(), TransactionError<sled::Error>> = (&self.data_tree, &self.rc_tree)
+            .transaction(|(d_tree, r_tree)| {
+                if count == 0 {
+                    r_tree.remove(key)?;
+                    d_tree.remove(key)?;
+                } else {
+                    r_tree.insert(key, &count.to_be_bytes())?;
+                }
+                Ok(())
+            });
+        Ok(tx_result?)
+    }
+
     fn put_raw(&self, key: &[u8], value: &[u8]) -> Result<(), BlobStorageError> {
         self.data_tree.insert(key, value)?;
         Ok(())
@@ -0,0 +1,425 @@
+use std::{
+    io::{self, Cursor},
+    sync::{Arc, Mutex},
+};
+
+use rusqlite::{Connection, OptionalExtension, params};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio_util::io::StreamReader;
+
+use crate::blob::{
+    AssetId, BlobEntry, BlobReader, BlobStorage, BlobStorageError, STREAM_CHUNK_SIZE, merge_entries,
+};
+
+// 数据表: 用rowid做主键，这样`peek_raw`可以按rowid打开blob增量读取
+// 引用计数表: Key = 20字节 Hash, Value = 引用计数
+// 暂存表: `save_stream`还没读完的数据块，读完后合并到数据表
+pub struct SqliteBlobStorage {
+    conn: Arc<Mutex<Connection>>,
+    blob_table: String,
+    rc_table: String,
+    upload_table: String,
+}
+
+impl SqliteBlobStorage {
+    pub fn new(conn: Arc<Mutex<Connection>>, table_name: &str) -> Result<Self, BlobStorageError> {
+        let blob_table = table_name.to_string();
+        let rc_table = format!("{}_rc", table_name);
+        let upload_table = format!("{}_upload", table_name);
+        conn.lock().unwrap().execute_batch(&format!(
+            "CREATE TABLE IF NOT EXISTS \"{blob_table}\" (
+                id INTEGER PRIMARY KEY,
+                key BLOB NOT NULL UNIQUE,
+                data BLOB NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS \"{rc_table}\" (
+                key BLOB PRIMARY KEY,
+                count INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS \"{upload_table}\" (
+                upload BLOB NOT NULL,
+                seq INTEGER NOT NULL,
+                data BLOB NOT NULL,
+                PRIMARY KEY (upload, seq)
+            );
+            -- 上次进程中断时没有读完的上传
+            DELETE FROM \"{upload_table}\";"
+        ))?;
+        Ok(Self {
+            conn,
+            blob_table,
+            rc_table,
+            upload_table,
+        })
+    }
+
+    fn ref_count(&self, conn: &Connection, key: &[u8]) -> Result<Option<u64>, BlobStorageError> {
+        Ok(conn
+            .query_row(
+                &format!("SELECT count FROM \"{}\" WHERE key = ?1", self.rc_table),
+                [key],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    fn set_count(&self, conn: &Connection, key: &[u8], count: u64) -> Result<(), BlobStorageError> {
+        conn.execute(
+            &format!(
+                "INSERT INTO \"{}\" (key, count) VALUES (?1, ?2)
+                 ON CONFLICT(key) DO UPDATE SET count = excluded.count",
+                self.rc_table
+            ),
+            params![key, count],
+        )?;
+        Ok(())
+    }
+
+    fn remove(&self, conn: &Connection, key: &[u8]) -> Result<(), BlobStorageError> {
+        conn.execute(
+            &format!("DELETE FROM \"{}\" WHERE key = ?1", self.rc_table),
+            [key],
+        )?;
+        conn.execute(
+            &format!("DELETE FROM \"{}\" WHERE key = ?1", self.blob_table),
+            [key],
+        )?;
+        Ok(())
+    }
+
+    /// 把暂存的数据块按顺序写入`id`的zeroblob，数据已经存在时只增加引用计数
+    fn commit_upload(
+        &self,
+        id: AssetId,
+        upload: &[u8],
+        len: usize,
+    ) -> Result<(), BlobStorageError> {
+        let key = id.as_bytes().as_slice();
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let exists = tx
+            .query_row(
+                &format!("SELECT 1 FROM \"{}\" WHERE key = ?1", self.blob_table),
+                [key],
+                |_| Ok(()),
+            )
+            .optional()?
+            .is_some();
+        let rc = if exists {
+            tracing::debug!("Asset Deduplicated: {}", id);
+            self.ref_count(&tx, key)?.unwrap_or(0) + 1
+        } else {
+            tx.execute(
+                &format!(
+                    "INSERT INTO \"{}\" (key, data) VALUES (?1, zeroblob(?2))",
+                    self.blob_table
+                ),
+                params![key, len as i64],
+            )?;
+            let mut blob = tx.blob_open(
+                "main",
+                self.blob_table.as_str(),
+                "data",
+                tx.last_insert_rowid(),
+                false,
+            )?;
+            let mut chunks = tx.prepare(&format!(
+                "SELECT data FROM \"{}\" WHERE upload = ?1 ORDER BY seq",
+                self.upload_table
+            ))?;
+            let mut rows = chunks.query([upload])?;
+            let mut offset = 0;
+            while let Some(row) = rows.next()? {
+                let chunk: Vec<u8> = row.get(0)?;
+                blob.write_at(&chunk, offset)?;
+                offset += chunk.len();
+            }
+            tracing::debug!("Asset Created: {}", id);
+            1
+        };
+        self.set_count(&tx, key, rc)?;
+        tx.execute(
+            &format!("DELETE FROM \"{}\" WHERE upload = ?1", self.upload_table),
+            [upload],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+/// 读满一块或读到结尾，返回读到的字节数
+async fn read_chunk(
+    reader: &mut (dyn AsyncRead + Unpin + Send),
+    buf: &mut [u8],
+) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        let n = reader.read(&mut buf[filled..]).await?;
+        if n == 0 {
+            break;
+        }
+        filled += n;
+    }
+    Ok(filled)
+}
+
+#[async_trait::async_trait]
+impl BlobStorage for SqliteBlobStorage {
+    fn save_with_id(&self, id: AssetId, data: &[u8]) -> Result<AssetId, BlobStorageError> {
+        let key = id.as_bytes().as_slice();
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let exists = tx
+            .query_row(
+                &format!("SELECT 1 FROM \"{}\" WHERE key = ?1", self.blob_table),
+                [key],
+                |_| Ok(()),
+            )
+            .optional()?
+            .is_some();
+        let rc = if exists {
+            tracing::debug!("Asset Deduplicated: {}", id);
+            self.ref_count(&tx, key)?.unwrap_or(0) + 1
+        } else {
+            tx.execute(
+                &format!(
+                    "INSERT INTO \"{}\" (key, data) VALUES (?1, ?2)",
+                    self.blob_table
+                ),
+                params![key, data],
+            )?;
+            tracing::debug!("Asset Created: {}", id);
+            1
+        };
+        self.set_count(&tx, key, rc)?;
+        tx.commit()?;
+        Ok(id)
+    }
+
+    fn streams_to_storage(&self) -> bool {
+        true
+    }
+
+    /// 每块先写入暂存表，读完后一次合并，内存中只有一块数据
+    async fn save_stream(
+        &self,
+        reader: &mut (dyn AsyncRead + Unpin + Send),
+    ) -> Result<AssetId, BlobStorageError> {
+        let upload = uuid::Uuid::new_v4();
+        let upload = upload.as_bytes().as_slice();
+        let mut hasher = blake3::Hasher::new();
+        let mut buf = vec![0u8; STREAM_CHUNK_SIZE];
+        let mut len = 0;
+        let staged: Result<(), BlobStorageError> = async {
+            for seq in 0i64.. {
+                let n = read_chunk(reader, &mut buf).await?;
+                if n == 0 {
+                    break;
+                }
+                hasher.update(&buf[..n]);
+                len += n;
+                self.conn.lock().unwrap().execute(
+                    &format!(
+                        "INSERT INTO \"{}\" (upload, seq, data) VALUES (?1, ?2, ?3)",
+                        self.upload_table
+                    ),
+                    params![upload, seq, &buf[..n]],
+                )?;
+            }
+            Ok(())
+        }
+        .await;
+        let id = AssetId::from_hash(&hasher.finalize());
+        if let Err(e) = staged.and_then(|_| self.commit_upload(id, upload, len)) {
+            let _ = self.conn.lock().unwrap().execute(
+                &format!("DELETE FROM \"{}\" WHERE upload = ?1", self.upload_table),
+                [upload],
+            );
+            return Err(e);
+        }
+        Ok(id)
+    }
+
+    fn open_read(&self, id: AssetId) -> Result<Option<BlobReader>, BlobStorageError> {
+        let row: Option<(i64, u64)> = self
+            .conn
+            .lock()
+            .unwrap()
+            .query_row(
+                &format!(
+                    "SELECT id, length(data) FROM \"{}\" WHERE key = ?1",
+                    self.blob_table
+                ),
+                [id.as_bytes().as_slice()],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+        let Some((rowid, len)) = row else {
+            return Ok(None);
+        };
+        let conn = self.conn.clone();
+        let table = self.blob_table.clone();
+        let key = id.as_bytes().to_vec();
+        // 每读一块重新锁住连接打开blob，读取大数据时不会一直占用连接
+        let chunks = futures::stream::try_unfold(0u64, move |offset| {
+            let conn = conn.clone();
+            let table = table.clone();
+            let key = key.clone();
+            async move {
+                if offset >= len {
+                    return Ok(None);
+                }
+                let conn = conn.lock().unwrap();
+                // 两次读取之间数据可能被删除，rowid又被新数据复用，重新打开前确认还是同一条
+                let current: Option<i64> = conn
+                    .query_row(
+                        &format!("SELECT id FROM \"{}\" WHERE key = ?1", table),
+                        [key.as_slice()],
+                        |row| row.get(0),
+                    )
+                    .optional()
+                    .map_err(io::Error::other)?;
+                if current != Some(rowid) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        "Blob was deleted while reading",
+                    ));
+                }
+                let blob = conn
+                    .blob_open("main", table.as_str(), "data", rowid, true)
+                    .map_err(io::Error::other)?;
+                let mut chunk = vec![0u8; (len - offset).min(STREAM_CHUNK_SIZE as u64) as usize];
+                blob.read_at_exact(&mut chunk, offset as usize)?;
+                let next = offset + chunk.len() as u64;
+                Ok::<_, io::Error>(Some((Cursor::new(chunk), next)))
+            }
+        });
+        Ok(Some(BlobReader {
+            len,
+            reader: Box::pin(StreamReader::new(chunks)),
+        }))
+    }
+
+    fn retain(&self, id: AssetId) -> Result<(), BlobStorageError> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            &format!(
+                "INSERT INTO \"{}\" (key, count) VALUES (?1, 1)
+                 ON CONFLICT(key) DO UPDATE SET count = count + 1",
+                self.rc_table
+            ),
+            [id.as_bytes().as_slice()],
+        )?;
+        Ok(())
+    }
+
+    fn release(&self, id: AssetId) -> Result<bool, BlobStorageError> {
+        let key = id.as_bytes().as_slice();
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let deleted = match self.ref_count(&tx, key)? {
+            // 数据不存在，视作不需要操作
+            None => false,
+            Some(count) if count <= 1 => {
+                self.remove(&tx, key)?;
+                true
+            }
+            Some(count) => {
+                self.set_count(&tx, key, count - 1)?;
+                false
+            }
+        };
+        tx.commit()?;
+        Ok(deleted)
+    }
+
+    fn entries(&self) -> Result<Vec<BlobEntry>, BlobStorageError> {
+        let conn = self.conn.lock().unwrap();
+        let data_keys = conn
+            .prepare(&format!("SELECT key FROM \"{}\"", self.blob_table))?
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<Vec<Vec<u8>>, _>>()?;
+        let ref_counts = conn
+            .prepare(&format!("SELECT key, count FROM \"{}\"", self.rc_table))?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<Vec<(Vec<u8>, u64)>, _>>()?;
+        Ok(merge_entries(data_keys.into_iter(), ref_counts.into_iter()))
+    }
+
+    fn raw_keys(&self) -> Result<Vec<Vec<u8>>, BlobStorageError> {
+        let conn = self.conn.lock().unwrap();
+        let keys = conn
+            .prepare(&format!(
+                "SELECT key FROM \"{}\" ORDER BY key",
+                self.blob_table
+            ))?
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<Vec<Vec<u8>>, _>>()?;
+        Ok(keys)
+    }
+
+    fn set_ref_count(&self, id: AssetId, count: u64) -> Result<(), BlobStorageError> {
+        let key = id.as_bytes().as_slice();
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        if count == 0 {
+            self.remove(&tx, key)?;
+        } else {
+            self.set_count(&tx, key, count)?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn peek_raw(&self, key: &[u8], n: usize) -> Result<Option<(Vec<u8>, usize)>, BlobStorageError> {
+        let conn = self.conn.lock().unwrap();
+        let rowid: Option<i64> = conn
+            .query_row(
+                &format!("SELECT id FROM \"{}\" WHERE key = ?1", self.blob_table),
+                [key],
+                |row| row.get(0),
+            )
+            .optional()?;
+        let Some(rowid) = rowid else {
+            return Ok(None);
+        };
+        // 只读取前n个字节，不把整个blob读入内存
+        let blob = conn.blob_open("main", self.blob_table.as_str(), "data", rowid, true)?;
+        let total = blob.len();
+        let mut preview = vec![0u8; n.min(total)];
+        blob.read_at_exact(&mut preview, 0)?;
+        Ok(Some((preview, total)))
+    }
+
+    fn put_raw(&self, key: &[u8], value: &[u8]) -> Result<(), BlobStorageError> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            &format!(
+                "INSERT INTO \"{}\" (key, data) VALUES (?1, ?2)
+                 ON CONFLICT(key) DO UPDATE SET data = excluded.data",
+                self.blob_table
+            ),
+            params![key, value],
+        )?;
+        Ok(())
+    }
+
+    fn get_raw(&self, key: &[u8]) -> Result<Option<Vec<u8>>, BlobStorageError> {
+        let conn = self.conn.lock().unwrap();
+        Ok(conn
+            .query_row(
+                &format!("SELECT data FROM \"{}\" WHERE key = ?1", self.blob_table),
+                [key],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    fn delete_raw(&self, key: &[u8]) -> Result<(), BlobStorageError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        self.remove(&tx, key)?;
+        tx.commit()?;
+        Ok(())
+    }
+}
@@ -1,20 +1,34 @@
-use std::{collections::HashSet, path::Path, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    io::{Read, Write},
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use crate::{
-    AssetId, ChatEntry, ChatMeta, FN_MAX_LEN, FN_STOP_WORDS, StorageKind, Storages,
-    ToolDescription, ToolKind,
+    AssetId, BlobMeta, BlobReader, BlobReferences, BlobStorage, BlobStorageError, BranchInfo,
+    ChatEntry, ChatMeta, ChatReport, ContextManager, ContextSummary, DEFAULT_IMAGE_MAX_PIXELS,
+    DEFAULT_IMAGE_MIN_PIXELS, FsBlobOptions, FsckReport, ImageFormatPolicy, ImageResizer,
+    ImportReport, JsLimits, JsSessionOptions, META_SNIFF_LEN, PushedMessage, ReportFormat,
+    ReportOptions, SearchHit, SearchResult, StorageKind, Storages, StreamEvent, StreamParser,
+    ToolCallFormat, ToolDescription, ToolFormatKind, ToolKind, check_store, image_dimensions,
+    image_mime_type, normalize_image, pending_summary, rank, resize_for_model,
     schema::{Message, MessageContent, Role, ToolUse},
-    tools::{FN_ARGS, FN_EXIT, FN_NAME, FN_RESULT, ToolSet},
+    search::{TOKENIZER_VERSION_TERM, query_tokens, searchable_text, term_frequencies, tokenize},
+    tools::{ToolContext, ToolProgress, ToolSet, delete_memo, memo_image_refs, tool_result},
 };
 use anyhow::{Error, anyhow, bail};
 use async_openai::types::{
-    ChatCompletionRequestAssistantMessage, ChatCompletionRequestAssistantMessageContent,
-    ChatCompletionRequestAssistantMessageContentPart, ChatCompletionRequestMessageContentPartImage,
-    ChatCompletionRequestMessageContentPartText, ChatCompletionRequestSystemMessage,
-    ChatCompletionRequestSystemMessageContent, ChatCompletionRequestToolMessage,
-    ChatCompletionRequestToolMessageContent, ChatCompletionRequestToolMessageContentPart,
-    ChatCompletionRequestUserMessage, ChatCompletionRequestUserMessageContent,
-    ChatCompletionStreamOptions, CompletionUsage,
+    ChatCompletionMessageToolCall, ChatCompletionMessageToolCallChunk,
+    ChatCompletionMessageToolCalls, ChatCompletionRequestAssistantMessage,
+    ChatCompletionRequestAssistantMessageContent, ChatCompletionRequestAssistantMessageContentPart,
+    ChatCompletionRequestMessageContentPartImage, ChatCompletionRequestMessageContentPartText,
+    ChatCompletionRequestSystemMessage, ChatCompletionRequestSystemMessageContent,
+    ChatCompletionRequestToolMessage, ChatCompletionRequestToolMessageContent,
+    ChatCompletionRequestToolMessageContentPart, ChatCompletionRequestUserMessage,
+    ChatCompletionRequestUserMessageContent, ChatCompletionStreamOptions, CompletionUsage,
+    FunctionCall,
 };
 
 use async_openai::{
@@ -29,6 +43,8 @@ use async_stream::try_stream;
 use base64::{Engine, prelude::BASE64_STANDARD};
 use futures::{Stream, StreamExt};
 use serde::{Deserialize, Serialize};
+use strum::{Display, EnumString};
+use tokio::io::AsyncRead;
 use tokio_util::sync::CancellationToken;
 use uuid::Uuid;
 
@@ -46,6 +62,39 @@ pub struct LLMConfig {
     pub parallel_function_call: Option<bool>,
     pub system_prompt_lang: Option<whatlang::Lang>,
     pub custom_system_prompt: Option<String>,
+    pub tool_call_mode: Option<ToolCallMode>,
+    /// 文本协议下的工具调用格式，为空时根据模型名判断
+    pub tool_call_format: Option<ToolFormatKind>,
+    /// 一次回复中最多执行几轮工具调用，为空时不限制
+    pub max_tool_rounds: Option<u32>,
+    /// 一次回复中最多执行多少次工具调用
+    pub max_tool_calls: Option<u32>,
+    /// 一次回复最长的时间（秒）
+    pub max_duration_secs: Option<u64>,
+    /// 单次工具调用最长的时间（秒），为空时不限制
+    pub tool_timeout_secs: Option<u64>,
+    /// 上下文的token预算，为空时发送全部历史
+    pub context_token_budget: Option<usize>,
+    /// 是否让模型总结被丢弃的旧对话
+    pub context_summarize: Option<bool>,
+    /// 发送给模型前把图片缩小到的像素范围，都为空时发送原图
+    pub image_min_pixels: Option<u64>,
+    pub image_max_pixels: Option<u64>,
+}
+
+/// 工具调用使用的协议
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Default, EnumString, Display, Serialize, Deserialize,
+)]
+#[strum(serialize_all = "snake_case")]
+pub enum ToolCallMode {
+    /// 在文本中使用 ✿FUNCTION✿/✿ARGS✿ 标记，工具写在system prompt里
+    #[default]
+    #[strum(serialize = "text")]
+    Text,
+    /// OpenAI原生的`tools`/`tool_calls` (llama.cpp `--jinja`, vLLM等)
+    #[strum(serialize = "native")]
+    Native,
 }
 
 impl LLMConfig {
@@ -66,8 +115,57 @@ impl LLMConfig {
             parallel_function_call: self.parallel_function_call.or(other.parallel_function_call),
             system_prompt_lang: self.system_prompt_lang.or(other.system_prompt_lang),
             custom_system_prompt: self.custom_system_prompt.or(other.custom_system_prompt),
+            tool_call_mode: self.tool_call_mode.or(other.tool_call_mode),
+            tool_call_format: self.tool_call_format.or(other.tool_call_format),
+            max_tool_rounds: self.max_tool_rounds.or(other.max_tool_rounds),
+            max_tool_calls: self.max_tool_calls.or(other.max_tool_calls),
+            max_duration_secs: self.max_duration_secs.or(other.max_duration_secs),
+            tool_timeout_secs: self.tool_timeout_secs.or(other.tool_timeout_secs),
+            context_token_budget: self.context_token_budget.or(other.context_token_budget),
+            context_summarize: self.context_summarize.or(other.context_summarize),
+            image_min_pixels: self.image_min_pixels.or(other.image_min_pixels),
+            image_max_pixels: self.image_max_pixels.or(other.image_max_pixels),
         }
     }
+
+    /// 检查工具循环是否超出预算
+    pub fn check_loop_limit(
+        &self,
+        tool_rounds: u32,
+        tool_calls: u32,
+        elapsed: Duration,
+    ) -> Option<LoopLimit> {
+        if let Some(max) = self.max_tool_rounds.filter(|max| tool_rounds >= *max) {
+            return Some(LoopLimit::ToolRounds(max));
+        }
+        if let Some(max) = self.max_tool_calls.filter(|max| tool_calls >= *max) {
+            return Some(LoopLimit::ToolCalls(max));
+        }
+        if let Some(max) = self
+            .max_duration_secs
+            .filter(|max| elapsed >= Duration::from_secs(*max))
+        {
+            return Some(LoopLimit::Duration(max));
+        }
+        None
+    }
+
+    /// 发送给模型前缩放图片使用的resizer，没有设置像素范围时为空
+    pub fn image_resizer(&self) -> Option<ImageResizer> {
+        if self.image_min_pixels.is_none() && self.image_max_pixels.is_none() {
+            return None;
+        }
+        Some(ImageResizer::new(
+            32,
+            self.image_min_pixels.unwrap_or(DEFAULT_IMAGE_MIN_PIXELS),
+            self.image_max_pixels.unwrap_or(DEFAULT_IMAGE_MAX_PIXELS),
+        ))
+    }
+
+    pub fn tool_format(&self) -> ToolFormatKind {
+        self.tool_call_format
+            .unwrap_or_else(|| ToolFormatKind::detect(self.model.as_deref().unwrap_or_default()))
+    }
 }
 
 impl Default for LLMConfig {
@@ -85,6 +183,37 @@ impl Default for LLMConfig {
             parallel_function_call: None,
             system_prompt_lang: Some(whatlang::Lang::Cmn),
             custom_system_prompt: None,
+            tool_call_mode: Some(ToolCallMode::Text),
+            tool_call_format: None,
+            max_tool_rounds: None,
+            max_tool_calls: None,
+            max_duration_secs: None,
+            tool_timeout_secs: None,
+            context_token_budget: None,
+            context_summarize: None,
+            image_min_pixels: None,
+            image_max_pixels: None,
+        }
+    }
+}
+
+/// 工具循环的预算
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LoopLimit {
+    /// 工具调用的轮数
+    ToolRounds(u32),
+    /// 工具调用的总次数
+    ToolCalls(u32),
+    /// 经过的时间（秒）
+    Duration(u64),
+}
+
+impl std::fmt::Display for LoopLimit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoopLimit::ToolRounds(n) => write!(f, "max_tool_rounds={}", n),
+            LoopLimit::ToolCalls(n) => write!(f, "max_tool_calls={}", n),
+            LoopLimit::Duration(n) => write!(f, "max_duration_secs={}", n),
         }
     }
 }
@@ -129,28 +258,35 @@ pub enum ChatEvent {
         result: Message,
     },
 
+    /// 正在执行的工具报告的进度，结果到达前可能有多个
+    ToolProgress(ToolProgress),
+
     /// LLM的“最终回复”的增量。
     ContentDelta(String),
     /// 通知UI已经结束
     StreamEnd {},
     /// Token数量的通知
     Usage(CompletionUsage),
+    /// 工具循环达到了上限，接下来是不使用工具的最终回答
+    LoopLimitReached(LoopLimit),
     /// 服务器错误信息
     Error(String),
 }
 
-#[derive(Debug, Clone, Copy)]
-enum StreamParseState {
-    /// 初始状态, 正在“窥视” FN_NAME 或等待足够的数据来决定
-    AwaitingDecision,
-    /// 已确定这是一个纯内容（无工具）流
-    /// 现在这个没有任何用处，因为一个流要不然是tool use，要不然是未来可能有tool use
-    #[allow(dead_code)]
-    Content,
-    /// 已找到 FN_NAME, 正在解析工具名称
-    ToolCallName,
-    /// 已找到 FN_ARGS, 正在解析工具参数
-    ToolCallArgs,
+/// 创建LLMProvider时的选项
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ProviderOptions {
+    /// 上传和下载的图片保存时使用的格式
+    pub image_format: ImageFormatPolicy,
+    /// 保存为文件的存储
+    #[serde(default)]
+    pub fs_blob: FsBlobOptions,
+    /// JS解释器的执行时间和内存限制
+    #[serde(default)]
+    pub js_limits: JsLimits,
+    /// 每个会话保留JS环境的有状态模式
+    #[serde(default)]
+    pub js_sessions: JsSessionOptions,
 }
 
 pub struct LLMProvider<T>
@@ -160,6 +296,7 @@ where
     client: Arc<Client<T>>,
     storages: Storages,
     toolset: Arc<ToolSet>,
+    options: ProviderOptions,
 }
 
 impl<T: Config> Clone for LLMProvider<T> {
@@ -168,6 +305,7 @@ impl<T: Config> Clone for LLMProvider<T> {
             client: self.client.clone(),
             storages: self.storages.clone(),
             toolset: self.toolset.clone(),
+            options: self.options.clone(),
         }
     }
 }
@@ -179,7 +317,23 @@ impl<T: Config> LLMProvider<T> {
         db: StorageKind,
         active_tools: &[ToolKind],
     ) -> Result<Self, Error> {
-        let storages = db.create_storages(db_path)?;
+        Self::new_with_options(
+            client,
+            db_path,
+            db,
+            active_tools,
+            ProviderOptions::default(),
+        )
+    }
+
+    pub fn new_with_options<P: AsRef<Path>>(
+        client: Client<T>,
+        db_path: P,
+        db: StorageKind,
+        active_tools: &[ToolKind],
+        options: ProviderOptions,
+    ) -> Result<Self, Error> {
+        let storages = db.create_storages_with(db_path, &options.fs_blob)?;
         tracing::info!("DB started.");
         let active_tools = active_tools
             .iter()
@@ -193,16 +347,33 @@ impl<T: Config> LLMProvider<T> {
                     storages.image.clone(),
                     storages.asset.clone(),
                     storages.memo.clone(),
+                    options.image_format,
+                    options.js_limits,
+                    options.js_sessions,
                 )
             })
             .fold(ToolSet::builder(), |ts, t| ts.add_tool(t))
             .build();
         tracing::info!("Active tools: {}", toolset);
-        Ok(Self {
+        let provider = Self {
             client: Arc::new(client),
             storages,
             toolset: Arc::new(toolset),
-        })
+            options,
+        };
+        // 旧数据库还没有索引，或者索引使用的是旧的分词方式
+        if provider
+            .storages
+            .search
+            .lookup(TOKENIZER_VERSION_TERM)?
+            .is_empty()
+        {
+            let indexed = provider.rebuild_search_index()?;
+            if indexed > 0 {
+                tracing::info!("Built search index for {} chats.", indexed);
+            }
+        }
+        Ok(provider)
     }
 
     pub async fn get_model_names(&self) -> Result<Vec<String>, anyhow::Error> {
@@ -211,13 +382,92 @@ impl<T: Config> LLMProvider<T> {
     }
 
     pub async fn call_tool(&self, tool: ToolUse) -> Message {
-        self.toolset.use_tool_async(tool).await.1
+        self.toolset
+            .use_tool_async(&ToolContext::default(), tool)
+            .await
+            .1
     }
 
     pub fn list_tools(&self) -> Vec<ToolDescription> {
         self.toolset.list_tools_to_human()
     }
 
+    /// 在所有会话的消息中搜索`query`，按相关度排序
+    pub fn search_history(
+        &self,
+        query: &str,
+        limit: Option<usize>,
+        offset: Option<usize>,
+    ) -> Result<Vec<SearchResult>, Error> {
+        let terms = query_tokens(query)
+            .into_iter()
+            .map(|t| t.term)
+            .collect::<HashSet<String>>();
+        let postings = terms
+            .iter()
+            .map(|t| Ok((t.clone(), self.storages.search.lookup(t)?)))
+            .collect::<Result<Vec<_>, Error>>()?;
+        let mut results = vec![];
+        for (chat_id, score, messages) in rank(&postings)
+            .into_iter()
+            .skip(offset.unwrap_or(0))
+            .take(limit.unwrap_or(20))
+        {
+            let Some(entry) = self.get_chat(chat_id)? else {
+                continue;
+            };
+            let hits = messages
+                .iter()
+                .filter_map(|(id, score)| {
+                    entry
+                        .messages
+                        .iter()
+                        .chain(entry.inactive.iter())
+                        .find(|m| m.id == *id)
+                        .map(|m| SearchHit::new(m, *score, &terms))
+                })
+                .collect();
+            results.push(SearchResult {
+                chat: ChatMeta::clone_from(&entry),
+                score,
+                hits,
+            });
+        }
+        Ok(results)
+    }
+
+    /// 重新索引所有会话，返回会话数
+    pub fn rebuild_search_index(&self) -> Result<usize, Error> {
+        let chats = self.storages.history.list(None, None)?;
+        for (chat_id, _) in chats.iter() {
+            self.storages.search.remove_chat(*chat_id)?;
+            if let Some(entry) = self.get_chat(*chat_id)? {
+                for msg in entry.messages.iter().chain(entry.inactive.iter()) {
+                    self.index_message(*chat_id, msg);
+                }
+            }
+        }
+        self.storages.search.index(
+            Uuid::nil(),
+            Uuid::nil(),
+            &[(TOKENIZER_VERSION_TERM.to_string(), 1)],
+        )?;
+        Ok(chats.len())
+    }
+
+    /// 索引失败不影响消息的保存，只记录错误
+    fn index_message(&self, chat_id: Uuid, message: &Message) {
+        let terms = term_frequencies(&tokenize(&searchable_text(message)));
+        if let Err(e) = self.storages.search.index(chat_id, message.id, &terms) {
+            tracing::error!(
+                "Failed to index message {} of chat {}: {}",
+                message.id,
+                chat_id,
+                e
+            );
+        }
+    }
+
     pub fn get_history_list(
         &self,
         limit: Option<usize>,
@@ -235,10 +485,28 @@ impl<T: Config> LLMProvider<T> {
     }
 
     pub fn get_chat(&self, chat_id: Uuid) -> Result<Option<ChatEntry>, Error> {
-        match self.storages.history.get_data(chat_id)? {
-            Some(ivec) => Ok(serde_json::from_slice(&ivec)?),
-            None => Ok(None),
-        }
+        self.open_chat(chat_id)?
+            .map(StoredChat::into_entry)
+            .transpose()
+    }
+
+    /// 读出会话但不解析消息，只需要其中几条时使用
+    pub fn open_chat(&self, chat_id: Uuid) -> Result<Option<StoredChat>, Error> {
+        let Some((data, messages)) = self.storages.history.get_data(chat_id)? else {
+            return Ok(None);
+        };
+        // 摘要只在追加消息时写入meta
+        let summary = self
+            .storages
+            .history
+            .get_meta(chat_id)?
+            .and_then(|meta| serde_json::from_slice::<ChatMeta>(&meta).ok())
+            .map(|meta| meta.summary);
+        Ok(Some(StoredChat {
+            data,
+            messages,
+            summary,
+        }))
     }
 
     pub fn get_image(&self, image_id: AssetId) -> Result<Option<Vec<u8>>, Error> {
@@ -248,8 +516,35 @@ impl<T: Config> LLMProvider<T> {
         }
     }
 
+    /// 获取图片以及根据文件头判断的MIME类型
+    pub fn get_image_with_mime(
+        &self,
+        image_id: AssetId,
+    ) -> Result<Option<(Vec<u8>, &'static str)>, Error> {
+        Ok(self.get_image(image_id)?.map(|data| {
+            let mime = image_mime_type(&data).unwrap_or("application/octet-stream");
+            (data, mime)
+        }))
+    }
+
+    /// 以流的方式读取图片，MIME类型根据开头的数据判断
+    pub fn open_image_with_mime(
+        &self,
+        image_id: AssetId,
+    ) -> Result<Option<(BlobReader, &'static str)>, Error> {
+        let Some((head, _)) = self.storages.image.peek(image_id, 64)? else {
+            return Ok(None);
+        };
+        let mime = image_mime_type(&head).unwrap_or("application/octet-stream");
+        Ok(self
+            .storages
+            .image
+            .open_read(image_id)?
+            .map(|reader| (reader, mime)))
+    }
+
     pub fn get_asset(&self, asset_id: AssetId) -> Result<Option<Vec<u8>>, Error> {
-        match self.storages.image.get(asset_id)? {
+        match self.storages.asset.get(asset_id)? {
             Some(ivec) => Ok(Some(ivec.to_vec())),
             None => Ok(None),
         }
@@ -259,12 +554,12 @@ impl<T: Config> LLMProvider<T> {
         for content in msg.content.iter() {
             match content {
                 MessageContent::ImageBin(_, img_id, _) | MessageContent::ImageRef(img_id, _) => {
-                    if let Err(e) = self.storages.image.release(img_id.clone()) {
+                    if let Err(e) = release_with_meta(self.storages.image.as_ref(), *img_id) {
                         tracing::error!("Failed to cleanup image {}: {}", img_id, e);
                     }
                 }
                 MessageContent::AssetRef(asset_id, _) => {
-                    if let Err(e) = self.storages.asset.release(asset_id.clone()) {
+                    if let Err(e) = release_with_meta(self.storages.asset.as_ref(), *asset_id) {
                         tracing::error!("Failed to cleanup asset {}: {}", asset_id, e);
                     }
                 }
@@ -273,25 +568,266 @@ impl<T: Config> LLMProvider<T> {
         }
     }
 
+    /// 为`contents`中的图片和附件各增加一次引用
+    fn retain_blobs<'a>(&self, contents: impl Iterator<Item = &'a MessageContent>) {
+        for content in contents {
+            let result = match content {
+                MessageContent::ImageBin(_, id, _) | MessageContent::ImageRef(id, _) => {
+                    self.storages.image.retain(id.clone())
+                }
+                MessageContent::AssetRef(id, _) => self.storages.asset.retain(id.clone()),
+                _ => continue,
+            };
+            if let Err(e) = result {
+                tracing::error!("Failed to retain blob of {}: {}", content, e);
+            }
+        }
+    }
+
     pub fn delete_chat(&self, chat_id: Uuid) -> Result<(), Error> {
-        if let Some(ivec) = self.storages.history.delete(chat_id)? {
-            if let Ok(entry) = serde_json::from_slice::<ChatEntry>(&ivec) {
-                for msg in entry.messages {
-                    self.delete_entry_with_blobs(&msg);
+        if let Err(e) = self.storages.search.remove_chat(chat_id) {
+            tracing::error!("Failed to remove chat {} from search index: {}", chat_id, e);
+        }
+        if let Some((data, messages)) = self.storages.history.delete(chat_id)? {
+            let chat = StoredChat {
+                data,
+                messages,
+                summary: None,
+            };
+            if let Ok(entry) = chat.into_entry() {
+                for msg in entry.messages.iter().chain(entry.inactive.iter()) {
+                    self.delete_entry_with_blobs(msg);
                 }
             }
         }
+        for id in delete_memo(self.storages.memo.as_ref(), chat_id)? {
+            if let Err(e) = release_with_meta(self.storages.image.as_ref(), id) {
+                tracing::error!("Failed to release memo image {}: {}", id, e);
+            }
+        }
+        self.toolset.close_chat(chat_id);
         Ok(())
     }
 
+    /// 导出会话和它们引用的数据，`chat_ids`为空时导出所有会话
+    pub fn export_chats(&self, chat_ids: &[Uuid], writer: &mut dyn Write) -> Result<(), Error> {
+        let chat_ids = if chat_ids.is_empty() {
+            self.storages
+                .history
+                .list(None, None)?
+                .into_iter()
+                .map(|(id, _)| id)
+                .collect()
+        } else {
+            chat_ids.to_vec()
+        };
+        let mut chats = vec![];
+        for chat_id in chat_ids {
+            chats.push(
+                self.get_chat(chat_id)?
+                    .ok_or(anyhow!("Chat {} does not exist", chat_id))?,
+            );
+        }
+        self.storages.export_chats(chats, writer)
+    }
+
+    /// 把会话的当前分支渲染为Markdown或HTML报告，会话不存在时返回 None
+    pub fn export_report(
+        &self,
+        chat_id: Uuid,
+        format: ReportFormat,
+        options: &ReportOptions,
+    ) -> Result<Option<ChatReport>, Error> {
+        let Some(entry) = self.get_chat(chat_id)? else {
+            return Ok(None);
+        };
+        Ok(Some(self.storages.render_report(&entry, format, options)?))
+    }
+
+    /// 导入`export_chats`导出的归档，并为导入的会话建立搜索索引
+    pub fn import_chats(&self, reader: &mut dyn Read) -> Result<ImportReport, Error> {
+        let report = self.storages.import_archive(reader)?;
+        for chat_id in report.chats.iter() {
+            if let Some(entry) = self.get_chat(*chat_id)? {
+                for msg in entry.messages.iter().chain(entry.inactive.iter()) {
+                    self.index_message(*chat_id, msg);
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    /// 遍历所有会话重新统计图片和附件的引用次数，与存储中的引用计数比较
+    /// `dry_run`为假时改写不一致的计数并删除没有引用的数据
+    /// 修复应在没有生成任务和未发送的上传时进行，否则刚保存的数据会被当作没有引用
+    pub fn fsck(&self, dry_run: bool) -> Result<FsckReport, Error> {
+        let chats = self.storages.history.list(None, None)?;
+        let mut refs = BlobReferences::default();
+        for (chat_id, _) in chats.iter() {
+            // 无法解析的会话会让统计偏少，直接报错而不是继续修复
+            if let Some(entry) = self.get_chat(*chat_id)? {
+                for msg in entry.messages.iter().chain(entry.inactive.iter()) {
+                    refs.add_message(msg);
+                }
+            }
+        }
+        // 每个会话画布上的图层持有一次引用
+        for id in memo_image_refs(self.storages.memo.as_ref())? {
+            *refs.image.entry(id).or_default() += 1;
+        }
+        let stores = vec![
+            check_store("image", self.storages.image.as_ref(), &refs.image, !dry_run)?,
+            check_store("asset", self.storages.asset.as_ref(), &refs.asset, !dry_run)?,
+        ];
+        Ok(FsckReport {
+            dry_run,
+            chats: chats.len(),
+            stores,
+        })
+    }
+
+    /// 列出与`message_id`同一个父消息下的所有分支
+    pub fn list_branches(&self, chat_id: Uuid, message_id: Uuid) -> Result<Vec<BranchInfo>, Error> {
+        let entry = self
+            .get_chat(chat_id)?
+            .ok_or(anyhow!("Can not found chat {} from database.", chat_id))?;
+        entry.branches_at(message_id).ok_or(anyhow!(
+            "Message {} not found in chat {}",
+            message_id,
+            chat_id
+        ))
+    }
+
+    /// 切换到`message_id`所在的分支
+    pub fn switch_branch(&self, chat_id: Uuid, message_id: Uuid) -> Result<ChatEntry, Error> {
+        self.update_chat(chat_id, |entry| {
+            if !entry.switch_branch(message_id) {
+                bail!("Message {} not found in chat {}", message_id, chat_id);
+            }
+            Ok(())
+        })
+    }
+
+    /// 删除一个不活跃的分支及其所有后续消息，并释放它们引用的图片和附件
+    pub fn prune_branch(&self, chat_id: Uuid, message_id: Uuid) -> Result<ChatEntry, Error> {
+        let mut pruned = vec![];
+        let entry = self.update_chat(chat_id, |entry| {
+            pruned = entry.prune_branch(message_id).ok_or(anyhow!(
+                "Message {} is not in an inactive branch of chat {}",
+                message_id,
+                chat_id
+            ))?;
+            Ok(())
+        })?;
+        for msg in pruned.iter() {
+            self.delete_entry_with_blobs(msg);
+            if let Err(e) = self.storages.search.remove_message(chat_id, msg.id) {
+                tracing::error!(
+                    "Failed to remove message {} from search index: {}",
+                    msg.id,
+                    e
+                );
+            }
+        }
+        tracing::info!(
+            "Pruned {} messages from branch {} of chat {}",
+            pruned.len(),
+            message_id,
+            chat_id
+        );
+        Ok(entry)
+    }
+
+    fn update_chat(
+        &self,
+        chat_id: Uuid,
+        f: impl FnOnce(&mut ChatEntry) -> Result<(), Error>,
+    ) -> Result<ChatEntry, Error> {
+        let mut entry = self
+            .get_chat(chat_id)?
+            .ok_or(anyhow!("Can not found chat {} from database.", chat_id))?;
+        f(&mut entry)?;
+        let (data, messages) = encode_chat(&mut entry)?;
+        self.storages.history.update(
+            chat_id,
+            &serde_json::to_vec(&ChatMeta::clone_from(&entry))?,
+            &data,
+            &messages,
+        )?;
+        Ok(entry)
+    }
+
     pub fn save_image(&self, binary: &[u8]) -> Result<AssetId, Error> {
         self.storages.image.save(binary).map_err(|e| e.into())
     }
 
+    /// 按`ProviderOptions::image_format`转换格式后保存上传的图片
+    /// 无法识别的图片原样保存
+    pub fn ingest_image(&self, binary: Vec<u8>, meta: BlobMeta) -> Result<AssetId, Error> {
+        let data = match normalize_image(binary.clone(), self.options.image_format) {
+            Ok(data) => data,
+            Err(e) => {
+                tracing::warn!(
+                    "Failed to convert image to {}: {}, keeping original",
+                    self.options.image_format,
+                    e
+                );
+                binary
+            }
+        };
+        // 格式可能已经转换，MIME类型按转换后的数据判断
+        Ok(self
+            .storages
+            .image
+            .save_with_meta(&data, BlobMeta { mime: None, ..meta })?)
+    }
+
     pub fn save_asset(&self, binary: &[u8]) -> Result<AssetId, Error> {
         self.storages.asset.save(binary).map_err(|e| e.into())
     }
 
+    /// 附件存储能否边接收边写入，不能时`save_asset_stream`会把整个文件读入内存
+    pub fn streams_assets(&self) -> bool {
+        self.storages.asset.streams_to_storage()
+    }
+
+    /// 边接收边保存附件，第一次保存时写入`meta`，MIME类型和图片尺寸从文件开头补全
+    /// sled和redb会把整个文件读入内存，见`streams_assets`
+    pub async fn save_asset_stream(
+        &self,
+        reader: &mut (dyn AsyncRead + Unpin + Send),
+        meta: BlobMeta,
+    ) -> Result<AssetId, Error> {
+        let asset = &self.storages.asset;
+        let id = asset.save_stream(reader).await?;
+        if asset.get_meta(id)?.is_none() {
+            let head = asset.peek(id, META_SNIFF_LEN)?.map(|(head, _)| head);
+            asset.put_meta(id, &meta.sniff(&head.unwrap_or_default()))?;
+        }
+        Ok(id)
+    }
+
+    /// 以流的方式读取附件，同时返回元数据
+    pub fn open_asset(
+        &self,
+        asset_id: AssetId,
+    ) -> Result<Option<(BlobReader, Option<BlobMeta>)>, Error> {
+        let Some(reader) = self.storages.asset.open_read(asset_id)? else {
+            return Ok(None);
+        };
+        Ok(Some((reader, self.storages.asset.get_meta(asset_id)?)))
+    }
+
+    /// 附件的元数据，附件不存在时返回 None
+    pub fn asset_meta(&self, asset_id: AssetId) -> Result<Option<BlobMeta>, Error> {
+        Ok(self.storages.asset.get_meta(asset_id)?)
+    }
+
+    /// 图片的元数据，图片不存在时返回 None
+    pub fn image_meta(&self, image_id: AssetId) -> Result<Option<BlobMeta>, Error> {
+        Ok(self.storages.image.get_meta(image_id)?)
+    }
+
     pub fn new_chat(&self) -> Result<ChatEntry, Error> {
         let mut e = ChatEntry::default();
         let mut meta = ChatMeta::default();
@@ -302,6 +838,7 @@ impl<T: Config> LLMProvider<T> {
             uuid,
             &serde_json::to_vec(&meta)?,
             &serde_json::to_vec(&e)?,
+            &[],
         )?;
         Ok(e)
     }
@@ -313,8 +850,8 @@ impl<T: Config> LLMProvider<T> {
         llm_config: LLMConfig,
         cancel_token: CancellationToken,
     ) -> Result<impl Stream<Item = Result<ChatEvent, Error>>, Error> {
-        self.truncate_chat_history(chat_id, target_id)?;
-        self.stream_chat_response(chat_id, llm_config, cancel_token)
+        self.fork_chat_history(chat_id, target_id)?;
+        self.stream_chat_response(chat_id, llm_config, cancel_token, None)
             .await
     }
 
@@ -326,39 +863,53 @@ impl<T: Config> LLMProvider<T> {
         llm_config: LLMConfig,
         cancel_token: CancellationToken,
     ) -> Result<impl Stream<Item = Result<ChatEvent, Error>>, Error> {
-        self.edit_and_truncate_history(chat_id, message_id, new_content)?;
-        self.stream_chat_response(chat_id, llm_config, cancel_token)
+        self.edit_and_fork_history(chat_id, message_id, new_content)?;
+        self.stream_chat_response(chat_id, llm_config, cancel_token, None)
             .await
     }
 
-    fn edit_and_truncate_history(
+    /// 编辑后的消息作为原消息的兄弟分支，原消息及其后续保留在不活跃分支中
+    fn edit_and_fork_history(
         &self,
         chat_id: Uuid,
         target_id: Uuid,
         new_content: Vec<MessageContent>,
     ) -> Result<(), Error> {
-        let mut entry = self
-            .get_chat(chat_id)?
-            .ok_or(anyhow!("Can not found chat {} from database.", chat_id))?;
-
-        if let Some(index) = entry.messages.iter().position(|m| m.id == target_id) {
-            if entry.messages[index].owner == Role::User {
-                for msg in entry.messages.iter().skip(index + 1) {
-                    self.delete_entry_with_blobs(msg);
-                }
-                entry.messages.truncate(index + 1);
-
-                entry.messages[index].content = new_content;
-
-                self.storages.history.update(
-                    chat_id,
-                    &serde_json::to_vec(&ChatMeta::clone_from(&entry))?,
-                    &serde_json::to_vec(&entry)?,
-                )?;
-                tracing::info!("Edited message {} and truncated history", target_id);
-            } else {
+        let mut reused = vec![];
+        let mut edited_message = None;
+        self.update_chat(chat_id, |entry| {
+            let Some(index) = entry.messages.iter().position(|m| m.id == target_id) else {
+                return Ok(());
+            };
+            let old = &entry.messages[index];
+            if old.owner != Role::User {
                 bail!("Edited message does not belong to user")
             }
+            // 新上传的图片在保存时已经计数，沿用旧消息的图片需要再增加一次引用
+            reused = new_content
+                .iter()
+                .filter(|c| !matches!(c, MessageContent::Text(_)))
+                .filter(|c| old.content.contains(c))
+                .cloned()
+                .collect();
+            let edited = Message {
+                id: Uuid::new_v4(),
+                owner: Role::User,
+                content: new_content,
+                reasoning: vec![],
+                tool_use: vec![],
+                interrupted: false,
+                parent: old.parent,
+            };
+            entry.deactivate_from(index);
+            edited_message = Some(edited.clone());
+            entry.messages.push(edited);
+            tracing::info!("Edited message {} into a new branch", target_id);
+            Ok(())
+        })?;
+        self.retain_blobs(reused.iter());
+        if let Some(edited) = edited_message {
+            self.index_message(chat_id, &edited);
         }
         Ok(())
     }
@@ -376,28 +927,72 @@ impl<T: Config> LLMProvider<T> {
             content: user_content,
             reasoning: vec![],
             tool_use: vec![],
+            interrupted: false,
+            parent: None,
         };
         self.append_message(chat_id, user_message)?;
-        self.stream_chat_response(chat_id, llm_config, cancel_token)
+        self.stream_chat_response(chat_id, llm_config, cancel_token, None)
+            .await
+    }
+
+    /// 从最后一条被中断的assistant消息继续生成
+    /// 已生成的内容作为assistant消息的前缀发送给模型
+    pub async fn continue_generation(
+        &self,
+        chat_id: Uuid,
+        llm_config: LLMConfig,
+        cancel_token: CancellationToken,
+    ) -> Result<impl Stream<Item = Result<ChatEvent, Error>>, Error> {
+        let last = self
+            .open_chat(chat_id)?
+            .ok_or(anyhow!("Can not found chat {} from database.", chat_id))?
+            .last_message()?;
+        let partial = match last {
+            Some(m) if m.owner == Role::Assistant && m.interrupted => m,
+            _ => bail!("Last message of chat {} is not interrupted", chat_id),
+        };
+        self.stream_chat_response(chat_id, llm_config, cancel_token, Some(partial))
             .await
     }
 
+    /// `resume`不为空时，第一轮从这条被中断的消息继续，并覆盖它
     async fn stream_chat_response(
         &self,
         chat_id: Uuid,
         llm_config: LLMConfig,
         cancel_token: CancellationToken,
+        mut resume: Option<Message>,
     ) -> Result<impl Stream<Item = Result<ChatEvent, Error>>, Error> {
         let provider = self.clone();
         Ok(try_stream! {
             let mut current_session = provider.get_chat(chat_id)?.ok_or(anyhow!("Unexpected empty chat {}", chat_id))?;
+            let tool_call_mode = llm_config.tool_call_mode.unwrap_or_default();
+            let format = llm_config.tool_format().formatter();
+            let started = Instant::now();
+            let mut tool_rounds = 0;
+            let mut tool_calls = 0;
+            // 达到上限之后，最后一轮不允许再调用工具
+            let mut loop_limit: Option<LoopLimit> = None;
+            let mut image_urls = ImageUrlCache::new(llm_config.image_resizer());
             loop {
-                let req_messages = provider.message_to_openai(current_session.clone(), llm_config.parallel_function_call.unwrap_or(false), llm_config.system_prompt_lang, llm_config.custom_system_prompt.clone());
+                let context = provider.prepare_context(current_session.clone(), &llm_config).await;
+                // 新的摘要已经保存，同步到内存中的会话
+                if context.context_summary.is_some() {
+                    current_session.context_summary = context.context_summary.clone();
+                }
+                let req_messages = provider.message_to_openai(context, &llm_config, loop_limit, &mut image_urls);
                 let mut req: CreateChatCompletionRequest = llm_config.clone().into();
                 req.messages = req_messages;
                 req.stream_options = Some(ChatCompletionStreamOptions{
                     include_usage: true
                 });
+                if tool_call_mode == ToolCallMode::Native && loop_limit.is_none() {
+                    let tools = provider.toolset.openai_tools();
+                    if !tools.is_empty() {
+                        req.tools = Some(tools);
+                        req.parallel_tool_calls = llm_config.parallel_function_call;
+                    }
+                }
 
                 let chat = self.client.chat();
                 let stream_future = chat.create_stream(req);
@@ -410,263 +1005,197 @@ impl<T: Config> LLMProvider<T> {
                 };
                 let mut stream = stream_result?;
 
-                let mut state = StreamParseState::AwaitingDecision;
-                            let mut parse_buffer: String = String::new(); // 切换回 String
-                            let mut assistant_thinking = String::new();
-                            let mut assistant_reasoning = String::new();
-                            let mut assistant_content = String::new();
-                            let mut assistant_tool_calls = Vec::new();
-                            let mut current_tool_name = String::new();
-
-                            while let Some(thunk) = stream.next().await {
-                                let thunk = thunk?;
-                                if let Some(usage) = thunk.usage {
-                                        yield ChatEvent::Usage(usage);
-                                }
-                                if let Some(content) = thunk
-                                    .choices
-                                    .first()
-                                    .and_then(|c| c.delta.reasoning_content.as_ref())
-                                {
-                                    // 原生的思考不涉及tool use，不需要任何处理
-                                    assistant_thinking.push_str(&content);
-                                    yield ChatEvent::ReasoningDelta(content.clone());
-                                    continue;
-                                } else if let Some(content) = thunk.choices.first().and_then(|c| c.delta.content.as_ref())
-                                {
-                                    parse_buffer.push_str(content);
-                                'parse_loop: loop {
-                                    match state {
-                                        StreamParseState::AwaitingDecision => {
-                                            if let Some(idx) = parse_buffer.find(FN_NAME) {
-                                                let reasoning_part: String = parse_buffer.drain(..idx).collect();
-                                                parse_buffer.drain(..FN_NAME.len());
-
-                                                if !reasoning_part.is_empty() {
-                                                    // 在第一次FNCALL之前，非主动think的时候
-                                                    // 当作普通的文本输出
-                                                    // 对于非thinking的model有必要
-                                                    if assistant_reasoning.is_empty() {
-                                                        assistant_content.push_str(&reasoning_part);
-                                                        yield ChatEvent::ContentDelta(reasoning_part);
-                                                    } else {
-                                                        assistant_reasoning.push_str(&reasoning_part);
-                                                        yield ChatEvent::ReasoningDelta(reasoning_part);
-                                                    }
-                                                }
-                                                yield ChatEvent::ToolDelta(FN_NAME.to_string());
-                                                state = StreamParseState::ToolCallName;
-                                            } else {
-                                                if parse_buffer.len() > FN_MAX_LEN {
-                                                    let yield_len_bytes =
-                                                        parse_buffer.len().saturating_sub(FN_MAX_LEN/2);
-
-                                                    // 寻找安全的 UTF-8 切割点
-                                                    let mut split_idx = yield_len_bytes;
-                                                    while !parse_buffer.is_char_boundary(split_idx) {
-                                                        split_idx -= 1;
-                                                    }
-
-                                                    if split_idx > 0 {
-                                                        let content_part: String =
-                                                            parse_buffer.drain(..split_idx).collect();
-                                                        if assistant_reasoning.is_empty() {
-                                                            assistant_content.push_str(&content_part);
-                                                            yield ChatEvent::ContentDelta(content_part);
-                                                        } else {
-                                                            //之前有过一次函数调用，之后任何信息都是reasoning
-                                                            assistant_thinking.push_str(&content_part);
-                                                            yield ChatEvent::ReasoningDelta(content_part);
-                                                        }
-                                                    }
-                                                    //这里不需要设为Content，说不定未来还有FN_NAME
-                                                    //state = StreamParseState::Content;
-                                                } else {
-                                                    // 缓冲区中没有标签, 但数据还不够, 无法确定.
-                                                    break 'parse_loop;
-                                                }
-                                            }
-                                        }
-                                        StreamParseState::Content => {
-                                            // 我们已处于内容模式. 将所有内容作为 ContentDelta 发送
-                                            if !parse_buffer.is_empty() {
-                                                let content_part: String = parse_buffer.drain(..).collect();
-                                                assistant_content.push_str(&content_part);
-                                                yield ChatEvent::ContentDelta(content_part);
-                                            }
-                                            break 'parse_loop;
-                                        }
-                                        StreamParseState::ToolCallName => {
-                                            if let Some(idx) = parse_buffer.find(FN_ARGS) {
-                                                let name_part_raw: String = parse_buffer.drain(..idx).collect();
-                                                parse_buffer.drain(..FN_ARGS.len());
-
-                                                current_tool_name =
-                                                    name_part_raw.trim()
-                                                        .trim_matches(':')
-                                                        .trim_matches('：')
-                                                        .trim().to_string();
-
-                                                if current_tool_name.len() == 0 {
-                                                    tracing::warn!("ToolName is empty: {}, current ctx: {}", idx, content);
-                                                }
-
-                                                let delta = name_part_raw + FN_ARGS;
-                                                assistant_reasoning.push_str(&delta);
-                                                yield ChatEvent::ToolDelta(delta);
-
-                                                state = StreamParseState::ToolCallArgs;
-                                            } else {
-                                                break 'parse_loop;
-                                            }
-                                        }
-                                        StreamParseState::ToolCallArgs => {
-                                            yield ChatEvent::ToolDelta(content.to_string());
-                                            let next_stop = FN_STOP_WORDS
-                                                .iter()
-                                                .filter_map(|tag| parse_buffer.find(tag).map(|idx| (idx, *tag)))
-                                                .min_by_key(|(idx, _)| *idx);
-
-                                            if let Some((idx, tag)) = next_stop {
-                                                let args_part_raw: String = parse_buffer.drain(..idx).collect();
-                                                parse_buffer.drain(..tag.len());
-
-                                                let args =
-                                                    args_part_raw.trim()
-                                                        .trim_matches(':')
-                                                        .trim_matches('：')
-                                                        .trim().to_string();
-
-                                                assistant_reasoning.push_str(&args_part_raw);
-
-                                                let tool_use =
-                                                    ToolUse {
-                                                        use_id: Uuid::new_v4(),
-                                                        function_name: current_tool_name.clone(), args };
-                                                yield ChatEvent::ToolCall(tool_use.clone());
-                                                assistant_tool_calls.push(tool_use);
-
-                                                //如果有连续的两个args，那就当作对同一个fn name使用2次
-                                                //current_tool_name.clear();
-                                                if tag != FN_EXIT {
-                                                    yield ChatEvent::ToolDelta(tag.to_string());
-                                                }
-
-                                                if tag == FN_NAME {
-                                                    state = StreamParseState::ToolCallName;
-                                                } else {
-                                                    // 但tool use本身就是reasoning，因此这里不是content
-                                                    state = StreamParseState::AwaitingDecision;
-                                                }
-                                            } else {
-                                                break 'parse_loop;
-                                            }
-                                        }
-                                    }
-                                }}
+                let mut parser = StreamParser::new(format);
+                // 继续生成时，接着被中断的内容累积
+                let (message_id, mut assistant_thinking, mut assistant_content) = match resume.take() {
+                    Some(m) => (m.id, contents_to_text(&m.reasoning), contents_to_text(&m.content)),
+                    None => (Uuid::new_v4(), String::new(), String::new()),
+                };
+                let mut assistant_tool_calls = Vec::new();
+                let mut native_tool_calls = NativeToolCalls::default();
+                // 中止或者出错时，已经生成的部分仍然需要保存
+                let mut cancelled = false;
+                let mut stream_error = None;
+
+                loop {
+                    let thunk = tokio::select! {
+                        thunk = stream.next() => thunk,
+                        _ = cancel_token.cancelled() => {
+                            tracing::info!("Chat {} cancelled during streaming", chat_id);
+                            cancelled = true;
+                            None
+                        }
+                    };
+                    let thunk = match thunk {
+                        Some(Ok(thunk)) => thunk,
+                        Some(Err(e)) => {
+                            stream_error = Some(e);
+                            break;
+                        }
+                        None => break,
+                    };
+                    if let Some(usage) = thunk.usage {
+                            yield ChatEvent::Usage(usage);
+                    }
+                    if let Some(content) = thunk
+                        .choices
+                        .first()
+                        .and_then(|c| c.delta.reasoning_content.as_ref())
+                    {
+                        // 原生的思考不涉及tool use，不需要任何处理
+                        assistant_thinking.push_str(&content);
+                        yield ChatEvent::ReasoningDelta(content.clone());
+                        continue;
+                    } else if tool_call_mode == ToolCallMode::Native {
+                        // 原生模式: 工具调用在delta.tool_calls里，content不需要解析
+                        let Some(delta) = thunk.choices.first().map(|c| &c.delta) else {
+                            continue;
+                        };
+                        for chunk in delta.tool_calls.iter().flatten() {
+                            for text in native_tool_calls.push(chunk) {
+                                yield ChatEvent::ToolDelta(text);
                             }
-                match state {
-                    StreamParseState::AwaitingDecision | StreamParseState::Content | StreamParseState::ToolCallName=> {
-                        // 剩余的都是内容
-                        if !parse_buffer.is_empty() {
-                            if assistant_reasoning.is_empty() {
-                                assistant_content.push_str(&parse_buffer);
-                                yield ChatEvent::ContentDelta(parse_buffer);
-                            } else {
-                                assistant_thinking.push_str(&parse_buffer);
-                                yield ChatEvent::ReasoningDelta(parse_buffer);
+                        }
+                        if let Some(content) = delta.content.as_ref() {
+                            assistant_content.push_str(content);
+                            yield ChatEvent::ContentDelta(content.clone());
+                        }
+                        continue;
+                    } else if let Some(content) = thunk.choices.first().and_then(|c| c.delta.content.as_ref()) {
+                        for event in parser.push(content) {
+                            if loop_limit.is_some() && matches!(event, StreamEvent::ToolCall(_)) {
+                                continue;
                             }
+                            yield apply_stream_event(event, &mut assistant_thinking, &mut assistant_content, &mut assistant_tool_calls);
                         }
                     }
-                    StreamParseState::ToolCallArgs => {
-                        // 这是最后一个工具的参数
-                        let args = parse_buffer.trim()
-                            .trim_matches(':')
-                            .trim_matches('：')
-                            .trim().to_string();
-                        assistant_reasoning.push_str(&parse_buffer);
-                        yield ChatEvent::ToolDelta(parse_buffer);
-
-                        let tool_use = ToolUse {
-                            use_id: Uuid::new_v4(),
-                            function_name: current_tool_name.clone(),
-                            args
+                }
+                let interrupted = cancelled || stream_error.is_some();
+                for event in parser.finish() {
+                    // 被中断时工具调用可能不完整，不执行
+                    if (loop_limit.is_some() || interrupted) && matches!(event, StreamEvent::ToolCall(_)) {
+                        continue;
+                    }
+                    yield apply_stream_event(event, &mut assistant_thinking, &mut assistant_content, &mut assistant_tool_calls);
+                }
+                if loop_limit.is_some() && !native_tool_calls.is_empty() {
+                    tracing::warn!("Ignore {} tool calls after loop limit reached", native_tool_calls.len());
+                    native_tool_calls.clear();
+                }
+                if interrupted {
+                    native_tool_calls.clear();
+                    if !assistant_thinking.is_empty() || !assistant_content.is_empty() {
+                        let partial = Message {
+                            id: message_id,
+                            owner: Role::Assistant,
+                            reasoning: text_to_contents(assistant_thinking),
+                            content: text_to_contents(assistant_content),
+                            tool_use: vec![],
+                            interrupted: true,
+                            parent: None,
                         };
-                        yield ChatEvent::ToolCall(tool_use.clone());
-                        assistant_tool_calls.push(tool_use);
+                        provider.append_message(chat_id, partial)?;
+                        tracing::info!("Saved interrupted message {} of chat {}", message_id, chat_id);
                     }
+                    if let Some(e) = stream_error {
+                        Err(e)?;
+                    }
+                    break;
+                }
+                for tool_use in native_tool_calls.into_tool_uses() {
+                    yield ChatEvent::ToolCall(tool_use.clone());
+                    assistant_tool_calls.push(tool_use);
                 }
                 let assistant_message = Message {
-                        id: Uuid::new_v4(),
+                        id: message_id,
                         owner: Role::Assistant,
-                        reasoning: {
-                            let mut v = vec![];
-                            if !assistant_thinking.is_empty() {
-                                v.push(MessageContent::Text(assistant_thinking));
-                            }
-                            // assistant_reasoning已经被解析成了tooluse，不需要保留
-                            // 否则会在gui里显示丑陋的原始输出
-                            //if !assistant_reasoning.is_empty() {
-                            //    v.push(MessageContent::Text(assistant_reasoning));
-                            //}
-                            v
-                        },
-                        content: if !assistant_content.is_empty() { vec![MessageContent::Text(assistant_content)] } else { vec![] },
+                        // assistant_reasoning已经被解析成了tooluse，不需要保留
+                        // 否则会在gui里显示丑陋的原始输出
+                        reasoning: text_to_contents(assistant_thinking),
+                        content: text_to_contents(assistant_content),
                         tool_use: assistant_tool_calls.clone(),
+                        interrupted: false,
+                        parent: None,
                     };
 
-                current_session = provider.append_message(chat_id, assistant_message)?;
+                current_session.push_message(provider.append_message(chat_id, assistant_message)?);
 
-                if assistant_tool_calls.is_empty() {
+                if assistant_tool_calls.is_empty() || loop_limit.is_some() {
                     // 没有工具调用 (这是一个内容流)
                     break;
                 }
 
+                let (progress_tx, mut progress_rx) = tokio::sync::mpsc::unbounded_channel();
+                let ctx = ToolContext::new(chat_id, message_id, cancel_token.child_token())
+                    .with_timeout(llm_config.tool_timeout_secs.map(Duration::from_secs))
+                    .with_progress(progress_tx);
                 let mut futures = Vec::new();
                 for tool_call in assistant_tool_calls.iter() {
-                    futures.push(provider.toolset.use_tool_async(tool_call.clone()));
+                    futures.push(provider.toolset.use_tool_async(&ctx, tool_call.clone()));
                 }
 
-                let results: Vec<(ToolUse, Message)> = futures::future::join_all(futures).await;
+                // 等待工具结果的同时转发进度，yield不能写在select!里面
+                let mut calls = std::pin::pin!(futures::future::join_all(futures));
+                let results = loop {
+                    let progress = tokio::select! {
+                        results = &mut calls => break Some(results),
+                        Some(progress) = progress_rx.recv() => progress,
+                        _ = cancel_token.cancelled() => break None,
+                    };
+                    yield ChatEvent::ToolProgress(progress);
+                };
+                let Some(results): Option<Vec<(ToolUse, Message)>> = results else {
+                    tracing::info!("Chat {} cancelled during tool calls", chat_id);
+                    // 每个工具调用都要有结果，否则之后的请求中tool_calls没有对应的tool消息
+                    for tool_use in assistant_tool_calls {
+                        let res = tool_result(
+                            tool_use.use_id,
+                            vec![MessageContent::Text(format!(
+                                "工具 '{}' 执行失败：Tool call cancelled",
+                                tool_use.function_name
+                            ))],
+                        );
+                        yield ChatEvent::ToolResult { tool_use, result: res.clone() };
+                        provider.append_message(chat_id, res)?;
+                    }
+                    break;
+                };
 
                 for (tool_use, res) in results.into_iter() {
                     yield ChatEvent::ToolResult { tool_use, result: res.clone() };
-                    current_session = provider.append_message(chat_id, res)?;
+                    current_session.push_message(provider.append_message(chat_id, res)?);
                 }
 
+                tool_rounds += 1;
+                tool_calls += assistant_tool_calls.len() as u32;
+                loop_limit = llm_config.check_loop_limit(tool_rounds, tool_calls, started.elapsed());
+                if let Some(limit) = loop_limit {
+                    tracing::info!("Chat {} reached loop limit {}", chat_id, limit);
+                    yield ChatEvent::LoopLimitReached(limit);
+                }
             }
         })
     }
 
-    fn truncate_chat_history(&self, chat_id: Uuid, target_id: Uuid) -> Result<(), Error> {
-        let mut entry = self
-            .get_chat(chat_id)?
-            .ok_or(anyhow!("Can not found chat {} from database.", chat_id))?;
-
-        if let Some(index) = entry.messages.iter().position(|m| m.id == target_id) {
+    /// 把重新生成位置之后的消息移到不活跃分支，新的回复成为它们的兄弟分支
+    /// 图片和附件只有在分支被`prune_branch`删除时才释放
+    fn fork_chat_history(&self, chat_id: Uuid, target_id: Uuid) -> Result<(), Error> {
+        self.update_chat(chat_id, |entry| {
+            let Some(index) = entry.messages.iter().position(|m| m.id == target_id) else {
+                tracing::warn!("Target message {} not found in chat {}", target_id, chat_id);
+                return Ok(());
+            };
             let keep_count = if entry.messages[index].owner == Role::User {
                 index + 1
             } else {
                 index
             };
-
-            for msg in entry.messages.iter().skip(keep_count) {
-                self.delete_entry_with_blobs(msg);
-            }
-            entry.messages.truncate(keep_count);
-
-            if entry.messages.is_empty() {
+            if keep_count == 0 {
                 bail!("Unexpected regeneration {} from starting", chat_id);
             }
-
-            self.storages.history.update(
-                chat_id,
-                &serde_json::to_vec(&ChatMeta::clone_from(&entry))?,
-                &serde_json::to_vec(&entry)?,
-            )?;
-        } else {
-            tracing::warn!("Target message {} not found in chat {}", target_id, chat_id);
-        }
+            entry.deactivate_from(keep_count);
+            Ok(())
+        })?;
         Ok(())
     }
 
@@ -689,25 +1218,198 @@ impl<T: Config> LLMProvider<T> {
         }
     }
 
-    fn append_message(&self, chat_id: Uuid, content: Message) -> Result<ChatEntry, Error> {
-        let (_, new_data_bytes) = self.storages.history.update_data_with(
+    /// 返回实际保存的消息，`parent`已经指向前一条消息
+    fn append_message(&self, chat_id: Uuid, content: Message) -> Result<Message, Error> {
+        let bytes = self.storages.history.push_message(
             chat_id,
-            Box::new(move |_, old_bytes| {
-                    append_message_to_buffer(chat_id, &old_bytes, &content)
+            Box::new(move |old_meta, last| {
+                append_message_to_meta(chat_id, old_meta, last, &content)
             }),
         )?;
-        let entry = serde_json::from_slice(&new_data_bytes)?;
-        Ok(entry)
+        let message = serde_json::from_slice(&bytes)?;
+        self.index_message(chat_id, &message);
+        self.record_blob_chat(chat_id, &message);
+        Ok(message)
     }
 
-    fn message_to_openai(
+    /// 图片和附件第一次出现在会话中时，在元数据中记录会话
+    fn record_blob_chat(&self, chat_id: Uuid, message: &Message) {
+        for content in message.content.iter() {
+            let (store, id) = match content {
+                MessageContent::ImageBin(_, id, _) | MessageContent::ImageRef(id, _) => {
+                    (&self.storages.image, id)
+                }
+                MessageContent::AssetRef(id, _) => (&self.storages.asset, id),
+                MessageContent::Text(_) => continue,
+            };
+            let result = match store.get_meta(*id) {
+                Ok(Some(meta)) if meta.chat_id.is_none() => store.put_meta(
+                    *id,
+                    &BlobMeta {
+                        chat_id: Some(chat_id),
+                        ..meta
+                    },
+                ),
+                Ok(_) => Ok(()),
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                tracing::warn!("Failed to record chat of blob {}: {}", id, e);
+            }
+        }
+    }
+
+    /// 根据`context_token_budget`裁剪发送给模型的历史
+    /// 有消息被丢弃时，`context_summary`为覆盖这些消息的摘要，否则为空
+    async fn prepare_context(&self, mut entry: ChatEntry, llm_config: &LLMConfig) -> ChatEntry {
+        let Some(budget) = llm_config.context_token_budget else {
+            entry.context_summary = None;
+            return entry;
+        };
+        let resizer = llm_config.image_resizer().unwrap_or_else(|| {
+            ImageResizer::new(32, DEFAULT_IMAGE_MIN_PIXELS, DEFAULT_IMAGE_MAX_PIXELS)
+        });
+        let manager = ContextManager::new(budget, resizer);
+        let lang = self.prompt_lang(&entry, llm_config);
+        let summarize = llm_config.context_summarize.unwrap_or(false);
+        let reserved = ContextManager::estimate_text(&self.system_prompt(lang, llm_config))
+            + entry
+                .context_summary
+                .as_ref()
+                .filter(|_| summarize)
+                .map(|s| ContextManager::estimate_text(&s.content))
+                .unwrap_or(0)
+            + llm_config.max_completion_tokens.unwrap_or(0) as usize;
+        let window = manager.fit(std::mem::take(&mut entry.messages), reserved, |c| {
+            self.image_size(c)
+        });
+        entry.messages = window.messages;
+        if window.stripped_images > 0 || !window.dropped.is_empty() {
+            tracing::info!(
+                "Chat {}: replaced {} images, dropped {} messages, ~{} tokens",
+                entry.id,
+                window.stripped_images,
+                window.dropped.len(),
+                window.tokens
+            );
+        }
+        let previous = entry.context_summary.take();
+        if window.dropped.is_empty() || !summarize {
+            return entry;
+        }
+        match self
+            .update_context_summary(entry.id, previous, &window.dropped, lang, llm_config)
+            .await
+        {
+            Ok(summary) => entry.context_summary = Some(summary),
+            Err(e) => tracing::warn!("Failed to summarize chat {}: {}", entry.id, e),
+        }
+        entry
+    }
+
+    /// 生成覆盖`dropped`的摘要并保存
+    /// 如果之前的摘要仍然有效，只总结之后新丢弃的消息
+    async fn update_context_summary(
         &self,
-        v: ChatEntry,
-        is_parallel_fc: bool,
-        lang: Option<whatlang::Lang>,
-        custom_prompt: Option<String>,
-    ) -> Vec<ChatCompletionRequestMessage> {
-        let lang = match lang {
+        chat_id: Uuid,
+        previous: Option<ContextSummary>,
+        dropped: &[Message],
+        lang: whatlang::Lang,
+        llm_config: &LLMConfig,
+    ) -> Result<ContextSummary, Error> {
+        let last = dropped.last().ok_or(anyhow!("Nothing to summarize"))?;
+        let (previous, pending) = match pending_summary(previous, dropped) {
+            (Some(p), []) => return Ok(p),
+            split => split,
+        };
+
+        let mut transcript = String::new();
+        if let Some(p) = previous {
+            transcript.push_str(&format!("[summary]\n{}\n\n", p.content));
+        }
+        for msg in pending {
+            let role = match msg.owner {
+                Role::User => "user".to_string(),
+                Role::Assistant => "assistant".to_string(),
+                Role::System => "system".to_string(),
+                Role::Tools(_) => "tool".to_string(),
+            };
+            let content = msg
+                .content
+                .iter()
+                .map(|c| c.to_string())
+                .chain(
+                    msg.tool_use
+                        .iter()
+                        .map(|t| format!("[call {}] {}", t.function_name, t.args)),
+                )
+                .collect::<Vec<String>>()
+                .join("\n");
+            transcript.push_str(&format!("[{}]\n{}\n\n", role, content));
+        }
+
+        let mut req: CreateChatCompletionRequest = llm_config.clone().into();
+        req.stream = Some(false);
+        req.messages = vec![
+            ChatCompletionRequestMessage::System(ChatCompletionRequestSystemMessage {
+                content: ChatCompletionRequestSystemMessageContent::Text(
+                    self.toolset.context_summary_prompt(lang),
+                ),
+                name: None,
+            }),
+            ChatCompletionRequestMessage::User({
+                let mut r = ChatCompletionRequestUserMessage::default();
+                r.content = ChatCompletionRequestUserMessageContent::Text(transcript);
+                r
+            }),
+        ];
+        let response = self.client.chat().create(req).await?;
+        let content = response
+            .choices
+            .into_iter()
+            .next()
+            .and_then(|c| c.message.content)
+            .map(|c| c.trim().to_string())
+            .filter(|c| !c.is_empty())
+            .ok_or(anyhow!("Empty summary from model"))?;
+
+        let summary = ContextSummary {
+            until: last.id,
+            content,
+        };
+        let saved = summary.clone();
+        self.storages.history.update_data_with(
+            chat_id,
+            Box::new(move |old_meta, old_bytes| {
+                let mut entry: ChatEntry = serde_json::from_slice(
+                    &old_bytes.ok_or(anyhow!("Chat {} does not exist", chat_id))?,
+                )?;
+                entry.context_summary = Some(saved.clone());
+                Ok((old_meta.unwrap_or_default(), serde_json::to_vec(&entry)?))
+            }),
+        )?;
+        tracing::info!("Updated context summary of chat {}", chat_id);
+        Ok(summary)
+    }
+
+    /// 读取图片的 (宽, 高)，优先只读取文件头
+    fn image_size(&self, content: &MessageContent) -> Option<(u32, u32)> {
+        match content {
+            MessageContent::ImageBin(blob, _, _) => image_dimensions(blob),
+            MessageContent::ImageRef(id, _) => {
+                let (head, _) = self.storages.image.peek(id.clone(), 64 * 1024).ok()??;
+                image_dimensions(&head).or_else(|| {
+                    let data = self.storages.image.get(id.clone()).ok()??;
+                    image_dimensions(&data)
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// 没有指定语言时，根据最后一条用户消息判断
+    fn prompt_lang(&self, v: &ChatEntry, llm_config: &LLMConfig) -> whatlang::Lang {
+        match llm_config.system_prompt_lang {
             Some(l) => l,
             None => v
                 .messages
@@ -728,10 +1430,19 @@ impl<T: Config> LLMProvider<T> {
                 .map(|v| whatlang::detect_lang(&v))
                 .flatten()
                 .unwrap_or(whatlang::Lang::Cmn),
+        }
+    }
+
+    fn system_prompt(&self, lang: whatlang::Lang, llm_config: &LLMConfig) -> String {
+        let core_system_prompt = match llm_config.tool_call_mode.unwrap_or_default() {
+            ToolCallMode::Text => self.toolset.system_prompt(
+                lang,
+                llm_config.parallel_function_call.unwrap_or(false),
+                llm_config.tool_format().formatter(),
+            ),
+            ToolCallMode::Native => self.toolset.native_system_prompt(lang),
         };
-        tracing::debug!("User input language: {}", lang);
-        let core_system_prompt = self.toolset.system_prompt(lang, is_parallel_fc);
-        let final_system_prompt = if let Some(user_prompt) = custom_prompt {
+        if let Some(user_prompt) = llm_config.custom_system_prompt.clone() {
             if !user_prompt.trim().is_empty() {
                 format!(
                     "{}\n\n--- System Capabilities ---\n{}",
@@ -742,42 +1453,117 @@ impl<T: Config> LLMProvider<T> {
             }
         } else {
             core_system_prompt
+        }
+    }
+
+    /// `loop_limit`不为空时，在最后追加要求模型直接回答的提示
+    fn message_to_openai(
+        &self,
+        v: ChatEntry,
+        llm_config: &LLMConfig,
+        loop_limit: Option<LoopLimit>,
+        image_urls: &mut ImageUrlCache,
+    ) -> Vec<ChatCompletionRequestMessage> {
+        let mode = llm_config.tool_call_mode.unwrap_or_default();
+        // 原生模式下不需要文本格式
+        let format = match mode {
+            ToolCallMode::Text => Some(llm_config.tool_format().formatter()),
+            ToolCallMode::Native => None,
         };
+        let lang = self.prompt_lang(&v, llm_config);
+        tracing::debug!("User input language: {}", lang);
+        let mut final_system_prompt = self.system_prompt(lang, llm_config);
+        if let Some(summary) = v.context_summary.as_ref() {
+            final_system_prompt.push_str(&format!(
+                "\n\n--- Earlier Conversation Summary ---\n{}",
+                summary.content
+            ));
+        }
 
         let system_message =
             ChatCompletionRequestMessage::System(ChatCompletionRequestSystemMessage {
                 content: ChatCompletionRequestSystemMessageContent::Text(final_system_prompt),
                 name: None,
             });
+        // 工具结果的tool_call_id要和assistant中的调用一致
+        let call_ids: HashMap<Uuid, String> = v
+            .messages
+            .iter()
+            .flat_map(|m| &m.tool_use)
+            .filter_map(|t| Some((t.use_id, t.call_id.clone()?)))
+            .collect();
         let mut history_messages: Vec<ChatCompletionRequestMessage> = v
             .messages
             .into_iter()
-            .map(|v| self.message_to_request(v))
+            .map(|v| self.message_to_request(v, format, &call_ids, image_urls))
             .filter_map(|v| v.ok())
             .collect();
         history_messages.insert(0, system_message);
+        if let Some(limit) = loop_limit {
+            let mut r = ChatCompletionRequestUserMessage::default();
+            r.content = ChatCompletionRequestUserMessageContent::Text(
+                self.toolset.loop_limit_prompt(lang, &limit.to_string()),
+            );
+            history_messages.push(ChatCompletionRequestMessage::User(r));
+        }
 
         history_messages
     }
 
-    fn message_to_request(&self, v: Message) -> Result<ChatCompletionRequestMessage, Error> {
+    /// `format`为None时使用原生的`tool_calls`，`call_ids`是服务端给出的tool_call id
+    /// 图片通过`image_urls`编码，设置了像素范围时在发送前被缩小
+    fn message_to_request(
+        &self,
+        v: Message,
+        format: Option<&dyn ToolCallFormat>,
+        call_ids: &HashMap<Uuid, String>,
+        image_urls: &mut ImageUrlCache,
+    ) -> Result<ChatCompletionRequestMessage, Error> {
         Ok(match v.owner {
+            Role::Assistant if format.is_none() => {
+                ChatCompletionRequestMessage::Assistant({
+                    let mut r = ChatCompletionRequestAssistantMessage::default();
+                    if !v.content.is_empty() {
+                        r.content = Some(ChatCompletionRequestAssistantMessageContent::Array(
+                            v.content.into_iter().map(|v| v.into()).collect(),
+                        ));
+                    }
+                    if !v.tool_use.is_empty() {
+                        // 没有服务端给出的id时使用use_id，与 Role::Tools 保持一致
+                        r.tool_calls = Some(
+                            v.tool_use
+                                .into_iter()
+                                .map(|t| {
+                                    ChatCompletionMessageToolCalls::Function(
+                                        ChatCompletionMessageToolCall {
+                                            id: t.call_id.unwrap_or_else(|| t.use_id.to_string()),
+                                            function: FunctionCall {
+                                                name: t.function_name,
+                                                arguments: t.args,
+                                            },
+                                        },
+                                    )
+                                })
+                                .collect(),
+                        );
+                    }
+                    r
+                })
+            }
             Role::Assistant => ChatCompletionRequestMessage::Assistant({
                 let mut r = ChatCompletionRequestAssistantMessage::default();
                 r.content = Some(ChatCompletionRequestAssistantMessageContent::Array(
                     v.content
                         .into_iter()
                         .map(|v| v.into())
-                        .chain(v.tool_use.into_iter().map(|v| {
-                            ChatCompletionRequestAssistantMessageContentPart::Text(
-                                ChatCompletionRequestMessageContentPartText {
-                                    text: format!(
-                                        "\n{FN_NAME}: {fn_name}\n{FN_ARGS}: {fn_args}\n",
-                                        fn_name = v.function_name,
-                                        fn_args = v.args, // remove to prevent repeat?
-                                    ),
-                                },
-                            )
+                        .chain(v.tool_use.into_iter().filter_map(|v| {
+                            format.map(|f| {
+                                ChatCompletionRequestAssistantMessageContentPart::Text(
+                                    ChatCompletionRequestMessageContentPartText {
+                                        text: f.serialize_call(&v),
+                                    },
+                                )
+                            })
                         }))
                         .collect(),
                 ));
@@ -794,7 +1580,7 @@ impl<T: Config> LLMProvider<T> {
             Role::User => ChatCompletionRequestMessage::User({
                 let mut r = ChatCompletionRequestUserMessage::default();
                 r.content = ChatCompletionRequestUserMessageContent::Array(
-                    self.map_multi_modal_user_messages(v)?,
+                    self.map_multi_modal_user_messages(v, image_urls)?,
                 );
                 r
             }),
@@ -805,9 +1591,9 @@ impl<T: Config> LLMProvider<T> {
                 //    self.map_multi_modal_tool_messages(v)?,
                 //);
                 let mut r = ChatCompletionRequestToolMessage::default();
-                r.tool_call_id = id.to_string();
+                r.tool_call_id = call_ids.get(&id).cloned().unwrap_or_else(|| id.to_string());
                 r.content = ChatCompletionRequestToolMessageContent::Array(
-                    self.map_multi_modal_tool_messages(v)?,
+                    self.map_multi_modal_tool_messages(v, format, image_urls)?,
                 );
                 r
             }),
@@ -817,15 +1603,18 @@ impl<T: Config> LLMProvider<T> {
     fn map_multi_modal_tool_messages(
         &self,
         v: Message,
+        format: Option<&dyn ToolCallFormat>,
+        image_urls: &mut ImageUrlCache,
     ) -> Result<Vec<ChatCompletionRequestToolMessageContentPart>, Error> {
         let mut res = Vec::new();
 
         // 添加 ✿RESULT✿: 前缀
-        res.push(ChatCompletionRequestToolMessageContentPart::Text(
-            ChatCompletionRequestMessageContentPartText {
-                text: format!("{FN_RESULT}: "),
-            },
-        ));
+        // 原生模式下tool message本身就是结果，不需要标记
+        if let Some(prefix) = format.map(|f| f.result_prefix()).filter(|p| !p.is_empty()) {
+            res.push(ChatCompletionRequestToolMessageContentPart::Text(
+                ChatCompletionRequestMessageContentPartText { text: prefix },
+            ));
+        }
 
         for msg in v.content {
             match msg {
@@ -851,9 +1640,7 @@ impl<T: Config> LLMProvider<T> {
                         },
                     ));
 
-                    match self.storages.image.get(id).map(|v| {
-                        v.map(|v| format!("data:image/png;base64,{}", BASE64_STANDARD.encode(&v)))
-                    }) {
+                    match image_urls.get_or_load(id, || self.storages.image.get(id)) {
                         Ok(Some(b)) => {
                             res.push(ChatCompletionRequestToolMessageContentPart::ImageUrl(
                                 ChatCompletionRequestMessageContentPartImage {
@@ -876,14 +1663,14 @@ impl<T: Config> LLMProvider<T> {
                         }
                     }
                 }
-                MessageContent::ImageBin(ref blob, _, _) => {
+                MessageContent::ImageBin(ref blob, id, _) => {
                     res.push(ChatCompletionRequestToolMessageContentPart::Text(
                         ChatCompletionRequestMessageContentPartText {
                             text: msg.to_string(),
                         },
                     ));
 
-                    let b = format!("data:image/png;base64,{}", BASE64_STANDARD.encode(blob));
+                    let b = image_urls.get_or_encode(id, blob);
                     res.push(ChatCompletionRequestToolMessageContentPart::ImageUrl(
                         ChatCompletionRequestMessageContentPartImage {
                             image_url: ImageUrl {
@@ -897,11 +1684,11 @@ impl<T: Config> LLMProvider<T> {
         }
 
         // 添加 ✿RETURN✿: 后缀
-        res.push(ChatCompletionRequestToolMessageContentPart::Text(
-            ChatCompletionRequestMessageContentPartText {
-                text: format!("\n{FN_EXIT}\n"),
-            },
-        ));
+        if let Some(suffix) = format.map(|f| f.result_suffix()).filter(|s| !s.is_empty()) {
+            res.push(ChatCompletionRequestToolMessageContentPart::Text(
+                ChatCompletionRequestMessageContentPartText { text: suffix },
+            ));
+        }
 
         Ok(res)
     }
@@ -909,6 +1696,7 @@ impl<T: Config> LLMProvider<T> {
     fn map_multi_modal_user_messages(
         &self,
         v: Message,
+        image_urls: &mut ImageUrlCache,
     ) -> Result<Vec<ChatCompletionRequestUserMessageContentPart>, Error> {
         let mut res = Vec::new();
         for msg in v.content {
@@ -928,9 +1716,7 @@ impl<T: Config> LLMProvider<T> {
                     ))
                 }
                 MessageContent::ImageRef(id, _) => {
-                    match self.storages.image.get(id).map(|v| {
-                        v.map(|v| format!("data:image/png;base64,{}", BASE64_STANDARD.encode(&v)))
-                    }) {
+                    match image_urls.get_or_load(id, || self.storages.image.get(id)) {
                         Ok(Some(b)) => {
                             res.push(ChatCompletionRequestUserMessageContentPart::Text(
                                 types::ChatCompletionRequestMessageContentPartText {
@@ -954,8 +1740,8 @@ impl<T: Config> LLMProvider<T> {
                         }
                     }
                 }
-                MessageContent::ImageBin(ref blob, _, _) => {
-                    let b = format!("data:image/png;base64,{}", BASE64_STANDARD.encode(&blob));
+                MessageContent::ImageBin(ref blob, id, _) => {
+                    let b = image_urls.get_or_encode(id, blob);
                     res.push(ChatCompletionRequestUserMessageContentPart::Text(
                         types::ChatCompletionRequestMessageContentPartText {
                             text: msg.to_string(),
@@ -976,30 +1762,226 @@ impl<T: Config> LLMProvider<T> {
     }
 }
 
-fn append_message_to_buffer(
+/// 一次生成中发送给模型的图片data url
+/// 工具循环的每一轮都会重新构造整个请求，缓存避免反复解码和缩小历史中的图片
+/// 缓存只对应创建时的`resizer`，不能跨越不同的像素范围使用
+struct ImageUrlCache {
+    resizer: Option<ImageResizer>,
+    urls: HashMap<AssetId, String>,
+}
+
+impl ImageUrlCache {
+    fn new(resizer: Option<ImageResizer>) -> Self {
+        Self {
+            resizer,
+            urls: HashMap::new(),
+        }
+    }
+
+    /// 缓存中没有时才调用`load`读取原图
+    fn get_or_load<E>(
+        &mut self,
+        id: AssetId,
+        load: impl FnOnce() -> Result<Option<Vec<u8>>, E>,
+    ) -> Result<Option<String>, E> {
+        if let Some(url) = self.urls.get(&id) {
+            return Ok(Some(url.clone()));
+        }
+        Ok(load()?.map(|data| self.get_or_encode(id, &data)))
+    }
+
+    fn get_or_encode(&mut self, id: AssetId, data: &[u8]) -> String {
+        let resizer = self.resizer.as_ref();
+        self.urls
+            .entry(id)
+            .or_insert_with(|| image_data_url(data, resizer))
+            .clone()
+    }
+}
+
+/// 把图片编码成data url，`resizer`不为空时先缩小
+/// 只影响发送给模型的数据，数据库中的原图不变
+fn image_data_url(data: &[u8], resizer: Option<&ImageResizer>) -> String {
+    let data = match resizer.map(|r| resize_for_model(data, r)) {
+        Some(Ok(resized)) => resized,
+        Some(Err(e)) => {
+            tracing::warn!("Failed to resize image, send original: {}", e);
+            data.into()
+        }
+        None => data.into(),
+    };
+    format!(
+        "data:{};base64,{}",
+        image_mime_type(&data).unwrap_or("image/png"),
+        BASE64_STANDARD.encode(&data)
+    )
+}
+
+fn contents_to_text(contents: &[MessageContent]) -> String {
+    contents
+        .iter()
+        .filter_map(|c| match c {
+            MessageContent::Text(s) => Some(s.as_str()),
+            _ => None,
+        })
+        .collect()
+}
+
+fn text_to_contents(text: String) -> Vec<MessageContent> {
+    if text.is_empty() {
+        vec![]
+    } else {
+        vec![MessageContent::Text(text)]
+    }
+}
+
+/// 把解析出的事件累积到assistant消息中，并转换成发给前端的事件
+fn apply_stream_event(
+    event: StreamEvent,
+    thinking: &mut String,
+    content: &mut String,
+    tool_calls: &mut Vec<ToolUse>,
+) -> ChatEvent {
+    match event {
+        StreamEvent::Content(s) => {
+            content.push_str(&s);
+            ChatEvent::ContentDelta(s)
+        }
+        StreamEvent::Reasoning(s) => {
+            //之前有过一次函数调用，之后任何信息都是reasoning
+            thinking.push_str(&s);
+            ChatEvent::ReasoningDelta(s)
+        }
+        StreamEvent::ToolDelta(s) => ChatEvent::ToolDelta(s),
+        StreamEvent::ToolCall(tool_use) => {
+            tool_calls.push(tool_use.clone());
+            ChatEvent::ToolCall(tool_use)
+        }
+    }
+}
+
+#[derive(Default)]
+struct NativeToolCall {
+    id: Option<String>,
+    name: String,
+    args: String,
+}
+
+/// 原生模式下按index累积的`delta.tool_calls`
+#[derive(Default)]
+struct NativeToolCalls {
+    calls: Vec<NativeToolCall>,
+    /// index对应的调用在`calls`中的位置
+    slots: HashMap<u32, usize>,
+}
+
+impl NativeToolCalls {
+    /// 累积一个chunk，返回需要显示的增量文本
+    /// 已有的index出现不同的id时当作新的调用；没有id和名字的新index当作上一个调用的继续
+    fn push(&mut self, chunk: &ChatCompletionMessageToolCallChunk) -> Vec<String> {
+        let name = chunk.function.as_ref().and_then(|f| f.name.as_ref());
+        let args = chunk.function.as_ref().and_then(|f| f.arguments.as_ref());
+        let slot = match self.slots.get(&chunk.index) {
+            Some(&slot)
+                if chunk
+                    .id
+                    .as_ref()
+                    .is_none_or(|id| self.calls[slot].id.as_ref().is_none_or(|old| old == id)) =>
+            {
+                slot
+            }
+            None if chunk.id.is_none() && name.is_none() && !self.calls.is_empty() => {
+                tracing::warn!("Tool call index changed to {} mid-call", chunk.index);
+                self.calls.len() - 1
+            }
+            _ => {
+                self.calls.push(NativeToolCall::default());
+                self.calls.len() - 1
+            }
+        };
+        self.slots.insert(chunk.index, slot);
+
+        let call = &mut self.calls[slot];
+        if call.id.is_none() {
+            call.id = chunk.id.clone();
+        }
+        let mut deltas = vec![];
+        if let Some(n) = name {
+            call.name.push_str(n);
+            deltas.push(n.clone());
+        }
+        if let Some(a) = args {
+            call.args.push_str(a);
+            deltas.push(a.clone());
+        }
+        deltas
+    }
+
+    fn len(&self) -> usize {
+        self.calls.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.calls.is_empty()
+    }
+
+    fn clear(&mut self) {
+        self.calls.clear();
+        self.slots.clear();
+    }
+
+    /// 没有名字的调用无法执行，丢弃
+    fn into_tool_uses(self) -> Vec<ToolUse> {
+        self.calls
+            .into_iter()
+            .filter_map(|call| {
+                let name = call.name.trim();
+                if name.is_empty() {
+                    tracing::warn!("Ignore tool call {:?} without name", call.id);
+                    return None;
+                }
+                Some(ToolUse {
+                    use_id: Uuid::new_v4(),
+                    function_name: name.to_string(),
+                    args: call.args,
+                    call_id: call.id,
+                })
+            })
+            .collect()
+    }
+}
+
+/// 释放一次引用，数据被删除时一起删除元数据
+pub(crate) fn release_with_meta(
+    store: &dyn BlobStorage,
+    id: AssetId,
+) -> Result<(), BlobStorageError> {
+    if store.release(id)? {
+        store.delete_meta(id)?;
+    }
+    Ok(())
+}
+
+fn append_message_to_meta(
     chat_id: Uuid,
-    old_buf: &Option<Vec<u8>>,
+    old_meta: Option<Vec<u8>>,
+    last: Option<Vec<u8>>,
     content: &Message,
-) -> Result<(Vec<u8>, Vec<u8>), Error> {
-    let mut vec: ChatEntry = match old_buf {
-        Some(buf) => {
-            if buf.len() > 1 {
-                serde_json::from_slice(buf)?
-            } else {
-                let mut c = ChatEntry::default();
-                c.id = chat_id;
-                c
+) -> Result<PushedMessage, Error> {
+    // 会话不存在时连同不含消息的data一起创建
+    let (mut meta, data) = match old_meta {
+        Some(buf) if buf.len() > 1 => (serde_json::from_slice::<ChatMeta>(&buf)?, None),
+        old_meta => {
+            if old_meta.is_none() {
+                tracing::info!("Chat Session {} does not exist, creating.", chat_id);
             }
-        }
-        None => {
-            tracing::info!("Chat Session {} does not exist, creating.", chat_id);
             let mut c = ChatEntry::default();
             c.id = chat_id;
-            c
+            (ChatMeta::clone_from(&c), Some(serde_json::to_vec(&c)?))
         }
     };
-    if content.owner == Role::User && vec.summary.len() == 0 {
-        vec.summary = content
+    if content.owner == Role::User && meta.summary.len() == 0 {
+        meta.summary = content
             .content
             .iter()
             .map(|v| match v {
@@ -1016,9 +1998,615 @@ fn append_message_to_buffer(
             .take(64)
             .collect::<String>();
     }
-    vec.messages.push(content.clone());
-    Ok((
-        serde_json::to_vec(&ChatMeta::clone_from(&vec))?,
-        serde_json::to_vec(&vec)?,
-    ))
+    let last = last
+        .map(|buf| serde_json::from_slice::<Message>(&buf))
+        .transpose()?;
+    // 继续生成的消息覆盖之前被中断的那一条
+    let (parent, replace_last) = match last {
+        Some(last) if last.id == content.id => (last.parent, true),
+        last => (last.map(|m| m.id), false),
+    };
+    Ok(PushedMessage {
+        meta: serde_json::to_vec(&meta)?,
+        data,
+        message: serde_json::to_vec(&Message {
+            parent,
+            ..content.clone()
+        })?,
+        replace_last,
+    })
+}
+
+/// 把会话拆分为不含消息的data和逐条序列化的消息
+pub(crate) fn encode_chat(entry: &mut ChatEntry) -> Result<(Vec<u8>, Vec<Vec<u8>>), Error> {
+    let messages = entry
+        .messages
+        .iter()
+        .map(serde_json::to_vec)
+        .collect::<Result<Vec<_>, _>>()?;
+    let taken = std::mem::take(&mut entry.messages);
+    let data = serde_json::to_vec(entry);
+    entry.messages = taken;
+    Ok((data?, messages))
+}
+
+/// 还没有解析的会话，消息在用到时才逐条解析
+pub struct StoredChat {
+    /// `encode_chat`得到的不含消息的data
+    data: Vec<u8>,
+    messages: Vec<Vec<u8>>,
+    /// meta中的摘要，没有时使用data中的
+    summary: Option<String>,
+}
+
+impl StoredChat {
+    /// 活跃分支上的消息数量
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    /// 解析活跃分支上的第`index`条消息，同时为旧数据补上`parent`
+    pub fn message(&self, index: usize) -> Result<Option<Message>, Error> {
+        let Some(buf) = self.messages.get(index) else {
+            return Ok(None);
+        };
+        let mut message: Message = serde_json::from_slice(buf)?;
+        if message.parent.is_none() && index > 0 {
+            #[derive(Deserialize)]
+            struct MessageId {
+                id: Uuid,
+            }
+            let previous: MessageId = serde_json::from_slice(&self.messages[index - 1])?;
+            message.parent = Some(previous.id);
+        }
+        Ok(Some(message))
+    }
+
+    pub fn last_message(&self) -> Result<Option<Message>, Error> {
+        match self.len() {
+            0 => Ok(None),
+            len => self.message(len - 1),
+        }
+    }
+
+    /// `encode_chat`的逆操作，解析所有消息
+    pub fn into_entry(self) -> Result<ChatEntry, Error> {
+        let mut entry: ChatEntry = serde_json::from_slice(&self.data)?;
+        entry.messages = self
+            .messages
+            .iter()
+            .map(|m| serde_json::from_slice(m))
+            .collect::<Result<Vec<_>, _>>()?;
+        entry.link_parents();
+        if let Some(summary) = self.summary {
+            entry.summary = summary;
+        }
+        Ok(entry)
+    }
+}
+
+/// 旧版本把整个`ChatEntry`保存为一条记录，迁移时拆分为新的布局
+pub(crate) fn split_legacy_chat(buf: &[u8]) -> Result<(Vec<u8>, Vec<Vec<u8>>), Error> {
+    if buf.len() <= 1 {
+        return Ok((buf.to_vec(), vec![]));
+    }
+    let mut entry: ChatEntry = serde_json::from_slice(buf)?;
+    entry.link_parents();
+    encode_chat(&mut entry)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::VecDeque, convert::Infallible, sync::Mutex};
+
+    use async_openai::config::OpenAIConfig;
+    use axum::{
+        Json, Router,
+        extract::State,
+        response::sse::{Event, Sse},
+        routing::post,
+    };
+    use serde_json::{Value, json};
+
+    use super::*;
+    use crate::{Tool, ToolDescription};
+
+    /// 按顺序返回录制好的流式回复，并记录收到的请求
+    #[derive(Clone, Default)]
+    struct MockLlm {
+        replies: Arc<Mutex<VecDeque<Vec<Value>>>>,
+        requests: Arc<Mutex<Vec<Value>>>,
+    }
+
+    async fn completions(
+        State(mock): State<MockLlm>,
+        Json(req): Json<Value>,
+    ) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+        mock.requests.lock().unwrap().push(req);
+        let chunks = mock.replies.lock().unwrap().pop_front().unwrap_or_default();
+        let events = chunks
+            .into_iter()
+            .map(|c| Event::default().data(c.to_string()))
+            .chain(std::iter::once(Event::default().data("[DONE]")))
+            .map(Ok);
+        Sse::new(futures::stream::iter(events))
+    }
+
+    impl MockLlm {
+        fn reply(self, chunks: Vec<Value>) -> Self {
+            self.replies.lock().unwrap().push_back(chunks);
+            self
+        }
+
+        fn request(&self, idx: usize) -> Value {
+            self.requests.lock().unwrap()[idx].clone()
+        }
+
+        async fn provider(
+            &self,
+            tools: Vec<Box<dyn Tool + Send + Sync>>,
+        ) -> LLMProvider<OpenAIConfig> {
+            let app = Router::new()
+                .route("/chat/completions", post(completions))
+                .with_state(self.clone());
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let base = format!("http://{}", listener.local_addr().unwrap());
+            tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+            let toolset = tools
+                .into_iter()
+                .fold(ToolSet::builder(), |ts, t| ts.add_tool(t))
+                .build();
+            LLMProvider {
+                client: Arc::new(Client::with_config(OpenAIConfig::new().with_api_base(base))),
+                storages: Storages::temporary(),
+                toolset: Arc::new(toolset),
+                options: ProviderOptions::default(),
+            }
+        }
+    }
+
+    fn chunk(delta: Value) -> Value {
+        json!({
+            "id": "chunk",
+            "object": "chat.completion.chunk",
+            "created": 0,
+            "model": "mock",
+            "choices": [{"index": 0, "delta": delta, "finish_reason": null}],
+        })
+    }
+
+    fn text_reply(text: &str) -> Vec<Value> {
+        vec![chunk(json!({"role": "assistant", "content": text}))]
+    }
+
+    fn native_config() -> LLMConfig {
+        LLMConfig {
+            model: Some("mock".into()),
+            tool_call_mode: Some(ToolCallMode::Native),
+            ..Default::default()
+        }
+    }
+
+    /// 直到被取消才返回的工具
+    struct Hang;
+
+    #[async_trait::async_trait]
+    impl Tool for Hang {
+        fn name(&self) -> String {
+            "hang".to_string()
+        }
+
+        fn description(&self) -> ToolDescription {
+            ToolDescription {
+                name_for_model: "hang".to_string(),
+                name_for_human: "hang".to_string(),
+                description_for_model: "Never returns.".to_string(),
+                parameters: json!({"type": "object"}),
+                args_format: "JSON.".to_string(),
+            }
+        }
+
+        async fn call(
+            &self,
+            _ctx: &ToolContext,
+            _args: &str,
+        ) -> Result<Vec<MessageContent>, Error> {
+            std::future::pending().await
+        }
+    }
+
+    /// 发送一条消息并收集所有事件，`cancel_on_tool_call`为真时在工具开始执行后取消
+    async fn send(
+        provider: &LLMProvider<OpenAIConfig>,
+        chat_id: Uuid,
+        text: &str,
+        config: LLMConfig,
+        cancel_on_tool_call: bool,
+    ) -> Vec<ChatEvent> {
+        let cancel = CancellationToken::new();
+        let stream = provider
+            .send_chat_message(
+                chat_id,
+                vec![MessageContent::Text(text.into())],
+                config,
+                cancel.clone(),
+            )
+            .await
+            .unwrap();
+        let mut stream = std::pin::pin!(stream);
+        let mut events = vec![];
+        while let Some(event) = stream.next().await {
+            let event = event.unwrap();
+            if cancel_on_tool_call && matches!(event, ChatEvent::ToolCall(_)) {
+                cancel.cancel();
+            }
+            events.push(event);
+        }
+        events
+    }
+
+    #[tokio::test]
+    async fn cancel_during_tool_call_keeps_history_valid() {
+        let mock = MockLlm::default()
+            .reply(vec![chunk(json!({
+                "role": "assistant",
+                "tool_calls": [{
+                    "index": 0,
+                    "id": "call_1",
+                    "type": "function",
+                    "function": {"name": "hang", "arguments": "{}"},
+                }],
+            }))])
+            .reply(text_reply("ok"));
+        let provider = mock.provider(vec![Box::new(Hang)]).await;
+        let chat_id = provider.new_chat().unwrap().id;
+
+        let events = send(&provider, chat_id, "go", native_config(), true).await;
+        assert!(
+            events
+                .iter()
+                .any(|e| matches!(e, ChatEvent::ToolResult { .. }))
+        );
+        let entry = provider.get_chat(chat_id).unwrap().unwrap();
+        let [_, assistant, result] = entry.messages.as_slice() else {
+            panic!("unexpected history: {:?}", entry.messages);
+        };
+        assert_eq!(assistant.tool_use.len(), 1);
+        assert_eq!(result.owner, Role::Tools(assistant.tool_use[0].use_id));
+
+        send(&provider, chat_id, "again", native_config(), false).await;
+        let messages = mock.request(1)["messages"].as_array().unwrap().clone();
+        let call_id = messages
+            .iter()
+            .find_map(|m| m["tool_calls"][0]["id"].as_str())
+            .unwrap()
+            .to_string();
+        let tool = messages.iter().find(|m| m["role"] == "tool").unwrap();
+        assert_eq!(tool["tool_call_id"], call_id);
+        assert!(tool.to_string().contains("cancelled"));
+    }
+
+    /// 立即返回的工具
+    struct Done;
+
+    #[async_trait::async_trait]
+    impl Tool for Done {
+        fn name(&self) -> String {
+            "done".to_string()
+        }
+
+        fn description(&self) -> ToolDescription {
+            ToolDescription {
+                name_for_model: "done".to_string(),
+                name_for_human: "done".to_string(),
+                description_for_model: "Returns immediately.".to_string(),
+                parameters: json!({"type": "object"}),
+                args_format: "JSON.".to_string(),
+            }
+        }
+
+        async fn call(
+            &self,
+            _ctx: &ToolContext,
+            _args: &str,
+        ) -> Result<Vec<MessageContent>, Error> {
+            Ok(vec![MessageContent::Text("done".into())])
+        }
+    }
+
+    fn tool_call_reply(id: &str, name: &str) -> Vec<Value> {
+        vec![chunk(json!({
+            "role": "assistant",
+            "tool_calls": [{
+                "index": 0,
+                "id": id,
+                "type": "function",
+                "function": {"name": name, "arguments": "{}"},
+            }],
+        }))]
+    }
+
+    #[test]
+    fn loop_limit_checks_each_budget() {
+        let unlimited = LLMConfig::default();
+        assert_eq!(
+            unlimited.check_loop_limit(1000, 1000, Duration::from_secs(3600)),
+            None
+        );
+
+        let config = LLMConfig {
+            max_tool_rounds: Some(2),
+            max_tool_calls: Some(3),
+            max_duration_secs: Some(10),
+            ..Default::default()
+        };
+        let secs = Duration::from_secs;
+        assert_eq!(config.check_loop_limit(1, 2, secs(9)), None);
+        assert_eq!(
+            config.check_loop_limit(2, 2, secs(0)),
+            Some(LoopLimit::ToolRounds(2))
+        );
+        assert_eq!(
+            config.check_loop_limit(1, 3, secs(0)),
+            Some(LoopLimit::ToolCalls(3))
+        );
+        assert_eq!(
+            config.check_loop_limit(1, 2, secs(10)),
+            Some(LoopLimit::Duration(10))
+        );
+        // 多个上限同时超出时按轮数、次数、时间的顺序报告
+        assert_eq!(
+            config.check_loop_limit(5, 5, secs(60)),
+            Some(LoopLimit::ToolRounds(2))
+        );
+    }
+
+    #[tokio::test]
+    async fn loop_limit_forces_final_answer_without_tools() {
+        let mock = MockLlm::default()
+            .reply(tool_call_reply("call_1", "done"))
+            .reply(text_reply("final"));
+        let provider = mock.provider(vec![Box::new(Done)]).await;
+        let chat_id = provider.new_chat().unwrap().id;
+        let config = LLMConfig {
+            max_tool_rounds: Some(1),
+            ..native_config()
+        };
+
+        let events = send(&provider, chat_id, "go", config, false).await;
+        let limits: Vec<_> = events
+            .iter()
+            .filter_map(|e| match e {
+                ChatEvent::LoopLimitReached(limit) => Some(*limit),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(limits, vec![LoopLimit::ToolRounds(1)]);
+
+        assert!(mock.request(0)["tools"].is_array());
+        let last = mock.request(1);
+        assert!(last.get("tools").is_none());
+        let messages = last["messages"].as_array().unwrap();
+        let prompt = messages.last().unwrap();
+        assert_eq!(prompt["role"], "user");
+        assert!(prompt.to_string().contains("max_tool_rounds=1"));
+
+        let entry = provider.get_chat(chat_id).unwrap().unwrap();
+        let reply = entry.messages.last().unwrap();
+        assert_eq!(reply.owner, Role::Assistant);
+        assert!(reply.tool_use.is_empty());
+    }
+
+    fn tool_call_chunk(index: u32, id: Option<&str>, name: Option<&str>, args: &str) -> Value {
+        let mut call = json!({"index": index, "function": {"arguments": args}});
+        if let Some(id) = id {
+            call["id"] = json!(id);
+            call["type"] = json!("function");
+        }
+        if let Some(name) = name {
+            call["function"]["name"] = json!(name);
+        }
+        call
+    }
+
+    #[test]
+    fn native_tool_calls_follow_ids_and_indexes() {
+        let mut calls = NativeToolCalls::default();
+        let chunks = [
+            tool_call_chunk(0, Some("call_a"), Some("done"), "{\"x\":"),
+            // index改变但没有id和名字，是同一个调用的继续
+            tool_call_chunk(1, None, None, "1}"),
+            // 同一个index出现新的id，是新的调用
+            tool_call_chunk(0, Some("call_b"), Some("done"), "{}"),
+            tool_call_chunk(2, Some("call_c"), None, "{}"),
+        ];
+        for chunk in chunks {
+            calls.push(&serde_json::from_value(chunk).unwrap());
+        }
+        let uses = calls.into_tool_uses();
+        let uses: Vec<_> = uses
+            .iter()
+            .map(|t| {
+                (
+                    t.call_id.as_deref(),
+                    t.function_name.as_str(),
+                    t.args.as_str(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            uses,
+            vec![
+                (Some("call_a"), "done", "{\"x\":1}"),
+                (Some("call_b"), "done", "{}"),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn native_tool_call_ids_are_replayed() {
+        let recorded = vec![
+            chunk(json!({"role": "assistant", "tool_calls": [
+                tool_call_chunk(0, Some("call_a"), Some("done"), ""),
+            ]})),
+            chunk(json!({"tool_calls": [tool_call_chunk(0, None, None, "{\"x\":")]})),
+            chunk(json!({"tool_calls": [tool_call_chunk(0, None, None, "1}")]})),
+            chunk(json!({"tool_calls": [tool_call_chunk(1, Some("call_b"), Some("done"), "{}")]})),
+            // 没有名字的调用被忽略
+            chunk(json!({"tool_calls": [tool_call_chunk(2, Some("call_c"), None, "{}")]})),
+        ];
+        let mock = MockLlm::default().reply(recorded).reply(text_reply("ok"));
+        let provider = mock.provider(vec![Box::new(Done)]).await;
+        let chat_id = provider.new_chat().unwrap().id;
+        send(&provider, chat_id, "go", native_config(), false).await;
+
+        let entry = provider.get_chat(chat_id).unwrap().unwrap();
+        let ids: Vec<_> = entry.messages[1]
+            .tool_use
+            .iter()
+            .map(|t| t.call_id.as_deref())
+            .collect();
+        assert_eq!(ids, vec![Some("call_a"), Some("call_b")]);
+
+        let messages = mock.request(1)["messages"].as_array().unwrap().clone();
+        let assistant = messages.iter().find(|m| m["role"] == "assistant").unwrap();
+        let calls: Vec<_> = assistant["tool_calls"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|c| {
+                (
+                    c["id"].as_str().unwrap(),
+                    c["function"]["arguments"].as_str().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(calls, vec![("call_a", "{\"x\":1}"), ("call_b", "{}")]);
+        let results: Vec<_> = messages
+            .iter()
+            .filter(|m| m["role"] == "tool")
+            .map(|m| m["tool_call_id"].as_str().unwrap())
+            .collect();
+        assert_eq!(results, vec!["call_a", "call_b"]);
+    }
+
+    fn decode_data_url(url: &str) -> (String, Vec<u8>) {
+        let (mime, data) = url
+            .strip_prefix("data:")
+            .and_then(|u| u.split_once(";base64,"))
+            .unwrap();
+        (mime.to_string(), BASE64_STANDARD.decode(data).unwrap())
+    }
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let mut buf = Vec::new();
+        image::DynamicImage::new_rgb8(width, height)
+            .write_to(&mut std::io::Cursor::new(&mut buf), image::ImageFormat::Png)
+            .unwrap();
+        buf
+    }
+
+    #[test]
+    fn image_url_cache_resizes_once() {
+        let mut cache = ImageUrlCache::new(Some(ImageResizer::new(32, 32 * 32, 256 * 256)));
+        let (large, small) = (png(1024, 768), png(64, 64));
+        let (large_id, small_id) = (AssetId::from_data(&large), AssetId::from_data(&small));
+
+        let url = cache
+            .get_or_load(large_id, || Ok::<_, Error>(Some(large.clone())))
+            .unwrap()
+            .unwrap();
+        let (mime, data) = decode_data_url(&url);
+        assert_eq!(mime, "image/png");
+        let (width, height) = image_dimensions(&data).unwrap();
+        assert!(width * height <= 256 * 256 && width < 1024);
+        // 第二次直接使用缓存，不再读取
+        let cached = cache
+            .get_or_load(large_id, || -> Result<Option<Vec<u8>>, Error> {
+                panic!("image loaded twice")
+            })
+            .unwrap();
+        assert_eq!(cached, Some(url));
+
+        // 小图不放大
+        let url = cache.get_or_encode(small_id, &small);
+        assert_eq!(decode_data_url(&url).1, small);
+        assert!(
+            cache
+                .get_or_load(AssetId::from_data(b"missing"), || Ok::<_, Error>(None))
+                .unwrap()
+                .is_none()
+        );
+
+        // 没有设置像素范围时发送原图
+        let mut cache = ImageUrlCache::new(None);
+        let url = cache.get_or_encode(large_id, &large);
+        assert_eq!(decode_data_url(&url).1, large);
+    }
+
+    fn jpeg(width: u32, height: u32) -> Vec<u8> {
+        let mut buf = Vec::new();
+        image::DynamicImage::new_rgb8(width, height)
+            .write_to(
+                &mut std::io::Cursor::new(&mut buf),
+                image::ImageFormat::Jpeg,
+            )
+            .unwrap();
+        buf
+    }
+
+    #[test]
+    fn jpeg_data_url_keeps_mime() {
+        let data = jpeg(1024, 768);
+        assert!(image_data_url(&data, None).starts_with("data:image/jpeg;base64,"));
+        let resizer = ImageResizer::new(32, 32 * 32, 256 * 256);
+        let (mime, resized) = decode_data_url(&image_data_url(&data, Some(&resizer)));
+        assert_eq!(mime, "image/jpeg");
+        assert!(image_dimensions(&resized).unwrap().0 < 1024);
+    }
+
+    #[tokio::test]
+    async fn stored_image_mime_follows_format_policy() {
+        let mut provider = MockLlm::default().provider(vec![]).await;
+        let mime = |provider: &LLMProvider<OpenAIConfig>, data: &[u8]| {
+            let id = provider
+                .ingest_image(data.to_vec(), BlobMeta::uploaded())
+                .unwrap();
+            provider.open_image_with_mime(id).unwrap().unwrap().1
+        };
+        assert_eq!(mime(&provider, &jpeg(16, 16)), "image/jpeg");
+        assert_eq!(mime(&provider, &png(16, 16)), "image/png");
+
+        provider.options.image_format = ImageFormatPolicy::Png;
+        assert_eq!(mime(&provider, &jpeg(24, 24)), "image/png");
+        provider.options.image_format = ImageFormatPolicy::Jpeg;
+        assert_eq!(mime(&provider, &png(24, 24)), "image/jpeg");
+    }
+
+    #[tokio::test]
+    async fn open_chat_decodes_messages_on_demand() {
+        let mock = MockLlm::default().reply(text_reply("a"));
+        let provider = mock.provider(vec![]).await;
+        let chat_id = provider.new_chat().unwrap().id;
+        send(&provider, chat_id, "q", native_config(), false).await;
+
+        let chat = provider.open_chat(chat_id).unwrap().unwrap();
+        assert_eq!(chat.len(), 2);
+        let first = chat.message(0).unwrap().unwrap();
+        let last = chat.last_message().unwrap().unwrap();
+        assert_eq!(last.owner, Role::Assistant);
+        assert_eq!(last.parent, Some(first.id));
+        assert!(chat.message(2).unwrap().is_none());
+
+        let entry = chat.into_entry().unwrap();
+        let expected = provider.get_chat(chat_id).unwrap().unwrap();
+        assert_eq!(entry.messages.len(), 2);
+        assert_eq!(entry.messages[1].id, last.id);
+        assert_eq!(entry.summary, expected.summary);
+        assert!(provider.open_chat(Uuid::new_v4()).unwrap().is_none());
+    }
 }
@@ -0,0 +1,434 @@
+use crate::{
+    ImageResizer,
+    schema::{ContextSummary, Message, MessageContent, Role},
+};
+
+/// 每个token对应的图片边长 (Qwen-VL: patch 16 + 2x2 merge)
+const IMAGE_TOKEN_SIZE: u32 = 32;
+/// 每张图片额外的 <vision_start>/<vision_end> 等标记
+const IMAGE_EXTRA_TOKENS: usize = 2;
+/// 每条消息的聊天模板开销 (<|im_start|>role\n ... <|im_end|>\n)
+const MESSAGE_OVERHEAD_TOKENS: usize = 4;
+/// 与ZoomIn工具相同的默认像素范围
+pub const DEFAULT_IMAGE_MIN_PIXELS: u64 = 262144;
+pub const DEFAULT_IMAGE_MAX_PIXELS: u64 = 12845056;
+/// 无法读取尺寸时的假定尺寸
+const UNKNOWN_IMAGE_SIZE: (u32, u32) = (1024, 1024);
+/// 最新一轮中始终保留的最近的工具结果图片数量，模型通常只需要看最后几次的结果
+const KEEP_RECENT_IMAGES: usize = 2;
+
+/// 根据token预算裁剪发送给模型的历史
+///
+/// 裁剪顺序:
+/// 1. 从最旧的开始，把工具结果中的图片替换成文字占位符
+/// 2. 从最旧的开始，按轮(以用户消息开头)丢弃整轮对话
+/// 3. 从最旧的开始，替换最新一轮工具结果中的图片，最近的`KEEP_RECENT_IMAGES`张除外
+///
+/// system prompt和最新一轮的消息始终保留
+pub struct ContextManager {
+    budget: usize,
+    resizer: ImageResizer,
+}
+
+/// 裁剪的结果
+pub struct ContextWindow {
+    /// 需要发送给模型的消息
+    pub messages: Vec<Message>,
+    /// 被丢弃的旧消息，保持原有顺序
+    pub dropped: Vec<Message>,
+    /// 被替换成占位符的图片数量
+    pub stripped_images: usize,
+    /// 裁剪后估计的token数量，包括`reserved`
+    pub tokens: usize,
+}
+
+impl ContextManager {
+    pub fn new(budget: usize, resizer: ImageResizer) -> Self {
+        Self { budget, resizer }
+    }
+
+    /// 粗略估计文本的token数量
+    /// CJK字符约1个token，其它字符约4个字符1个token
+    pub fn estimate_text(text: &str) -> usize {
+        let (wide, narrow) = text.chars().fold((0, 0), |(w, n), c| {
+            if is_wide_char(c) {
+                (w + 1, n)
+            } else {
+                (w, n + 1)
+            }
+        });
+        wide + narrow.div_ceil(4)
+    }
+
+    /// 按模型实际看到的分辨率估计图片的token数量
+    pub fn estimate_image(&self, width: u32, height: u32) -> usize {
+        let (h, w) = self.resizer.smart_resize(height, width);
+        ((h / IMAGE_TOKEN_SIZE) * (w / IMAGE_TOKEN_SIZE)) as usize + IMAGE_EXTRA_TOKENS
+    }
+
+    /// 估计一条消息的token数量
+    /// `image_size`返回图片的 (宽, 高)
+    pub fn estimate_message(
+        &self,
+        msg: &Message,
+        image_size: &impl Fn(&MessageContent) -> Option<(u32, u32)>,
+    ) -> usize {
+        // reasoning不会发送给模型
+        let content = msg
+            .content
+            .iter()
+            .map(|c| match c {
+                MessageContent::Text(s) => Self::estimate_text(s),
+                MessageContent::AssetRef(_, _) => Self::estimate_text(&c.to_string()),
+                MessageContent::ImageRef(_, _) | MessageContent::ImageBin(_, _, _) => {
+                    let (w, h) = image_size(c).unwrap_or(UNKNOWN_IMAGE_SIZE);
+                    Self::estimate_text(&c.to_string()) + self.estimate_image(w, h)
+                }
+            })
+            .sum::<usize>();
+        let tool_use = msg
+            .tool_use
+            .iter()
+            .map(|t| Self::estimate_text(&t.function_name) + Self::estimate_text(&t.args))
+            .sum::<usize>();
+        content + tool_use + MESSAGE_OVERHEAD_TOKENS
+    }
+
+    /// 把`messages`裁剪到预算之内
+    /// `reserved`是system prompt、摘要以及输出需要的token
+    pub fn fit(
+        &self,
+        mut messages: Vec<Message>,
+        reserved: usize,
+        image_size: impl Fn(&MessageContent) -> Option<(u32, u32)>,
+    ) -> ContextWindow {
+        let mut costs: Vec<usize> = messages
+            .iter()
+            .map(|m| self.estimate_message(m, &image_size))
+            .collect();
+        let mut total = reserved + costs.iter().sum::<usize>();
+        let mut stripped_images = 0;
+        if total <= self.budget {
+            return ContextWindow {
+                messages,
+                dropped: vec![],
+                stripped_images,
+                tokens: total,
+            };
+        }
+
+        // 最新的一轮从最后一条用户消息开始
+        let last_turn = messages
+            .iter()
+            .rposition(|m| m.owner == Role::User)
+            .unwrap_or(0);
+
+        for (msg, cost) in messages[..last_turn].iter_mut().zip(costs.iter_mut()) {
+            if total <= self.budget {
+                break;
+            }
+            if matches!(msg.owner, Role::Tools(_)) {
+                stripped_images += self.strip_message(msg, 0, cost, &mut total, &image_size);
+            }
+        }
+
+        let mut start = 0;
+        while total > self.budget && start < last_turn {
+            let next = messages[start + 1..]
+                .iter()
+                .position(|m| m.owner == Role::User)
+                .map(|p| p + start + 1)
+                .unwrap_or(last_turn)
+                .min(last_turn);
+            total -= costs[start..next].iter().sum::<usize>();
+            start = next;
+        }
+
+        // 一条用户消息之后有很多次工具调用时，只能替换这一轮中较早的图片
+        if total > self.budget {
+            let mut keep = vec![0; messages.len()];
+            let mut remaining = KEEP_RECENT_IMAGES;
+            for (msg, keep) in messages.iter().zip(keep.iter_mut()).skip(last_turn).rev() {
+                if matches!(msg.owner, Role::Tools(_)) {
+                    *keep = count_images(msg).min(remaining);
+                    remaining -= *keep;
+                }
+            }
+            for ((msg, cost), keep) in messages
+                .iter_mut()
+                .zip(costs.iter_mut())
+                .zip(keep)
+                .skip(last_turn)
+            {
+                if total <= self.budget {
+                    break;
+                }
+                if matches!(msg.owner, Role::Tools(_)) {
+                    stripped_images += self.strip_message(msg, keep, cost, &mut total, &image_size);
+                }
+            }
+        }
+
+        if total > self.budget {
+            tracing::warn!(
+                "Context still exceeds budget after truncation: {} > {}",
+                total,
+                self.budget
+            );
+        }
+
+        let kept = messages.split_off(start);
+        ContextWindow {
+            messages: kept,
+            dropped: messages,
+            stripped_images,
+            tokens: total,
+        }
+    }
+
+    /// 替换消息中最后`keep`张以外的图片，同时更新它的token估计和总数，返回替换的数量
+    fn strip_message(
+        &self,
+        msg: &mut Message,
+        keep: usize,
+        cost: &mut usize,
+        total: &mut usize,
+        image_size: &impl Fn(&MessageContent) -> Option<(u32, u32)>,
+    ) -> usize {
+        let count = strip_images(msg, keep);
+        if count > 0 {
+            let new_cost = self.estimate_message(msg, image_size);
+            *total = *total - *cost + new_cost;
+            *cost = new_cost;
+        }
+        count
+    }
+}
+
+fn count_images(msg: &Message) -> usize {
+    msg.content
+        .iter()
+        .filter(|c| {
+            matches!(
+                c,
+                MessageContent::ImageRef(_, _) | MessageContent::ImageBin(_, _, _)
+            )
+        })
+        .count()
+}
+
+/// 把消息中最后`keep`张以外的图片替换成文字占位符，返回替换的数量
+/// 模型仍然可以通过`Image`工具重新查看
+fn strip_images(msg: &mut Message, keep: usize) -> usize {
+    let limit = count_images(msg).saturating_sub(keep);
+    let mut count = 0;
+    for c in msg.content.iter_mut() {
+        if count == limit {
+            break;
+        }
+        let (id, desc) = match c {
+            MessageContent::ImageRef(id, desc) | MessageContent::ImageBin(_, id, desc) => {
+                (id.clone(), desc.clone())
+            }
+            _ => continue,
+        };
+        *c = MessageContent::Text(
+            serde_json::json!({
+                "image_idx": id.to_string(),
+                "description": desc,
+                "note": "image omitted to save context, call tool `Image` to view it again",
+            })
+            .to_string(),
+        );
+        count += 1;
+    }
+    count
+}
+
+/// 找出需要新总结的消息，返回仍然有效的旧摘要和它之后被丢弃的消息
+/// 之前的摘要只有在覆盖的消息全部被丢弃时才能继续使用
+pub(crate) fn pending_summary(
+    previous: Option<ContextSummary>,
+    dropped: &[Message],
+) -> (Option<ContextSummary>, &[Message]) {
+    match previous.and_then(|p| {
+        dropped
+            .iter()
+            .position(|m| m.id == p.until)
+            .map(|idx| (p, idx))
+    }) {
+        Some((p, idx)) => (Some(p), &dropped[idx + 1..]),
+        None => (None, dropped),
+    }
+}
+
+fn is_wide_char(c: char) -> bool {
+    matches!(c as u32,
+        0x1100..=0x11FF // Hangul Jamo
+        | 0x2E80..=0x9FFF // CJK, Kana
+        | 0xAC00..=0xD7AF // Hangul
+        | 0xF900..=0xFAFF // CJK Compatibility
+        | 0xFF00..=0xFFEF // Fullwidth
+        | 0x20000..=0x2FFFF)
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::AssetId;
+
+    fn manager(budget: usize) -> ContextManager {
+        ContextManager::new(
+            budget,
+            ImageResizer::new(32, DEFAULT_IMAGE_MIN_PIXELS, DEFAULT_IMAGE_MAX_PIXELS),
+        )
+    }
+
+    fn msg(owner: Role, content: Vec<MessageContent>) -> Message {
+        Message {
+            id: Uuid::now_v7(),
+            owner,
+            reasoning: vec![],
+            content,
+            tool_use: vec![],
+            interrupted: false,
+            parent: None,
+        }
+    }
+
+    fn text(owner: Role, s: &str) -> Message {
+        msg(owner, vec![MessageContent::Text(s.to_string())])
+    }
+
+    fn image(desc: &str) -> MessageContent {
+        MessageContent::ImageRef(AssetId::from_data(desc.as_bytes()), desc.to_string())
+    }
+
+    #[test]
+    fn estimate_text_counts_wide_chars() {
+        assert_eq!(ContextManager::estimate_text(""), 0);
+        assert_eq!(ContextManager::estimate_text("abcd"), 1);
+        assert_eq!(ContextManager::estimate_text("abcde"), 2);
+        assert_eq!(ContextManager::estimate_text("你好世界"), 4);
+        assert_eq!(ContextManager::estimate_text("你好ab"), 3);
+    }
+
+    #[test]
+    fn fit_keeps_everything_within_budget() {
+        let messages = vec![text(Role::User, "hi"), text(Role::Assistant, "hello")];
+        let window = manager(1000).fit(messages, 10, |_| None);
+        assert_eq!(window.messages.len(), 2);
+        assert!(window.dropped.is_empty());
+        assert_eq!(window.stripped_images, 0);
+        assert_eq!(window.tokens, 10 + 1 + 4 + 2 + 4);
+    }
+
+    #[test]
+    fn fit_strips_old_tool_images_before_dropping() {
+        let m = manager(0);
+        let size = |_: &MessageContent| Some((1024, 1024));
+        let old = vec![
+            text(Role::User, "look"),
+            msg(Role::Tools(Uuid::nil()), vec![image("a \"quoted\" desc")]),
+            text(Role::User, "again"),
+            msg(Role::Tools(Uuid::nil()), vec![image("latest")]),
+        ];
+        let full = old
+            .iter()
+            .map(|x| m.estimate_message(x, &size))
+            .sum::<usize>();
+        let image_cost = m.estimate_image(1024, 1024);
+
+        // 预算只够去掉一张图片
+        let window = manager(full - image_cost + 64).fit(old.clone(), 0, size);
+        assert_eq!(window.stripped_images, 1);
+        assert!(window.dropped.is_empty());
+        assert!(window.tokens <= full - image_cost + 64);
+        let MessageContent::Text(placeholder) = &window.messages[1].content[0] else {
+            panic!("image not replaced");
+        };
+        let value: serde_json::Value = serde_json::from_str(placeholder).unwrap();
+        assert_eq!(value["description"], "a \"quoted\" desc");
+        // 最新一轮的图片保留
+        assert!(matches!(
+            window.messages[3].content[0],
+            MessageContent::ImageRef(_, _)
+        ));
+
+        // 去掉图片仍然不够时丢弃整轮
+        let window = manager(full - image_cost).fit(old, 0, size);
+        assert_eq!(window.stripped_images, 1);
+        assert_eq!(window.dropped.len(), 2);
+        assert_eq!(window.messages.len(), 2);
+        assert_eq!(window.messages[0].owner, Role::User);
+    }
+
+    #[test]
+    fn fit_strips_earlier_images_in_current_turn() {
+        // 每张约260个token
+        let size = |_: &MessageContent| Some((512, 512));
+        let mut messages = vec![text(Role::User, "draw it step by step")];
+        for i in 0..6 {
+            let mut assistant = text(Role::Assistant, "");
+            assistant.tool_use.push(crate::schema::ToolUse {
+                use_id: Uuid::nil(),
+                function_name: "js".into(),
+                args: "...".into(),
+                call_id: None,
+            });
+            messages.push(assistant);
+            messages.push(msg(
+                Role::Tools(Uuid::nil()),
+                vec![MessageContent::Text("ok".into()), image(&i.to_string())],
+            ));
+        }
+
+        let window = manager(900).fit(messages, 0, size);
+        assert!(window.dropped.is_empty());
+        assert_eq!(window.messages.len(), 13);
+        assert_eq!(window.stripped_images, 6 - KEEP_RECENT_IMAGES);
+        assert!(window.tokens <= 900);
+        let images = window
+            .messages
+            .iter()
+            .flat_map(|m| m.content.iter())
+            .filter_map(|c| match c {
+                MessageContent::ImageRef(_, desc) => Some(desc.as_str()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(images, ["4", "5"]);
+    }
+
+    #[test]
+    fn pending_summary_reuses_covered_prefix() {
+        let dropped = vec![
+            text(Role::User, "a"),
+            text(Role::Assistant, "b"),
+            text(Role::User, "c"),
+        ];
+        let summary = |until| ContextSummary {
+            until,
+            content: "s".into(),
+        };
+
+        let (previous, pending) = pending_summary(None, &dropped);
+        assert!(previous.is_none());
+        assert_eq!(pending.len(), 3);
+
+        let (previous, pending) = pending_summary(Some(summary(dropped[1].id)), &dropped);
+        assert!(previous.is_some());
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].id, dropped[2].id);
+
+        let (previous, pending) = pending_summary(Some(summary(dropped[2].id)), &dropped);
+        assert!(previous.is_some() && pending.is_empty());
+
+        // 覆盖的消息不在丢弃范围内时重新总结
+        let (previous, pending) = pending_summary(Some(summary(Uuid::nil())), &dropped);
+        assert!(previous.is_none());
+        assert_eq!(pending.len(), 3);
+    }
+}
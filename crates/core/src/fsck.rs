@@ -0,0 +1,208 @@
+use std::collections::{HashMap, HashSet};
+
+use serde::Serialize;
+
+use crate::{AssetId, BlobStorage, BlobStorageError, Message, MessageContent, meta_key_id};
+
+/// 所有会话中对图片和附件的引用次数
+/// 与`delete_entry_with_blobs`一致，只统计消息的`content`
+#[derive(Clone, Debug, Default)]
+pub struct BlobReferences {
+    pub image: HashMap<AssetId, u64>,
+    pub asset: HashMap<AssetId, u64>,
+}
+
+impl BlobReferences {
+    pub fn add_message(&mut self, message: &Message) {
+        for content in message.content.iter() {
+            match content {
+                MessageContent::ImageBin(_, id, _) | MessageContent::ImageRef(id, _) => {
+                    *self.image.entry(*id).or_default() += 1
+                }
+                MessageContent::AssetRef(id, _) => *self.asset.entry(*id).or_default() += 1,
+                MessageContent::Text(_) => {}
+            }
+        }
+    }
+}
+
+/// 引用计数与实际引用次数不一致的数据
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct RefCountMismatch {
+    pub id: AssetId,
+    pub stored: Option<u64>,
+    pub expected: u64,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct StoreReport {
+    pub store: String,
+    /// 存储中的数据数
+    pub blobs: usize,
+    pub mismatched: Vec<RefCountMismatch>,
+    /// 没有被任何消息引用的数据，修复时删除
+    pub orphaned: Vec<AssetId>,
+    /// 被消息引用但数据已经不存在，无法修复
+    pub missing: Vec<AssetId>,
+    /// 数据已经不存在的元数据，修复时删除
+    pub stale_meta: Vec<AssetId>,
+}
+
+impl StoreReport {
+    pub fn is_clean(&self) -> bool {
+        self.mismatched.is_empty()
+            && self.orphaned.is_empty()
+            && self.missing.is_empty()
+            && self.stale_meta.is_empty()
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct FsckReport {
+    pub dry_run: bool,
+    pub chats: usize,
+    pub stores: Vec<StoreReport>,
+}
+
+impl FsckReport {
+    pub fn is_clean(&self) -> bool {
+        self.stores.iter().all(|s| s.is_clean())
+    }
+}
+
+/// 用`expected`检查一个存储的引用计数，`repair`为真时改写为期望值并删除没有引用的数据
+pub fn check_store(
+    name: &str,
+    store: &dyn BlobStorage,
+    expected: &HashMap<AssetId, u64>,
+    repair: bool,
+) -> Result<StoreReport, BlobStorageError> {
+    let mut report = StoreReport {
+        store: name.to_string(),
+        ..Default::default()
+    };
+    let mut seen = HashSet::new();
+    let mut with_data = HashSet::new();
+    for entry in store.entries()? {
+        seen.insert(entry.id);
+        if entry.has_data {
+            with_data.insert(entry.id);
+        }
+        let want = expected.get(&entry.id).copied().unwrap_or(0);
+        if !entry.has_data && want > 0 {
+            report.missing.push(entry.id);
+            continue;
+        }
+        if entry.has_data {
+            report.blobs += 1;
+        }
+        if want == 0 && entry.has_data {
+            report.orphaned.push(entry.id);
+        } else if entry.ref_count != Some(want) {
+            report.mismatched.push(RefCountMismatch {
+                id: entry.id,
+                stored: entry.ref_count,
+                expected: want,
+            });
+        } else {
+            continue;
+        }
+        if repair {
+            store.set_ref_count(entry.id, want)?;
+            if want == 0 {
+                with_data.remove(&entry.id);
+            }
+        }
+    }
+    for id in store.raw_keys()?.iter().filter_map(|k| meta_key_id(k)) {
+        if !with_data.contains(&id) {
+            report.stale_meta.push(id);
+            if repair {
+                store.delete_meta(id)?;
+            }
+        }
+    }
+    let mut missing: Vec<_> = expected
+        .keys()
+        .filter(|id| !seen.contains(*id))
+        .copied()
+        .collect();
+    missing.sort();
+    report.missing.extend(missing);
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FsBlobStorage, RedbBlobStorage, SledBlobStorage, SqliteBlobStorage};
+    use redb::{Database, backends::InMemoryBackend};
+    use std::sync::{Arc, Mutex};
+
+    fn check(store: &dyn BlobStorage) {
+        let ok = store.save(b"ok").unwrap();
+        let leaked = store.save(b"leaked").unwrap();
+        store.retain(leaked).unwrap();
+        let orphan = store.save(b"orphan").unwrap();
+        let gone = AssetId::from_data(b"gone");
+        store.put_meta(orphan, &crate::BlobMeta::uploaded()).unwrap();
+        store.put_meta(gone, &crate::BlobMeta::uploaded()).unwrap();
+        store.put_raw(b"current", b"not a blob").unwrap();
+
+        let expected = HashMap::from([(ok, 1), (leaked, 1), (gone, 1)]);
+        let report = check_store("t", store, &expected, false).unwrap();
+        assert_eq!(report.blobs, 3);
+        assert_eq!(
+            report.mismatched,
+            [RefCountMismatch {
+                id: leaked,
+                stored: Some(2),
+                expected: 1
+            }]
+        );
+        assert_eq!(report.orphaned, [orphan]);
+        assert_eq!(report.missing, [gone]);
+        assert_eq!(report.stale_meta, [gone]);
+        assert!(store.get(orphan).unwrap().is_some());
+
+        check_store("t", store, &expected, true).unwrap();
+        assert!(store.get(orphan).unwrap().is_none());
+        assert_eq!(store.get_raw(b"current").unwrap().unwrap(), b"not a blob");
+        let report = check_store("t", store, &expected, false).unwrap();
+        assert!(report.mismatched.is_empty() && report.orphaned.is_empty());
+        assert!(report.stale_meta.is_empty());
+        assert!(store.get_meta(orphan).unwrap().is_none());
+        // 修复后引用计数为1，释放一次即删除
+        assert!(store.release(leaked).unwrap());
+    }
+
+    #[test]
+    fn sled_check_and_repair() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        check(&SledBlobStorage::new_from_db(&db, "t").unwrap());
+    }
+
+    #[test]
+    fn redb_check_and_repair() {
+        let db = Database::builder()
+            .create_with_backend(InMemoryBackend::new())
+            .unwrap();
+        check(&RedbBlobStorage::new(Arc::new(db), "t").unwrap());
+    }
+
+    #[test]
+    fn sqlite_check_and_repair() {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        check(&SqliteBlobStorage::new(Arc::new(Mutex::new(conn)), "t").unwrap());
+    }
+
+    #[test]
+    fn fs_check_and_repair() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let inner = Arc::new(SledBlobStorage::new_from_db(&db, "t").unwrap());
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        // "ok"保存在数据库中，其余的保存为文件
+        check(&FsBlobStorage::new(inner, dir.clone(), 4).unwrap());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
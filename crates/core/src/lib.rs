@@ -1,17 +1,34 @@
+mod archive;
+mod backend_migration;
 mod blob;
 mod chat_handler;
+mod context;
+mod fsck;
+mod report;
 mod schema;
+mod search;
 mod session;
+mod stream_parser;
 mod tools;
 
-use std::{path::Path, sync::Arc};
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+};
 
+pub use archive::*;
+pub use backend_migration::*;
 pub use blob::*;
 pub use chat_handler::*;
+pub use context::*;
+pub use fsck::*;
 use redb::Database;
+pub use report::*;
 pub use schema::*;
+pub use search::*;
 use serde::{Deserialize, Serialize};
 pub use session::*;
+pub use stream_parser::*;
 use strum::{Display, EnumIter, EnumString};
 pub use tools::*;
 
@@ -24,28 +41,107 @@ pub enum StorageKind {
     Sled,
     #[strum(serialize = "redb")]
     Redb,
+    #[strum(serialize = "sqlite")]
+    Sqlite,
 }
 
 #[derive(Clone)]
 pub struct Storages {
     history: Arc<dyn SessionStorage>,
+    search: Arc<dyn SearchIndex>,
     image: Arc<dyn BlobStorage>,
     asset: Arc<dyn BlobStorage>,
     memo: Arc<dyn BlobStorage>,
 }
 
+#[cfg(test)]
+impl Storages {
+    /// 测试用的临时sled存储，所有表在同一个数据库中
+    pub(crate) fn temporary() -> Self {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        Self {
+            history: Arc::new(SledSessionStore::new_from_db(&db, "history").unwrap()),
+            search: Arc::new(SledSearchIndex::new_from_db(&db, "history").unwrap()),
+            image: Arc::new(SledBlobStorage::new_from_db(&db, "image").unwrap()),
+            asset: Arc::new(SledBlobStorage::new_from_db(&db, "asset").unwrap()),
+            memo: Arc::new(SledBlobStorage::new_from_db(&db, "memo").unwrap()),
+        }
+    }
+}
+
+/// 旧版本的数据库中每个会话保存为一整条记录，启动时拆分为逐条消息
+fn migrate_history(history: &dyn SessionStorage) -> Result<(), anyhow::Error> {
+    let migrated = history.migrate(&chat_handler::split_legacy_chat)?;
+    if migrated > 0 {
+        tracing::info!("Migrated {} chats to per-message storage.", migrated);
+    }
+    Ok(())
+}
+
+/// 旧版本所有会话共用一个画布，启动时移到单独的键下保留，并为上面的图片增加引用
+fn migrate_memo(memo: &dyn BlobStorage, image: &dyn BlobStorage) -> Result<(), anyhow::Error> {
+    if tools::keep_legacy_memo(memo, image)? {
+        tracing::info!("Kept the memo canvas shared by all chats of an older version.");
+    }
+    Ok(())
+}
+
 impl StorageKind {
     pub fn create_storages<T: AsRef<Path>>(&self, path: T) -> Result<Storages, anyhow::Error> {
+        self.create_storages_with(path, &FsBlobOptions::default())
+    }
+
+    /// `fs_blob.stores`中的存储把大数据保存为文件，引用计数仍在数据库中
+    pub fn create_storages_with<T: AsRef<Path>>(
+        &self,
+        path: T,
+        fs_blob: &FsBlobOptions,
+    ) -> Result<Storages, anyhow::Error> {
+        let path = path.as_ref();
+        let storages = self.open_storages(path)?;
+        let storages = Storages {
+            image: fs_blob.wrap(BlobStoreKind::Image, storages.image, path)?,
+            asset: fs_blob.wrap(BlobStoreKind::Asset, storages.asset, path)?,
+            memo: fs_blob.wrap(BlobStoreKind::Memo, storages.memo, path)?,
+            ..storages
+        };
+        // 保存为文件的图片只能通过包装后的存储读到
+        migrate_memo(storages.memo.as_ref(), storages.image.as_ref())?;
+        Ok(storages)
+    }
+
+    fn open_storages(&self, path: &Path) -> Result<Storages, anyhow::Error> {
         match self {
             StorageKind::Redb => {
                 tracing::info!("Use redb as storage backend.");
-                let db = Arc::new(Database::create(path.as_ref())?);
+                let db = Arc::new(Database::create(path)?);
                 let history = Arc::new(RedbSessionStore::new(db.clone(), "history")?);
+                migrate_history(history.as_ref())?;
+                let search = Arc::new(RedbSearchIndex::new(db.clone(), "history")?);
                 let image = Arc::new(RedbBlobStorage::new(db.clone(), "image")?);
                 let asset = Arc::new(RedbBlobStorage::new(db.clone(), "asset")?);
                 let memo = Arc::new(RedbBlobStorage::new(db.clone(), "memo")?);
                 Ok(Storages {
                     history,
+                    search,
+                    image,
+                    asset,
+                    memo,
+                })
+            }
+            StorageKind::Sqlite => {
+                tracing::info!("Use sqlite as storage backend.");
+                let conn = rusqlite::Connection::open(path)?;
+                conn.execute_batch("PRAGMA journal_mode = WAL; PRAGMA synchronous = NORMAL;")?;
+                let db = Arc::new(Mutex::new(conn));
+                let history = Arc::new(SqliteSessionStore::new(db.clone(), "history")?);
+                let search = Arc::new(SqliteSearchIndex::new(db.clone(), "history")?);
+                let image = Arc::new(SqliteBlobStorage::new(db.clone(), "image")?);
+                let asset = Arc::new(SqliteBlobStorage::new(db.clone(), "asset")?);
+                let memo = Arc::new(SqliteBlobStorage::new(db, "memo")?);
+                Ok(Storages {
+                    history,
+                    search,
                     image,
                     asset,
                     memo,
@@ -55,15 +151,18 @@ impl StorageKind {
                 tracing::info!("Use sled as storage backend.");
                 let db = sled::Config::new()
                     .temporary(false)
-                    .path(path.as_ref())
+                    .path(path)
                     .use_compression(true)
                     .open()?;
                 let history = Arc::new(SledSessionStore::new_from_db(&db, "history")?);
+                migrate_history(history.as_ref())?;
+                let search = Arc::new(SledSearchIndex::new_from_db(&db, "history")?);
                 let image = Arc::new(SledBlobStorage::new_from_db(&db, "image")?);
                 let asset = Arc::new(SledBlobStorage::new_from_db(&db, "asset")?);
                 let memo = Arc::new(SledBlobStorage::new_from_db(&db, "memo")?);
                 Ok(Storages {
                     history,
+                    search,
                     image,
                     asset,
                     memo,
@@ -0,0 +1,391 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write as _,
+    io::{Cursor, Write},
+};
+
+use anyhow::Error;
+use base64::{Engine, prelude::BASE64_STANDARD};
+use pulldown_cmark::{Event, Options, Parser, html::push_html};
+use serde::{Deserialize, Serialize};
+use strum::{Display, EnumString};
+use uuid::Uuid;
+use zip::{CompressionMethod, ZipWriter, write::SimpleFileOptions};
+
+use crate::{AssetId, ChatEntry, Message, MessageContent, Role, Storages};
+
+/// 导出报告的格式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumString, Display, Serialize, Deserialize)]
+#[strum(serialize_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum ReportFormat {
+    /// zip压缩包，包含`chat.md`和`images`文件夹
+    Markdown,
+    /// 单个HTML文件，图片以base64内嵌
+    Html,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ReportOptions {
+    /// 是否包含模型的思考过程
+    pub reasoning: bool,
+}
+
+/// 渲染好的报告文件
+pub struct ChatReport {
+    pub filename: String,
+    pub mime: &'static str,
+    pub data: Vec<u8>,
+}
+
+/// 报告中引用的一张图片
+struct ReportImage {
+    mime: &'static str,
+    extension: &'static str,
+    data: Vec<u8>,
+}
+
+impl ReportImage {
+    fn new(data: Vec<u8>) -> Self {
+        let (mime, extension) = infer::get(&data)
+            .map(|t| (t.mime_type(), t.extension()))
+            .unwrap_or(("application/octet-stream", "bin"));
+        Self {
+            mime,
+            extension,
+            data,
+        }
+    }
+
+    fn path(&self, id: AssetId) -> String {
+        format!("images/{}.{}", id, self.extension)
+    }
+
+    fn data_url(&self) -> String {
+        format!(
+            "data:{};base64,{}",
+            self.mime,
+            BASE64_STANDARD.encode(&self.data)
+        )
+    }
+}
+
+/// 用比内容中最长的连续反引号更长的围栏，避免代码块被提前关闭
+fn fence(text: &str) -> String {
+    let longest = text
+        .split(|c| c != '`')
+        .map(|run| run.len())
+        .max()
+        .unwrap_or(0);
+    "`".repeat(longest.max(2) + 1)
+}
+
+fn code_block(out: &mut String, lang: &str, text: &str) {
+    let fence = fence(text);
+    let _ = writeln!(out, "{}{}\n{}\n{}\n", fence, lang, text.trim_end(), fence);
+}
+
+/// 图片描述作为alt文本，去掉会破坏链接语法的字符
+fn alt_text(label: &str) -> String {
+    label
+        .chars()
+        .map(|c| match c {
+            '[' | ']' | '\n' | '\r' => ' ',
+            c => c,
+        })
+        .collect()
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Markdown转为HTML，消息中的HTML原样显示为文本，数学公式保留LaTeX源码
+fn markdown_to_html(markdown: &str) -> String {
+    let options = Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH | Options::ENABLE_MATH;
+    let parser = Parser::new_ext(markdown, options).map(|event| match event {
+        Event::Html(html) | Event::InlineHtml(html) => Event::Text(html),
+        event => event,
+    });
+    let mut html = String::new();
+    push_html(&mut html, parser);
+    html
+}
+
+const HTML_STYLE: &str = "body{max-width:960px;margin:auto;padding:1em;font-family:sans-serif;line-height:1.5}\
+img{max-width:100%}\
+pre{background:#f5f5f5;padding:.5em;overflow-x:auto}\
+blockquote{color:#666;border-left:3px solid #ccc;margin-left:0;padding-left:1em}\
+.math-display{display:block;text-align:center}";
+
+impl Storages {
+    /// 读取会话中所有图片，`ImageBin`直接使用消息中的数据，不存在的图片跳过
+    fn report_images(&self, entry: &ChatEntry) -> Result<BTreeMap<AssetId, ReportImage>, Error> {
+        let mut images = BTreeMap::new();
+        for content in entry
+            .messages
+            .iter()
+            .flat_map(|m| m.content.iter().chain(m.reasoning.iter()))
+        {
+            match content {
+                MessageContent::ImageBin(data, id, _) => {
+                    images
+                        .entry(*id)
+                        .or_insert_with(|| ReportImage::new(data.clone()));
+                }
+                MessageContent::ImageRef(id, _) if !images.contains_key(id) => {
+                    match self.image.get(*id)? {
+                        Some(data) => {
+                            images.insert(*id, ReportImage::new(data));
+                        }
+                        None => tracing::warn!("Image {} in report does not exist", id),
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(images)
+    }
+
+    fn render_content(
+        &self,
+        out: &mut String,
+        content: &MessageContent,
+        image_url: &dyn Fn(AssetId) -> Option<String>,
+    ) -> Result<(), Error> {
+        match content {
+            MessageContent::Text(text) => {
+                let _ = writeln!(out, "{}\n", text.trim_end());
+            }
+            MessageContent::ImageRef(id, label) | MessageContent::ImageBin(_, id, label) => {
+                match image_url(*id) {
+                    Some(url) => {
+                        let _ = writeln!(out, "![{}]({})\n", alt_text(label), url);
+                    }
+                    None => {
+                        let _ = writeln!(out, "*[Missing image {}]*\n", id);
+                    }
+                }
+            }
+            MessageContent::AssetRef(id, _) => {
+                let name = self
+                    .asset
+                    .get_meta(*id)?
+                    .map(|meta| meta.download_name(*id))
+                    .unwrap_or_else(|| id.to_string());
+                let _ = writeln!(out, "*Attachment: `{}`*\n", name.replace('`', "'"));
+            }
+        }
+        Ok(())
+    }
+
+    fn render_message(
+        &self,
+        out: &mut String,
+        message: &Message,
+        options: &ReportOptions,
+        tool_names: &HashMap<Uuid, &str>,
+        image_url: &dyn Fn(AssetId) -> Option<String>,
+    ) -> Result<(), Error> {
+        match &message.owner {
+            Role::User => out.push_str("## User\n\n"),
+            Role::Assistant => out.push_str("## Assistant\n\n"),
+            Role::System => out.push_str("## System\n\n"),
+            Role::Tools(use_id) => {
+                let name = tool_names.get(use_id).copied().unwrap_or("unknown");
+                let _ = writeln!(out, "### Tool result: `{}`\n", name);
+            }
+        }
+        if options.reasoning && !message.reasoning.is_empty() {
+            let mut reasoning = String::new();
+            for content in message.reasoning.iter() {
+                self.render_content(&mut reasoning, content, image_url)?;
+            }
+            out.push_str("**Reasoning**\n\n");
+            for line in reasoning.trim_end().lines() {
+                let _ = writeln!(out, "> {}", line);
+            }
+            out.push('\n');
+        }
+        for content in message.content.iter() {
+            match (&message.owner, content) {
+                // 工具返回的文本通常是JSON或日志，放进代码块避免打乱排版
+                (Role::Tools(_), MessageContent::Text(text)) => code_block(out, "text", text),
+                _ => self.render_content(out, content, image_url)?,
+            }
+        }
+        for tool in message.tool_use.iter() {
+            let _ = writeln!(out, "**Tool call** `{}`\n", tool.function_name);
+            let args = serde_json::from_str::<serde_json::Value>(&tool.args)
+                .and_then(|v| serde_json::to_string_pretty(&v))
+                .unwrap_or_else(|_| tool.args.clone());
+            code_block(out, "json", &args);
+        }
+        if message.interrupted {
+            out.push_str("*(interrupted)*\n\n");
+        }
+        Ok(())
+    }
+
+    /// 把当前分支渲染为Markdown，图片链接由`image_url`决定
+    fn render_markdown(
+        &self,
+        entry: &ChatEntry,
+        options: &ReportOptions,
+        image_url: &dyn Fn(AssetId) -> Option<String>,
+    ) -> Result<String, Error> {
+        let mut out = String::new();
+        let title = match entry.summary.trim() {
+            "" => "Chat",
+            s => s,
+        };
+        let _ = writeln!(out, "# {}\n", title.replace('\n', " "));
+        let _ = writeln!(out, "*{}*\n", entry.date.format("%Y-%m-%d %H:%M:%S UTC"));
+        let tool_names = entry
+            .messages
+            .iter()
+            .flat_map(|m| m.tool_use.iter())
+            .map(|t| (t.use_id, t.function_name.as_str()))
+            .collect::<HashMap<_, _>>();
+        for message in entry.messages.iter() {
+            self.render_message(&mut out, message, options, &tool_names, image_url)?;
+        }
+        Ok(out)
+    }
+
+    /// 把会话渲染为可以交给其他人阅读的报告
+    pub fn render_report(
+        &self,
+        entry: &ChatEntry,
+        format: ReportFormat,
+        options: &ReportOptions,
+    ) -> Result<ChatReport, Error> {
+        let images = self.report_images(entry)?;
+        match format {
+            ReportFormat::Markdown => {
+                let markdown = self
+                    .render_markdown(entry, options, &|id| images.get(&id).map(|i| i.path(id)))?;
+                let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+                zip.start_file("chat.md", SimpleFileOptions::default())?;
+                zip.write_all(markdown.as_bytes())?;
+                // 图片本身已经压缩过
+                let stored =
+                    SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+                for (id, image) in images.iter() {
+                    zip.start_file(image.path(*id), stored)?;
+                    zip.write_all(&image.data)?;
+                }
+                Ok(ChatReport {
+                    filename: format!("{}.zip", entry.id),
+                    mime: "application/zip",
+                    data: zip.finish()?.into_inner(),
+                })
+            }
+            ReportFormat::Html => {
+                let markdown = self
+                    .render_markdown(entry, options, &|id| images.get(&id).map(|i| i.data_url()))?;
+                let html = format!(
+                    "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n<style>{}</style>\n</head>\n<body>\n{}</body>\n</html>\n",
+                    escape_html(&entry.summary),
+                    HTML_STYLE,
+                    markdown_to_html(&markdown)
+                );
+                Ok(ChatReport {
+                    filename: format!("{}.html", entry.id),
+                    mime: "text/html; charset=utf-8",
+                    data: html.into_bytes(),
+                })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BlobStorage, ToolUse};
+    use std::io::Read;
+
+    fn message(owner: Role, content: Vec<MessageContent>) -> Message {
+        Message {
+            id: Uuid::new_v4(),
+            owner,
+            reasoning: vec![],
+            content,
+            tool_use: vec![],
+            interrupted: false,
+            parent: None,
+        }
+    }
+
+    #[test]
+    fn render_chat() {
+        let storages = Storages::temporary();
+        let mut png = vec![];
+        image::RgbImage::new(2, 2)
+            .write_to(&mut Cursor::new(&mut png), image::ImageFormat::Png)
+            .unwrap();
+        let image = storages.image.save(&png).unwrap();
+
+        let use_id = Uuid::new_v4();
+        let mut assistant = message(
+            Role::Assistant,
+            vec![MessageContent::Text("Area is $x_1^2$ <b>".into())],
+        );
+        assistant.reasoning = vec![MessageContent::Text("secret thought".into())];
+        assistant.tool_use = vec![ToolUse {
+            use_id,
+            function_name: "zoom_in".into(),
+            args: "{\"x\":1}".into(),
+            call_id: None,
+        }];
+        let entry = ChatEntry {
+            summary: "Report".into(),
+            messages: vec![
+                message(Role::User, vec![MessageContent::Text("look".into())]),
+                assistant,
+                message(
+                    Role::Tools(use_id),
+                    vec![MessageContent::ImageRef(image, "zoomed".into())],
+                ),
+            ],
+            ..Default::default()
+        };
+
+        let report = storages
+            .render_report(&entry, ReportFormat::Html, &ReportOptions::default())
+            .unwrap();
+        let html = String::from_utf8(report.data).unwrap();
+        assert!(html.contains("data:image/png;base64,"));
+        assert!(html.contains("x_1^2"));
+        assert!(html.contains("&lt;b&gt;"));
+        assert!(html.contains("zoom_in"));
+        assert!(!html.contains("secret thought"));
+
+        let options = ReportOptions { reasoning: true };
+        let report = storages
+            .render_report(&entry, ReportFormat::Markdown, &options)
+            .unwrap();
+        let mut zip = zip::ZipArchive::new(Cursor::new(report.data)).unwrap();
+        let mut markdown = String::new();
+        zip.by_name("chat.md")
+            .unwrap()
+            .read_to_string(&mut markdown)
+            .unwrap();
+        let path = format!("images/{}.png", image);
+        assert!(markdown.contains(&format!("![zoomed]({})", path)));
+        assert!(markdown.contains("> secret thought"));
+        assert!(markdown.contains("### Tool result: `zoom_in`"));
+        assert_eq!(zip.by_name(&path).unwrap().size(), png.len() as u64);
+    }
+}
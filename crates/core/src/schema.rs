@@ -52,7 +52,15 @@ pub struct ChatEntry {
     pub id: Uuid,
     pub date: DateTime<Utc>,
     pub summary: String,
+    /// 当前活跃的分支，从根消息开始
     pub messages: Vec<Message>,
+    /// 超出上下文预算被丢弃的旧对话的摘要
+    #[serde(default)]
+    pub context_summary: Option<ContextSummary>,
+    /// 不活跃分支上的消息，通过`parent`组成树
+    /// 越靠后的越晚被切换出去，重新切换回来时沿用它们
+    #[serde(default)]
+    pub inactive: Vec<Message>,
 }
 
 impl Default for ChatEntry {
@@ -62,10 +70,164 @@ impl Default for ChatEntry {
             date: Utc::now(),
             summary: String::new(),
             messages: vec![],
+            context_summary: None,
+            inactive: vec![],
         }
     }
 }
 
+impl ChatEntry {
+    /// 旧数据中没有`parent`，按顺序补上
+    pub fn link_parents(&mut self) {
+        for i in 1..self.messages.len() {
+            if self.messages[i].parent.is_none() {
+                self.messages[i].parent = Some(self.messages[i - 1].id);
+            }
+        }
+    }
+
+    /// 追加一条已经保存的消息，继续生成的消息覆盖之前被中断的那一条
+    pub fn push_message(&mut self, message: Message) {
+        match self.messages.last_mut() {
+            Some(last) if last.id == message.id => *last = message,
+            _ => self.messages.push(message),
+        }
+    }
+
+    /// 把活跃分支从`index`开始的消息移到不活跃分支
+    pub fn deactivate_from(&mut self, index: usize) {
+        let tail = self.messages.split_off(index.min(self.messages.len()));
+        self.inactive.extend(tail);
+    }
+
+    /// 与`message_id`有相同父消息的所有消息，包括它自己
+    pub fn branches_at(&self, message_id: Uuid) -> Option<Vec<BranchInfo>> {
+        let parent = self
+            .messages
+            .iter()
+            .chain(self.inactive.iter())
+            .find(|m| m.id == message_id)?
+            .parent;
+        let active = self
+            .messages
+            .iter()
+            .filter(|m| m.parent == parent)
+            .map(|m| BranchInfo::new(m, true));
+        let inactive = self
+            .inactive
+            .iter()
+            .filter(|m| m.parent == parent)
+            .map(|m| BranchInfo::new(m, false));
+        Some(active.chain(inactive).collect())
+    }
+
+    /// 把`message_id`所在的分支切换为活跃分支
+    /// 之后的每个分叉选择最近一次使用的子分支
+    pub fn switch_branch(&mut self, message_id: Uuid) -> bool {
+        if self.messages.iter().any(|m| m.id == message_id) {
+            return true;
+        }
+        let Some(parent) = self
+            .inactive
+            .iter()
+            .find(|m| m.id == message_id)
+            .map(|m| m.parent)
+        else {
+            return false;
+        };
+        // 父消息也不在活跃分支上时，先切换到父消息
+        let index = match parent {
+            None => 0,
+            Some(p) => match self.messages.iter().position(|m| m.id == p) {
+                Some(i) => i + 1,
+                None => {
+                    if !self.switch_branch(p) {
+                        return false;
+                    }
+                    match self.messages.iter().position(|m| m.id == p) {
+                        Some(i) => i + 1,
+                        None => return false,
+                    }
+                }
+            },
+        };
+        self.deactivate_from(index);
+
+        let mut next = self.inactive.iter().position(|m| m.id == message_id);
+        while let Some(i) = next {
+            let node = self.inactive.remove(i);
+            let id = node.id;
+            self.messages.push(node);
+            next = self.inactive.iter().rposition(|m| m.parent == Some(id));
+        }
+        true
+    }
+
+    /// 删除一个不活跃的分支，返回被删除的消息
+    /// 活跃分支上的消息不能删除
+    pub fn prune_branch(&mut self, message_id: Uuid) -> Option<Vec<Message>> {
+        if !self.inactive.iter().any(|m| m.id == message_id) {
+            return None;
+        }
+        let mut ids = vec![message_id];
+        let mut i = 0;
+        while i < ids.len() {
+            let id = ids[i];
+            ids.extend(
+                self.inactive
+                    .iter()
+                    .filter(|m| m.parent == Some(id))
+                    .map(|m| m.id),
+            );
+            i += 1;
+        }
+        let (pruned, kept) = std::mem::take(&mut self.inactive)
+            .into_iter()
+            .partition(|m| ids.contains(&m.id));
+        self.inactive = kept;
+        Some(pruned)
+    }
+}
+
+/// 某个分叉上的一个分支
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BranchInfo {
+    pub id: Uuid,
+    pub owner: Role,
+    /// 分支第一条消息的前64个字符
+    pub preview: String,
+    pub active: bool,
+}
+
+impl BranchInfo {
+    fn new(message: &Message, active: bool) -> Self {
+        Self {
+            id: message.id,
+            owner: message.owner.clone(),
+            preview: message
+                .content
+                .iter()
+                .filter_map(|c| match c {
+                    MessageContent::Text(s) => Some(s.as_str()),
+                    _ => None,
+                })
+                .collect::<String>()
+                .chars()
+                .take(64)
+                .collect(),
+            active,
+        }
+    }
+}
+
+/// 由模型生成的旧对话摘要
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ContextSummary {
+    /// 摘要覆盖到的最后一条消息
+    pub until: Uuid,
+    pub content: String,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Message {
     #[serde(default = "Uuid::new_v4")]
@@ -74,6 +236,12 @@ pub struct Message {
     pub reasoning: Vec<MessageContent>,
     pub content: Vec<MessageContent>,
     pub tool_use: Vec<ToolUse>,
+    /// 生成被中止或出错时保存的部分输出
+    #[serde(default)]
+    pub interrupted: bool,
+    /// 上一条消息，为空时是根消息
+    #[serde(default)]
+    pub parent: Option<Uuid>,
 }
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
@@ -88,7 +256,7 @@ pub enum Role {
     Tools(Uuid),
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum MessageContent {
     Text(String),
     // uuid in database, additional description(e.g. label generated by llm)
@@ -140,4 +308,104 @@ pub struct ToolUse {
     pub use_id: Uuid,
     pub function_name: String,
     pub args: String,
+    /// 原生模式下服务端给出的tool_call id，回放历史时原样发送
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub call_id: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(owner: Role, text: &str) -> Message {
+        Message {
+            id: Uuid::new_v4(),
+            owner,
+            content: vec![MessageContent::Text(text.to_string())],
+            reasoning: vec![],
+            tool_use: vec![],
+            interrupted: false,
+            parent: None,
+        }
+    }
+
+    fn chat(texts: &[(Role, &str)]) -> ChatEntry {
+        let mut entry = ChatEntry::default();
+        entry.messages = texts.iter().map(|(o, t)| message(o.clone(), t)).collect();
+        entry.link_parents();
+        entry
+    }
+
+    fn active_texts(entry: &ChatEntry) -> Vec<String> {
+        entry
+            .messages
+            .iter()
+            .map(|m| m.content[0].to_string())
+            .collect()
+    }
+
+    /// 模拟重新生成：把`index`之后的消息移走，再接上新的回复
+    fn regenerate(entry: &mut ChatEntry, index: usize, text: &str) -> Uuid {
+        entry.deactivate_from(index);
+        let mut reply = message(Role::Assistant, text);
+        reply.parent = entry.messages.last().map(|m| m.id);
+        let id = reply.id;
+        entry.messages.push(reply);
+        id
+    }
+
+    #[test]
+    fn link_parents_fills_legacy_entries() {
+        let entry = chat(&[(Role::User, "q"), (Role::Assistant, "a")]);
+        assert_eq!(entry.messages[0].parent, None);
+        assert_eq!(entry.messages[1].parent, Some(entry.messages[0].id));
+    }
+
+    #[test]
+    fn regenerate_keeps_old_branch() {
+        let mut entry = chat(&[(Role::User, "q"), (Role::Assistant, "a1")]);
+        let first = entry.messages[1].id;
+        let second = regenerate(&mut entry, 1, "a2");
+
+        assert_eq!(active_texts(&entry), ["q", "a2"]);
+        let branches = entry.branches_at(second).unwrap();
+        assert_eq!(branches.len(), 2);
+        assert!(branches.iter().any(|b| b.id == first && !b.active));
+        assert!(branches.iter().any(|b| b.id == second && b.active));
+    }
+
+    #[test]
+    fn switch_branch_restores_descendants() {
+        let mut entry = chat(&[
+            (Role::User, "q1"),
+            (Role::Assistant, "a1"),
+            (Role::User, "q2"),
+            (Role::Assistant, "a2"),
+        ]);
+        let first = entry.messages[1].id;
+        let second = regenerate(&mut entry, 1, "b1");
+
+        assert!(entry.switch_branch(first));
+        assert_eq!(active_texts(&entry), ["q1", "a1", "q2", "a2"]);
+        assert!(entry.switch_branch(second));
+        assert_eq!(active_texts(&entry), ["q1", "b1"]);
+        assert!(!entry.switch_branch(Uuid::new_v4()));
+    }
+
+    #[test]
+    fn prune_only_removes_inactive_subtree() {
+        let mut entry = chat(&[
+            (Role::User, "q1"),
+            (Role::Assistant, "a1"),
+            (Role::User, "q2"),
+        ]);
+        let first = entry.messages[1].id;
+        let second = regenerate(&mut entry, 1, "b1");
+
+        assert!(entry.prune_branch(second).is_none());
+        let pruned = entry.prune_branch(first).unwrap();
+        assert_eq!(pruned.len(), 2);
+        assert!(entry.inactive.is_empty());
+        assert_eq!(active_texts(&entry), ["q1", "b1"]);
+    }
 }
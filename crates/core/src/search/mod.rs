@@ -0,0 +1,9 @@
+mod redb_search;
+mod search;
+mod sled_search;
+mod sqlite_search;
+
+pub use redb_search::*;
+pub use search::*;
+pub use sled_search::*;
+pub use sqlite_search::*;
@@ -0,0 +1,121 @@
+use std::sync::Arc;
+
+use redb::{Database, ReadableDatabase, ReadableTable, ReadableTableMetadata, TableDefinition};
+use uuid::Uuid;
+
+use crate::{
+    SearchIndex, SessionStoreError,
+    search::{decode_posting, forward_key, join_terms, posting_key, split_terms, term_prefix},
+};
+
+// 倒排表: Key = 词 + 0 + chat_id + message_id, Value = 词频
+type TablePosting<'a, 'b> = TableDefinition<'a, &'b [u8], u32>;
+// 正排表: Key = chat_id + message_id, Value = 以0分隔的词
+type TableForward<'a, 'b, 'c> = TableDefinition<'a, &'b [u8], &'c [u8]>;
+
+pub struct RedbSearchIndex {
+    db: Arc<Database>,
+    posting_name: String,
+    forward_name: String,
+}
+
+impl RedbSearchIndex {
+    pub fn new(db: Arc<Database>, table_name: &str) -> Result<Self, SessionStoreError> {
+        let posting_name = format!("{}_postings", table_name);
+        let forward_name = format!("{}_terms", table_name);
+        let write_txn = db.begin_write()?;
+        {
+            write_txn.open_table(TablePosting::new(&posting_name))?;
+            write_txn.open_table(TableForward::new(&forward_name))?;
+        }
+        write_txn.commit()?;
+        Ok(Self {
+            db,
+            posting_name,
+            forward_name,
+        })
+    }
+
+    /// 删除`message_ids`的索引后，写入`terms`
+    fn replace(
+        &self,
+        chat_id: Uuid,
+        message_ids: &[Uuid],
+        terms: &[(String, u32)],
+    ) -> Result<(), SessionStoreError> {
+        let write_txn = self.db.begin_write()?;
+        {
+            let mut tb_posting = write_txn.open_table(TablePosting::new(&self.posting_name))?;
+            let mut tb_forward = write_txn.open_table(TableForward::new(&self.forward_name))?;
+            for message_id in message_ids {
+                let fwd = forward_key(chat_id, *message_id);
+                let old = tb_forward
+                    .remove(fwd.as_slice())?
+                    .map(|v| split_terms(v.value()));
+                for term in old.unwrap_or_default() {
+                    tb_posting.remove(posting_key(&term, chat_id, *message_id).as_slice())?;
+                }
+            }
+            if let (Some(message_id), false) = (message_ids.first(), terms.is_empty()) {
+                for (term, tf) in terms {
+                    tb_posting.insert(posting_key(term, chat_id, *message_id).as_slice(), tf)?;
+                }
+                let fwd = forward_key(chat_id, *message_id);
+                tb_forward.insert(fwd.as_slice(), join_terms(terms).as_slice())?;
+            }
+        }
+        write_txn.commit()?;
+        Ok(())
+    }
+}
+
+impl SearchIndex for RedbSearchIndex {
+    fn index(
+        &self,
+        chat_id: Uuid,
+        message_id: Uuid,
+        terms: &[(String, u32)],
+    ) -> Result<(), SessionStoreError> {
+        self.replace(chat_id, &[message_id], terms)
+    }
+
+    fn remove_chat(&self, chat_id: Uuid) -> Result<(), SessionStoreError> {
+        let message_ids = {
+            let read_txn = self.db.begin_read()?;
+            let tb_forward = read_txn.open_table(TableForward::new(&self.forward_name))?;
+            let mut ids = vec![];
+            for item in tb_forward.range(chat_id.as_bytes().as_slice()..)? {
+                let (k, _) = item?;
+                match decode_posting(k.value()) {
+                    Some((c, m)) if c == chat_id => ids.push(m),
+                    _ => break,
+                }
+            }
+            ids
+        };
+        self.replace(chat_id, &message_ids, &[])
+    }
+
+    fn lookup(&self, term: &str) -> Result<Vec<(Uuid, Uuid, u32)>, SessionStoreError> {
+        let prefix = term_prefix(term);
+        let read_txn = self.db.begin_read()?;
+        let tb_posting = read_txn.open_table(TablePosting::new(&self.posting_name))?;
+        let mut result = vec![];
+        for item in tb_posting.range(prefix.as_slice()..)? {
+            let (k, v) = item?;
+            if !k.value().starts_with(&prefix) {
+                break;
+            }
+            if let Some((chat_id, message_id)) = decode_posting(k.value()) {
+                result.push((chat_id, message_id, v.value()));
+            }
+        }
+        Ok(result)
+    }
+
+    fn is_empty(&self) -> Result<bool, SessionStoreError> {
+        let read_txn = self.db.begin_read()?;
+        let tb_forward = read_txn.open_table(TableForward::new(&self.forward_name))?;
+        Ok(tb_forward.is_empty()?)
+    }
+}
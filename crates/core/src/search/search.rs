@@ -0,0 +1,393 @@
+use std::collections::{HashMap, HashSet};
+
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::{ChatMeta, Message, MessageContent, SessionStoreError};
+
+/// 超过这个长度的词(例如base64、hex)不进入索引
+const MAX_TERM_CHARS: usize = 64;
+/// 分词方式的版本，作为一个特殊的词写入索引，不一致时重建索引
+/// `tokenize`不会产生包含`#`的词
+pub const TOKENIZER_VERSION_TERM: &str = "#tokenizer-2";
+/// 命中位置前保留的字符数
+const SNIPPET_BEFORE: usize = 40;
+const SNIPPET_CHARS: usize = 200;
+
+/// 以消息为单位的倒排索引，与历史记录保存在同一个数据库中
+/// 倒排项的键为(词, chat_id, message_id)，值为词频
+pub trait SearchIndex: Send + Sync {
+    /// 写入一条消息的所有词，覆盖这条消息之前的索引
+    fn index(
+        &self,
+        chat_id: Uuid,
+        message_id: Uuid,
+        terms: &[(String, u32)],
+    ) -> Result<(), SessionStoreError>;
+    fn remove_message(&self, chat_id: Uuid, message_id: Uuid) -> Result<(), SessionStoreError> {
+        self.index(chat_id, message_id, &[])
+    }
+    fn remove_chat(&self, chat_id: Uuid) -> Result<(), SessionStoreError>;
+    /// 包含`term`的所有(chat_id, message_id, 词频)
+    fn lookup(&self, term: &str) -> Result<Vec<(Uuid, Uuid, u32)>, SessionStoreError>;
+    fn is_empty(&self) -> Result<bool, SessionStoreError>;
+}
+
+/// 倒排项的键: 词 + 0 + chat_id + message_id
+pub(crate) fn posting_key(term: &str, chat_id: Uuid, message_id: Uuid) -> Vec<u8> {
+    let mut key = term_prefix(term);
+    key.extend_from_slice(chat_id.as_bytes());
+    key.extend_from_slice(message_id.as_bytes());
+    key
+}
+
+pub(crate) fn term_prefix(term: &str) -> Vec<u8> {
+    let mut key = Vec::with_capacity(term.len() + 33);
+    key.extend_from_slice(term.as_bytes());
+    key.push(0);
+    key
+}
+
+pub(crate) fn decode_posting(key: &[u8]) -> Option<(Uuid, Uuid)> {
+    let ids = key.get(key.len().checked_sub(32)?..)?;
+    Some((
+        Uuid::from_slice(&ids[..16]).ok()?,
+        Uuid::from_slice(&ids[16..]).ok()?,
+    ))
+}
+
+/// 正排项的键: chat_id + message_id，值为这条消息的所有词，用于删除
+pub(crate) fn forward_key(chat_id: Uuid, message_id: Uuid) -> [u8; 32] {
+    let mut key = [0u8; 32];
+    key[..16].copy_from_slice(chat_id.as_bytes());
+    key[16..].copy_from_slice(message_id.as_bytes());
+    key
+}
+
+pub(crate) fn join_terms(terms: &[(String, u32)]) -> Vec<u8> {
+    terms
+        .iter()
+        .map(|(t, _)| t.as_str())
+        .collect::<Vec<_>>()
+        .join("\0")
+        .into_bytes()
+}
+
+pub(crate) fn split_terms(buf: &[u8]) -> Vec<String> {
+    buf.split(|b| *b == 0)
+        .filter(|t| !t.is_empty())
+        .map(|t| String::from_utf8_lossy(t).into_owned())
+        .collect()
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Token {
+    pub term: String,
+    /// 在原文中的字符区间
+    pub start: usize,
+    pub end: usize,
+}
+
+fn is_cjk(c: char) -> bool {
+    matches!(c,
+        '\u{3040}'..='\u{30ff}'
+        | '\u{3400}'..='\u{4dbf}'
+        | '\u{4e00}'..='\u{9fff}'
+        | '\u{f900}'..='\u{faff}'
+        | '\u{ac00}'..='\u{d7af}'
+        | '\u{20000}'..='\u{2ffff}')
+}
+
+fn fold(c: char) -> char {
+    c.to_lowercase().next().unwrap_or(c)
+}
+
+/// 字母数字按单词切分，中日韩文字没有分隔符，切分成单字和相邻的两个字
+pub fn tokenize(text: &str) -> Vec<Token> {
+    split_tokens(text, true)
+}
+
+/// 搜索词的切分，连续的中日韩文字只使用相邻的两个字，单独的一个字才按单字查找
+pub fn query_tokens(text: &str) -> Vec<Token> {
+    split_tokens(text, false)
+}
+
+fn split_tokens(text: &str, cjk_unigrams: bool) -> Vec<Token> {
+    let chars: Vec<char> = text.chars().map(fold).collect();
+    let mut tokens = vec![];
+    let mut push = |start: usize, end: usize| {
+        tokens.push(Token {
+            term: chars[start..end].iter().collect(),
+            start,
+            end,
+        })
+    };
+    let mut i = 0;
+    while i < chars.len() {
+        let start = i;
+        if is_cjk(chars[i]) {
+            while i < chars.len() && is_cjk(chars[i]) {
+                i += 1;
+            }
+            for j in start..i {
+                if cjk_unigrams || i - start == 1 {
+                    push(j, j + 1);
+                }
+                if j + 1 < i {
+                    push(j, j + 2);
+                }
+            }
+        } else if chars[i].is_alphanumeric() {
+            while i < chars.len() && chars[i].is_alphanumeric() && !is_cjk(chars[i]) {
+                i += 1;
+            }
+            if i - start <= MAX_TERM_CHARS {
+                push(start, i);
+            }
+        } else {
+            i += 1;
+        }
+    }
+    tokens
+}
+
+pub fn term_frequencies(tokens: &[Token]) -> Vec<(String, u32)> {
+    let mut tf: HashMap<&str, u32> = HashMap::new();
+    for t in tokens {
+        *tf.entry(t.term.as_str()).or_default() += 1;
+    }
+    tf.into_iter().map(|(t, n)| (t.to_string(), n)).collect()
+}
+
+/// 消息中参与搜索的文本: 正文、图片和附件的标签、思考过程以及工具调用的参数
+pub fn searchable_text(message: &Message) -> String {
+    let contents = message
+        .content
+        .iter()
+        .chain(message.reasoning.iter())
+        .map(|c| match c {
+            MessageContent::Text(s) => s.as_str(),
+            MessageContent::ImageRef(_, label)
+            | MessageContent::ImageBin(_, _, label)
+            | MessageContent::AssetRef(_, label) => label.as_str(),
+        })
+        .filter(|s| !s.is_empty())
+        .map(|s| s.to_string());
+    let tools = message
+        .tool_use
+        .iter()
+        .map(|t| format!("{} {}", t.function_name, t.args));
+    contents.chain(tools).collect::<Vec<_>>().join("\n")
+}
+
+/// 一条命中的消息
+#[derive(Clone, Debug, Serialize)]
+pub struct SearchHit {
+    pub message_id: Uuid,
+    pub score: f32,
+    /// 第一个命中位置附近的文本
+    pub snippet: String,
+    /// `snippet`在`searchable_text`中的起始字符位置
+    pub offset: usize,
+    /// `snippet`中命中的字符区间
+    pub positions: Vec<(usize, usize)>,
+}
+
+impl SearchHit {
+    pub fn new(message: &Message, score: f32, terms: &HashSet<String>) -> Self {
+        let text = searchable_text(message);
+        let mut spans: Vec<(usize, usize)> = vec![];
+        for t in tokenize(&text)
+            .into_iter()
+            .filter(|t| terms.contains(&t.term))
+        {
+            match spans.last_mut() {
+                // 中文按两个字切分，相邻的命中合并为一段
+                Some(last) if t.start <= last.1 => last.1 = last.1.max(t.end),
+                _ => spans.push((t.start, t.end)),
+            }
+        }
+        let offset = spans
+            .first()
+            .map(|s| s.0.saturating_sub(SNIPPET_BEFORE))
+            .unwrap_or(0);
+        let end = offset + SNIPPET_CHARS;
+        Self {
+            message_id: message.id,
+            score,
+            snippet: text.chars().skip(offset).take(SNIPPET_CHARS).collect(),
+            offset,
+            positions: spans
+                .into_iter()
+                .filter(|s| s.0 < end)
+                .map(|s| (s.0 - offset, s.1.min(end) - offset))
+                .collect(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SearchResult {
+    pub chat: ChatMeta,
+    pub score: f32,
+    pub hits: Vec<SearchHit>,
+}
+
+/// 按TF-IDF给会话排序，只保留包含所有词的会话
+/// 返回(chat_id, 分数, [(message_id, 分数)])，都按分数从高到低排列
+pub fn rank(postings: &[(String, Vec<(Uuid, Uuid, u32)>)]) -> Vec<(Uuid, f32, Vec<(Uuid, f32)>)> {
+    if postings.is_empty() {
+        return vec![];
+    }
+    let docs = postings
+        .iter()
+        .flat_map(|(_, p)| p.iter().map(|(c, m, _)| (*c, *m)))
+        .collect::<HashSet<_>>()
+        .len();
+    let mut chats: HashMap<Uuid, (HashSet<&str>, HashMap<Uuid, f32>)> = HashMap::new();
+    for (term, list) in postings {
+        let idf = (1.0 + docs as f32 / list.len().max(1) as f32).ln();
+        for (chat_id, message_id, tf) in list {
+            let (terms, messages) = chats.entry(*chat_id).or_default();
+            terms.insert(term.as_str());
+            *messages.entry(*message_id).or_default() += (1.0 + (*tf as f32).ln()) * idf;
+        }
+    }
+    let mut ranked: Vec<_> = chats
+        .into_iter()
+        .filter(|(_, (terms, _))| terms.len() == postings.len())
+        .map(|(chat_id, (_, messages))| {
+            let mut messages: Vec<_> = messages.into_iter().collect();
+            messages.sort_by(|a, b| b.1.total_cmp(&a.1));
+            (chat_id, messages.iter().map(|m| m.1).sum(), messages)
+        })
+        .collect();
+    ranked.sort_by(|a, b| b.1.total_cmp(&a.1));
+    ranked
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{RedbSearchIndex, SledSearchIndex, SqliteSearchIndex};
+    use redb::{Database, backends::InMemoryBackend};
+    use std::sync::{Arc, Mutex};
+
+    fn terms(text: &str) -> Vec<String> {
+        tokenize(text).into_iter().map(|t| t.term).collect()
+    }
+
+    fn query(text: &str) -> Vec<String> {
+        query_tokens(text).into_iter().map(|t| t.term).collect()
+    }
+
+    #[test]
+    fn tokenize_words_and_cjk() {
+        assert_eq!(terms("Zoom_In the Image!"), ["zoom", "in", "the", "image"]);
+        assert_eq!(
+            terms("左上角 cat"),
+            ["左", "左上", "上", "上角", "角", "cat"]
+        );
+        assert_eq!(terms("猫"), ["猫"]);
+        assert!(terms(&"a".repeat(MAX_TERM_CHARS + 1)).is_empty());
+        assert!(terms("#tokenizer-2").iter().all(|t| !t.contains('#')));
+    }
+
+    #[test]
+    fn query_uses_bigrams_for_cjk_runs() {
+        assert_eq!(query("左上角 cat"), ["左上", "上角", "cat"]);
+        assert_eq!(query("猫"), ["猫"]);
+    }
+
+    #[test]
+    fn single_cjk_char_matches_inside_words() {
+        let text = "一只小猫咪";
+        let query: HashSet<String> = query("猫").into_iter().collect();
+        assert!(terms(text).iter().any(|t| query.contains(t)));
+
+        let message = Message {
+            id: Uuid::new_v4(),
+            owner: crate::Role::User,
+            reasoning: vec![],
+            content: vec![MessageContent::Text(text.into())],
+            tool_use: vec![],
+            interrupted: false,
+            parent: None,
+        };
+        let hit = SearchHit::new(&message, 1.0, &query);
+        assert_eq!(hit.positions, [(3, 4)]);
+    }
+
+    #[test]
+    fn token_positions_are_chars() {
+        let tokens = tokenize("放大 zoom");
+        assert_eq!((tokens[0].start, tokens[0].end), (0, 1));
+        assert_eq!((tokens[1].start, tokens[1].end), (0, 2));
+        assert_eq!((tokens[3].start, tokens[3].end), (3, 7));
+    }
+
+    #[test]
+    fn posting_key_roundtrip() {
+        let (c, m) = (Uuid::new_v4(), Uuid::new_v4());
+        assert_eq!(decode_posting(&posting_key("猫", c, m)), Some((c, m)));
+        assert_eq!(
+            split_terms(&join_terms(&[("a".into(), 1), ("猫".into(), 2)])),
+            ["a", "猫"]
+        );
+    }
+
+    #[test]
+    fn rank_requires_all_terms() {
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+        let (m1, m2, m3) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let postings = vec![
+            ("cat".to_string(), vec![(a, m1, 3), (b, m3, 1)]),
+            ("zoom".to_string(), vec![(a, m2, 1)]),
+        ];
+        let ranked = rank(&postings);
+        assert_eq!(ranked.len(), 1);
+        assert_eq!(ranked[0].0, a);
+        assert_eq!(ranked[0].2[0].0, m1);
+    }
+
+    fn check_index(index: &dyn SearchIndex) {
+        assert!(index.is_empty().unwrap());
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+        let (m1, m2) = (Uuid::new_v4(), Uuid::new_v4());
+        index.index(a, m1, &[("cat".into(), 2), ("猫".into(), 1)]).unwrap();
+        index.index(b, m2, &[("cat".into(), 1)]).unwrap();
+        assert!(!index.is_empty().unwrap());
+        let mut cats = index.lookup("cat").unwrap();
+        cats.sort_by_key(|p| p.2);
+        assert_eq!(cats, [(b, m2, 1), (a, m1, 2)]);
+
+        index.index(a, m1, &[("dog".into(), 1)]).unwrap();
+        assert!(index.lookup("猫").unwrap().is_empty());
+        assert_eq!(index.lookup("dog").unwrap(), [(a, m1, 1)]);
+        index.remove_message(a, m1).unwrap();
+        assert!(index.lookup("dog").unwrap().is_empty());
+        index.remove_chat(b).unwrap();
+        assert!(index.lookup("cat").unwrap().is_empty());
+        assert!(index.is_empty().unwrap());
+    }
+
+    #[test]
+    fn sled_index() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        check_index(&SledSearchIndex::new_from_db(&db, "t").unwrap());
+    }
+
+    #[test]
+    fn redb_index() {
+        let db = Database::builder()
+            .create_with_backend(InMemoryBackend::new())
+            .unwrap();
+        check_index(&RedbSearchIndex::new(Arc::new(db), "t").unwrap());
+    }
+
+    #[test]
+    fn sqlite_index() {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        check_index(&SqliteSearchIndex::new(Arc::new(Mutex::new(conn)), "t").unwrap());
+    }
+}
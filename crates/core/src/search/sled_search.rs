@@ -0,0 +1,83 @@
+use sled::{
+    Transactional,
+    transaction::{ConflictableTransactionError, TransactionError},
+};
+use uuid::Uuid;
+
+use crate::{
+    SearchIndex, SessionStoreError,
+    search::{decode_posting, forward_key, join_terms, posting_key, split_terms, term_prefix},
+};
+
+pub struct SledSearchIndex {
+    posting_tree: sled::Tree,
+    forward_tree: sled::Tree,
+}
+
+impl SledSearchIndex {
+    pub fn new_from_db(db: &sled::Db, name: &str) -> Result<Self, SessionStoreError> {
+        Ok(Self {
+            posting_tree: db.open_tree(format!("{}_postings", name))?,
+            forward_tree: db.open_tree(format!("{}_terms", name))?,
+        })
+    }
+}
+
+impl SearchIndex for SledSearchIndex {
+    fn index(
+        &self,
+        chat_id: Uuid,
+        message_id: Uuid,
+        terms: &[(String, u32)],
+    ) -> Result<(), SessionStoreError> {
+        let fwd = forward_key(chat_id, message_id);
+        (&self.posting_tree, &self.forward_tree)
+            .transaction(|(t_posting, t_forward)| {
+                if let Some(old) = t_forward.remove(&fwd[..])? {
+                    for term in split_terms(&old) {
+                        t_posting.remove(posting_key(&term, chat_id, message_id))?;
+                    }
+                }
+                if terms.is_empty() {
+                    return Ok(());
+                }
+                for (term, tf) in terms {
+                    t_posting.insert(
+                        posting_key(term, chat_id, message_id),
+                        &tf.to_be_bytes()[..],
+                    )?;
+                }
+                t_forward.insert(&fwd[..], join_terms(terms))?;
+                Ok::<(), ConflictableTransactionError<SessionStoreError>>(())
+            })
+            .map_err(|e: TransactionError<SessionStoreError>| {
+                SessionStoreError::SledTransactionError(e.to_string())
+            })
+    }
+
+    fn remove_chat(&self, chat_id: Uuid) -> Result<(), SessionStoreError> {
+        for item in self.forward_tree.scan_prefix(chat_id.as_bytes()).keys() {
+            let key = item?;
+            if let Some((_, message_id)) = decode_posting(&key) {
+                self.remove_message(chat_id, message_id)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn lookup(&self, term: &str) -> Result<Vec<(Uuid, Uuid, u32)>, SessionStoreError> {
+        let mut result = vec![];
+        for item in self.posting_tree.scan_prefix(term_prefix(term)) {
+            let (k, v) = item?;
+            let tf = v.as_ref().try_into().map(u32::from_be_bytes).unwrap_or(1);
+            if let Some((chat_id, message_id)) = decode_posting(&k) {
+                result.push((chat_id, message_id, tf));
+            }
+        }
+        Ok(result)
+    }
+
+    fn is_empty(&self) -> Result<bool, SessionStoreError> {
+        Ok(self.forward_tree.is_empty())
+    }
+}
@@ -0,0 +1,101 @@
+use std::sync::{Arc, Mutex};
+
+use rusqlite::{Connection, params};
+use uuid::Uuid;
+
+use crate::{SearchIndex, SessionStoreError};
+
+/// 倒排项保存为(词, chat_id, message_id, 词频)一行，按消息删除时走第二个索引，不需要正排表
+pub struct SqliteSearchIndex {
+    conn: Arc<Mutex<Connection>>,
+    table: String,
+}
+
+impl SqliteSearchIndex {
+    pub fn new(conn: Arc<Mutex<Connection>>, name: &str) -> Result<Self, SessionStoreError> {
+        let table = format!("{}_postings", name);
+        conn.lock().unwrap().execute_batch(&format!(
+            "CREATE TABLE IF NOT EXISTS \"{table}\" (
+                term TEXT NOT NULL,
+                chat_id BLOB NOT NULL,
+                message_id BLOB NOT NULL,
+                tf INTEGER NOT NULL,
+                PRIMARY KEY (term, chat_id, message_id)
+            ) WITHOUT ROWID;
+            CREATE INDEX IF NOT EXISTS \"{table}_message\" ON \"{table}\" (chat_id, message_id);"
+        ))?;
+        Ok(Self { conn, table })
+    }
+}
+
+impl SearchIndex for SqliteSearchIndex {
+    fn index(
+        &self,
+        chat_id: Uuid,
+        message_id: Uuid,
+        terms: &[(String, u32)],
+    ) -> Result<(), SessionStoreError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        tx.execute(
+            &format!(
+                "DELETE FROM \"{}\" WHERE chat_id = ?1 AND message_id = ?2",
+                self.table
+            ),
+            params![&chat_id.as_bytes()[..], &message_id.as_bytes()[..]],
+        )?;
+        {
+            let mut stmt = tx.prepare(&format!(
+                "INSERT INTO \"{}\" (term, chat_id, message_id, tf) VALUES (?1, ?2, ?3, ?4)",
+                self.table
+            ))?;
+            for (term, tf) in terms {
+                stmt.execute(params![
+                    term,
+                    &chat_id.as_bytes()[..],
+                    &message_id.as_bytes()[..],
+                    tf
+                ])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn remove_chat(&self, chat_id: Uuid) -> Result<(), SessionStoreError> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            &format!("DELETE FROM \"{}\" WHERE chat_id = ?1", self.table),
+            [&chat_id.as_bytes()[..]],
+        )?;
+        Ok(())
+    }
+
+    fn lookup(&self, term: &str) -> Result<Vec<(Uuid, Uuid, u32)>, SessionStoreError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT chat_id, message_id, tf FROM \"{}\" WHERE term = ?1",
+            self.table
+        ))?;
+        let result = stmt
+            .query_map([term], |row| {
+                Ok((
+                    Uuid::from_bytes(row.get(0)?),
+                    Uuid::from_bytes(row.get(1)?),
+                    row.get(2)?,
+                ))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(result)
+    }
+
+    fn is_empty(&self) -> Result<bool, SessionStoreError> {
+        let conn = self.conn.lock().unwrap();
+        let exists: bool = conn.query_row(
+            &format!("SELECT EXISTS (SELECT 1 FROM \"{}\")", self.table),
+            [],
+            |row| row.get(0),
+        )?;
+        Ok(!exists)
+    }
+}
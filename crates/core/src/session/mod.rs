@@ -1,7 +1,9 @@
 mod session;
 mod redb_session;
 mod sled_session;
+mod sqlite_session;
 
 pub use session::*;
 pub use redb_session::*;
 pub use sled_session::*;
+pub use sqlite_session::*;
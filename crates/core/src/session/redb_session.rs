@@ -1,38 +1,77 @@
-use redb::{Database, ReadableDatabase, ReadableTable, TableDefinition};
+use redb::{Database, ReadableDatabase, ReadableTable, Table, TableDefinition};
 use std::sync::Arc;
 use uuid::Uuid;
 
-use crate::SessionStoreError;
+use crate::{LegacySplitter, PushedMessage, SessionStoreError};
 
 type TableMeta<'a, 'b, 'c> = TableDefinition<'a, &'b [u8; 16], &'c [u8]>;
 type TableData<'a, 'b, 'c> = TableDefinition<'a, &'b [u8; 16], &'c [u8]>;
+/// 键为(chat_id, seq)
+type TableMessage<'a, 'b> = TableDefinition<'a, (u128, u64), &'b [u8]>;
 
 pub struct RedbSessionStore {
+    legacy_table_name: String,
     data_table_name: String,
     meta_table_name: String,
+    message_table_name: String,
     db: Arc<Database>,
 }
 
 impl RedbSessionStore {
     pub fn new(db: Arc<Database>, table_name: &str) -> Result<Self, SessionStoreError> {
         let write_txn = db.begin_write()?;
-        let data_table_name = format!("{}_data", table_name);
+        let legacy_table_name = format!("{}_data", table_name);
+        let data_table_name = format!("{}_head", table_name);
         let meta_table_name = format!("{}_meta", table_name);
+        let message_table_name = format!("{}_messages", table_name);
         {
             let tb_meta = TableMeta::new(&meta_table_name);
             let tb_data = TableData::new(&data_table_name);
+            let tb_message = TableMessage::new(&message_table_name);
             write_txn.open_table(tb_meta)?;
             write_txn.open_table(tb_data)?;
+            write_txn.open_table(tb_message)?;
         }
         write_txn.commit()?;
         Ok(Self {
+            legacy_table_name,
             data_table_name,
             meta_table_name,
+            message_table_name,
             db,
         })
     }
 }
 
+/// 会话的全部消息，按seq排列
+fn read_messages(
+    tb_message: &impl ReadableTable<(u128, u64), &'static [u8]>,
+    id: Uuid,
+) -> Result<Vec<(u64, Vec<u8>)>, SessionStoreError> {
+    let id = id.as_u128();
+    let mut messages = Vec::new();
+    for item in tb_message.range((id, 0)..=(id, u64::MAX))? {
+        let (k, v) = item?;
+        messages.push((k.value().1, v.value().to_vec()));
+    }
+    Ok(messages)
+}
+
+/// 用`messages`替换会话原有的全部消息
+fn replace_messages(
+    tb_message: &mut Table<'_, (u128, u64), &'static [u8]>,
+    id: Uuid,
+    messages: &[Vec<u8>],
+) -> Result<(), SessionStoreError> {
+    for (seq, _) in read_messages(&*tb_message, id)? {
+        tb_message.remove((id.as_u128(), seq))?;
+    }
+    for (seq, message) in messages.iter().enumerate() {
+        tb_message.insert((id.as_u128(), seq as u64), message.as_slice())?;
+    }
+    Ok(())
+}
+
 impl super::SessionStorage for RedbSessionStore {
     fn append(&self, meta: &[u8], data: &[u8]) -> Result<Uuid, SessionStoreError> {
         for _ in 0..10 {
@@ -58,17 +97,26 @@ impl super::SessionStorage for RedbSessionStore {
         Err(SessionStoreError::UuidCollision)
     }
 
-    fn update(&self, id: Uuid, meta: &[u8], data: &[u8]) -> Result<(), SessionStoreError> {
+    fn update(
+        &self,
+        id: Uuid,
+        meta: &[u8],
+        data: &[u8],
+        messages: &[Vec<u8>],
+    ) -> Result<(), SessionStoreError> {
         let key = id.as_bytes();
         let write_txn = self.db.begin_write()?;
         {
             let tb_meta = TableMeta::new(&self.meta_table_name);
             let tb_data = TableData::new(&self.data_table_name);
+            let tb_message = TableMessage::new(&self.message_table_name);
             let mut tb_meta = write_txn.open_table(tb_meta)?;
             let mut tb_data = write_txn.open_table(tb_data)?;
+            let mut tb_message = write_txn.open_table(tb_message)?;
 
             tb_meta.insert(key, meta)?;
             tb_data.insert(key, data)?;
+            replace_messages(&mut tb_message, id, messages)?;
         }
         write_txn.commit()?;
         Ok(())
@@ -82,12 +130,20 @@ impl super::SessionStorage for RedbSessionStore {
         Ok(result.map(|v| v.value().to_vec()))
     }
 
-    fn get_data(&self, id: Uuid) -> Result<Option<Vec<u8>>, SessionStoreError> {
+    fn get_data(&self, id: Uuid) -> Result<Option<(Vec<u8>, Vec<Vec<u8>>)>, SessionStoreError> {
         let read_txn = self.db.begin_read()?;
         let tb_data = TableData::new(&self.data_table_name);
         let tb_data = read_txn.open_table(tb_data)?;
-        let result = tb_data.get(id.as_bytes())?;
-        Ok(result.map(|v| v.value().to_vec()))
+        let Some(data) = tb_data.get(id.as_bytes())? else {
+            return Ok(None);
+        };
+        let tb_message = TableMessage::new(&self.message_table_name);
+        let tb_message = read_txn.open_table(tb_message)?;
+        let messages = read_messages(&tb_message, id)?
+            .into_iter()
+            .map(|(_, v)| v)
+            .collect();
+        Ok(Some((data.value().to_vec(), messages)))
     }
 
     fn list(
@@ -116,17 +172,24 @@ impl super::SessionStorage for RedbSessionStore {
         Ok(result)
     }
 
-    fn delete(&self, id: Uuid) -> Result<Option<Vec<u8>>, SessionStoreError> {
+    fn delete(&self, id: Uuid) -> Result<Option<(Vec<u8>, Vec<Vec<u8>>)>, SessionStoreError> {
         let key = id.as_bytes();
         let write_txn = self.db.begin_write()?;
         let buf = {
             let tb_meta = TableMeta::new(&self.meta_table_name);
             let tb_data = TableData::new(&self.data_table_name);
+            let tb_message = TableMessage::new(&self.message_table_name);
             let mut tb_meta = write_txn.open_table(tb_meta)?;
             let mut tb_data = write_txn.open_table(tb_data)?;
+            let mut tb_message = write_txn.open_table(tb_message)?;
 
             tb_meta.remove(key)?;
-            tb_data.remove(key)?.map(|v| v.value().to_vec())
+            let mut messages = Vec::new();
+            for (seq, message) in read_messages(&tb_message, id)? {
+                tb_message.remove((id.as_u128(), seq))?;
+                messages.push(message);
+            }
+            tb_data.remove(key)?.map(|v| (v.value().to_vec(), messages))
         };
         write_txn.commit()?;
         Ok(buf)
@@ -161,4 +224,96 @@ impl super::SessionStorage for RedbSessionStore {
         write_txn.commit()?;
         Ok((new_meta_bytes, new_data_bytes))
     }
+
+    fn push_message(
+        &self,
+        id: Uuid,
+        f: Box<
+            dyn Fn(Option<Vec<u8>>, Option<Vec<u8>>) -> Result<PushedMessage, anyhow::Error> + Send,
+        >,
+    ) -> Result<Vec<u8>, SessionStoreError> {
+        let key = id.as_bytes();
+        let write_txn = self.db.begin_write()?;
+        let message = {
+            let tb_meta = TableMeta::new(&self.meta_table_name);
+            let tb_data = TableData::new(&self.data_table_name);
+            let tb_message = TableMessage::new(&self.message_table_name);
+            let mut tb_meta = write_txn.open_table(tb_meta)?;
+            let mut tb_data = write_txn.open_table(tb_data)?;
+            let mut tb_message = write_txn.open_table(tb_message)?;
+
+            let old_meta = tb_meta.get(key)?.map(|v| v.value().to_vec());
+            let last = tb_message
+                .range((id.as_u128(), 0)..=(id.as_u128(), u64::MAX))?
+                .next_back()
+                .transpose()?
+                .map(|(k, v)| (k.value().1, v.value().to_vec()));
+            let (last_seq, last) = match last {
+                Some((seq, v)) => (Some(seq), Some(v)),
+                None => (None, None),
+            };
+
+            let pushed = f(old_meta, last)?;
+
+            tb_meta.insert(key, pushed.meta.as_slice())?;
+            if let Some(data) = pushed.data {
+                tb_data.insert(key, data.as_slice())?;
+            }
+            let seq = match last_seq {
+                Some(seq) if pushed.replace_last => seq,
+                Some(seq) => seq + 1,
+                None => 0,
+            };
+            tb_message.insert((id.as_u128(), seq), pushed.message.as_slice())?;
+            pushed.message
+        };
+        write_txn.commit()?;
+        Ok(message)
+    }
+
+    fn migrate(&self, split: &LegacySplitter) -> Result<usize, SessionStoreError> {
+        let tb_legacy = TableData::new(&self.legacy_table_name);
+        // 写事务中打开不存在的表会新建它，先用读事务确认旧表存在
+        match self.db.begin_read()?.open_table(tb_legacy) {
+            Ok(_) => {}
+            Err(redb::TableError::TableDoesNotExist(_)) => return Ok(0),
+            Err(e) => return Err(e.into()),
+        }
+        let write_txn = self.db.begin_write()?;
+        let (migrated, skipped) = {
+            let tb_data = TableData::new(&self.data_table_name);
+            let tb_message = TableMessage::new(&self.message_table_name);
+            let mut tb_legacy = write_txn.open_table(tb_legacy)?;
+            let mut tb_data = write_txn.open_table(tb_data)?;
+            let mut tb_message = write_txn.open_table(tb_message)?;
+
+            let mut migrated = vec![];
+            let mut skipped = 0;
+            for item in tb_legacy.iter()? {
+                let (k, v) = item?;
+                let id = Uuid::from_bytes(*k.value());
+                // 无法解析的会话留在旧表中，不影响其它会话
+                let (data, messages) = match split(v.value()) {
+                    Ok(split) => split,
+                    Err(e) => {
+                        tracing::warn!("Failed to migrate chat {}, keep it as is: {}", id, e);
+                        skipped += 1;
+                        continue;
+                    }
+                };
+                tb_data.insert(id.as_bytes(), data.as_slice())?;
+                replace_messages(&mut tb_message, id, &messages)?;
+                migrated.push(id);
+            }
+            for id in migrated.iter() {
+                tb_legacy.remove(id.as_bytes())?;
+            }
+            (migrated.len(), skipped)
+        };
+        if skipped == 0 {
+            write_txn.delete_table(tb_legacy)?;
+        }
+        write_txn.commit()?;
+        Ok(migrated)
+    }
 }
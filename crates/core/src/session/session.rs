@@ -18,24 +18,202 @@ pub enum SessionStoreError {
     SledError(#[from] sled::Error),
     #[error("Sled transaction error: {0}")]
     SledTransactionError(String),
+    #[error("SQLite error: {0}")]
+    SqliteError(#[from] rusqlite::Error),
     #[error("Cas Inner function error: {0}")]
     CASInnerError(#[from] anyhow::Error),
 }
 
+/// `push_message`回调的返回值
+pub struct PushedMessage {
+    pub meta: Vec<u8>,
+    /// 会话不存在时一起写入的data
+    pub data: Option<Vec<u8>>,
+    pub message: Vec<u8>,
+    /// 覆盖最后一条消息而不是追加
+    pub replace_last: bool,
+}
+
+/// 把旧版本整条保存的data拆分为(不含消息的data, 按顺序的消息)
+pub type LegacySplitter = dyn Fn(&[u8]) -> Result<(Vec<u8>, Vec<Vec<u8>>), anyhow::Error>;
+
+/// 每个会话保存为一条meta、一条不含消息的data，以及按`(chat_id, seq)`保存的消息
 pub trait SessionStorage: Send + Sync {
     fn append(&self, meta: &[u8], data: &[u8]) -> Result<Uuid, SessionStoreError>;
-    fn update(&self, id: Uuid, meta: &[u8], data: &[u8]) -> Result<(), SessionStoreError>;
+    /// 覆盖meta、data以及全部消息
+    fn update(
+        &self,
+        id: Uuid,
+        meta: &[u8],
+        data: &[u8],
+        messages: &[Vec<u8>],
+    ) -> Result<(), SessionStoreError>;
     fn get_meta(&self, id: Uuid) -> Result<Option<Vec<u8>>, SessionStoreError>;
-    fn get_data(&self, id: Uuid) -> Result<Option<Vec<u8>>, SessionStoreError>;
+    /// 返回data和按顺序排列的消息
+    fn get_data(&self, id: Uuid) -> Result<Option<(Vec<u8>, Vec<Vec<u8>>)>, SessionStoreError>;
     fn list(
         &self,
         limit: Option<usize>,
         offset: Option<usize>,
     ) -> Result<Vec<(Uuid, Vec<u8>)>, SessionStoreError>;
-    fn delete(&self, id: Uuid) -> Result<Option<Vec<u8>>, SessionStoreError>;
+    fn delete(&self, id: Uuid) -> Result<Option<(Vec<u8>, Vec<Vec<u8>>)>, SessionStoreError>;
+    /// 只修改meta和data，不读取消息
     fn update_data_with(
         &self,
         id: Uuid,
         f: Box<dyn Fn(Option<Vec<u8>>, Option<Vec<u8>>) -> Result<(Vec<u8>, Vec<u8>), anyhow::Error> + Send>
     ) -> Result<(Vec<u8>, Vec<u8>), SessionStoreError>;
+    /// 追加一条消息，回调的参数为(meta, 最后一条消息)
+    /// 只读写这两条记录，开销与会话长度无关
+    fn push_message(
+        &self,
+        id: Uuid,
+        f: Box<dyn Fn(Option<Vec<u8>>, Option<Vec<u8>>) -> Result<PushedMessage, anyhow::Error> + Send>
+    ) -> Result<Vec<u8>, SessionStoreError>;
+    /// 把旧版本的会话迁移到新的布局，返回迁移的会话数
+    fn migrate(&self, split: &LegacySplitter) -> Result<usize, SessionStoreError>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{RedbSessionStore, SledSessionStore, SqliteSessionStore};
+    use redb::{Database, TableDefinition, backends::InMemoryBackend};
+    use std::sync::{Arc, Mutex};
+
+    fn push(store: &dyn SessionStorage, id: Uuid, message: &str, replace_last: bool) -> Vec<u8> {
+        let message = message.as_bytes().to_vec();
+        store
+            .push_message(
+                id,
+                Box::new(move |meta, _| {
+                    Ok(PushedMessage {
+                        meta: meta.unwrap_or_default(),
+                        data: None,
+                        message: message.clone(),
+                        replace_last,
+                    })
+                }),
+            )
+            .unwrap()
+    }
+
+    fn messages(store: &dyn SessionStorage, id: Uuid) -> Vec<Vec<u8>> {
+        store.get_data(id).unwrap().unwrap().1
+    }
+
+    fn check_layout(store: &dyn SessionStorage) {
+        let id = store.append(b"meta", b"data").unwrap();
+        let other = store.append(b"meta", b"data").unwrap();
+        push(store, id, "a", false);
+        push(store, other, "x", false);
+        push(store, id, "b", false);
+        push(store, id, "c", true);
+        assert_eq!(messages(store, id), [b"a".to_vec(), b"c".to_vec()]);
+        assert_eq!(messages(store, other), [b"x".to_vec()]);
+
+        store
+            .update(id, b"meta", b"data2", &[b"d".to_vec()])
+            .unwrap();
+        push(store, id, "e", false);
+        let (data, msgs) = store.get_data(id).unwrap().unwrap();
+        assert_eq!(data, b"data2");
+        assert_eq!(msgs, [b"d".to_vec(), b"e".to_vec()]);
+
+        let (_, deleted) = store.delete(id).unwrap().unwrap();
+        assert_eq!(deleted.len(), 2);
+        assert!(store.get_data(id).unwrap().is_none());
+        assert_eq!(messages(store, other), [b"x".to_vec()]);
+    }
+
+    fn split(buf: &[u8]) -> Result<(Vec<u8>, Vec<Vec<u8>>), anyhow::Error> {
+        if buf.starts_with(b"bad") {
+            anyhow::bail!("broken chat");
+        }
+        let mut parts = buf.split(|b| *b == b'|').map(|p| p.to_vec());
+        let data = parts.next().unwrap_or_default();
+        Ok((data, parts.collect()))
+    }
+
+    #[test]
+    fn sled_layout() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        check_layout(&SledSessionStore::new_from_db(&db, "t").unwrap());
+    }
+
+    #[test]
+    fn redb_layout() {
+        let db = Database::builder()
+            .create_with_backend(InMemoryBackend::new())
+            .unwrap();
+        check_layout(&RedbSessionStore::new(Arc::new(db), "t").unwrap());
+    }
+
+    #[test]
+    fn sqlite_layout() {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        check_layout(&SqliteSessionStore::new(Arc::new(Mutex::new(conn)), "t").unwrap());
+    }
+
+    #[test]
+    fn sled_migrate() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let (id, bad) = (Uuid::now_v7(), Uuid::now_v7());
+        let legacy = db.open_tree("t_data").unwrap();
+        legacy
+            .insert(id.as_bytes(), b"data|a|b".as_slice())
+            .unwrap();
+        legacy.insert(bad.as_bytes(), b"bad".as_slice()).unwrap();
+        let store = SledSessionStore::new_from_db(&db, "t").unwrap();
+        // 无法解析的会话留在旧表中，其它会话照常迁移
+        assert_eq!(store.migrate(&split).unwrap(), 1);
+        assert_eq!(store.migrate(&split).unwrap(), 0);
+        assert!(store.get_data(bad).unwrap().is_none());
+        assert_eq!(legacy.len(), 1);
+        legacy.clear().unwrap();
+        assert_eq!(store.migrate(&split).unwrap(), 0);
+        assert!(!db.tree_names().iter().any(|n| n.as_ref() == b"t_data"));
+        push(&store, id, "c", false);
+        let (data, msgs) = store.get_data(id).unwrap().unwrap();
+        assert_eq!(data, b"data");
+        assert_eq!(msgs, [b"a".to_vec(), b"b".to_vec(), b"c".to_vec()]);
+    }
+
+    #[test]
+    fn redb_migrate() {
+        let db = Database::builder()
+            .create_with_backend(InMemoryBackend::new())
+            .unwrap();
+        let db = Arc::new(db);
+        let legacy = TableDefinition::<&[u8; 16], &[u8]>::new("t_data");
+        let legacy_exists = || match db.begin_read().unwrap().open_table(legacy) {
+            Ok(_) => true,
+            Err(redb::TableError::TableDoesNotExist(_)) => false,
+            Err(e) => panic!("{}", e),
+        };
+        let store = RedbSessionStore::new(db.clone(), "t").unwrap();
+        // 没有旧表时不会新建
+        assert_eq!(store.migrate(&split).unwrap(), 0);
+        assert!(!legacy_exists());
+
+        let (id, bad) = (Uuid::now_v7(), Uuid::now_v7());
+        let write_txn = db.begin_write().unwrap();
+        {
+            let mut legacy = write_txn.open_table(legacy).unwrap();
+            legacy
+                .insert(id.as_bytes(), b"data|a|b".as_slice())
+                .unwrap();
+            legacy.insert(bad.as_bytes(), b"bad".as_slice()).unwrap();
+        }
+        write_txn.commit().unwrap();
+        // 无法解析的会话留在旧表中，其它会话照常迁移
+        assert_eq!(store.migrate(&split).unwrap(), 1);
+        assert_eq!(store.migrate(&split).unwrap(), 0);
+        assert!(store.get_data(bad).unwrap().is_none());
+        assert!(legacy_exists());
+        push(&store, id, "c", false);
+        let (data, msgs) = store.get_data(id).unwrap().unwrap();
+        assert_eq!(data, b"data");
+        assert_eq!(msgs, [b"a".to_vec(), b"b".to_vec(), b"c".to_vec()]);
+    }
 }
@@ -1,24 +1,69 @@
-use crate::session::{SessionStorage, SessionStoreError};
+use crate::session::{LegacySplitter, PushedMessage, SessionStorage, SessionStoreError};
 use sled::{
     Transactional,
-    transaction::{ConflictableTransactionError, TransactionError},
+    transaction::{ConflictableTransactionError, TransactionError, TransactionalTree},
 };
 use uuid::Uuid;
 
 pub struct SledSessionStore {
+    db: sled::Db,
+    legacy_name: String,
     meta_tree: sled::Tree,
     data_tree: sled::Tree,
+    message_tree: sled::Tree,
+    /// 每个会话的消息数，sled的事务中不能遍历，只能通过它找到最后一条消息
+    seq_tree: sled::Tree,
 }
 
 impl SledSessionStore {
     pub fn new_from_db(db: &sled::Db, name: &str) -> Result<Self, SessionStoreError> {
         Ok(Self {
+            db: db.clone(),
+            legacy_name: format!("{}_data", name),
             meta_tree: db.open_tree(format!("{}_meta", name))?,
-            data_tree: db.open_tree(format!("{}_data", name))?,
+            data_tree: db.open_tree(format!("{}_head", name))?,
+            message_tree: db.open_tree(format!("{}_messages", name))?,
+            seq_tree: db.open_tree(format!("{}_seq", name))?,
         })
     }
 }
 
+fn message_key(id: Uuid, seq: u64) -> [u8; 24] {
+    let mut key = [0u8; 24];
+    key[..16].copy_from_slice(id.as_bytes());
+    key[16..].copy_from_slice(&seq.to_be_bytes());
+    key
+}
+
+fn get_count(
+    t_seq: &TransactionalTree,
+    id: Uuid,
+) -> Result<u64, ConflictableTransactionError<SessionStoreError>> {
+    Ok(t_seq
+        .get(id.as_bytes())?
+        .and_then(|v| v.as_ref().try_into().ok())
+        .map(u64::from_be_bytes)
+        .unwrap_or(0))
+}
+
+/// 用`messages`替换会话原有的全部消息
+fn replace_messages(
+    t_message: &TransactionalTree,
+    t_seq: &TransactionalTree,
+    id: Uuid,
+    messages: &[Vec<u8>],
+) -> Result<(), ConflictableTransactionError<SessionStoreError>> {
+    let old_count = get_count(t_seq, id)?;
+    for seq in messages.len() as u64..old_count {
+        t_message.remove(&message_key(id, seq))?;
+    }
+    for (seq, message) in messages.iter().enumerate() {
+        t_message.insert(&message_key(id, seq as u64)[..], message.as_slice())?;
+    }
+    t_seq.insert(id.as_bytes(), &(messages.len() as u64).to_be_bytes()[..])?;
+    Ok(())
+}
+
 impl SessionStorage for SledSessionStore {
     fn append(&self, meta: &[u8], data: &[u8]) -> Result<Uuid, SessionStoreError> {
         for _ in 0..10 {
@@ -50,15 +95,26 @@ impl SessionStorage for SledSessionStore {
         Err(SessionStoreError::UuidCollision)
     }
 
-    fn update(&self, id: Uuid, meta: &[u8], data: &[u8]) -> Result<(), SessionStoreError> {
+    fn update(
+        &self,
+        id: Uuid,
+        meta: &[u8],
+        data: &[u8],
+        messages: &[Vec<u8>],
+    ) -> Result<(), SessionStoreError> {
         let key = id.as_bytes();
-        (&self.meta_tree, &self.data_tree)
-            .transaction(|(t_meta, t_data)| {
+        (
+            &self.meta_tree,
+            &self.data_tree,
+            &self.message_tree,
+            &self.seq_tree,
+        )
+            .transaction(|(t_meta, t_data, t_message, t_seq)| {
                 t_meta.insert(key, meta)?;
                 t_data.insert(key, data)?;
-                Ok(())
+                replace_messages(t_message, t_seq, id, messages)
             })
-            .map_err(|e: TransactionError<sled::Error>| {
+            .map_err(|e: TransactionError<SessionStoreError>| {
                 SessionStoreError::SledTransactionError(e.to_string())
             })?;
         Ok(())
@@ -69,9 +125,17 @@ impl SessionStorage for SledSessionStore {
         Ok(val.map(|iv| iv.to_vec()))
     }
 
-    fn get_data(&self, id: Uuid) -> Result<Option<Vec<u8>>, SessionStoreError> {
-        let val = self.data_tree.get(id.as_bytes())?;
-        Ok(val.map(|iv| iv.to_vec()))
+    fn get_data(&self, id: Uuid) -> Result<Option<(Vec<u8>, Vec<Vec<u8>>)>, SessionStoreError> {
+        let Some(data) = self.data_tree.get(id.as_bytes())? else {
+            return Ok(None);
+        };
+        let messages = self
+            .message_tree
+            .scan_prefix(id.as_bytes())
+            .values()
+            .map(|v| v.map(|v| v.to_vec()))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Some((data.to_vec(), messages)))
     }
 
     fn list(
@@ -97,17 +161,27 @@ impl SessionStorage for SledSessionStore {
         Ok(result)
     }
 
-    fn delete(&self, id: Uuid) -> Result<Option<Vec<u8>>, SessionStoreError> {
+    fn delete(&self, id: Uuid) -> Result<Option<(Vec<u8>, Vec<Vec<u8>>)>, SessionStoreError> {
         let key = id.as_bytes();
-        (&self.meta_tree, &self.data_tree)
-            .transaction(|(t_meta, t_data)| {
+        (
+            &self.meta_tree,
+            &self.data_tree,
+            &self.message_tree,
+            &self.seq_tree,
+        )
+            .transaction(|(t_meta, t_data, t_message, t_seq)| {
                 t_meta.remove(key)?;
-                t_data
-                    .remove(key)
-                    .map(|v| v.map(|v| v.to_vec()))
-                    .map_err(|e| e.into())
+                let count = get_count(t_seq, id)?;
+                let mut messages = Vec::with_capacity(count as usize);
+                for seq in 0..count {
+                    if let Some(v) = t_message.remove(&message_key(id, seq))? {
+                        messages.push(v.to_vec());
+                    }
+                }
+                t_seq.remove(key)?;
+                Ok(t_data.remove(key)?.map(|v| (v.to_vec(), messages)))
             })
-            .map_err(|e: TransactionError<sled::Error>| {
+            .map_err(|e: TransactionError<SessionStoreError>| {
                 SessionStoreError::SledTransactionError(e.to_string())
             })
     }
@@ -137,4 +211,97 @@ impl SessionStorage for SledSessionStore {
                 SessionStoreError::SledTransactionError(e.to_string())
             })
     }
+
+    fn push_message(
+        &self,
+        id: Uuid,
+        f: Box<
+            dyn Fn(Option<Vec<u8>>, Option<Vec<u8>>) -> Result<PushedMessage, anyhow::Error> + Send,
+        >,
+    ) -> Result<Vec<u8>, SessionStoreError> {
+        let key = id.as_bytes();
+
+        (
+            &self.meta_tree,
+            &self.data_tree,
+            &self.message_tree,
+            &self.seq_tree,
+        )
+            .transaction(|(t_meta, t_data, t_message, t_seq)| {
+                let old_meta = t_meta.get(key)?.map(|e| e.to_vec());
+                let count = get_count(t_seq, id)?;
+                let last = match count {
+                    0 => None,
+                    n => t_message.get(&message_key(id, n - 1))?.map(|e| e.to_vec()),
+                };
+                let pushed = f(old_meta, last).map_err(|e| {
+                    ConflictableTransactionError::Abort(SessionStoreError::CASInnerError(e))
+                })?;
+                t_meta.insert(key, pushed.meta)?;
+                if let Some(data) = pushed.data {
+                    t_data.insert(key, data)?;
+                }
+                let seq = if pushed.replace_last && count > 0 {
+                    count - 1
+                } else {
+                    t_seq.insert(key, &(count + 1).to_be_bytes()[..])?;
+                    count
+                };
+                t_message.insert(&message_key(id, seq)[..], pushed.message.as_slice())?;
+                Ok(pushed.message)
+            })
+            .map_err(|e: TransactionError<SessionStoreError>| {
+                SessionStoreError::SledTransactionError(e.to_string())
+            })
+    }
+
+    fn migrate(&self, split: &LegacySplitter) -> Result<usize, SessionStoreError> {
+        if !self
+            .db
+            .tree_names()
+            .iter()
+            .any(|n| n.as_ref() == self.legacy_name.as_bytes())
+        {
+            return Ok(0);
+        }
+        let legacy_tree = self.db.open_tree(&self.legacy_name)?;
+        let mut migrated = 0;
+        let mut skipped = 0;
+        for item in legacy_tree.iter() {
+            let (k, v) = item?;
+            let Ok(id) = Uuid::from_slice(&k) else {
+                continue;
+            };
+            // 无法解析的会话留在旧表中，不影响其它会话
+            let (data, messages) = match split(&v) {
+                Ok(split) => split,
+                Err(e) => {
+                    tracing::warn!("Failed to migrate chat {}, keep it as is: {}", id, e);
+                    skipped += 1;
+                    continue;
+                }
+            };
+            // 每个会话单独迁移，中途退出时下次启动继续
+            (
+                &legacy_tree,
+                &self.data_tree,
+                &self.message_tree,
+                &self.seq_tree,
+            )
+                .transaction(|(t_legacy, t_data, t_message, t_seq)| {
+                    t_data.insert(id.as_bytes(), data.as_slice())?;
+                    replace_messages(t_message, t_seq, id, &messages)?;
+                    t_legacy.remove(id.as_bytes())?;
+                    Ok(())
+                })
+                .map_err(|e: TransactionError<SessionStoreError>| {
+                    SessionStoreError::SledTransactionError(e.to_string())
+                })?;
+            migrated += 1;
+        }
+        if skipped == 0 {
+            self.db.drop_tree(&self.legacy_name)?;
+        }
+        Ok(migrated)
+    }
 }
@@ -0,0 +1,275 @@
+use std::sync::{Arc, Mutex};
+
+use rusqlite::{Connection, OptionalExtension, params};
+use uuid::Uuid;
+
+use crate::session::{LegacySplitter, PushedMessage, SessionStorage, SessionStoreError};
+
+/// 每个会话一行保存meta和不含消息的data，消息按(chat_id, seq)保存在另一张表
+pub struct SqliteSessionStore {
+    conn: Arc<Mutex<Connection>>,
+    chat_table: String,
+    message_table: String,
+}
+
+impl SqliteSessionStore {
+    pub fn new(conn: Arc<Mutex<Connection>>, name: &str) -> Result<Self, SessionStoreError> {
+        let chat_table = format!("{}_chats", name);
+        let message_table = format!("{}_messages", name);
+        conn.lock().unwrap().execute_batch(&format!(
+            "CREATE TABLE IF NOT EXISTS \"{chat_table}\" (
+                id BLOB PRIMARY KEY,
+                meta BLOB NOT NULL,
+                data BLOB
+            );
+            CREATE TABLE IF NOT EXISTS \"{message_table}\" (
+                chat_id BLOB NOT NULL,
+                seq INTEGER NOT NULL,
+                message BLOB NOT NULL,
+                PRIMARY KEY (chat_id, seq)
+            );"
+        ))?;
+        Ok(Self {
+            conn,
+            chat_table,
+            message_table,
+        })
+    }
+
+    /// 会话的全部消息，按seq排列
+    fn read_messages(
+        &self,
+        conn: &Connection,
+        id: Uuid,
+    ) -> Result<Vec<Vec<u8>>, SessionStoreError> {
+        let mut stmt = conn.prepare(&format!(
+            "SELECT message FROM \"{}\" WHERE chat_id = ?1 ORDER BY seq",
+            self.message_table
+        ))?;
+        let messages = stmt
+            .query_map([&id.as_bytes()[..]], |row| row.get(0))?
+            .collect::<Result<Vec<Vec<u8>>, _>>()?;
+        Ok(messages)
+    }
+
+    /// 用`messages`替换会话原有的全部消息
+    fn replace_messages(
+        &self,
+        conn: &Connection,
+        id: Uuid,
+        messages: &[Vec<u8>],
+    ) -> Result<(), SessionStoreError> {
+        conn.execute(
+            &format!("DELETE FROM \"{}\" WHERE chat_id = ?1", self.message_table),
+            [&id.as_bytes()[..]],
+        )?;
+        let mut stmt = conn.prepare(&format!(
+            "INSERT INTO \"{}\" (chat_id, seq, message) VALUES (?1, ?2, ?3)",
+            self.message_table
+        ))?;
+        for (seq, message) in messages.iter().enumerate() {
+            stmt.execute(params![&id.as_bytes()[..], seq as u64, message])?;
+        }
+        Ok(())
+    }
+
+    fn upsert(
+        &self,
+        conn: &Connection,
+        id: Uuid,
+        meta: &[u8],
+        data: Option<&[u8]>,
+    ) -> Result<(), SessionStoreError> {
+        match data {
+            Some(data) => conn.execute(
+                &format!(
+                    "INSERT INTO \"{}\" (id, meta, data) VALUES (?1, ?2, ?3)
+                     ON CONFLICT(id) DO UPDATE SET meta = excluded.meta, data = excluded.data",
+                    self.chat_table
+                ),
+                params![&id.as_bytes()[..], meta, data],
+            )?,
+            None => conn.execute(
+                &format!(
+                    "INSERT INTO \"{}\" (id, meta) VALUES (?1, ?2)
+                     ON CONFLICT(id) DO UPDATE SET meta = excluded.meta",
+                    self.chat_table
+                ),
+                params![&id.as_bytes()[..], meta],
+            )?,
+        };
+        Ok(())
+    }
+
+    fn read_chat(
+        &self,
+        conn: &Connection,
+        id: Uuid,
+    ) -> Result<Option<(Vec<u8>, Option<Vec<u8>>)>, SessionStoreError> {
+        Ok(conn
+            .query_row(
+                &format!(
+                    "SELECT meta, data FROM \"{}\" WHERE id = ?1",
+                    self.chat_table
+                ),
+                [&id.as_bytes()[..]],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?)
+    }
+}
+
+impl SessionStorage for SqliteSessionStore {
+    fn append(&self, meta: &[u8], data: &[u8]) -> Result<Uuid, SessionStoreError> {
+        let conn = self.conn.lock().unwrap();
+        for _ in 0..10 {
+            let id = Uuid::now_v7();
+            let inserted = conn.execute(
+                &format!(
+                    "INSERT OR IGNORE INTO \"{}\" (id, meta, data) VALUES (?1, ?2, ?3)",
+                    self.chat_table
+                ),
+                params![&id.as_bytes()[..], meta, data],
+            )?;
+            if inserted == 1 {
+                return Ok(id);
+            }
+        }
+        Err(SessionStoreError::UuidCollision)
+    }
+
+    fn update(
+        &self,
+        id: Uuid,
+        meta: &[u8],
+        data: &[u8],
+        messages: &[Vec<u8>],
+    ) -> Result<(), SessionStoreError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        self.upsert(&tx, id, meta, Some(data))?;
+        self.replace_messages(&tx, id, messages)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn get_meta(&self, id: Uuid) -> Result<Option<Vec<u8>>, SessionStoreError> {
+        let conn = self.conn.lock().unwrap();
+        Ok(self.read_chat(&conn, id)?.map(|(meta, _)| meta))
+    }
+
+    fn get_data(&self, id: Uuid) -> Result<Option<(Vec<u8>, Vec<Vec<u8>>)>, SessionStoreError> {
+        let conn = self.conn.lock().unwrap();
+        let Some((_, Some(data))) = self.read_chat(&conn, id)? else {
+            return Ok(None);
+        };
+        Ok(Some((data, self.read_messages(&conn, id)?)))
+    }
+
+    fn list(
+        &self,
+        limit: Option<usize>,
+        offset: Option<usize>,
+    ) -> Result<Vec<(Uuid, Vec<u8>)>, SessionStoreError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT id, meta FROM \"{}\" ORDER BY id DESC LIMIT ?1 OFFSET ?2",
+            self.chat_table
+        ))?;
+        // LIMIT为负数时不限制
+        let limit = limit.map(|l| l as i64).unwrap_or(-1);
+        let offset = offset.unwrap_or(0) as i64;
+        let result = stmt
+            .query_map(params![limit, offset], |row| {
+                Ok((Uuid::from_bytes(row.get(0)?), row.get(1)?))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(result)
+    }
+
+    fn delete(&self, id: Uuid) -> Result<Option<(Vec<u8>, Vec<Vec<u8>>)>, SessionStoreError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let chat = self.read_chat(&tx, id)?;
+        let messages = self.read_messages(&tx, id)?;
+        tx.execute(
+            &format!("DELETE FROM \"{}\" WHERE id = ?1", self.chat_table),
+            [&id.as_bytes()[..]],
+        )?;
+        tx.execute(
+            &format!("DELETE FROM \"{}\" WHERE chat_id = ?1", self.message_table),
+            [&id.as_bytes()[..]],
+        )?;
+        tx.commit()?;
+        Ok(chat.and_then(|(_, data)| data).map(|data| (data, messages)))
+    }
+
+    fn update_data_with(
+        &self,
+        id: Uuid,
+        f: Box<
+            dyn Fn(Option<Vec<u8>>, Option<Vec<u8>>) -> Result<(Vec<u8>, Vec<u8>), anyhow::Error>
+                + Send,
+        >,
+    ) -> Result<(Vec<u8>, Vec<u8>), SessionStoreError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let (old_meta, old_data) = match self.read_chat(&tx, id)? {
+            Some((meta, data)) => (Some(meta), data),
+            None => (None, None),
+        };
+        let (new_meta, new_data) = f(old_meta, old_data)?;
+        self.upsert(&tx, id, &new_meta, Some(&new_data))?;
+        tx.commit()?;
+        Ok((new_meta, new_data))
+    }
+
+    fn push_message(
+        &self,
+        id: Uuid,
+        f: Box<
+            dyn Fn(Option<Vec<u8>>, Option<Vec<u8>>) -> Result<PushedMessage, anyhow::Error> + Send,
+        >,
+    ) -> Result<Vec<u8>, SessionStoreError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let old_meta = self.read_chat(&tx, id)?.map(|(meta, _)| meta);
+        let last: Option<(u64, Vec<u8>)> = tx
+            .query_row(
+                &format!(
+                    "SELECT seq, message FROM \"{}\" WHERE chat_id = ?1 ORDER BY seq DESC LIMIT 1",
+                    self.message_table
+                ),
+                [&id.as_bytes()[..]],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+        let (last_seq, last) = match last {
+            Some((seq, v)) => (Some(seq), Some(v)),
+            None => (None, None),
+        };
+
+        let pushed = f(old_meta, last)?;
+
+        self.upsert(&tx, id, &pushed.meta, pushed.data.as_deref())?;
+        let seq = match last_seq {
+            Some(seq) if pushed.replace_last => seq,
+            Some(seq) => seq + 1,
+            None => 0,
+        };
+        tx.execute(
+            &format!(
+                "INSERT OR REPLACE INTO \"{}\" (chat_id, seq, message) VALUES (?1, ?2, ?3)",
+                self.message_table
+            ),
+            params![&id.as_bytes()[..], seq, pushed.message],
+        )?;
+        tx.commit()?;
+        Ok(pushed.message)
+    }
+
+    fn migrate(&self, _split: &LegacySplitter) -> Result<usize, SessionStoreError> {
+        // SQLite后端从一开始就是逐条保存消息，没有旧格式
+        Ok(0)
+    }
+}
@@ -0,0 +1,478 @@
+use crate::{ToolCallFormat, ToolUse};
+
+/// `StreamParser` 解析出的事件
+#[derive(Debug, Clone, PartialEq)]
+pub enum StreamEvent {
+    /// 第一次工具调用之前的普通文本
+    Content(String),
+    /// 工具调用之后的文本，当作思考过程
+    Reasoning(String),
+    /// 工具调用的原始文本，只用于展示
+    ToolDelta(String),
+    /// 一个完整的工具调用
+    ToolCall(ToolUse),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StreamParseState {
+    /// 初始状态, 正在“窥视”工具调用的开始标记或等待足够的数据来决定
+    AwaitingDecision,
+    /// 已找到开始标记, 正在等待结束标记
+    ToolCall,
+}
+
+/// 文本协议下的增量解析器
+/// 输入LLM返回的delta，输出内容/思考/工具调用事件，标记可以被切断在任意位置
+pub struct StreamParser {
+    format: &'static dyn ToolCallFormat,
+    state: StreamParseState,
+    /// 还不能确定类型的文本
+    buffer: String,
+    /// 工具调用标记之间的原始文本
+    tool_block: String,
+    /// 不完整的UTF-8字节
+    pending_bytes: Vec<u8>,
+    /// 是否已经出现过工具调用
+    seen_tool_call: bool,
+}
+
+impl StreamParser {
+    pub fn new(format: &'static dyn ToolCallFormat) -> Self {
+        Self {
+            format,
+            state: StreamParseState::AwaitingDecision,
+            buffer: String::new(),
+            tool_block: String::new(),
+            pending_bytes: Vec::new(),
+            seen_tool_call: false,
+        }
+    }
+
+    /// 输入原始字节，末尾不完整的UTF-8字符会留到下一次
+    pub fn push_bytes(&mut self, bytes: &[u8]) -> Vec<StreamEvent> {
+        self.pending_bytes.extend_from_slice(bytes);
+        let mut text = String::new();
+        loop {
+            match std::str::from_utf8(&self.pending_bytes) {
+                Ok(s) => {
+                    text.push_str(s);
+                    self.pending_bytes.clear();
+                    break;
+                }
+                Err(e) => {
+                    let valid = e.valid_up_to();
+                    // valid_up_to 之前一定是合法的UTF-8
+                    text.push_str(
+                        std::str::from_utf8(&self.pending_bytes[..valid]).unwrap_or_default(),
+                    );
+                    match e.error_len() {
+                        Some(len) => {
+                            text.push(char::REPLACEMENT_CHARACTER);
+                            self.pending_bytes.drain(..valid + len);
+                        }
+                        None => {
+                            self.pending_bytes.drain(..valid);
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        self.push(&text)
+    }
+
+    pub fn push(&mut self, delta: &str) -> Vec<StreamEvent> {
+        let mut events = Vec::new();
+        self.buffer.push_str(delta);
+        let start_marker = self.format.start_marker();
+        let max_marker_len = self.format.max_marker_len();
+        loop {
+            match self.state {
+                StreamParseState::AwaitingDecision => {
+                    if let Some(idx) = self.buffer.find(start_marker) {
+                        let text: String = self.buffer.drain(..idx).collect();
+                        self.buffer.drain(..start_marker.len());
+                        self.emit_text(text, &mut events);
+                        self.seen_tool_call = true;
+                        events.push(StreamEvent::ToolDelta(start_marker.to_string()));
+                        self.state = StreamParseState::ToolCall;
+                    } else {
+                        if self.buffer.len() > max_marker_len {
+                            let split_idx = floor_char_boundary(
+                                &self.buffer,
+                                self.buffer.len() - max_marker_len,
+                            );
+                            let text: String = self.buffer.drain(..split_idx).collect();
+                            self.emit_text(text, &mut events);
+                        }
+                        // 缓冲区中没有标签, 但数据还不够, 无法确定.
+                        break;
+                    }
+                }
+                StreamParseState::ToolCall => {
+                    let next_stop = self
+                        .format
+                        .end_markers()
+                        .iter()
+                        .filter_map(|tag| self.buffer.find(tag).map(|idx| (idx, *tag)))
+                        .min_by_key(|(idx, _)| *idx);
+
+                    if let Some((idx, tag)) = next_stop {
+                        let block: String = self.buffer.drain(..idx).collect();
+                        self.buffer.drain(..tag.len());
+                        self.emit_block(block, &mut events);
+                        self.finish_block(&mut events);
+                        // 但tool use本身就是reasoning，因此这里不是content
+                        self.state = StreamParseState::AwaitingDecision;
+                    } else {
+                        // 保留可能被切断的结束标记, 其余部分可以先输出
+                        if self.buffer.len() > max_marker_len {
+                            let split_idx = floor_char_boundary(
+                                &self.buffer,
+                                self.buffer.len() - max_marker_len,
+                            );
+                            let block: String = self.buffer.drain(..split_idx).collect();
+                            self.emit_block(block, &mut events);
+                        }
+                        break;
+                    }
+                }
+            }
+        }
+        events
+    }
+
+    /// 流结束，输出所有剩余内容
+    /// 没有结束标记的工具调用也会被解析
+    pub fn finish(&mut self) -> Vec<StreamEvent> {
+        let mut events = Vec::new();
+        if !self.pending_bytes.is_empty() {
+            let rest = String::from_utf8_lossy(&self.pending_bytes).to_string();
+            self.pending_bytes.clear();
+            events.extend(self.push(&rest));
+        }
+        let rest = std::mem::take(&mut self.buffer);
+        match self.state {
+            StreamParseState::AwaitingDecision => self.emit_text(rest, &mut events),
+            StreamParseState::ToolCall => {
+                self.emit_block(rest, &mut events);
+                self.finish_block(&mut events);
+            }
+        }
+        self.state = StreamParseState::AwaitingDecision;
+        events
+    }
+
+    fn emit_text(&self, text: String, events: &mut Vec<StreamEvent>) {
+        if text.is_empty() {
+            return;
+        }
+        // 在第一次FNCALL之前，非主动think的时候
+        // 当作普通的文本输出
+        // 对于非thinking的model有必要
+        if self.seen_tool_call {
+            events.push(StreamEvent::Reasoning(text));
+        } else {
+            events.push(StreamEvent::Content(text));
+        }
+    }
+
+    fn emit_block(&mut self, block: String, events: &mut Vec<StreamEvent>) {
+        if block.is_empty() {
+            return;
+        }
+        self.tool_block.push_str(&block);
+        events.push(StreamEvent::ToolDelta(block));
+    }
+
+    fn finish_block(&mut self, events: &mut Vec<StreamEvent>) {
+        let block = std::mem::take(&mut self.tool_block);
+        events.extend(
+            self.format
+                .parse_block(&block)
+                .into_iter()
+                .map(StreamEvent::ToolCall),
+        );
+    }
+}
+
+/// 寻找安全的 UTF-8 切割点
+fn floor_char_boundary(s: &str, mut idx: usize) -> usize {
+    while !s.is_char_boundary(idx) {
+        idx -= 1;
+    }
+    idx
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ToolFormatKind;
+
+    /// 一次完整的Qwen输出
+    const QWEN_TRANSCRIPT: &str = "我先放大左上角看看。\n✿FUNCTION✿: zoom_in\n✿ARGS✿: {\"img_idx\": \"3f2a\", \"bbox_2d\": [0, 0, 500, 500], \"label\": \"左上\"}\n✿FUNCTION✿: draw_bbox\n✿ARGS✿: {\"img_idx\": \"3f2a\", \"bbox\": [[10, 20, 30, 40]]}\n✿RETURN✿\n放大之后再确认。";
+
+    #[derive(Debug, Default, PartialEq)]
+    struct Collected {
+        content: String,
+        reasoning: String,
+        tool_delta: String,
+        calls: Vec<(String, String)>,
+    }
+
+    fn collect(events: impl IntoIterator<Item = StreamEvent>, c: &mut Collected) {
+        for e in events {
+            match e {
+                StreamEvent::Content(s) => c.content.push_str(&s),
+                StreamEvent::Reasoning(s) => c.reasoning.push_str(&s),
+                StreamEvent::ToolDelta(s) => c.tool_delta.push_str(&s),
+                StreamEvent::ToolCall(t) => c.calls.push((t.function_name, t.args)),
+            }
+        }
+    }
+
+    fn parse_chunks<'a>(
+        kind: ToolFormatKind,
+        chunks: impl IntoIterator<Item = &'a str>,
+    ) -> Collected {
+        let mut parser = StreamParser::new(kind.formatter());
+        let mut c = Collected::default();
+        for chunk in chunks {
+            collect(parser.push(chunk), &mut c);
+        }
+        collect(parser.finish(), &mut c);
+        c
+    }
+
+    fn parse_byte_chunks<'a>(
+        kind: ToolFormatKind,
+        chunks: impl IntoIterator<Item = &'a [u8]>,
+    ) -> Collected {
+        let mut parser = StreamParser::new(kind.formatter());
+        let mut c = Collected::default();
+        for chunk in chunks {
+            collect(parser.push_bytes(chunk), &mut c);
+        }
+        collect(parser.finish(), &mut c);
+        c
+    }
+
+    fn qwen_expected() -> Collected {
+        Collected {
+            content: "我先放大左上角看看。\n".to_string(),
+            reasoning: "\n放大之后再确认。".to_string(),
+            tool_delta: QWEN_TRANSCRIPT
+                .split("✿RETURN✿")
+                .next()
+                .unwrap()
+                .trim_start_matches("我先放大左上角看看。\n")
+                .to_string(),
+            calls: vec![
+                (
+                    "zoom_in".to_string(),
+                    "{\"img_idx\": \"3f2a\", \"bbox_2d\": [0, 0, 500, 500], \"label\": \"左上\"}"
+                        .to_string(),
+                ),
+                (
+                    "draw_bbox".to_string(),
+                    "{\"img_idx\": \"3f2a\", \"bbox\": [[10, 20, 30, 40]]}".to_string(),
+                ),
+            ],
+        }
+    }
+
+    #[test]
+    fn qwen_single_chunk() {
+        assert_eq!(
+            parse_chunks(ToolFormatKind::Qwen, [QWEN_TRANSCRIPT]),
+            qwen_expected()
+        );
+    }
+
+    #[test]
+    fn qwen_every_split_position() {
+        let expected = qwen_expected();
+        let bytes = QWEN_TRANSCRIPT.as_bytes();
+        for i in 0..=bytes.len() {
+            let (a, b) = bytes.split_at(i);
+            assert_eq!(
+                parse_byte_chunks(ToolFormatKind::Qwen, [a, b]),
+                expected,
+                "split at byte {}",
+                i
+            );
+        }
+    }
+
+    #[test]
+    fn qwen_every_pair_of_split_positions() {
+        let expected = qwen_expected();
+        let boundaries: Vec<usize> = (0..=QWEN_TRANSCRIPT.len())
+            .filter(|i| QWEN_TRANSCRIPT.is_char_boundary(*i))
+            .collect();
+        for (n, &i) in boundaries.iter().enumerate() {
+            for &j in &boundaries[n..] {
+                let chunks = [
+                    &QWEN_TRANSCRIPT[..i],
+                    &QWEN_TRANSCRIPT[i..j],
+                    &QWEN_TRANSCRIPT[j..],
+                ];
+                assert_eq!(
+                    parse_chunks(ToolFormatKind::Qwen, chunks),
+                    expected,
+                    "split at {} and {}",
+                    i,
+                    j
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn qwen_byte_by_byte() {
+        let chunks: Vec<&[u8]> = QWEN_TRANSCRIPT.as_bytes().chunks(1).collect();
+        assert_eq!(
+            parse_byte_chunks(ToolFormatKind::Qwen, chunks),
+            qwen_expected()
+        );
+    }
+
+    #[test]
+    fn qwen_char_by_char() {
+        let chars: Vec<String> = QWEN_TRANSCRIPT.chars().map(|c| c.to_string()).collect();
+        assert_eq!(
+            parse_chunks(ToolFormatKind::Qwen, chars.iter().map(|s| s.as_str())),
+            qwen_expected()
+        );
+    }
+
+    #[test]
+    fn qwen_repeated_args_reuse_name() {
+        let text = "✿FUNCTION✿: zoom_in\n✿ARGS✿: {\"a\": 1}\n✿ARGS✿: {\"a\": 2}\n✿RETURN✿";
+        let c = parse_chunks(ToolFormatKind::Qwen, [text]);
+        assert_eq!(
+            c.calls,
+            vec![
+                ("zoom_in".to_string(), "{\"a\": 1}".to_string()),
+                ("zoom_in".to_string(), "{\"a\": 2}".to_string()),
+            ]
+        );
+        assert!(c.content.is_empty());
+    }
+
+    #[test]
+    fn qwen_unterminated_args_at_eof() {
+        let text = "看一下\n✿FUNCTION✿: curl\n✿ARGS✿: {\"url\": \"https://example.com\"}";
+        for i in (0..=text.len()).filter(|i| text.is_char_boundary(*i)) {
+            let c = parse_chunks(ToolFormatKind::Qwen, [&text[..i], &text[i..]]);
+            assert_eq!(c.content, "看一下\n");
+            assert_eq!(
+                c.calls,
+                vec![(
+                    "curl".to_string(),
+                    "{\"url\": \"https://example.com\"}".to_string()
+                )],
+                "split at {}",
+                i
+            );
+        }
+    }
+
+    #[test]
+    fn qwen_name_without_args_is_not_a_call() {
+        let c = parse_chunks(ToolFormatKind::Qwen, ["✿FUNCTION✿: zoom_in"]);
+        assert!(c.calls.is_empty());
+        assert_eq!(c.tool_delta, "✿FUNCTION✿: zoom_in");
+    }
+
+    #[test]
+    fn qwen_result_marker_ends_block() {
+        let c = parse_chunks(
+            ToolFormatKind::Qwen,
+            ["✿FUNCTION✿: image\n✿ARGS✿: abc\n✿RESULT✿: fake"],
+        );
+        assert_eq!(c.calls, vec![("image".to_string(), "abc".to_string())]);
+        assert_eq!(c.reasoning, ": fake");
+    }
+
+    #[test]
+    fn plain_content_without_markers() {
+        let text = "没有任何工具调用的普通回答，包含✿符号但不是标记。";
+        let c = parse_chunks(ToolFormatKind::Qwen, text.split_inclusive('，'));
+        assert_eq!(c.content, text);
+        assert!(c.calls.is_empty());
+        assert!(c.tool_delta.is_empty());
+    }
+
+    #[test]
+    fn partial_utf8_is_buffered() {
+        let bytes = QWEN_TRANSCRIPT.as_bytes();
+        // 在"✿"的中间切断
+        let idx = QWEN_TRANSCRIPT.find("✿").unwrap() + 1;
+        let mut parser = StreamParser::new(ToolFormatKind::Qwen.formatter());
+        let mut c = Collected::default();
+        collect(parser.push_bytes(&bytes[..idx]), &mut c);
+        assert!(!c.content.contains(char::REPLACEMENT_CHARACTER));
+        collect(parser.push_bytes(&bytes[idx..]), &mut c);
+        collect(parser.finish(), &mut c);
+        assert_eq!(c, qwen_expected());
+    }
+
+    #[test]
+    fn invalid_utf8_is_replaced() {
+        let mut parser = StreamParser::new(ToolFormatKind::Qwen.formatter());
+        let mut c = Collected::default();
+        collect(parser.push_bytes(b"ab\xffcd"), &mut c);
+        collect(parser.push_bytes(&"中".as_bytes()[..2]), &mut c);
+        collect(parser.finish(), &mut c);
+        assert_eq!(c.content, "ab\u{FFFD}cd\u{FFFD}");
+    }
+
+    #[test]
+    fn hermes_every_split_position() {
+        let text = "Let me check.<tool_call>\n{\"name\": \"curl\", \"arguments\": {\"url\": \"https://a.b\"}}\n</tool_call><tool_call>{\"name\": \"image\", \"arguments\": \"x\"}</tool_call>";
+        for i in 0..=text.len() {
+            let c = parse_chunks(ToolFormatKind::Hermes, [&text[..i], &text[i..]]);
+            assert_eq!(c.content, "Let me check.");
+            assert_eq!(
+                c.calls,
+                vec![
+                    ("curl".to_string(), "{\"url\":\"https://a.b\"}".to_string()),
+                    ("image".to_string(), "x".to_string()),
+                ],
+                "split at {}",
+                i
+            );
+        }
+    }
+
+    #[test]
+    fn llama3_python_tag() {
+        let text =
+            "<|python_tag|>{\"name\": \"zoom_in\", \"parameters\": {\"img_idx\": \"a\"}}<|eom_id|>";
+        for i in 0..=text.len() {
+            let c = parse_chunks(ToolFormatKind::Llama3, [&text[..i], &text[i..]]);
+            assert!(c.content.is_empty());
+            assert_eq!(
+                c.calls,
+                vec![("zoom_in".to_string(), "{\"img_idx\":\"a\"}".to_string())],
+                "split at {}",
+                i
+            );
+        }
+        // 没有eom的情况
+        let c = parse_chunks(
+            ToolFormatKind::Llama3,
+            [
+                "<|python_tag|>{\"name\": \"a\", \"parameters\": {}}; {\"name\": \"b\", \"parameters\": {}}",
+            ],
+        );
+        assert_eq!(
+            c.calls,
+            vec![
+                ("a".to_string(), "{}".to_string()),
+                ("b".to_string(), "{}".to_string())
+            ]
+        );
+    }
+}
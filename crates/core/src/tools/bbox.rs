@@ -1,7 +1,7 @@
 use crate::AssetId;
-use crate::blob::BlobStorage;
+use crate::blob::{BlobMeta, BlobStorage};
 use crate::schema::MessageContent;
-use crate::tools::{FONT_DATA, Tool, ToolDescription};
+use crate::tools::{FONT_DATA, Tool, ToolContext, ToolDescription, ToolKind};
 use crate::{ImageResizer, parse_tool_args};
 use ab_glyph::PxScale;
 use anyhow::Result;
@@ -61,7 +61,7 @@ impl Tool for BboxDrawTool {
             args_format: "JSON. Img must be UUID.".to_string(),
         }
     }
-    async fn call(&self, args: &str) -> Result<Vec<MessageContent>> {
+    async fn call(&self, _ctx: &ToolContext, args: &str) -> Result<Vec<MessageContent>> {
         let args: BboxDrawArgs = parse_tool_args(args)?;
         let id = AssetId::from_str(&args.img_idx)?;
         let image = self
@@ -69,7 +69,9 @@ impl Tool for BboxDrawTool {
             .get(id)?
             .ok_or(anyhow::anyhow!("Image does not exist"))?;
         let cropped_img = draw_bboxes_rgba(&image, &args.bboxes)?;
-        let uuid = self.db.save(&cropped_img)?;
+        let uuid = self
+            .db
+            .save_with_meta(&cropped_img, BlobMeta::from_tool(ToolKind::DrawBbox))?;
         Ok(vec![MessageContent::ImageRef(uuid, "".to_string())])
     }
 }
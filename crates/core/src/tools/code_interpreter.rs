@@ -1,7 +1,7 @@
 use crate::{AssetId, AssetIdError};
-use crate::blob::{BlobStorage, BlobStorageError};
-use crate::{FN_RAWHTML, FN_RAWSVG, get_usvg_options, parse_sourcecode_args};
-use crate::{MessageContent, Tool, ToolDescription};
+use crate::blob::{BlobMeta, BlobStorage, BlobStorageError};
+use crate::{get_usvg_options, parse_sourcecode_args};
+use crate::{MessageContent, Tool, ToolContext, ToolDescription, ToolKind};
 use base64::Engine;
 use base64::prelude::BASE64_STANDARD;
 use deno_error::JsError;
@@ -13,15 +13,35 @@ use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use std::io::Cursor;
 use std::str::FromStr;
-use std::sync::{Arc, mpsc};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock, mpsc};
+use std::time::Duration;
 use tokio::time::Instant;
+use uuid::Uuid;
 
+use super::js_prelude::{LOAD_SOURCE, LibCategory, load_prelude, snapshot_marker};
+use super::js_session::{JsSessionOptions, JsSessions};
 use anyhow::{Error, anyhow};
 use deno_core::{JsRuntime, OpState, RuntimeOptions, extension, op2, scope, v8};
 
 #[derive(Deserialize, JsonSchema)]
 pub struct JsInterpreterArgs(pub String);
 
+/// 有状态模式下用对象形式传入`reset`
+#[derive(Deserialize)]
+struct JsSessionArgs {
+    code: String,
+    #[serde(default)]
+    reset: bool,
+}
+
+fn parse_js_args(args: &str) -> Result<(String, bool), Error> {
+    match serde_json::from_str::<JsSessionArgs>(args) {
+        Ok(args) => Ok((args.code, args.reset)),
+        Err(_) => Ok((parse_sourcecode_args(args)?, false)),
+    }
+}
+
 #[async_trait::async_trait]
 impl Tool for JsInterpreter {
     fn name(&self) -> String {
@@ -35,6 +55,12 @@ impl Tool for JsInterpreter {
         });
         let libs = generate_libs_list();
         let cheatsheet = generate_cheatsheet_prompt();
+        let session = if self.sessions.is_some() {
+            r#"
+        **Session:** Values on `globalThis` and `fs` files persist between calls in this chat. Pass `{"code": "...", "reset": true}` to start over."#
+        } else {
+            ""
+        };
         let description = format!(
             r##"V8 sandbox environments.
         **Imported Libs:** {libs}
@@ -43,8 +69,11 @@ impl Tool for JsInterpreter {
         - `load_blob('asset'|'image', uuid):bytes`
         - `convert_to_png(bytes):bytes`
         - `QRCode.save(str, 'png'|'svg'):uuid` / `QRCode.decode(bytes|uuid):str`
-        **Notes:** NO Network. NO Canvas (Use d3/UPNG). Top-level await OK.
-        **Cheatsheet:** {cheatsheet}"##
+        - `progress(msg, fraction?)`: report progress of long jobs to user
+        **Notes:** NO Network. NO Canvas (Use d3/UPNG). Top-level await OK. Killed after {timeout}s or {heap} MB heap.
+        **Cheatsheet:** {cheatsheet}{session}"##,
+            timeout = self.limits.wall_timeout_secs,
+            heap = self.limits.max_heap_mb,
         );
 
         ToolDescription {
@@ -55,15 +84,38 @@ impl Tool for JsInterpreter {
             args_format: "Raw JavaScript code string (NO quote/backticks). Use `return` or `console.log` to output.".to_string(),
         }
     }
-    async fn call(&self, args: &str) -> Result<Vec<MessageContent>, anyhow::Error> {
-        let code = parse_sourcecode_args(args)?;
-        let image = self.image.clone();
-        let asset = self.asset.clone();
-        let result = tokio::task::spawn_blocking(move || run_code(image, asset, code)).await??;
-
-        let mut v = vec![MessageContent::Text(
-            result.terminal + "\nReturn: " + &result.return_value,
-        )];
+    async fn call(
+        &self,
+        ctx: &ToolContext,
+        args: &str,
+    ) -> Result<Vec<MessageContent>, anyhow::Error> {
+        let (code, reset) = parse_js_args(args)?;
+        let (result, fresh) = match (&self.sessions, ctx.chat_id) {
+            (Some(sessions), Some(chat_id)) => {
+                sessions.run(chat_id, ctx.clone(), code, reset).await?
+            }
+            _ => {
+                let image = self.image.clone();
+                let asset = self.asset.clone();
+                let ctx = ctx.clone();
+                let (limits, snapshot) = (self.limits, self.snapshot);
+                let result = tokio::task::spawn_blocking(move || {
+                    run_code(image, asset, ctx, limits, snapshot, code)
+                })
+                .await??;
+                (result, false)
+            }
+        };
+
+        // 被终止的脚本也返回已经输出的内容
+        let mut text = match result.error {
+            Some(e) => result.terminal + "\nError: " + &e,
+            None => result.terminal + "\nReturn: " + &result.return_value,
+        };
+        if fresh {
+            text.insert_str(0, "(New JS session, earlier globals are gone)\n");
+        }
+        let mut v = vec![MessageContent::Text(text)];
         for (idx, &uuid) in result.uuids_img.iter().enumerate() {
             v.push(MessageContent::ImageRef(
                 uuid,
@@ -78,16 +130,24 @@ impl Tool for JsInterpreter {
         }
         Ok(v)
     }
+
+    fn close_chat(&self, chat_id: Uuid) {
+        if let Some(sessions) = &self.sessions {
+            sessions.close(chat_id);
+        }
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
-struct CodeResult {
-    return_value: String,
-    terminal: String,
+pub(crate) struct CodeResult {
+    pub(crate) return_value: String,
+    pub(crate) terminal: String,
+    /// 超时或超出内存被终止的原因
+    pub(crate) error: Option<String>,
     #[serde(skip)]
-    uuids_img: Vec<AssetId>,
+    pub(crate) uuids_img: Vec<AssetId>,
     #[serde(skip)]
-    uuids_asset: Vec<AssetId>,
+    pub(crate) uuids_asset: Vec<AssetId>,
 }
 
 struct LogSender(mpsc::Sender<String>);
@@ -172,7 +232,10 @@ fn op_save_svg(state: &mut OpState, #[string] svg_data: &str) -> Result<String,
         .map_err(|_| ImageError::InternalErrorConvertPixMapToPng)?;
 
     let db = state.borrow::<DbHandle>();
-    match db.image.save(&output_buf) {
+    match db
+        .image
+        .save_with_meta(&output_buf, BlobMeta::from_tool(ToolKind::JsInterpreter))
+    {
         Ok(uuid) => {
             if let Err(e) = state.borrow::<UuidSender>().image.send(uuid.clone()) {
                 tracing::warn!("Error to send svg from javascript back to llm, {}.", e)
@@ -219,7 +282,10 @@ fn op_save_blob(
     match schema {
         Schema::Image => {
             let _ = image::guess_format(img)?;
-            match db.image.save(&img) {
+            match db
+                .image
+                .save_with_meta(img, BlobMeta::from_tool(ToolKind::JsInterpreter))
+            {
                 Ok(uuid) => {
                     if let Err(e) = state.borrow::<UuidSender>().image.send(uuid.clone()) {
                         tracing::warn!("Error to saved image from javascript back to llm, {}.", e)
@@ -229,7 +295,10 @@ fn op_save_blob(
                 Err(e) => Err(ImageError::DatabaseError(e)),
             }
         }
-        Schema::Asset => match db.asset.save(&img) {
+        Schema::Asset => match db
+            .asset
+            .save_with_meta(img, BlobMeta::from_tool(ToolKind::JsInterpreter))
+        {
             Ok(uuid) => {
                 if let Err(e) = state.borrow::<UuidSender>().asset.send(uuid.clone()) {
                     tracing::warn!("Error to saved asset from javascript back to llm, {}.", e)
@@ -361,12 +430,20 @@ fn op_base64_decode(#[string] data: String) -> Result<Vec<u8>, ImageError> {
     Ok(BASE64_STANDARD.decode(data)?)
 }
 
+#[op2(fast)]
+fn op_report_progress(state: &mut OpState, #[string] msg: String, fraction: f64) {
+    // JS端没有传比例时为负数
+    let fraction = (fraction >= 0.0).then_some(fraction as f32);
+    state.borrow::<ToolContext>().progress(msg, fraction);
+}
+
 #[op2(fast)]
 fn op_performance_now(state: &mut OpState) -> f64 {
     let origin = state.borrow::<TimeOrigin>().0;
     origin.elapsed().as_secs_f64() * 1000.0
 }
 
+// build.rs用名字和签名相同的空ops生成快照，增删或修改ops时同步修改
 extension!(
     sandbox_ext,
     ops = [
@@ -381,6 +458,7 @@ extension!(
         op_base64_decode,
         op_base64_encode,
         op_performance_now,
+        op_report_progress,
         op_qrcode_png,
         op_qrcode_svg,
         op_qrcode_decode,
@@ -390,195 +468,73 @@ extension!(
 pub struct JsInterpreter {
     image: Arc<dyn BlobStorage>,
     asset: Arc<dyn BlobStorage>,
+    limits: JsLimits,
+    /// 是否从加载好库的快照启动
+    snapshot: bool,
+    /// 有状态模式下每个会话的环境
+    sessions: Option<JsSessions>,
+}
+
+/// 每次执行脚本的资源限制
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct JsLimits {
+    /// 用户代码最长的执行时间（秒），不超过工具调用剩余的时间
+    /// 按墙钟时间计算，等待Promise的时间也计算在内，不是CPU时间
+    #[serde(alias = "timeout_secs")]
+    pub wall_timeout_secs: u64,
+    /// V8堆的上限（MB）
+    pub max_heap_mb: usize,
+}
+
+impl Default for JsLimits {
+    fn default() -> Self {
+        Self {
+            wall_timeout_secs: 30,
+            max_heap_mb: 512,
+        }
+    }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-enum LibCategory {
-    Environment,    // 基础环境 (DOM, Base64)
-    DataProcessing, // 数据处理 (Lodash, Math, CSV)
-    Visualization,  // 可视化 (D3, Plot)
-    Utility,        // 其他工具
-}
+/// 看门狗检查取消和超时的间隔
+const WATCHDOG_TICK: Duration = Duration::from_millis(50);
+/// 达到堆上限时额外给出的空间，让正在终止的脚本能够退出
+const HEAP_HEADROOM: usize = 32 * 1024 * 1024;
 
-impl std::fmt::Display for LibCategory {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            LibCategory::Environment => write!(f, "Environment"),
-            LibCategory::DataProcessing => write!(f, "Data Processing"),
-            LibCategory::Visualization => write!(f, "Visualization"),
-            LibCategory::Utility => write!(f, "Utilities"),
+impl JsInterpreter {
+    pub fn new(image: Arc<dyn BlobStorage>, asset: Arc<dyn BlobStorage>) -> Self {
+        Self {
+            image,
+            asset,
+            limits: JsLimits::default(),
+            snapshot: true,
+            sessions: None,
         }
     }
-}
 
-struct LibraryConfig {
-    require_name: &'static str,       // 用于 require('name')
-    global_var: &'static str,         // 注入到 globalThis 的变量名
-    src: &'static str,                // 源码内容
-    category: LibCategory,            // 分类
-    prompt_hint: &'static str,        // Prompt 中的展示文本 (例如: "`_` (Lodash)")
-    after_hook: Option<&'static str>, // Polyfill用的配置脚本
-}
-const LOAD_SOURCE: &[LibraryConfig] = &[
-    // --- Environment ---
-    LibraryConfig {
-        require_name: "linkedom",
-        global_var: "LinkeDOM",
-        src: include_str!("prelude/linkedom.bundle.js"),
-        category: LibCategory::Environment,
-        prompt_hint: "`document`, `window`; NO canvas.",
-        after_hook: Some(
-            r###"
-        if (globalThis.LinkeDOM) {
-            const { parseHTML, XMLSerializer} = globalThis.LinkeDOM;
-            const dom = parseHTML('<!doctype html><html><body></body></html>');
-            globalThis.window = dom.window;
-            globalThis.document = dom.document;
-            globalThis.Element = dom.HTMLElement;
-            globalThis.SVGElement = dom.SVGElement;
-            globalThis.Node = dom.Node;
-            if (XMLSerializer) {
-                globalThis.XMLSerializer = XMLSerializer;
-            } else if (dom.window && dom.window.XMLSerializer) {
-                globalThis.XMLSerializer = dom.window.XMLSerializer;
-            } else {
-                Deno.core.ops.console_op_print("Notice: Using simple XMLSerializer polyfill.\n", true);
-                globalThis.XMLSerializer = class {
-                    serializeToString(node) {
-                        return node.outerHTML || "";
-                    }
-                };
-            }
+    /// 关闭快照时每次都重新加载所有库，用于对比启动时间
+    pub fn with_snapshot(mut self, snapshot: bool) -> Self {
+        self.snapshot = snapshot;
+        self
+    }
 
-            globalThis.requestAnimationFrame = (callback) => {
-                return setTimeout(callback, 0);
-            };
-            globalThis.cancelAnimationFrame = (id) => {
-                clearTimeout(id);
-            };
-            const originalSetAttribute = globalThis.Element.prototype.setAttribute;
-            globalThis.Element.prototype.setAttribute = function(name, value) {
-                originalSetAttribute.call(this, name, value);
-                return this;
-            };
-            if (globalThis.SVGElement) {
-                    globalThis.SVGElement.prototype.setAttribute = globalThis.Element.prototype.setAttribute;
-            }
-        } else {
-            Deno.core.ops.console_op_print("stderr: LinkeDOM not loaded!\n", true);
-        }"###,
-        ),
-    },
-    // --- Data Processing ---
-    LibraryConfig {
-        require_name: "lodash",
-        global_var: "_",
-        src: include_str!("prelude/lodash.min.js"),
-        category: LibCategory::DataProcessing,
-        prompt_hint: r##"const users=[{n:'a',g:'tech'},{n:'b',g:'hr'},{n:'c',g:'tech'}];console.log(_.groupBy(users,'g'));"##,
-        after_hook: Some(
-            r#"
-        if (typeof _ !== "undefined") {
-            globalThis.structuredClone = _.cloneDeep;
-        }"#,
-        ),
-    },
-    LibraryConfig {
-        require_name: "mathjs",
-        global_var: "math",
-        src: include_str!("prelude/math.min.js"),
-        category: LibCategory::DataProcessing,
-        prompt_hint: "math.evaluate('12.7 cm to inch').toString()",
-        after_hook: None,
-    },
-    LibraryConfig {
-        require_name: "simplify",
-        global_var: "simplify",
-        src: include_str!("prelude/simplify.min.js"),
-        category: LibCategory::DataProcessing,
-        prompt_hint: "",
-        after_hook: None,
-    },
-    LibraryConfig {
-        require_name: "dayjs",
-        global_var: "dayjs",
-        src: include_str!("prelude/dayjs.min.js"),
-        category: LibCategory::DataProcessing,
-        prompt_hint: "dayjs().format('YYYY-MM-DD HH:mm:ss')",
-        after_hook: None,
-    },
-    LibraryConfig {
-        require_name: "papaparse",
-        global_var: "Papa",
-        src: include_str!("prelude/papaparse.min.js"),
-        category: LibCategory::DataProcessing,
-        prompt_hint: "",
-        after_hook: None,
-    },
-    LibraryConfig {
-        require_name: "arquero",
-        global_var: "aq",
-        src: include_str!("prelude/arquero.min.js"),
-        category: LibCategory::DataProcessing,
-        prompt_hint: r##"const dt=aq.fromCSV(new TextDecoder().decode(bytes));
-console.log(dt.filter(d=>d.a>1).derive({c:d=>d.a+d.b}).toCSV());"##,
-        after_hook: Some(r#"if (typeof aq !== "undefined") { aq.aq = aq; }"#)
-    },
-    LibraryConfig {
-        require_name: "mustache",
-        global_var: "Mustache",
-        src: include_str!("prelude/mustache.min.js"),
-        category: LibCategory::Utility,
-        prompt_hint: r##"const htmlStr=Mustache.render(template,{title:"A",list:["1","2"]});"##,
-        after_hook: None,
-    },
-    LibraryConfig {
-        require_name: "nerdamer",
-        global_var: "nerdamer",
-        src: include_str!("prelude/nerdamer.min.js"),
-        category: LibCategory::DataProcessing,
-        prompt_hint: "nerdamer('solve(x^2=4, x)').toString()",
-        after_hook: None,
-    },
-    // --- Visualization ---
-    LibraryConfig {
-        require_name: "d3",
-        global_var: "d3",
-        src: include_str!("prelude/d3.v7.min.js"),
-        category: LibCategory::Visualization,
-        prompt_hint: "const s=d3.create('svg').attr('width',400).attr('height',300)/*NO append*/;s.append/*draw*/; save_svg(svg.node().outerHTML)",
-        after_hook: Some(r#"if (typeof d3 !== "undefined") d3.d3 = d3;"#),
-    },
-    LibraryConfig {
-        require_name: "vega",
-        global_var: "vega",
-        src: include_str!("prelude/vega.min.js"),
-        category: LibCategory::Visualization,
-        prompt_hint: r##"/*Spec must have width/height*/ const vegaSpec=vegaLite.compile(vlSpec).spec;
-const v=new vega.View(vega.parse(vegaSpec),{renderer:'svg'}).initialize(); save_svg(await v.toSVG());"##,
-        after_hook: Some(r#"if (typeof vega !== "undefined") vega.vega = vega;"#),
-    },
-    LibraryConfig {
-        require_name: "vega-lite",
-        global_var: "vegaLite",
-        src: include_str!("prelude/vega-lite.min.js"),
-        category: LibCategory::Visualization,
-        prompt_hint: "",
-        after_hook: None,
-    },
-    LibraryConfig {
-        require_name: "UPNG",
-        global_var: "UPNG",
-        src: include_str!("prelude/UPNG.min.js"),
-        category: LibCategory::DataProcessing,
-        prompt_hint: r##"const b=new Uint8Array([255,0,0,255]).buffer;UPNG.encode([b],width,height,depth);"##,
-        after_hook: None,
-    },
-];
+    pub fn with_limits(mut self, limits: JsLimits) -> Self {
+        self.limits = limits;
+        self
+    }
 
-impl JsInterpreter {
-    pub fn new(image: Arc<dyn BlobStorage>, asset: Arc<dyn BlobStorage>) -> Self {
-        Self { image, asset }
+    /// 开启有状态模式，使用此时的`limits`和`snapshot`
+    pub fn with_sessions(mut self, options: JsSessionOptions) -> Self {
+        self.sessions = options.enabled.then(|| {
+            JsSessions::new(
+                self.image.clone(),
+                self.asset.clone(),
+                self.limits,
+                self.snapshot,
+                options,
+            )
+        });
+        self
     }
 }
 
@@ -614,485 +570,318 @@ fn generate_cheatsheet_prompt() -> String {
         .join("\n")
 }
 
-fn get_env_script() -> &'static str {
-    r#"
-    globalThis.console = {
-        log: (...args) => {
-            let msg = args.map(String).join(" ");
-            Deno.core.ops.console_op_print(msg + "\n", false);
-        },
-        error: (...args) => {
-            let msg = args.map(String).join(" ");
-            Deno.core.ops.console_op_print("stderr: " + msg + "\n", true);
-        },
-        warn: (...args) => {
-            let msg = args.map(String).join(" ");
-            Deno.core.ops.console_op_print("warn: " + msg + "\n", false);
-        },
-        info: (...args) => {
-            let msg = args.map(String).join(" ");
-            Deno.core.ops.console_op_print("info: " + msg + "\n", false);
-        },
-        trace: (...args) => {
-            let msg = args.map(String).join(" ");
-            Deno.core.ops.console_op_print("trace: " + msg + "\n", false);
-        },
-        table: (data) => {
-            Deno.core.ops.console_op_print((Array.isArray(data) ? JSON.stringify(data) : String(data)) + "\n", false);
+/// 在另一个线程等待脚本结束，超过墙钟时间或工具调用被取消时终止V8
+/// 返回的Sender被drop时看门狗退出
+fn spawn_watchdog(
+    handle: v8::IsolateHandle,
+    ctx: ToolContext,
+    timeout: Duration,
+    abort: Arc<OnceLock<String>>,
+) -> mpsc::Sender<()> {
+    let (done_tx, done_rx) = mpsc::channel::<()>();
+    let timeout = ctx.remaining().map_or(timeout, |r| r.min(timeout));
+    let deadline = Instant::now() + timeout;
+    std::thread::spawn(move || {
+        while let Err(mpsc::RecvTimeoutError::Timeout) = done_rx.recv_timeout(WATCHDOG_TICK) {
+            let reason = if ctx.cancel.is_cancelled() {
+                "Execution cancelled".to_string()
+            } else if Instant::now() >= deadline {
+                format!("Execution timed out after {:.1}s", timeout.as_secs_f64())
+            } else {
+                continue;
+            };
+            let _ = abort.set(reason);
+            handle.terminate_execution();
+            break;
         }
-    };
-    globalThis.console.warn = globalThis.console.log;
-    globalThis.console.info = globalThis.console.log;
-    globalThis.console.trace = globalThis.console.log;
-
-    if (typeof setTimeout === "undefined") {
-        globalThis.setTimeout = (cb, delay, ...args) => {
-            queueMicrotask(() => {
-                if (typeof cb === 'string') {
-                    (0, eval)(cb);
-                } else {
-                    cb(...args);
-                }});
-            return 1;
-        };
-        globalThis.clearTimeout = (_id) => {};
-    }
+    });
+    done_tx
+}
+
+/// build.rs用相同签名的ops生成的快照，生成失败时为空
+static PRELUDE_SNAPSHOT: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/prelude.snap"));
+/// 生成快照时的`snapshot_marker`
+const PRELUDE_SNAPSHOT_MARKER: &str = include_str!(concat!(env!("OUT_DIR"), "/prelude.marker"));
+/// 快照加载失败过，之后都直接加载库
+static SNAPSHOT_BROKEN: AtomicBool = AtomicBool::new(false);
+
+/// 快照和当前的ops、脚本及V8版本一致时返回快照，否则为空
+/// 必须在`JsRuntime::new`之前检查，不匹配的快照会让V8直接终止进程
+fn prelude_snapshot() -> Option<&'static [u8]> {
+    static MATCHED: OnceLock<bool> = OnceLock::new();
+    let matched = *MATCHED.get_or_init(|| {
+        let matched = !PRELUDE_SNAPSHOT.is_empty()
+            && PRELUDE_SNAPSHOT_MARKER == snapshot_marker(&sandbox_ext::init());
+        if !matched {
+            tracing::warn!("JS snapshot is missing or outdated, loading libraries on every run");
+        }
+        matched
+    });
+    (matched && !SNAPSHOT_BROKEN.load(Ordering::Relaxed)).then_some(PRELUDE_SNAPSHOT)
+}
 
-    if (typeof setInterval === "undefined") {
-        globalThis.setInterval = (cb, delay, ...args) => {
-            // 警告：为了防止 D3 timer 陷入死循环，这里 Mock 为只运行一次
-            queueMicrotask(() => cb(...args));
-            return 1;
-        };
-        globalThis.clearInterval = (_id) => {};
-    }
-    // --- TextEncoder / TextDecoder (UTF-8) ---
-    if (typeof TextEncoder === "undefined") {
-        globalThis.TextEncoder = class TextEncoder {
-            get encoding() { return "utf-8"; }
-            encode(input) {
-                const str = input === undefined ? "" : String(input);
-                return Deno.core.ops.op_text_encode(str);
-            }
-            encodeInto(source, destination) {
-                const encoded = this.encode(source);
-                const len = Math.min(encoded.length, destination.length);
-                destination.set(encoded.subarray(0, len));
-                return { read: source.length, written: len };
-            }
-        };
-    }
+fn new_runtime(snapshot: Option<&'static [u8]>, max_heap: usize) -> JsRuntime {
+    // 从快照启动时扩展的ops按名字重新绑定，扩展必须和生成快照时一致
+    JsRuntime::new(RuntimeOptions {
+        extensions: vec![sandbox_ext::init()],
+        startup_snapshot: snapshot,
+        create_params: Some(v8::CreateParams::default().heap_limits(0, max_heap)),
+        ..Default::default()
+    })
+}
 
-    if (typeof TextDecoder === "undefined") {
-        globalThis.TextDecoder = class TextDecoder {
-            constructor(label = "utf-8", options = {}) {
-                // 目前只支持 utf-8，忽略 label
-                this.encoding = "utf-8";
-                this.fatal = options.fatal || false;
-                this.ignoreBOM = options.ignoreBOM || false;
-            }
-            decode(input, options) {
-                let buffer;
-                if (input === undefined) {
-                    buffer = new Uint8Array(0);
-                } else if (input instanceof ArrayBuffer) {
-                    buffer = new Uint8Array(input);
-                } else if (ArrayBuffer.isView(input)) {
-                    buffer = new Uint8Array(input.buffer, input.byteOffset, input.byteLength);
-                } else {
-                    throw new TypeError("Failed to execute 'decode' on 'TextDecoder': The provided value is not of type '(ArrayBuffer or ArrayBufferView)'");
-                }
-                return Deno.core.ops.op_text_decode(buffer);
-            }
-        };
+/// 从快照启动，快照中没有加载完的库时返回空
+fn runtime_from_snapshot(snapshot: &'static [u8], max_heap: usize) -> Option<JsRuntime> {
+    let mut runtime = new_runtime(Some(snapshot), max_heap);
+    let ready = match runtime.execute_script("<check>", "globalThis.__prelude_ready === true") {
+        Ok(value) => {
+            scope!(scope, runtime);
+            v8::Local::new(scope, value).is_true()
+        }
+        Err(_) => false,
+    };
+    if !ready {
+        tracing::warn!("JS snapshot is incomplete, loading libraries on every run");
+        SNAPSHOT_BROKEN.store(true, Ordering::Relaxed);
+        return None;
     }
-
-    // --- URL / URLSearchParams ---
-    if (typeof URL === "undefined") {
-        globalThis.URL = class URL {
-            constructor(url, base) {
-                this.href = url;
-                this.searchParams = new URLSearchParams();
-            }
-        };
-        globalThis.URLSearchParams = class URLSearchParams {
-            constructor(init) { this.params = new Map(); }
-            get(name) { return this.params.get(name); }
-            set(name, val) { this.params.set(name, val); }
+    Some(runtime)
+}
+
+/// 加载好库的JS环境，无状态模式每次调用新建，有状态模式每个会话保留一个
+pub(crate) struct Sandbox {
+    runtime: JsRuntime,
+    rt: tokio::runtime::Runtime,
+    limits: JsLimits,
+    /// 第一个终止原因，看门狗和堆回调都可能写入
+    abort: Arc<OnceLock<String>>,
+}
+
+impl Sandbox {
+    pub(crate) fn new(
+        image: Arc<dyn BlobStorage>,
+        asset: Arc<dyn BlobStorage>,
+        limits: JsLimits,
+        snapshot: bool,
+    ) -> Result<Self, Error> {
+        let max_heap = limits.max_heap_mb * 1024 * 1024;
+        let (mut runtime, loaded) = match snapshot
+            .then(prelude_snapshot)
+            .flatten()
+            .and_then(|snapshot| runtime_from_snapshot(snapshot, max_heap))
+        {
+            Some(runtime) => (runtime, true),
+            None => (new_runtime(None, max_heap), false),
         };
-    }
 
-    globalThis.btoa = (str) => {
-        const encoder = new TextEncoder();
-        return Deno.core.ops.op_base64_encode(str);
-    };
+        let abort = Arc::new(OnceLock::<String>::new());
+        {
+            let abort = abort.clone();
+            let handle = runtime.v8_isolate().thread_safe_handle();
+            runtime.add_near_heap_limit_callback(move |current, _initial| {
+                let _ = abort.set(format!(
+                    "Out of memory: heap limit of {} MB reached",
+                    limits.max_heap_mb
+                ));
+                handle.terminate_execution();
+                // 只放宽一次，给正在终止的脚本留出余量，之后上限保持不变
+                current.max(max_heap + HEAP_HEADROOM)
+            });
+        }
 
-    globalThis.atob = (base64) => {
-        const bytes = Deno.core.ops.op_base64_decode(base64);
-        return new TextDecoder().decode(bytes);
-    };
+        {
+            let state = runtime.op_state();
+            let mut state = state.borrow_mut();
+            state.put(DbHandle { image, asset });
+            state.put(TimeOrigin(Instant::now()));
+            state.put(ToolContext::default());
+        }
 
-    globalThis.Base64 = {
-        encode: (data) => {
-            const bytes = typeof data === 'string' ? new TextEncoder().encode(data) : data;
-            return Deno.core.ops.op_base64_encode(bytes);
-        },
-        decode: (str) => Deno.core.ops.op_base64_decode(str) // 返回 Uint8Array
-    };
+        if !loaded {
+            load_prelude(&mut runtime)?;
+        }
+        if let Some(reason) = abort.get() {
+            anyhow::bail!("Failed to load JS libraries: {}", reason);
+        }
 
-    // --- Performance ---
-    if (typeof performance === "undefined") {
-        globalThis.performance = { now: () => Deno.core.ops.op_performance_now() };
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        Ok(Self {
+            runtime,
+            rt,
+            limits,
+            abort,
+        })
     }
-"#
-}
-
-fn get_setup_script() -> String {
-    let memfs_polyfill = r#"
-    const MEMFS_LIMIT = 50 * 1024 * 1024;
-    const vfs = new Map();
-    let currentSize = 0;
 
-    function normalizePath(p) {
-        return p.replace(/^[\.\/]+/, '');
+    /// 被终止过的环境不能再使用
+    pub(crate) fn is_aborted(&self) -> bool {
+        self.abort.get().is_some()
     }
 
-    const MemFS = {
-        writeFileSync: (path, data, options) => {
-            const key = normalizePath(path);
-            let content;
-
-            // 统一转为 Uint8Array
-            if (typeof data === 'string') {
-                content = new TextEncoder().encode(data);
-            } else if (data instanceof Uint8Array) {
-                content = data;
-            } else {
-                // 尝试处理 buffer-like
-                content = new Uint8Array(data);
-            }
-
-            // 检查大小限制
-            const newSize = content.length;
-            const oldSize = vfs.has(key) ? vfs.get(key).length : 0;
-
-            if (currentSize - oldSize + newSize > MEMFS_LIMIT) {
-                throw new Error(`❌ MemFS Limit Exceeded: Cannot write file '${path}'. Storage full.`);
-            }
-
-            vfs.set(key, content);
-            currentSize = currentSize - oldSize + newSize;
-            return undefined;
-        },
-
-        readFileSync: (path, options) => {
-            const key = normalizePath(path);
-
-            if (vfs.has(key)) {
-                const data = vfs.get(key);
-                // 处理编码参数 (简单支持 utf8)
-                if (options === 'utf8' || (typeof options === 'object' && options.encoding === 'utf8')) {
-                    return new TextDecoder().decode(data);
-                }
-                return data;
-            }
+    pub(crate) fn run(&mut self, ctx: ToolContext, code: String) -> Result<CodeResult, Error> {
+        let code = format!(
+            r#"(async () => {{
+            globalThis.__internal_output = undefined;
+            try {{
+                globalThis.__internal_output = await (async () => {{
+                    "use strict";
+                    {}
+                }})();
+            }} catch (error) {{
+                globalThis.__internal_output = error;
+            }}
+        }})()"#,
+            code
+        );
+        let (tx, rx) = mpsc::channel::<String>();
+        let (tx_img, rx_img) = mpsc::channel::<AssetId>();
+        let (tx_asset, rx_asset) = mpsc::channel::<AssetId>();
+        {
+            // 同类型的状态会被替换，每次执行使用新的通道
+            let state = self.runtime.op_state();
+            let mut state = state.borrow_mut();
+            state.put(LogSender(tx));
+            state.put(UuidSender {
+                image: tx_img,
+                asset: tx_asset,
+            });
+            state.put(ctx.clone());
+        }
 
-            if (globalThis.contain_blob('asset', key)) {
-                try {
-                    const bytes = globalThis.load_blob('asset', key);
-                    if (options === 'utf8' || (typeof options === 'object' && options.encoding === 'utf8')) {
-                        return new TextDecoder().decode(bytes);
-                    }
-                    return bytes;
-                } catch (e) {
-                    throw new Error(`ENOENT: no such file or directory, open '${path}'. \n(Also failed to load blob by UUID from DB)`);
+        let timeout = Duration::from_secs(self.limits.wall_timeout_secs);
+        let abort = self.abort.clone();
+        let js_runtime = &mut self.runtime;
+        let res = self.rt.block_on(async {
+            ctx.progress("Running script", None);
+            let _watchdog = spawn_watchdog(
+                js_runtime.v8_isolate().thread_safe_handle(),
+                ctx.clone(),
+                timeout,
+                abort,
+            );
+            let _ = js_runtime.execute_script("<user_code>", code)?;
+
+            let _ = js_runtime.run_event_loop(Default::default()).await?;
+
+            let result_str: String = {
+                scope!(scope, js_runtime);
+                let context = scope.get_current_context();
+                let global = context.global(scope);
+                let output_key = v8::String::new(scope, "__internal_output").unwrap();
+                let output_val = global.get(scope, output_key.into()).unwrap();
+
+                if output_val.is_native_error() {
+                    let e = v8::Local::<v8::Value>::try_from(output_val)
+                        .map_err(|_| anyhow!("Failed to cast error object"))?;
+                    let js_error = deno_core::error::JsError::from_v8_exception(scope, e);
+                    Err(anyhow!("Runtime Error: {}", js_error.to_string()))
+                } else if output_val.is_undefined() {
+                    Ok("undefined".to_string())
+                } else {
+                    let serialized =
+                        deno_core::serde_v8::from_v8::<serde_json::Value>(scope, output_val)
+                            .map(|v| v.to_string())
+                            .unwrap_or_else(|_| output_val.to_rust_string_lossy(scope));
+                    Ok(serialized)
                 }
-            }
+            }?;
 
-            if (globalThis.contain_blob('image', key)){
-                try {
-                    const bytes = globalThis.load_blob('image', key);
-                    if (options === 'utf8' || (typeof options === 'object' && options.encoding === 'utf8')) {
-                        return new TextDecoder().decode(bytes);
-                    }
-                    return bytes;
-                } catch (e) {
-                    throw new Error(`ENOENT: no such file or directory, open '${path}'. \n(Also failed to load blob by UUID from DB)`);
-                }
-            }
-            throw new Error(`ENOENT: no such file or directory, open '${path}'. `);
-        },
+            Ok::<String, Error>(result_str)
+        });
+        // 被终止时V8的错误只是"execution terminated"，换成终止原因
+        let (res, error) = match self.abort.get() {
+            Some(reason) => (String::new(), Some(reason.clone())),
+            None => (res?, None),
+        };
 
-        existsSync: (path) => {
-            const key = normalizePath(path);
-            return vfs.has(key) || globalThis.contain_blob('image', key) || globalThis.contain_blob('asset', key);
-        },
-        mkdirSync: () => {},
-        statSync: (path) => {
-            const key = normalizePath(path);
-            if (!vfs.has(key)) throw new Error(`ENOENT: '${path}'`);
-            return { isFile: () => true, isDirectory: () => false, size: vfs.get(key).length };
-        },
-        unlinkSync: (path) => {
-                const key = normalizePath(path);
-                if (vfs.has(key)) {
-                    currentSize -= vfs.get(key).length;
-                    vfs.delete(key);
-                }
-        },
-        readdirSync: () => Array.from(vfs.keys()),
-
-        // Promise 版本
-        promises: {
-            readFile: async (...args) => MemFS.readFileSync(...args),
-            writeFile: async (...args) => MemFS.writeFileSync(...args),
-            unlink: async (...args) => MemFS.unlinkSync(...args),
-            mkdir: async () => {},
-            stat: async (...args) => MemFS.statSync(...args),
-            access: async (path) => { if (!MemFS.existsSync(path)) throw new Error('ENOENT'); }
-        }
-    };
-    globalThis.fs = MemFS;
-    globalThis.process = {
-        env: {},
-        version: 'v16.0.0',
-        versions: { node: '16.0.0' },
-        platform: 'browser',
-        browser: true,
-        cwd: () => '/',
-        stdout: { write: (msg) => console.log(msg) },
-        stderr: { write: (msg) => console.error(msg) },
-        nextTick: (cb, ...args) => queueMicrotask(() => cb(...args))
-    };
-"#;
-    let require_cases = LOAD_SOURCE
-        .iter()
-        .map(|lib| {
-            format!(
-                "case '{}': return globalThis['{}'];",
-                lib.require_name, lib.global_var
-            )
+        // 发送方还在OpState里，脚本已经结束，只取出已经发送的内容
+        let logs: String = rx.try_iter().collect();
+        let uuids_img: Vec<AssetId> = rx_img.try_iter().collect();
+        let uuids_asset: Vec<AssetId> = rx_asset.try_iter().collect();
+        Ok(CodeResult {
+            return_value: res,
+            terminal: logs,
+            error,
+            uuids_img: uuids_img,
+            uuids_asset: uuids_asset,
         })
-        .collect::<Vec<_>>()
-        .join("\n");
-    let available_libs = LOAD_SOURCE
-        .iter()
-        .map(|lib| lib.require_name)
-        .collect::<Vec<_>>()
-        .join(", ");
-    r#"if (typeof structuredClone === "undefined" && typeof _ !== "undefined") {
-        globalThis.structuredClone = (value) => _.cloneDeep(value);
-    } else if (typeof structuredClone === "undefined") {
-        globalThis.structuredClone = (value) => JSON.parse(JSON.stringify(value));
     }
-    {memfs_polyfill}
-    globalThis.require = function(name) {
-        switch(name) {
-            {require_cases}
-            case 'fs': return MemFS;
-            case 'fs/promises': return MemFS.promises;
-            case 'path': return {
-                resolve: (...args) => args.join('/').replace(/\/+/g, '/'),
-                join: (...args) => args.join('/').replace(/\/+/g, '/'),
-                basename: (p) => p.split('/').pop(),
-                extname: (p) => {{ const i = p.lastIndexOf('.'); return i < 0 ? '' : p.slice(i); }}
-            };
-
-            case 'os': return {
-                platform: () => 'browser',
-                arch: () => 'x64',
-                tmpdir: () => '/tmp'
-            };
-            default: throw new Error(`❌ Module '${name}' not found. Available: {available_libs}`);
-        }
-    };
-    function op_anybuffer_to_uint8array(data) {
-        let buffer;
-        if (data instanceof ArrayBuffer){
-            buffer = new Uint8Array(data);
-        } else if (data instanceof Array) {
-            buffer = Uint8Array.from(data);
-        } else if (typeof data === 'string') {
-            const binString = atob(data);
-            buffer = new Uint8Array(binString.length);
-            for (let i = 0; i < binString.length; i++) {
-                buffer[i] = binString.charCodeAt(i);
-            }
-        } else if (data instanceof Uint8Array) {
-            buffer = data;
-        }else {
-            buffer = UInt8Array.from(data);
-        }
-        return buffer;
-    }
-
-    globalThis.html = (content) => {
-        return "{FN_RAWHTML}" + content;
-    };
-    globalThis.svg = (content) => {
-        return "{FN_RAWSVG}" + content;
-    };
-
-    globalThis.load_blob = (schema, uuid) => Deno.core.ops.op_load_blob(schema, uuid);
-    globalThis.save_blob = (schema, img) => {
-        const img_bin = op_anybuffer_to_uint8array(img);
-        return Deno.core.ops.op_save_blob(schema, img_bin);
-    };
-    globalThis.save_svg = (svg) => Deno.core.ops.op_save_svg(svg);
-    globalThis.contain_blob = (uuid) => Deno.core.ops.op_contain_blob(uuid);
-    globalThis.convert_to_png = (img) => {
-        const img_bin = op_anybuffer_to_uint8array(img);
-        return Deno.core.ops.op_convert_to_png(img_bin);
-    };
-    globalThis.QRCode = {
-        save: (text, format = 'png') => {
-            const str = String(text);
-            if (!str) throw new Error("QRCode: Text cannot be empty");
-            switch (format.toLowerCase()) {
-                case 'png': {
-                    const bytes = Deno.core.ops.op_qrcode_png(str);
-                    return save_blob('image', bytes);
-                }
-                case 'svg': {
-                    const svgStr = Deno.core.ops.op_qrcode_svg(str);
-                    return Deno.core.ops.op_save_svg(svgStr);
-                }
-                default:
-                    throw new Error(`QRCode: Unsupported format '${format}'. Use 'png' or 'svg'.`);
-            }
-        },
-        encode: (text, format = 'png') => {
-            const str = String(text);
-            switch (format.toLowerCase()) {
-                case 'png': return Deno.core.ops.op_qrcode_png(str); // Returns Uint8Array
-                case 'svg': return Deno.core.ops.op_qrcode_svg(str); // Returns String
-                default: throw new Error(`QRCode: Unsupported format '${format}'`);
-            }
-        },
-        decode: (input) => {
-            let buffer;
-            if (typeof input === 'string') {
-                try {
-                    buffer = globalThis.load_blob('image', input);
-                } catch (e) {
-                    throw new Error("QRCode.decode: Failed to retrieve image from UUID.");
-                }
-            } else if (input instanceof Uint8Array) {
-                buffer = input;
-            } else {
-                throw new Error("QRCode.decode: Input must be Uint8Array or Image UUID string.");
-            }
-            return Deno.core.ops.op_qrcode_decode(buffer);
-        }
-    };"#
-    .replace("{require_cases}", &require_cases)
-    .replace("{available_libs}", &available_libs)
-    .replace("{memfs_polyfill}", &memfs_polyfill)
-    .replace("{RAWHTML}", FN_RAWHTML)
-    .replace("{RAWSVG}", FN_RAWSVG)
 }
 
 fn run_code(
     image: Arc<dyn BlobStorage>,
     asset: Arc<dyn BlobStorage>,
+    ctx: ToolContext,
+    limits: JsLimits,
+    snapshot: bool,
     code: String,
 ) -> Result<CodeResult, Error> {
-    let code = format!(
-        r#"(async () => {{
-            try {{
-                globalThis.__internal_output = await (async () => {{
-                    "use strict";
-                    {}
-                }})();
-            }} catch (error) {{
-                globalThis.__internal_output = error;
-            }}
-        }})()"#,
-        code
-    );
-    let (tx, rx) = mpsc::channel::<String>();
-    let (tx_img, rx_img) = mpsc::channel::<AssetId>();
-    let (tx_asset, rx_asset) = mpsc::channel::<AssetId>();
+    Sandbox::new(image, asset, limits, snapshot)?.run(ctx, code)
+}
 
-    let mut js_runtime = JsRuntime::new(RuntimeOptions {
-        extensions: vec![sandbox_ext::init()],
-        ..Default::default()
-    });
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SledBlobStorage;
 
-    {
-        let state = js_runtime.op_state();
-        let mut state = state.borrow_mut();
-        state.put(LogSender(tx.clone()));
-        state.put(UuidSender {
-            image: tx_img.clone(),
-            asset: tx_asset.clone(),
-        });
-        state.put(DbHandle { image, asset });
-        state.put(TimeOrigin(Instant::now()));
-    }
+    #[test]
+    fn terminates_runaway_script() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let blob: Arc<dyn BlobStorage> = Arc::new(SledBlobStorage::new_from_db(&db, "t").unwrap());
+        let limits = JsLimits {
+            wall_timeout_secs: 1,
+            max_heap_mb: 256,
+        };
+        let run = |code: &str| {
+            run_code(
+                blob.clone(),
+                blob.clone(),
+                ToolContext::default(),
+                limits,
+                true,
+                code.to_string(),
+            )
+            .unwrap()
+        };
 
-    let rt = tokio::runtime::Builder::new_current_thread()
-        .enable_all()
-        .build()?;
+        let result = run("console.log('before'); while (true) {}");
+        assert_eq!(result.terminal, "before\n");
+        assert!(result.error.unwrap().contains("timed out"));
 
-    let res = rt.block_on(async {
-        let setup_script = get_env_script();
-        js_runtime.execute_script("<env>", setup_script)?;
+        let result = run("const a = []; while (true) { a.push(new Array(1e6).fill(1)); }");
+        assert!(result.error.unwrap().contains("Out of memory"));
 
-        if !LOAD_SOURCE.is_empty() {
-            for lib in LOAD_SOURCE {
-                js_runtime.execute_script(lib.require_name, lib.src)?;
-                if let Some(hook) = lib.after_hook {
-                    let hook_name = format!("{}_after_hook", lib.require_name);
-                    js_runtime.execute_script(hook_name, hook)?;
-                }
-            }
+        let result = run("return 1 + 1;");
+        assert!(result.error.is_none());
+        assert_eq!(result.return_value, "2");
+    }
+
+    #[test]
+    fn snapshot_matches_plain_prelude() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let blob: Arc<dyn BlobStorage> = Arc::new(SledBlobStorage::new_from_db(&db, "t").unwrap());
+        for snapshot in [true, false] {
+            let mut sandbox =
+                Sandbox::new(blob.clone(), blob.clone(), JsLimits::default(), snapshot).unwrap();
+            let code = "console.log(performance.now() >= 0); \
+                return [typeof require('d3').scaleLinear, typeof fs.writeFileSync].join();";
+            let result = sandbox.run(ToolContext::default(), code.into()).unwrap();
+            assert_eq!(result.terminal, "true\n");
+            assert_eq!(result.return_value, "\"function,function\"");
         }
-        let setup_script = get_setup_script();
-        js_runtime.execute_script("<setup>", setup_script)?;
-
-        let _ = js_runtime.execute_script("<user_code>", code)?;
-
-        let _ = js_runtime.run_event_loop(Default::default()).await?;
-
-        let result_str: String = {
-            scope!(scope, js_runtime);
-            let context = scope.get_current_context();
-            let global = context.global(scope);
-            let output_key = v8::String::new(scope, "__internal_output").unwrap();
-            let output_val = global.get(scope, output_key.into()).unwrap();
-
-            if output_val.is_native_error() {
-                let e = v8::Local::<v8::Value>::try_from(output_val)
-                    .map_err(|_| anyhow!("Failed to cast error object"))?;
-                let js_error = deno_core::error::JsError::from_v8_exception(scope, e);
-                Err(anyhow!("Runtime Error: {}", js_error.to_string()))
-            } else if output_val.is_undefined() {
-                Ok("undefined".to_string())
-            } else {
-                let serialized =
-                    deno_core::serde_v8::from_v8::<serde_json::Value>(scope, output_val)
-                        .map(|v| v.to_string())
-                        .unwrap_or_else(|_| output_val.to_rust_string_lossy(scope));
-                Ok(serialized)
-            }
-        }?;
-
-        Ok::<String, Error>(result_str)
-    })?;
-
-    drop(js_runtime);
-    drop(tx);
-    drop(tx_img);
-    drop(tx_asset);
-
-    let logs: String = rx.into_iter().collect();
-    let uuids_img: Vec<AssetId> = rx_img.into_iter().collect();
-    let uuids_asset: Vec<AssetId> = rx_asset.into_iter().collect();
-    Ok(CodeResult {
-        return_value: res,
-        terminal: logs,
-        uuids_img: uuids_img,
-        uuids_asset: uuids_asset,
-    })
+        assert_eq!(
+            PRELUDE_SNAPSHOT_MARKER,
+            snapshot_marker(&sandbox_ext::init())
+        );
+        assert!(prelude_snapshot().is_some());
+    }
+
+    #[test]
+    fn limits_accept_old_timeout_name() {
+        let limits: JsLimits = serde_json::from_str(r#"{"timeout_secs": 5}"#).unwrap();
+        assert_eq!(limits.wall_timeout_secs, 5);
+        assert_eq!(limits.max_heap_mb, JsLimits::default().max_heap_mb);
+    }
 }
@@ -4,16 +4,23 @@ use schemars::JsonSchema;
 use schemars::schema_for;
 use serde::Deserialize;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
+use crate::ImageFormatPolicy;
 use crate::MessageContent;
 use crate::Tool;
+use crate::ToolContext;
 use crate::ToolDescription;
-use crate::blob::BlobStorage;
+use crate::blob::{BlobMeta, BlobStorage};
+use crate::ToolKind;
 use crate::convert_svg_to_png;
+use crate::normalize_image;
 use crate::parse_tool_args;
 
 const MAX_TEXT_LEN: usize = 10 * 1024;
+/// 下载进度最多每隔这么久报告一次，除非比例前进了`PROGRESS_STEP`
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+const PROGRESS_STEP: f32 = 0.01;
 
 #[derive(Deserialize, JsonSchema)]
 struct FetchArgs {
@@ -41,6 +48,42 @@ struct FetchArgs {
     label: Option<String>,
 }
 
+/// 读取响应体，知道总长度时按已下载的比例报告进度
+async fn read_body(
+    ctx: &ToolContext,
+    mut res: reqwest::Response,
+) -> Result<Vec<u8>, anyhow::Error> {
+    let total = res.content_length();
+    let mut bytes = Vec::with_capacity(total.unwrap_or(0).min(64 << 20) as usize);
+    // 上一次报告的时间和比例，数据块很小时不必每块都报告
+    let mut reported: Option<(Instant, f32)> = None;
+    while let Some(chunk) = res.chunk().await? {
+        bytes.extend_from_slice(&chunk);
+        let fraction = total.map(|t| bytes.len() as f32 / t.max(1) as f32);
+        let due = reported.is_none_or(|(at, last)| {
+            at.elapsed() >= PROGRESS_INTERVAL || fraction.is_some_and(|f| f - last >= PROGRESS_STEP)
+        });
+        if due {
+            ctx.progress(format!("Downloaded {} bytes", bytes.len()), fraction);
+            reported = Some((Instant::now(), fraction.unwrap_or(0.0)));
+        }
+    }
+    Ok(bytes)
+}
+
+/// 下载数据的元数据，文件名取URL路径的最后一段
+fn url_meta(url: &str) -> BlobMeta {
+    let meta = BlobMeta::from_tool(ToolKind::Curl);
+    let name = reqwest::Url::parse(url)
+        .ok()
+        .and_then(|u| Some(u.path_segments()?.next_back()?.to_string()))
+        .filter(|name| !name.is_empty());
+    match name {
+        Some(name) => meta.with_filename(name),
+        None => meta,
+    }
+}
+
 #[derive(Deserialize, JsonSchema, Copy, Clone)]
 enum FetchMethod {
     #[serde(alias = "GET", alias = "get")]
@@ -53,6 +96,7 @@ pub struct FetchTool {
     image : Arc<dyn BlobStorage>,
     asset: Arc<dyn BlobStorage>,
     client: reqwest::Client,
+    image_format: ImageFormatPolicy,
 }
 
 impl FetchTool {
@@ -65,8 +109,15 @@ impl FetchTool {
                 .timeout(Duration::from_secs(40))
                 .build()
                 .unwrap_or_else(|_| reqwest::Client::new()),
+            image_format: ImageFormatPolicy::default(),
         }
     }
+
+    /// 下载的图片保存时使用的格式
+    pub fn with_image_format(mut self, policy: ImageFormatPolicy) -> Self {
+        self.image_format = policy;
+        self
+    }
 }
 
 #[async_trait::async_trait]
@@ -89,8 +140,13 @@ impl Tool for FetchTool {
         }
     }
 
-    async fn call(&self, args: &str) -> Result<Vec<MessageContent>, anyhow::Error> {
+    async fn call(
+        &self,
+        ctx: &ToolContext,
+        args: &str,
+    ) -> Result<Vec<MessageContent>, anyhow::Error> {
         let args: FetchArgs = parse_tool_args(args)?;
+        ctx.progress(format!("Fetching {}", args.url), None);
         let mut req_builder = match args.method.unwrap_or(FetchMethod::Get) {
             FetchMethod::Get => self.client.get(&args.url),
             FetchMethod::Post => self.client.post(&args.url),
@@ -107,6 +163,10 @@ impl Tool for FetchTool {
                     .body(content);
             }
         }
+        // 客户端的超时之外，不超过这次调用剩余的时间
+        if let Some(remaining) = ctx.remaining() {
+            req_builder = req_builder.timeout(remaining.min(Duration::from_secs(40)));
+        }
         let res = req_builder.send().await?;
         let status = res.status();
         if !status.is_success() {
@@ -156,11 +216,17 @@ impl Tool for FetchTool {
             | (mime::APPLICATION, mime::XML) => Ok(vec![MessageContent::Text(res.text().await?)]),
 
             (mime::IMAGE, sub_type) => {
+                // 格式可能被转换，MIME类型从保存的数据中判断
+                let meta = url_meta(&args.url);
                 let uuid = if sub_type.as_str().to_lowercase().contains("svg") {
-                    self.image.save(&convert_svg_to_png(&res.text().await?)?)?
+                    self.image.save_with_meta(&normalize_image(
+                        convert_svg_to_png(&res.text().await?)?,
+                        self.image_format,
+                    )?, meta)?
                 } else {
-                    let bytes = res.bytes().await?.to_vec();
-                    self.image.save(&super::convert_to_png(bytes)?)?
+                    let bytes = read_body(ctx, res).await?;
+                    self.image
+                        .save_with_meta(&normalize_image(bytes, self.image_format)?, meta)?
                 };
                 Ok(vec![MessageContent::ImageRef(
                     uuid,
@@ -174,12 +240,15 @@ impl Tool for FetchTool {
                         return Ok(vec![MessageContent::Text(res.text().await?)]);
                     }
                 }
-                let bytes = res.bytes().await?.to_vec();
+                let bytes = read_body(ctx, res).await?;
 
                 match String::from_utf8(bytes.clone()) {
                     Ok(text) if text.len() < MAX_TEXT_LEN => Ok(vec![MessageContent::Text(text)]),
                     _ => {
-                        let uuid = self.asset.save(&bytes)?;
+                        let uuid = self.asset.save_with_meta(
+                            &bytes,
+                            url_meta(&args.url).with_mime(mime_type.to_string()),
+                        )?;
                         tracing::info!("Blob fetched and saved as asset {}", uuid);
                         Ok(vec![MessageContent::AssetRef(uuid, mime_type.to_string())])
                     }
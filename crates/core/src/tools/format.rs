@@ -0,0 +1,458 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use strum::{Display, EnumIter, EnumString};
+use uuid::Uuid;
+
+use crate::{
+    FN_ARGS, FN_EXIT, FN_NAME, FN_RESULT, ToolUse,
+    tools::prompt_template::{self, HERMES_CALL_TEMPLATE, LLAMA3_CALL_TEMPLATE},
+};
+
+/// 文本协议下工具调用的"语法"
+/// 负责标记识别、参数提取、历史回写以及system prompt中的格式说明
+pub trait ToolCallFormat: Send + Sync {
+    /// 一段工具调用开始的标记
+    fn start_marker(&self) -> &'static str;
+
+    /// 一段工具调用结束的标记，取最先出现的一个
+    /// 流结束时未闭合的调用同样会被解析
+    fn end_markers(&self) -> &'static [&'static str];
+
+    /// 解析 start_marker 与 end_marker 之间的文本
+    /// 一段文本中可以包含多个调用
+    fn parse_block(&self, block: &str) -> Vec<ToolUse>;
+
+    /// 把一个工具调用写回到assistant的历史中
+    fn serialize_call(&self, tool_use: &ToolUse) -> String;
+
+    /// 工具结果的前缀和后缀
+    fn result_prefix(&self) -> String;
+    fn result_suffix(&self) -> String;
+
+    /// system prompt中描述调用格式的部分
+    fn prompt_section(&self, lang: whatlang::Lang, tool_names: &str, parallel: bool) -> String;
+
+    /// 流式解析时需要保留的最大长度，防止标记被切断
+    /// 最长的标记再加上一个UTF-8字符，切割点向前对齐字符边界后仍然能保留被切断的标记
+    fn max_marker_len(&self) -> usize {
+        self.end_markers()
+            .iter()
+            .map(|m| m.len())
+            .chain(std::iter::once(self.start_marker().len()))
+            .max()
+            .unwrap_or(0)
+            + MAX_UTF8_CHAR_LEN
+    }
+}
+
+/// 一个UTF-8字符最多占用的字节数
+const MAX_UTF8_CHAR_LEN: usize = 4;
+
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Hash, EnumString, Display, EnumIter, Serialize, Deserialize,
+)]
+#[strum(serialize_all = "snake_case")]
+pub enum ToolFormatKind {
+    /// Qwen-Agent: ✿FUNCTION✿ / ✿ARGS✿ / ✿RESULT✿ / ✿RETURN✿
+    #[strum(serialize = "qwen")]
+    Qwen,
+    /// Hermes: <tool_call>{json}</tool_call>
+    #[strum(serialize = "hermes")]
+    Hermes,
+    /// Llama-3: <|python_tag|>{json}
+    #[strum(serialize = "llama3")]
+    Llama3,
+}
+
+static QWEN_FORMAT: QwenFormat = QwenFormat;
+static HERMES_FORMAT: HermesFormat = HermesFormat;
+static LLAMA3_FORMAT: Llama3Format = Llama3Format;
+
+impl ToolFormatKind {
+    pub fn formatter(&self) -> &'static dyn ToolCallFormat {
+        match self {
+            ToolFormatKind::Qwen => &QWEN_FORMAT,
+            ToolFormatKind::Hermes => &HERMES_FORMAT,
+            ToolFormatKind::Llama3 => &LLAMA3_FORMAT,
+        }
+    }
+
+    /// 根据模型名称猜测格式，无法判断时使用Qwen
+    pub fn detect(model: &str) -> Self {
+        let model = model.to_lowercase();
+        if model.contains("hermes") {
+            ToolFormatKind::Hermes
+        } else if model.contains("llama-3") || model.contains("llama3") {
+            ToolFormatKind::Llama3
+        } else {
+            ToolFormatKind::Qwen
+        }
+    }
+}
+
+fn trim_marker_text(s: &str) -> String {
+    s.trim()
+        .trim_matches(':')
+        .trim_matches('：')
+        .trim()
+        .to_string()
+}
+
+/// json中的参数可能是字符串或者对象，统一成字符串
+fn json_args_to_string(v: Option<&Value>) -> String {
+    match v {
+        Some(Value::String(s)) => s.clone(),
+        Some(v) => v.to_string(),
+        None => "{}".to_string(),
+    }
+}
+
+/// 历史中的参数如果是json就原样嵌入，否则当作字符串
+fn args_to_json(args: &str) -> Value {
+    serde_json::from_str(args).unwrap_or_else(|_| Value::String(args.to_string()))
+}
+
+pub struct QwenFormat;
+
+impl ToolCallFormat for QwenFormat {
+    fn start_marker(&self) -> &'static str {
+        FN_NAME
+    }
+
+    fn end_markers(&self) -> &'static [&'static str] {
+        &[FN_RESULT, FN_EXIT]
+    }
+
+    fn parse_block(&self, block: &str) -> Vec<ToolUse> {
+        let mut calls = Vec::new();
+        for segment in block.split(FN_NAME) {
+            let mut parts = segment.split(FN_ARGS).peekable();
+            let name = trim_marker_text(parts.next().unwrap_or_default());
+            if name.is_empty() && parts.peek().is_some() {
+                tracing::warn!("ToolName is empty, current block: {}", block);
+            }
+            //如果有连续的两个args，那就当作对同一个fn name使用多次
+            for args in parts {
+                calls.push(ToolUse {
+                    use_id: Uuid::new_v4(),
+                    function_name: name.clone(),
+                    args: trim_marker_text(args),
+                    call_id: None,
+                });
+            }
+        }
+        calls
+    }
+
+    fn serialize_call(&self, tool_use: &ToolUse) -> String {
+        format!(
+            "\n{FN_NAME}: {fn_name}\n{FN_ARGS}: {fn_args}\n",
+            fn_name = tool_use.function_name,
+            fn_args = tool_use.args,
+        )
+    }
+
+    fn result_prefix(&self) -> String {
+        format!("{FN_RESULT}: ")
+    }
+
+    fn result_suffix(&self) -> String {
+        format!("\n{FN_EXIT}\n")
+    }
+
+    fn prompt_section(&self, lang: whatlang::Lang, tool_names: &str, parallel: bool) -> String {
+        let templates = prompt_template::get_templates(lang);
+        let tool_fmt_string = if parallel {
+            templates.parallel_call_template
+        } else {
+            templates.single_call_template
+        };
+        tool_fmt_string
+            .replace("{tool_names}", tool_names)
+            .replace("{FN_NAME}", FN_NAME)
+            .replace("{FN_ARGS}", FN_ARGS)
+            .replace("{FN_RESULT}", FN_RESULT)
+            .replace("{FN_EXIT}", FN_EXIT)
+    }
+}
+
+pub struct HermesFormat;
+
+const HERMES_CALL: &str = "<tool_call>";
+const HERMES_CALL_END: &str = "</tool_call>";
+
+impl ToolCallFormat for HermesFormat {
+    fn start_marker(&self) -> &'static str {
+        HERMES_CALL
+    }
+
+    fn end_markers(&self) -> &'static [&'static str] {
+        &[HERMES_CALL_END]
+    }
+
+    fn parse_block(&self, block: &str) -> Vec<ToolUse> {
+        match serde_json::from_str::<Value>(block.trim()) {
+            Ok(v) => vec![ToolUse {
+                use_id: Uuid::new_v4(),
+                function_name: v
+                    .get("name")
+                    .and_then(|n| n.as_str())
+                    .unwrap_or_default()
+                    .trim()
+                    .to_string(),
+                args: json_args_to_string(v.get("arguments")),
+                call_id: None,
+            }],
+            Err(e) => {
+                tracing::warn!("Invalid hermes tool call {}: {}", block, e);
+                vec![]
+            }
+        }
+    }
+
+    fn serialize_call(&self, tool_use: &ToolUse) -> String {
+        let call = serde_json::json!({
+            "name": tool_use.function_name,
+            "arguments": args_to_json(&tool_use.args),
+        });
+        format!("\n{HERMES_CALL}\n{call}\n{HERMES_CALL_END}\n")
+    }
+
+    fn result_prefix(&self) -> String {
+        "<tool_response>\n".to_string()
+    }
+
+    fn result_suffix(&self) -> String {
+        "\n</tool_response>\n".to_string()
+    }
+
+    fn prompt_section(&self, _lang: whatlang::Lang, tool_names: &str, _parallel: bool) -> String {
+        HERMES_CALL_TEMPLATE.replace("{tool_names}", tool_names)
+    }
+}
+
+pub struct Llama3Format;
+
+const LLAMA3_PYTHON_TAG: &str = "<|python_tag|>";
+
+impl ToolCallFormat for Llama3Format {
+    fn start_marker(&self) -> &'static str {
+        LLAMA3_PYTHON_TAG
+    }
+
+    fn end_markers(&self) -> &'static [&'static str] {
+        &["<|eom_id|>", "<|eot_id|>"]
+    }
+
+    /// 服务端通常会去掉`<|eom_id|>`/`<|eot_id|>`，调用一直持续到流结束
+    /// 因此逐个读取json，部分模型会用`;`分隔多个调用，最后一个json之后的文本被忽略
+    fn parse_block(&self, block: &str) -> Vec<ToolUse> {
+        let mut calls = Vec::new();
+        let mut rest = block;
+        loop {
+            rest = rest.trim_start_matches(|c: char| c == ';' || c.is_whitespace());
+            if rest.is_empty() {
+                break;
+            }
+            let mut values = serde_json::Deserializer::from_str(rest).into_iter::<Value>();
+            match values.next() {
+                Some(Ok(v)) if v.is_object() => calls.push(ToolUse {
+                    use_id: Uuid::new_v4(),
+                    function_name: v
+                        .get("name")
+                        .and_then(|n| n.as_str())
+                        .unwrap_or_default()
+                        .trim()
+                        .to_string(),
+                    args: json_args_to_string(v.get("parameters").or(v.get("arguments"))),
+                    call_id: None,
+                }),
+                Some(_) if !calls.is_empty() => {
+                    tracing::debug!("Ignore text after llama3 tool calls: {}", rest);
+                    break;
+                }
+                Some(_) => {
+                    tracing::warn!("Invalid llama3 tool call: {}", block);
+                    break;
+                }
+                None => break,
+            }
+            rest = &rest[values.byte_offset()..];
+        }
+        calls
+    }
+
+    fn serialize_call(&self, tool_use: &ToolUse) -> String {
+        let call = serde_json::json!({
+            "name": tool_use.function_name,
+            "parameters": args_to_json(&tool_use.args),
+        });
+        format!("{LLAMA3_PYTHON_TAG}{call}")
+    }
+
+    fn result_prefix(&self) -> String {
+        String::new()
+    }
+
+    fn result_suffix(&self) -> String {
+        String::new()
+    }
+
+    fn prompt_section(&self, _lang: whatlang::Lang, tool_names: &str, _parallel: bool) -> String {
+        LLAMA3_CALL_TEMPLATE.replace("{tool_names}", tool_names)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use strum::IntoEnumIterator;
+
+    use super::*;
+    use crate::{StreamEvent, StreamParser};
+
+    fn tool_use(name: &str, args: &str) -> ToolUse {
+        ToolUse {
+            use_id: Uuid::new_v4(),
+            function_name: name.to_string(),
+            args: args.to_string(),
+            call_id: None,
+        }
+    }
+
+    fn parse_text(kind: ToolFormatKind, text: &str) -> Vec<(String, String)> {
+        let mut parser = StreamParser::new(kind.formatter());
+        let mut events = parser.push(text);
+        events.extend(parser.finish());
+        events
+            .into_iter()
+            .filter_map(|e| match e {
+                StreamEvent::ToolCall(t) => Some((t.function_name, t.args)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn detect_from_model_name() {
+        let cases = [
+            ("Hermes-3-Llama-3.1-8B", ToolFormatKind::Hermes),
+            ("Meta-Llama-3.1-8B-Instruct", ToolFormatKind::Llama3),
+            ("llama3.2:3b", ToolFormatKind::Llama3),
+            ("Qwen2.5-VL-7B-Instruct", ToolFormatKind::Qwen),
+            ("", ToolFormatKind::Qwen),
+        ];
+        for (model, kind) in cases {
+            assert_eq!(ToolFormatKind::detect(model), kind, "{}", model);
+        }
+    }
+
+    #[test]
+    fn serialized_call_parses_back() {
+        for kind in ToolFormatKind::iter() {
+            for args in ["{\"img_idx\":\"a\"}", "abc"] {
+                let text = kind.formatter().serialize_call(&tool_use("zoom_in", args));
+                assert_eq!(
+                    parse_text(kind, &text),
+                    vec![("zoom_in".to_string(), args.to_string())],
+                    "{} {}",
+                    kind,
+                    text
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn result_markers() {
+        let qwen = ToolFormatKind::Qwen.formatter();
+        assert_eq!(qwen.result_prefix(), format!("{FN_RESULT}: "));
+        assert_eq!(qwen.result_suffix(), format!("\n{FN_EXIT}\n"));
+        // 模型模仿结果格式时，调用在结果标记处结束
+        assert!(
+            qwen.end_markers()
+                .iter()
+                .any(|m| qwen.result_prefix().starts_with(m))
+        );
+
+        let hermes = ToolFormatKind::Hermes.formatter();
+        assert_eq!(hermes.result_prefix(), "<tool_response>\n");
+        assert_eq!(hermes.result_suffix(), "\n</tool_response>\n");
+
+        let llama3 = ToolFormatKind::Llama3.formatter();
+        assert!(llama3.result_prefix().is_empty());
+        assert!(llama3.result_suffix().is_empty());
+    }
+
+    #[test]
+    fn prompt_section_fills_markers() {
+        for kind in ToolFormatKind::iter() {
+            let format = kind.formatter();
+            for parallel in [false, true] {
+                let section = format.prompt_section(whatlang::Lang::Eng, "zoom_in, curl", parallel);
+                assert!(section.contains(format.start_marker()), "{}", kind);
+                assert!(!section.contains("{tool_names}"), "{}", kind);
+                assert!(!section.contains("{FN_"), "{}", kind);
+            }
+        }
+
+        let qwen = ToolFormatKind::Qwen.formatter();
+        let single = qwen.prompt_section(whatlang::Lang::Cmn, "a", false);
+        let parallel = qwen.prompt_section(whatlang::Lang::Cmn, "a", true);
+        assert_ne!(single, parallel);
+        for marker in [FN_ARGS, FN_RESULT, FN_EXIT] {
+            assert!(single.contains(marker) && parallel.contains(marker));
+        }
+        // Hermes和Llama-3的格式说明中列出工具名
+        for kind in [ToolFormatKind::Hermes, ToolFormatKind::Llama3] {
+            let section =
+                kind.formatter()
+                    .prompt_section(whatlang::Lang::Eng, "zoom_in, curl", false);
+            assert!(section.contains("zoom_in, curl"), "{}", kind);
+        }
+    }
+
+    #[test]
+    fn max_marker_len_covers_every_marker() {
+        for kind in ToolFormatKind::iter() {
+            let format = kind.formatter();
+            let longest = format
+                .end_markers()
+                .iter()
+                .chain(std::iter::once(&format.start_marker()))
+                .map(|m| m.len())
+                .max()
+                .unwrap();
+            assert_eq!(format.max_marker_len(), longest + MAX_UTF8_CHAR_LEN);
+        }
+    }
+
+    #[test]
+    fn llama3_call_without_end_marker() {
+        let text = "<|python_tag|>{\"name\": \"curl\", \"parameters\": {\"url\": \"https://a.b\"}}\n\nWaiting for the result.";
+        for i in 0..=text.len() {
+            let mut parser = StreamParser::new(ToolFormatKind::Llama3.formatter());
+            let mut events = parser.push(&text[..i]);
+            events.extend(parser.push(&text[i..]));
+            events.extend(parser.finish());
+            let calls: Vec<_> = events
+                .into_iter()
+                .filter_map(|e| match e {
+                    StreamEvent::ToolCall(t) => Some((t.function_name, t.args)),
+                    _ => None,
+                })
+                .collect();
+            assert_eq!(
+                calls,
+                vec![("curl".to_string(), "{\"url\":\"https://a.b\"}".to_string())],
+                "split at {}",
+                i
+            );
+        }
+
+        let calls = Llama3Format.parse_block("{\"name\": \"a\"} ; ;{\"name\": \"b\"}; trailing");
+        let names: Vec<_> = calls.iter().map(|c| c.function_name.as_str()).collect();
+        assert_eq!(names, vec!["a", "b"]);
+        assert!(Llama3Format.parse_block("not json").is_empty());
+        assert!(Llama3Format.parse_block("1 apple").is_empty());
+    }
+}
@@ -1,4 +1,8 @@
-use std::{str::FromStr, sync::Arc};
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
 
 use anyhow::anyhow;
 use resvg::{tiny_skia, usvg};
@@ -7,8 +11,8 @@ use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
 use crate::{
-    MessageContent, Tool, ToolDescription, AssetId, blob::BlobStorage, get_usvg_options,
-    parse_tool_args,
+    MessageContent, Tool, ToolContext, ToolDescription, ToolKind, AssetId, blob::{BlobMeta, BlobStorage},
+    chat_handler::release_with_meta, get_usvg_options, parse_tool_args,
 };
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
@@ -74,6 +78,90 @@ pub enum LayoutMode {
     Absolute { bbox: [f64; 4] },
 }
 
+/// 每个会话的画布保存在键为前缀加会话id的记录中
+/// 长度不是 20 字节，不会被`entries`当作数据
+const MEMO_PREFIX: &[u8] = b"chat/";
+/// 旧版本所有会话共用的画布，上面的图片没有计入引用
+const OLD_MEMO_KEY: &[u8] = b"current";
+/// 迁移后保留旧版本画布的位置，不属于任何会话
+const LEGACY_MEMO_KEY: &[u8] = b"legacy/current";
+
+pub(crate) fn memo_key(chat_id: Uuid) -> Vec<u8> {
+    [MEMO_PREFIX, chat_id.as_bytes().as_slice()].concat()
+}
+
+/// `memo_key`的反向操作，不是画布的键返回 None
+pub(crate) fn memo_key_chat(key: &[u8]) -> Option<Uuid> {
+    Uuid::from_slice(key.strip_prefix(MEMO_PREFIX)?).ok()
+}
+
+/// 画布图层引用的图片，每个图层一次
+pub(crate) fn memo_layer_images(data: &[u8]) -> Result<Vec<AssetId>, anyhow::Error> {
+    let state: MemoState = serde_json::from_slice(data)?;
+    Ok(state
+        .layers
+        .into_iter()
+        .filter_map(|l| match l.kind {
+            LayerKind::ImageRef(id) => Some(id),
+            LayerKind::SvgContent(_) => None,
+        })
+        .collect())
+}
+
+/// 所有会话的画布和保留的旧版本画布引用的图片，每个图层持有一次引用计数
+pub(crate) fn memo_image_refs(memo_db: &dyn BlobStorage) -> Result<Vec<AssetId>, anyhow::Error> {
+    let mut refs = vec![];
+    for key in memo_db.raw_keys()? {
+        if memo_key_chat(&key).is_none() && key != LEGACY_MEMO_KEY {
+            continue;
+        }
+        if let Some(data) = memo_db.get_raw(&key)? {
+            refs.extend(memo_layer_images(&data)?);
+        }
+    }
+    Ok(refs)
+}
+
+/// 把旧版本所有会话共用的画布移到`LEGACY_MEMO_KEY`，返回是否存在
+/// 它无法归属到某个会话，保留下来并像会话的画布一样为每个图层的图片持有一次引用，
+/// 图片已经被删除的图层丢弃
+pub(crate) fn keep_legacy_memo(
+    memo_db: &dyn BlobStorage,
+    image_db: &dyn BlobStorage,
+) -> Result<bool, anyhow::Error> {
+    let Some(data) = memo_db.get_raw(OLD_MEMO_KEY)? else {
+        return Ok(false);
+    };
+    let mut state: MemoState = serde_json::from_slice(&data)?;
+    let mut layers = Vec::with_capacity(state.layers.len());
+    for layer in state.layers {
+        if let LayerKind::ImageRef(id) = layer.kind {
+            if image_db.peek(id, 0)?.is_none() {
+                continue;
+            }
+            image_db.retain(id)?;
+        }
+        layers.push(layer);
+    }
+    state.layers = layers;
+    memo_db.put_raw(LEGACY_MEMO_KEY, &serde_json::to_vec(&state)?)?;
+    memo_db.delete_raw(OLD_MEMO_KEY)?;
+    Ok(true)
+}
+
+/// 删除会话的画布，返回需要释放引用的图片
+pub(crate) fn delete_memo(
+    memo_db: &dyn BlobStorage,
+    chat_id: Uuid,
+) -> Result<Vec<AssetId>, anyhow::Error> {
+    let key = memo_key(chat_id);
+    let Some(data) = memo_db.get_raw(&key)? else {
+        return Ok(vec![]);
+    };
+    memo_db.delete_raw(&key)?;
+    memo_layer_images(&data)
+}
+
 fn default_true() -> bool {
     true
 }
@@ -81,6 +169,8 @@ fn default_true() -> bool {
 pub struct ImageMemoTool {
     image_db: Arc<dyn BlobStorage>,
     memo_db: Arc<dyn BlobStorage>,
+    /// 每个会话一把锁，同一个会话的画布操作依次执行
+    locks: Mutex<HashMap<Uuid, Arc<tokio::sync::Mutex<()>>>>,
 }
 
 #[async_trait::async_trait]
@@ -98,7 +188,7 @@ impl Tool for ImageMemoTool {
 1. **Complex Reasoning**: Draw diagrams/relations.
 2. **Comparison**: Copy images side-by-side.
 3. **State**: Save intermediate results.
-**Note:** Context is persistent across turns."##
+**Note:** Context is persistent across turns of this chat."##
                 .to_string(),
             parameters: serde_json::to_value(schema_for!(ImageMemoArgs)).unwrap(),
             args_format: "JSON.".to_string(),
@@ -109,9 +199,19 @@ impl Tool for ImageMemoTool {
         false
     }
 
-    async fn call(&self, args: &str) -> Result<Vec<MessageContent>, anyhow::Error> {
+    async fn call(
+        &self,
+        ctx: &ToolContext,
+        args: &str,
+    ) -> Result<Vec<MessageContent>, anyhow::Error> {
         let args: ImageMemoArgs = parse_tool_args(args)?;
-        let mut state = self.get_state()?;
+        let chat_id = ctx
+            .chat_id
+            .ok_or(anyhow!("Memo is only available inside a chat"))?;
+        // 读取、修改、保存画布之间不能插入同一会话的其它调用，否则会丢失图层
+        let lock = self.chat_lock(chat_id);
+        let _guard = lock.lock().await;
+        let mut state = self.get_state(chat_id)?;
 
         match args {
             ImageMemoArgs::Add { content, layout } => {
@@ -121,6 +221,8 @@ impl Tool for ImageMemoTool {
                         let uuid = AssetId::from_str(&uuid_str)?;
                         let bytes = self.image_db.get(uuid)?.ok_or(anyhow!("Img not found"))?;
                         let meta = image::load_from_memory(&bytes)?;
+                        // 图层持有一次引用，撤销、清空或删除会话时释放
+                        self.image_db.retain(uuid)?;
                         (LayerKind::ImageRef(uuid), meta.width(), meta.height())
                     }
                     MemoContentInput::Text(txt) => {
@@ -167,14 +269,26 @@ impl Tool for ImageMemoTool {
                     height: h,
                 });
 
-                self.save_state(&state)?;
+                if let Err(e) = self.save_state(chat_id, &state) {
+                    if let Some(Layer {
+                        kind: LayerKind::ImageRef(uuid),
+                        ..
+                    }) = state.layers.pop()
+                    {
+                        release_with_meta(self.image_db.as_ref(), uuid)?;
+                    }
+                    return Err(e);
+                }
 
                 Ok(vec![MessageContent::Text("Layer added.".into())])
             }
 
             ImageMemoArgs::Read { grid } => {
-                let png_data = self.render_view(&state, grid)?;
-                let uuid = self.image_db.save(&png_data)?;
+                ctx.progress(format!("Rendering {} layers", state.layers.len()), None);
+                let png_data = self.render_view(chat_id, &state, grid)?;
+                let uuid = self
+                    .image_db
+                    .save_with_meta(&png_data, BlobMeta::from_tool(ToolKind::ImageMemo))?;
                 Ok(vec![
                     MessageContent::Text("✅ Read Success".to_string()),
                     MessageContent::ImageRef(uuid, "Memo Snapshot".into()),
@@ -182,7 +296,7 @@ impl Tool for ImageMemoTool {
             }
 
             ImageMemoArgs::Undo => {
-                if let Some(_l) = state.layers.pop() {
+                if let Some(layer) = state.layers.pop() {
                     state.cursor_y = state
                         .layers
                         .iter()
@@ -191,7 +305,10 @@ impl Tool for ImageMemoTool {
                         .unwrap_or(0)
                         + 20;
 
-                    self.save_state(&state)?;
+                    self.save_state(chat_id, &state)?;
+                    if let LayerKind::ImageRef(uuid) = layer.kind {
+                        release_with_meta(self.image_db.as_ref(), uuid)?;
+                    }
                     Ok(vec![MessageContent::Text("Undone last action.".into())])
                 } else {
                     Ok(vec![MessageContent::Text("Nothing to undo.".into())])
@@ -199,7 +316,9 @@ impl Tool for ImageMemoTool {
             }
 
             ImageMemoArgs::Clear => {
-                self.memo_db.delete_raw(b"current")?;
+                for uuid in delete_memo(self.memo_db.as_ref(), chat_id)? {
+                    release_with_meta(self.image_db.as_ref(), uuid)?;
+                }
                 Ok(vec![MessageContent::Text("Memo cleared.".into())])
             }
         }
@@ -211,11 +330,19 @@ impl ImageMemoTool {
         Self {
             image_db: image_db,
             memo_db: memo_db,
+            locks: Mutex::new(HashMap::new()),
         }
     }
 
-    fn get_state(&self) -> Result<MemoState, anyhow::Error> {
-        if let Some(data) = self.memo_db.get_raw(b"current")? {
+    fn chat_lock(&self, chat_id: Uuid) -> Arc<tokio::sync::Mutex<()>> {
+        let mut locks = self.locks.lock().unwrap();
+        // 没有其它持有者的锁可以丢弃，下次使用时重新创建
+        locks.retain(|id, lock| *id == chat_id || Arc::strong_count(lock) > 1);
+        locks.entry(chat_id).or_default().clone()
+    }
+
+    fn get_state(&self, chat_id: Uuid) -> Result<MemoState, anyhow::Error> {
+        if let Some(data) = self.memo_db.get_raw(&memo_key(chat_id))? {
             Ok(serde_json::from_slice(&data)?)
         } else {
             Ok(MemoState {
@@ -227,13 +354,18 @@ impl ImageMemoTool {
         }
     }
 
-    fn save_state(&self, state: &MemoState) -> Result<(), anyhow::Error> {
+    fn save_state(&self, chat_id: Uuid, state: &MemoState) -> Result<(), anyhow::Error> {
         let data = serde_json::to_vec(state)?;
-        self.memo_db.put_raw(b"current", &data)?;
+        self.memo_db.put_raw(&memo_key(chat_id), &data)?;
         Ok(())
     }
 
-    fn render_view(&self, state: &MemoState, show_grid: bool) -> Result<Vec<u8>, anyhow::Error> {
+    fn render_view(
+        &self,
+        chat_id: Uuid,
+        state: &MemoState,
+        show_grid: bool,
+    ) -> Result<Vec<u8>, anyhow::Error> {
         let header_height = 40;
         let total_height = state.height + header_height;
         let mut canvas = tiny_skia::Pixmap::new(state.width, total_height)
@@ -290,7 +422,7 @@ impl ImageMemoTool {
 
         let header_svg = format!(
             r###"<svg><text x="10" y="25" font-family="sans-serif" font-size="16" fill="#555" font-weight="bold">Visual Notebook (Session ID: {}) - Cursor Y: {}</text></svg>"###,
-            "Current", state.cursor_y
+            chat_id, state.cursor_y
         );
         let tree = usvg::Tree::from_str(&header_svg, &get_usvg_options())?;
         resvg::render(&tree, tiny_skia::Transform::default(), &mut canvas.as_mut());
@@ -423,3 +555,115 @@ pub fn to_abs_bbox(rel_bbox: [f64; 4], w: u32, h: u32) -> [u32; 4] {
         normalize_to_pixel(rel_bbox[3], h),
     ]
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SledBlobStorage;
+    use tokio_util::sync::CancellationToken;
+
+    #[tokio::test]
+    async fn memo_per_chat() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let image_db: Arc<dyn BlobStorage> =
+            Arc::new(SledBlobStorage::new_from_db(&db, "image").unwrap());
+        let memo_db: Arc<dyn BlobStorage> =
+            Arc::new(SledBlobStorage::new_from_db(&db, "memo").unwrap());
+        let tool = ImageMemoTool::new(image_db.clone(), memo_db.clone());
+        let mut png = vec![];
+        image::RgbImage::new(4, 4)
+            .write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)
+            .unwrap();
+        let image = image_db.save(&png).unwrap();
+        let a = ToolContext::new(Uuid::new_v4(), Uuid::new_v4(), CancellationToken::new());
+        let b = ToolContext::new(Uuid::new_v4(), Uuid::new_v4(), CancellationToken::new());
+
+        let add = format!(
+            r#"{{"op":"add","content":{{"image":"{}"}},"layout":{{"Append":{{}}}}}}"#,
+            image
+        );
+        tool.call(&a, &add).await.unwrap();
+        assert_eq!(memo_image_refs(memo_db.as_ref()).unwrap(), [image]);
+        // 清空另一个会话的画布不影响这个会话
+        tool.call(&b, r#"{"op":"clear"}"#).await.unwrap();
+        assert_eq!(memo_image_refs(memo_db.as_ref()).unwrap(), [image]);
+        assert!(tool.call(&ToolContext::default(), &add).await.is_err());
+
+        tool.call(&a, r#"{"op":"undo"}"#).await.unwrap();
+        assert!(memo_image_refs(memo_db.as_ref()).unwrap().is_empty());
+        tool.call(&a, &add).await.unwrap();
+        let chat_id = a.chat_id.unwrap();
+        assert_eq!(delete_memo(memo_db.as_ref(), chat_id).unwrap(), [image]);
+        assert!(memo_db.get_raw(&memo_key(chat_id)).unwrap().is_none());
+        // 保存时的一次引用加上仍未释放的图层引用
+        assert!(!image_db.release(image).unwrap());
+        assert!(image_db.release(image).unwrap());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_adds_keep_all_layers() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let image_db: Arc<dyn BlobStorage> =
+            Arc::new(SledBlobStorage::new_from_db(&db, "image").unwrap());
+        let memo_db: Arc<dyn BlobStorage> =
+            Arc::new(SledBlobStorage::new_from_db(&db, "memo").unwrap());
+        let tool = Arc::new(ImageMemoTool::new(image_db, memo_db.clone()));
+        let ctx = ToolContext::new(Uuid::new_v4(), Uuid::new_v4(), CancellationToken::new());
+
+        let tasks = (0..8)
+            .map(|i| {
+                let (tool, ctx) = (tool.clone(), ctx.clone());
+                tokio::spawn(async move {
+                    let add = format!(
+                        r#"{{"op":"add","content":{{"text":"{}"}},"layout":{{"Append":{{}}}}}}"#,
+                        i
+                    );
+                    tool.call(&ctx, &add).await.unwrap();
+                })
+            })
+            .collect::<Vec<_>>();
+        for task in tasks {
+            task.await.unwrap();
+        }
+        let state = tool.get_state(ctx.chat_id.unwrap()).unwrap();
+        assert_eq!(state.layers.len(), 8);
+        assert!(tool.locks.lock().unwrap().len() <= 1);
+    }
+
+    #[test]
+    fn legacy_memo_is_kept_with_refs() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let image_db = SledBlobStorage::new_from_db(&db, "image").unwrap();
+        let memo_db = SledBlobStorage::new_from_db(&db, "memo").unwrap();
+        assert!(!keep_legacy_memo(&memo_db, &image_db).unwrap());
+
+        let image = image_db.save(b"image").unwrap();
+        let layer = |kind| Layer {
+            id: Uuid::new_v4(),
+            kind,
+            x: 0,
+            y: 0,
+            width: 1,
+            height: 1,
+        };
+        let state = MemoState {
+            layers: vec![
+                layer(LayerKind::ImageRef(image)),
+                layer(LayerKind::ImageRef(AssetId::from_data(b"deleted"))),
+                layer(LayerKind::SvgContent("<svg/>".into())),
+            ],
+            ..Default::default()
+        };
+        memo_db
+            .put_raw(OLD_MEMO_KEY, &serde_json::to_vec(&state).unwrap())
+            .unwrap();
+        assert!(keep_legacy_memo(&memo_db, &image_db).unwrap());
+        assert!(!keep_legacy_memo(&memo_db, &image_db).unwrap());
+
+        assert_eq!(memo_db.raw_keys().unwrap(), [LEGACY_MEMO_KEY.to_vec()]);
+        assert_eq!(memo_image_refs(&memo_db).unwrap(), [image]);
+        // 保存时的一次引用加上画布的引用
+        assert!(!image_db.release(image).unwrap());
+        assert!(image_db.release(image).unwrap());
+    }
+}
@@ -0,0 +1,607 @@
+//! JS沙箱的环境脚本和内置库，build.rs也通过`#[path]`包含这个文件来生成快照
+//! 这里只能依赖deno_core和std
+
+use anyhow::Error;
+use deno_core::{Extension, JsRuntime, v8};
+use std::hash::{DefaultHasher, Hash, Hasher};
+
+pub const FN_RAWHTML: &str = "✿RAWHTML✿";
+pub const FN_RAWSVG: &str = "✿RAWSVG✿";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LibCategory {
+    Environment,    // 基础环境 (DOM, Base64)
+    DataProcessing, // 数据处理 (Lodash, Math, CSV)
+    Visualization,  // 可视化 (D3, Plot)
+    Utility,        // 其他工具
+}
+
+impl std::fmt::Display for LibCategory {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LibCategory::Environment => write!(f, "Environment"),
+            LibCategory::DataProcessing => write!(f, "Data Processing"),
+            LibCategory::Visualization => write!(f, "Visualization"),
+            LibCategory::Utility => write!(f, "Utilities"),
+        }
+    }
+}
+
+pub struct LibraryConfig {
+    pub require_name: &'static str,       // 用于 require('name')
+    pub global_var: &'static str,         // 注入到 globalThis 的变量名
+    pub src: &'static str,                // 源码内容
+    pub category: LibCategory,            // 分类
+    pub prompt_hint: &'static str,        // Prompt 中的展示文本 (例如: "`_` (Lodash)")
+    pub after_hook: Option<&'static str>, // Polyfill用的配置脚本
+}
+pub const LOAD_SOURCE: &[LibraryConfig] = &[
+    // --- Environment ---
+    LibraryConfig {
+        require_name: "linkedom",
+        global_var: "LinkeDOM",
+        src: include_str!("prelude/linkedom.bundle.js"),
+        category: LibCategory::Environment,
+        prompt_hint: "`document`, `window`; NO canvas.",
+        after_hook: Some(
+            r###"
+        if (globalThis.LinkeDOM) {
+            const { parseHTML, XMLSerializer} = globalThis.LinkeDOM;
+            const dom = parseHTML('<!doctype html><html><body></body></html>');
+            globalThis.window = dom.window;
+            globalThis.document = dom.document;
+            globalThis.Element = dom.HTMLElement;
+            globalThis.SVGElement = dom.SVGElement;
+            globalThis.Node = dom.Node;
+            if (XMLSerializer) {
+                globalThis.XMLSerializer = XMLSerializer;
+            } else if (dom.window && dom.window.XMLSerializer) {
+                globalThis.XMLSerializer = dom.window.XMLSerializer;
+            } else {
+                Deno.core.ops.console_op_print("Notice: Using simple XMLSerializer polyfill.\n", true);
+                globalThis.XMLSerializer = class {
+                    serializeToString(node) {
+                        return node.outerHTML || "";
+                    }
+                };
+            }
+
+            globalThis.requestAnimationFrame = (callback) => {
+                return setTimeout(callback, 0);
+            };
+            globalThis.cancelAnimationFrame = (id) => {
+                clearTimeout(id);
+            };
+            const originalSetAttribute = globalThis.Element.prototype.setAttribute;
+            globalThis.Element.prototype.setAttribute = function(name, value) {
+                originalSetAttribute.call(this, name, value);
+                return this;
+            };
+            if (globalThis.SVGElement) {
+                    globalThis.SVGElement.prototype.setAttribute = globalThis.Element.prototype.setAttribute;
+            }
+        } else {
+            Deno.core.ops.console_op_print("stderr: LinkeDOM not loaded!\n", true);
+        }"###,
+        ),
+    },
+    // --- Data Processing ---
+    LibraryConfig {
+        require_name: "lodash",
+        global_var: "_",
+        src: include_str!("prelude/lodash.min.js"),
+        category: LibCategory::DataProcessing,
+        prompt_hint: r##"const users=[{n:'a',g:'tech'},{n:'b',g:'hr'},{n:'c',g:'tech'}];console.log(_.groupBy(users,'g'));"##,
+        after_hook: Some(
+            r#"
+        if (typeof _ !== "undefined") {
+            globalThis.structuredClone = _.cloneDeep;
+        }"#,
+        ),
+    },
+    LibraryConfig {
+        require_name: "mathjs",
+        global_var: "math",
+        src: include_str!("prelude/math.min.js"),
+        category: LibCategory::DataProcessing,
+        prompt_hint: "math.evaluate('12.7 cm to inch').toString()",
+        after_hook: None,
+    },
+    LibraryConfig {
+        require_name: "simplify",
+        global_var: "simplify",
+        src: include_str!("prelude/simplify.min.js"),
+        category: LibCategory::DataProcessing,
+        prompt_hint: "",
+        after_hook: None,
+    },
+    LibraryConfig {
+        require_name: "dayjs",
+        global_var: "dayjs",
+        src: include_str!("prelude/dayjs.min.js"),
+        category: LibCategory::DataProcessing,
+        prompt_hint: "dayjs().format('YYYY-MM-DD HH:mm:ss')",
+        after_hook: None,
+    },
+    LibraryConfig {
+        require_name: "papaparse",
+        global_var: "Papa",
+        src: include_str!("prelude/papaparse.min.js"),
+        category: LibCategory::DataProcessing,
+        prompt_hint: "",
+        after_hook: None,
+    },
+    LibraryConfig {
+        require_name: "arquero",
+        global_var: "aq",
+        src: include_str!("prelude/arquero.min.js"),
+        category: LibCategory::DataProcessing,
+        prompt_hint: r##"const dt=aq.fromCSV(new TextDecoder().decode(bytes));
+console.log(dt.filter(d=>d.a>1).derive({c:d=>d.a+d.b}).toCSV());"##,
+        after_hook: Some(r#"if (typeof aq !== "undefined") { aq.aq = aq; }"#),
+    },
+    LibraryConfig {
+        require_name: "mustache",
+        global_var: "Mustache",
+        src: include_str!("prelude/mustache.min.js"),
+        category: LibCategory::Utility,
+        prompt_hint: r##"const htmlStr=Mustache.render(template,{title:"A",list:["1","2"]});"##,
+        after_hook: None,
+    },
+    LibraryConfig {
+        require_name: "nerdamer",
+        global_var: "nerdamer",
+        src: include_str!("prelude/nerdamer.min.js"),
+        category: LibCategory::DataProcessing,
+        prompt_hint: "nerdamer('solve(x^2=4, x)').toString()",
+        after_hook: None,
+    },
+    // --- Visualization ---
+    LibraryConfig {
+        require_name: "d3",
+        global_var: "d3",
+        src: include_str!("prelude/d3.v7.min.js"),
+        category: LibCategory::Visualization,
+        prompt_hint: "const s=d3.create('svg').attr('width',400).attr('height',300)/*NO append*/;s.append/*draw*/; save_svg(svg.node().outerHTML)",
+        after_hook: Some(r#"if (typeof d3 !== "undefined") d3.d3 = d3;"#),
+    },
+    LibraryConfig {
+        require_name: "vega",
+        global_var: "vega",
+        src: include_str!("prelude/vega.min.js"),
+        category: LibCategory::Visualization,
+        prompt_hint: r##"/*Spec must have width/height*/ const vegaSpec=vegaLite.compile(vlSpec).spec;
+const v=new vega.View(vega.parse(vegaSpec),{renderer:'svg'}).initialize(); save_svg(await v.toSVG());"##,
+        after_hook: Some(r#"if (typeof vega !== "undefined") vega.vega = vega;"#),
+    },
+    LibraryConfig {
+        require_name: "vega-lite",
+        global_var: "vegaLite",
+        src: include_str!("prelude/vega-lite.min.js"),
+        category: LibCategory::Visualization,
+        prompt_hint: "",
+        after_hook: None,
+    },
+    LibraryConfig {
+        require_name: "UPNG",
+        global_var: "UPNG",
+        src: include_str!("prelude/UPNG.min.js"),
+        category: LibCategory::DataProcessing,
+        prompt_hint: r##"const b=new Uint8Array([255,0,0,255]).buffer;UPNG.encode([b],width,height,depth);"##,
+        after_hook: None,
+    },
+];
+
+pub fn get_env_script() -> &'static str {
+    r#"
+    globalThis.console = {
+        log: (...args) => {
+            let msg = args.map(String).join(" ");
+            Deno.core.ops.console_op_print(msg + "\n", false);
+        },
+        error: (...args) => {
+            let msg = args.map(String).join(" ");
+            Deno.core.ops.console_op_print("stderr: " + msg + "\n", true);
+        },
+        warn: (...args) => {
+            let msg = args.map(String).join(" ");
+            Deno.core.ops.console_op_print("warn: " + msg + "\n", false);
+        },
+        info: (...args) => {
+            let msg = args.map(String).join(" ");
+            Deno.core.ops.console_op_print("info: " + msg + "\n", false);
+        },
+        trace: (...args) => {
+            let msg = args.map(String).join(" ");
+            Deno.core.ops.console_op_print("trace: " + msg + "\n", false);
+        },
+        table: (data) => {
+            Deno.core.ops.console_op_print((Array.isArray(data) ? JSON.stringify(data) : String(data)) + "\n", false);
+        }
+    };
+    globalThis.console.warn = globalThis.console.log;
+    globalThis.console.info = globalThis.console.log;
+    globalThis.console.trace = globalThis.console.log;
+
+    if (typeof setTimeout === "undefined") {
+        globalThis.setTimeout = (cb, delay, ...args) => {
+            queueMicrotask(() => {
+                if (typeof cb === 'string') {
+                    (0, eval)(cb);
+                } else {
+                    cb(...args);
+                }});
+            return 1;
+        };
+        globalThis.clearTimeout = (_id) => {};
+    }
+
+    if (typeof setInterval === "undefined") {
+        globalThis.setInterval = (cb, delay, ...args) => {
+            // 警告：为了防止 D3 timer 陷入死循环，这里 Mock 为只运行一次
+            queueMicrotask(() => cb(...args));
+            return 1;
+        };
+        globalThis.clearInterval = (_id) => {};
+    }
+    // --- TextEncoder / TextDecoder (UTF-8) ---
+    if (typeof TextEncoder === "undefined") {
+        globalThis.TextEncoder = class TextEncoder {
+            get encoding() { return "utf-8"; }
+            encode(input) {
+                const str = input === undefined ? "" : String(input);
+                return Deno.core.ops.op_text_encode(str);
+            }
+            encodeInto(source, destination) {
+                const encoded = this.encode(source);
+                const len = Math.min(encoded.length, destination.length);
+                destination.set(encoded.subarray(0, len));
+                return { read: source.length, written: len };
+            }
+        };
+    }
+
+    if (typeof TextDecoder === "undefined") {
+        globalThis.TextDecoder = class TextDecoder {
+            constructor(label = "utf-8", options = {}) {
+                // 目前只支持 utf-8，忽略 label
+                this.encoding = "utf-8";
+                this.fatal = options.fatal || false;
+                this.ignoreBOM = options.ignoreBOM || false;
+            }
+            decode(input, options) {
+                let buffer;
+                if (input === undefined) {
+                    buffer = new Uint8Array(0);
+                } else if (input instanceof ArrayBuffer) {
+                    buffer = new Uint8Array(input);
+                } else if (ArrayBuffer.isView(input)) {
+                    buffer = new Uint8Array(input.buffer, input.byteOffset, input.byteLength);
+                } else {
+                    throw new TypeError("Failed to execute 'decode' on 'TextDecoder': The provided value is not of type '(ArrayBuffer or ArrayBufferView)'");
+                }
+                return Deno.core.ops.op_text_decode(buffer);
+            }
+        };
+    }
+
+    // --- URL / URLSearchParams ---
+    if (typeof URL === "undefined") {
+        globalThis.URL = class URL {
+            constructor(url, base) {
+                this.href = url;
+                this.searchParams = new URLSearchParams();
+            }
+        };
+        globalThis.URLSearchParams = class URLSearchParams {
+            constructor(init) { this.params = new Map(); }
+            get(name) { return this.params.get(name); }
+            set(name, val) { this.params.set(name, val); }
+        };
+    }
+
+    globalThis.btoa = (str) => {
+        const encoder = new TextEncoder();
+        return Deno.core.ops.op_base64_encode(str);
+    };
+
+    globalThis.atob = (base64) => {
+        const bytes = Deno.core.ops.op_base64_decode(base64);
+        return new TextDecoder().decode(bytes);
+    };
+
+    globalThis.Base64 = {
+        encode: (data) => {
+            const bytes = typeof data === 'string' ? new TextEncoder().encode(data) : data;
+            return Deno.core.ops.op_base64_encode(bytes);
+        },
+        decode: (str) => Deno.core.ops.op_base64_decode(str) // 返回 Uint8Array
+    };
+
+    // --- Performance ---
+    if (typeof performance === "undefined") {
+        globalThis.performance = { now: () => Deno.core.ops.op_performance_now() };
+    }
+
+    // --- Progress ---
+    globalThis.progress = (msg, fraction) =>
+        Deno.core.ops.op_report_progress(String(msg), typeof fraction === "number" ? fraction : -1);
+"#
+}
+
+pub fn get_setup_script() -> String {
+    let memfs_polyfill = r#"
+    const MEMFS_LIMIT = 50 * 1024 * 1024;
+    const vfs = new Map();
+    let currentSize = 0;
+
+    function normalizePath(p) {
+        return p.replace(/^[\.\/]+/, '');
+    }
+
+    const MemFS = {
+        writeFileSync: (path, data, options) => {
+            const key = normalizePath(path);
+            let content;
+
+            // 统一转为 Uint8Array
+            if (typeof data === 'string') {
+                content = new TextEncoder().encode(data);
+            } else if (data instanceof Uint8Array) {
+                content = data;
+            } else {
+                // 尝试处理 buffer-like
+                content = new Uint8Array(data);
+            }
+
+            // 检查大小限制
+            const newSize = content.length;
+            const oldSize = vfs.has(key) ? vfs.get(key).length : 0;
+
+            if (currentSize - oldSize + newSize > MEMFS_LIMIT) {
+                throw new Error(`❌ MemFS Limit Exceeded: Cannot write file '${path}'. Storage full.`);
+            }
+
+            vfs.set(key, content);
+            currentSize = currentSize - oldSize + newSize;
+            return undefined;
+        },
+
+        readFileSync: (path, options) => {
+            const key = normalizePath(path);
+
+            if (vfs.has(key)) {
+                const data = vfs.get(key);
+                // 处理编码参数 (简单支持 utf8)
+                if (options === 'utf8' || (typeof options === 'object' && options.encoding === 'utf8')) {
+                    return new TextDecoder().decode(data);
+                }
+                return data;
+            }
+
+            if (globalThis.contain_blob('asset', key)) {
+                try {
+                    const bytes = globalThis.load_blob('asset', key);
+                    if (options === 'utf8' || (typeof options === 'object' && options.encoding === 'utf8')) {
+                        return new TextDecoder().decode(bytes);
+                    }
+                    return bytes;
+                } catch (e) {
+                    throw new Error(`ENOENT: no such file or directory, open '${path}'. \n(Also failed to load blob by UUID from DB)`);
+                }
+            }
+
+            if (globalThis.contain_blob('image', key)){
+                try {
+                    const bytes = globalThis.load_blob('image', key);
+                    if (options === 'utf8' || (typeof options === 'object' && options.encoding === 'utf8')) {
+                        return new TextDecoder().decode(bytes);
+                    }
+                    return bytes;
+                } catch (e) {
+                    throw new Error(`ENOENT: no such file or directory, open '${path}'. \n(Also failed to load blob by UUID from DB)`);
+                }
+            }
+            throw new Error(`ENOENT: no such file or directory, open '${path}'. `);
+        },
+
+        existsSync: (path) => {
+            const key = normalizePath(path);
+            return vfs.has(key) || globalThis.contain_blob('image', key) || globalThis.contain_blob('asset', key);
+        },
+        mkdirSync: () => {},
+        statSync: (path) => {
+            const key = normalizePath(path);
+            if (!vfs.has(key)) throw new Error(`ENOENT: '${path}'`);
+            return { isFile: () => true, isDirectory: () => false, size: vfs.get(key).length };
+        },
+        unlinkSync: (path) => {
+                const key = normalizePath(path);
+                if (vfs.has(key)) {
+                    currentSize -= vfs.get(key).length;
+                    vfs.delete(key);
+                }
+        },
+        readdirSync: () => Array.from(vfs.keys()),
+
+        // Promise 版本
+        promises: {
+            readFile: async (...args) => MemFS.readFileSync(...args),
+            writeFile: async (...args) => MemFS.writeFileSync(...args),
+            unlink: async (...args) => MemFS.unlinkSync(...args),
+            mkdir: async () => {},
+            stat: async (...args) => MemFS.statSync(...args),
+            access: async (path) => { if (!MemFS.existsSync(path)) throw new Error('ENOENT'); }
+        }
+    };
+    globalThis.fs = MemFS;
+    globalThis.process = {
+        env: {},
+        version: 'v16.0.0',
+        versions: { node: '16.0.0' },
+        platform: 'browser',
+        browser: true,
+        cwd: () => '/',
+        stdout: { write: (msg) => console.log(msg) },
+        stderr: { write: (msg) => console.error(msg) },
+        nextTick: (cb, ...args) => queueMicrotask(() => cb(...args))
+    };
+"#;
+    let require_cases = LOAD_SOURCE
+        .iter()
+        .map(|lib| {
+            format!(
+                "case '{}': return globalThis['{}'];",
+                lib.require_name, lib.global_var
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    let available_libs = LOAD_SOURCE
+        .iter()
+        .map(|lib| lib.require_name)
+        .collect::<Vec<_>>()
+        .join(", ");
+    r#"if (typeof structuredClone === "undefined" && typeof _ !== "undefined") {
+        globalThis.structuredClone = (value) => _.cloneDeep(value);
+    } else if (typeof structuredClone === "undefined") {
+        globalThis.structuredClone = (value) => JSON.parse(JSON.stringify(value));
+    }
+    {memfs_polyfill}
+    globalThis.require = function(name) {
+        switch(name) {
+            {require_cases}
+            case 'fs': return MemFS;
+            case 'fs/promises': return MemFS.promises;
+            case 'path': return {
+                resolve: (...args) => args.join('/').replace(/\/+/g, '/'),
+                join: (...args) => args.join('/').replace(/\/+/g, '/'),
+                basename: (p) => p.split('/').pop(),
+                extname: (p) => {{ const i = p.lastIndexOf('.'); return i < 0 ? '' : p.slice(i); }}
+            };
+
+            case 'os': return {
+                platform: () => 'browser',
+                arch: () => 'x64',
+                tmpdir: () => '/tmp'
+            };
+            default: throw new Error(`❌ Module '${name}' not found. Available: {available_libs}`);
+        }
+    };
+    function op_anybuffer_to_uint8array(data) {
+        let buffer;
+        if (data instanceof ArrayBuffer){
+            buffer = new Uint8Array(data);
+        } else if (data instanceof Array) {
+            buffer = Uint8Array.from(data);
+        } else if (typeof data === 'string') {
+            const binString = atob(data);
+            buffer = new Uint8Array(binString.length);
+            for (let i = 0; i < binString.length; i++) {
+                buffer[i] = binString.charCodeAt(i);
+            }
+        } else if (data instanceof Uint8Array) {
+            buffer = data;
+        }else {
+            buffer = UInt8Array.from(data);
+        }
+        return buffer;
+    }
+
+    globalThis.html = (content) => {
+        return "{FN_RAWHTML}" + content;
+    };
+    globalThis.svg = (content) => {
+        return "{FN_RAWSVG}" + content;
+    };
+
+    globalThis.load_blob = (schema, uuid) => Deno.core.ops.op_load_blob(schema, uuid);
+    globalThis.save_blob = (schema, img) => {
+        const img_bin = op_anybuffer_to_uint8array(img);
+        return Deno.core.ops.op_save_blob(schema, img_bin);
+    };
+    globalThis.save_svg = (svg) => Deno.core.ops.op_save_svg(svg);
+    globalThis.contain_blob = (uuid) => Deno.core.ops.op_contain_blob(uuid);
+    globalThis.convert_to_png = (img) => {
+        const img_bin = op_anybuffer_to_uint8array(img);
+        return Deno.core.ops.op_convert_to_png(img_bin);
+    };
+    globalThis.QRCode = {
+        save: (text, format = 'png') => {
+            const str = String(text);
+            if (!str) throw new Error("QRCode: Text cannot be empty");
+            switch (format.toLowerCase()) {
+                case 'png': {
+                    const bytes = Deno.core.ops.op_qrcode_png(str);
+                    return save_blob('image', bytes);
+                }
+                case 'svg': {
+                    const svgStr = Deno.core.ops.op_qrcode_svg(str);
+                    return Deno.core.ops.op_save_svg(svgStr);
+                }
+                default:
+                    throw new Error(`QRCode: Unsupported format '${format}'. Use 'png' or 'svg'.`);
+            }
+        },
+        encode: (text, format = 'png') => {
+            const str = String(text);
+            switch (format.toLowerCase()) {
+                case 'png': return Deno.core.ops.op_qrcode_png(str); // Returns Uint8Array
+                case 'svg': return Deno.core.ops.op_qrcode_svg(str); // Returns String
+                default: throw new Error(`QRCode: Unsupported format '${format}'`);
+            }
+        },
+        decode: (input) => {
+            let buffer;
+            if (typeof input === 'string') {
+                try {
+                    buffer = globalThis.load_blob('image', input);
+                } catch (e) {
+                    throw new Error("QRCode.decode: Failed to retrieve image from UUID.");
+                }
+            } else if (input instanceof Uint8Array) {
+                buffer = input;
+            } else {
+                throw new Error("QRCode.decode: Input must be Uint8Array or Image UUID string.");
+            }
+            return Deno.core.ops.op_qrcode_decode(buffer);
+        }
+    };"#
+    .replace("{require_cases}", &require_cases)
+    .replace("{available_libs}", &available_libs)
+    .replace("{memfs_polyfill}", &memfs_polyfill)
+    .replace("{RAWHTML}", FN_RAWHTML)
+    .replace("{RAWSVG}", FN_RAWSVG)
+}
+
+/// 执行环境脚本和所有库，生成快照和不使用快照时都用这个
+pub fn load_prelude(runtime: &mut JsRuntime) -> Result<(), Error> {
+    runtime.execute_script("<env>", get_env_script())?;
+    for lib in LOAD_SOURCE {
+        runtime.execute_script(lib.require_name, lib.src)?;
+        if let Some(hook) = lib.after_hook {
+            let hook_name = format!("{}_after_hook", lib.require_name);
+            runtime.execute_script(hook_name, hook)?;
+        }
+    }
+    runtime.execute_script("<setup>", get_setup_script())?;
+    runtime.execute_script("<ready>", "globalThis.__prelude_ready = true;")?;
+    Ok(())
+}
+
+/// 快照对应的内容：V8版本、平台、扩展ops的签名和所有脚本
+/// build.rs生成快照时记录，启动时不一致就不使用快照，否则V8会直接终止进程
+pub fn snapshot_marker(ext: &Extension) -> String {
+    let mut hasher = DefaultHasher::new();
+    std::env::consts::ARCH.hash(&mut hasher);
+    std::env::consts::OS.hash(&mut hasher);
+    for op in ext.ops.iter() {
+        (op.name, op.arg_count, op.is_async).hash(&mut hasher);
+    }
+    get_env_script().hash(&mut hasher);
+    for lib in LOAD_SOURCE {
+        (lib.require_name, lib.src, lib.after_hook).hash(&mut hasher);
+    }
+    get_setup_script().hash(&mut hasher);
+    format!("v8-{}-{:016x}", v8::V8::get_version(), hasher.finish())
+}
@@ -0,0 +1,258 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, mpsc},
+    time::Duration,
+};
+
+use anyhow::{Error, anyhow};
+use serde::{Deserialize, Serialize};
+use tokio::{sync::oneshot, time::Instant};
+use uuid::Uuid;
+
+use super::code_interpreter::{CodeResult, JsLimits, Sandbox};
+use crate::{ToolContext, blob::BlobStorage};
+
+/// 有状态模式的选项，每个会话保留一个JS环境
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct JsSessionOptions {
+    pub enabled: bool,
+    /// 超过这个时间没有调用的环境被关闭（秒）
+    pub idle_ttl_secs: u64,
+    /// 同时保留的环境数，超出时关闭最久没用的
+    pub max_sessions: usize,
+    /// 所有环境V8堆上限的总和（MB），保留的环境数不超过它除以单个环境的上限
+    pub max_total_heap_mb: usize,
+}
+
+impl Default for JsSessionOptions {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            idle_ttl_secs: 600,
+            max_sessions: 8,
+            max_total_heap_mb: 2048,
+        }
+    }
+}
+
+struct JsJob {
+    ctx: ToolContext,
+    code: String,
+    reply: oneshot::Sender<Result<CodeResult, Error>>,
+}
+
+struct JsSession {
+    jobs: mpsc::Sender<JsJob>,
+    last_used: Instant,
+}
+
+/// 会话id到JS环境的映射，V8的isolate不能跨线程，每个环境有自己的线程
+pub(crate) struct JsSessions {
+    image: Arc<dyn BlobStorage>,
+    asset: Arc<dyn BlobStorage>,
+    limits: JsLimits,
+    snapshot: bool,
+    options: JsSessionOptions,
+    sessions: Mutex<HashMap<Uuid, JsSession>>,
+}
+
+impl JsSessions {
+    pub(crate) fn new(
+        image: Arc<dyn BlobStorage>,
+        asset: Arc<dyn BlobStorage>,
+        limits: JsLimits,
+        snapshot: bool,
+        options: JsSessionOptions,
+    ) -> Self {
+        Self {
+            image,
+            asset,
+            limits,
+            snapshot,
+            options,
+            sessions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// 在会话的环境中执行，返回的布尔值表示是否新建了环境
+    pub(crate) async fn run(
+        &self,
+        chat_id: Uuid,
+        ctx: ToolContext,
+        code: String,
+        reset: bool,
+    ) -> Result<(CodeResult, bool), Error> {
+        let (jobs, mut fresh) = self.session(chat_id, reset);
+        let (reply, result) = oneshot::channel();
+        let job = JsJob { ctx, code, reply };
+        // 线程因为空闲或被终止已经退出时，换一个新的环境
+        if let Err(mpsc::SendError(job)) = jobs.send(job) {
+            let (jobs, _) = self.session(chat_id, true);
+            fresh = true;
+            jobs.send(job)
+                .map_err(|_| anyhow!("JS session exited unexpectedly"))?;
+        }
+        let result = result
+            .await
+            .map_err(|_| anyhow!("JS session exited unexpectedly"))??;
+        Ok((result, fresh))
+    }
+
+    /// 关闭会话的环境，正在执行的调用完成后线程退出
+    pub(crate) fn close(&self, chat_id: Uuid) {
+        if self.sessions.lock().unwrap().remove(&chat_id).is_some() {
+            tracing::info!("Closed JS session of chat {}", chat_id);
+        }
+    }
+
+    /// 同时保留的环境数，同时受`max_sessions`和`max_total_heap_mb`限制
+    fn capacity(&self) -> usize {
+        let by_heap = self.options.max_total_heap_mb / self.limits.max_heap_mb.max(1);
+        self.options.max_sessions.min(by_heap).max(1)
+    }
+
+    fn session(&self, chat_id: Uuid, reset: bool) -> (mpsc::Sender<JsJob>, bool) {
+        let mut sessions = self.sessions.lock().unwrap();
+        let now = Instant::now();
+        let ttl = Duration::from_secs(self.options.idle_ttl_secs);
+        sessions.retain(|_, s| now.duration_since(s.last_used) < ttl);
+        if reset {
+            sessions.remove(&chat_id);
+        }
+        if !sessions.contains_key(&chat_id) && sessions.len() >= self.capacity() {
+            let oldest = sessions
+                .iter()
+                .min_by_key(|(_, s)| s.last_used)
+                .map(|(id, _)| *id);
+            if let Some(oldest) = oldest {
+                tracing::info!("Closing JS session of chat {} to make room", oldest);
+                sessions.remove(&oldest);
+            }
+        }
+        let fresh = !sessions.contains_key(&chat_id);
+        let session = sessions.entry(chat_id).or_insert_with(|| JsSession {
+            jobs: spawn_session(
+                self.image.clone(),
+                self.asset.clone(),
+                self.limits,
+                self.snapshot,
+                ttl,
+            ),
+            last_used: now,
+        });
+        session.last_used = now;
+        (session.jobs.clone(), fresh)
+    }
+}
+
+/// 环境所在的线程，空闲超过`ttl`、被终止或Sender全部drop时退出
+fn spawn_session(
+    image: Arc<dyn BlobStorage>,
+    asset: Arc<dyn BlobStorage>,
+    limits: JsLimits,
+    snapshot: bool,
+    ttl: Duration,
+) -> mpsc::Sender<JsJob> {
+    let (tx, rx) = mpsc::channel::<JsJob>();
+    std::thread::spawn(move || {
+        let mut sandbox = None;
+        while let Ok(job) = rx.recv_timeout(ttl) {
+            if sandbox.is_none() {
+                match Sandbox::new(image.clone(), asset.clone(), limits, snapshot) {
+                    Ok(s) => sandbox = Some(s),
+                    Err(e) => {
+                        drop(rx);
+                        let _ = job.reply.send(Err(e));
+                        break;
+                    }
+                }
+            }
+            let Some(s) = sandbox.as_mut() else {
+                break;
+            };
+            let result = s.run(job.ctx, job.code);
+            // 先关闭通道再回复，之后的调用会换一个新的环境
+            if s.is_aborted() {
+                drop(rx);
+                let _ = job.reply.send(result);
+                break;
+            }
+            let _ = job.reply.send(result);
+        }
+    });
+    tx
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SledBlobStorage;
+
+    #[tokio::test]
+    async fn globals_persist_per_chat() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let blob: Arc<dyn BlobStorage> = Arc::new(SledBlobStorage::new_from_db(&db, "t").unwrap());
+        let sessions = JsSessions::new(
+            blob.clone(),
+            blob,
+            JsLimits::default(),
+            true,
+            JsSessionOptions {
+                enabled: true,
+                ..Default::default()
+            },
+        );
+        let (a, b) = (Uuid::now_v7(), Uuid::now_v7());
+        let run = |chat_id, code: &str, reset| {
+            sessions.run(chat_id, ToolContext::default(), code.to_string(), reset)
+        };
+
+        let (result, fresh) = run(a, "globalThis.n = 41; fs.writeFileSync('x', 'y');", false)
+            .await
+            .unwrap();
+        assert!(fresh && result.error.is_none());
+        let (result, fresh) = run(a, "return n + 1 + fs.readFileSync('x', 'utf8');", false)
+            .await
+            .unwrap();
+        assert!(!fresh);
+        assert_eq!(result.return_value, "\"42y\"");
+
+        let (result, _) = run(b, "return typeof n;", false).await.unwrap();
+        assert_eq!(result.return_value, "\"undefined\"");
+        let (result, fresh) = run(a, "return typeof n;", true).await.unwrap();
+        assert!(fresh);
+        assert_eq!(result.return_value, "\"undefined\"");
+
+        sessions.close(a);
+        let (_, fresh) = run(a, "return 1;", false).await.unwrap();
+        assert!(fresh);
+    }
+
+    #[test]
+    fn capacity_respects_total_heap() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let blob: Arc<dyn BlobStorage> = Arc::new(SledBlobStorage::new_from_db(&db, "t").unwrap());
+        let sessions = |max_heap_mb, max_total_heap_mb| {
+            JsSessions::new(
+                blob.clone(),
+                blob.clone(),
+                JsLimits {
+                    max_heap_mb,
+                    ..Default::default()
+                },
+                true,
+                JsSessionOptions {
+                    enabled: true,
+                    max_sessions: 8,
+                    max_total_heap_mb,
+                    ..Default::default()
+                },
+            )
+            .capacity()
+        };
+        assert_eq!(sessions(512, 2048), 4);
+        assert_eq!(sessions(128, 2048), 8);
+        assert_eq!(sessions(512, 256), 1);
+    }
+}
@@ -1,13 +1,19 @@
 use crate::{blob::BlobStorage, schema::*};
 use anyhow::Error;
+use async_openai::types::{ChatCompletionTool, ChatCompletionTools, FunctionObject};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, future::Future, sync::Arc, time::Duration};
 use strum::{Display, EnumIter, EnumString, IntoEnumIterator};
+use tokio::{sync::mpsc::UnboundedSender, time::Instant};
+use tokio_util::sync::CancellationToken;
 use uuid::Uuid;
 
 mod prompt_template;
 
+mod format;
+pub use format::*;
+
 mod zoomin;
 pub use zoomin::ZoomInTool;
 
@@ -16,9 +22,16 @@ pub use bbox::BboxDrawTool;
 
 mod image_memo;
 pub use image_memo::ImageMemoTool;
+pub(crate) use image_memo::{
+    delete_memo, keep_legacy_memo, memo_image_refs, memo_key, memo_layer_images,
+};
 
 mod code_interpreter;
-pub use code_interpreter::JsInterpreter;
+pub use code_interpreter::{JsInterpreter, JsLimits};
+
+mod js_prelude;
+mod js_session;
+pub use js_session::JsSessionOptions;
 
 mod fetch;
 pub use fetch::FetchTool;
@@ -61,13 +74,22 @@ impl ToolKind {
         image: Arc<dyn BlobStorage>,
         asset: Arc<dyn BlobStorage>,
         memo: Arc<dyn BlobStorage>,
+        image_format: ImageFormatPolicy,
+        js_limits: JsLimits,
+        js_sessions: JsSessionOptions,
     ) -> Box<dyn Tool + Send + Sync> {
         match self {
             ToolKind::ZoomIn => Box::new(ZoomInTool::new(image)),
             ToolKind::ImageMemo => Box::new(ImageMemoTool::new(image, memo)),
             ToolKind::DrawBbox => Box::new(BboxDrawTool::new(image)),
-            ToolKind::JsInterpreter => Box::new(JsInterpreter::new(image, asset)),
-            ToolKind::Curl => Box::new(FetchTool::new(image, asset)),
+            ToolKind::JsInterpreter => Box::new(
+                JsInterpreter::new(image, asset)
+                    .with_limits(js_limits)
+                    .with_sessions(js_sessions),
+            ),
+            ToolKind::Curl => {
+                Box::new(FetchTool::new(image, asset).with_image_format(image_format))
+            }
             ToolKind::Image => Box::new(ImageTool::new(image)),
             ToolKind::Asset => Box::new(AssetTool::new(asset)),
             ToolKind::ResourceInspector => Box::new(ResourceInspector::new(image, asset)),
@@ -124,11 +146,114 @@ pub struct ToolDescription {
     pub args_format: String,           // 例如: "此工具的输入应为JSON对象。"
 }
 
+/// 工具执行中报告的进度
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ToolProgress {
+    pub use_id: Uuid,
+    pub message: String,
+    /// 0到1之间，无法估计时为空
+    pub fraction: Option<f32>,
+}
+
+/// 一次工具调用的上下文
+#[derive(Clone, Debug, Default)]
+pub struct ToolContext {
+    /// 发起调用的会话，直接通过接口调用工具时为空
+    pub chat_id: Option<Uuid>,
+    /// 包含这次调用的助手消息
+    pub message_id: Option<Uuid>,
+    /// 正在执行的工具调用，由`ToolSet`填写
+    pub use_id: Option<Uuid>,
+    /// 生成被取消时触发
+    pub cancel: CancellationToken,
+    /// 超过后工具调用被中止
+    pub deadline: Option<Instant>,
+    progress: Option<UnboundedSender<ToolProgress>>,
+}
+
+impl ToolContext {
+    pub fn new(chat_id: Uuid, message_id: Uuid, cancel: CancellationToken) -> Self {
+        Self {
+            chat_id: Some(chat_id),
+            message_id: Some(message_id),
+            cancel,
+            ..Default::default()
+        }
+    }
+
+    /// 从现在开始最多执行`timeout`
+    pub fn with_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.deadline = timeout.map(|t| Instant::now() + t);
+        self
+    }
+
+    pub fn with_progress(mut self, sink: UnboundedSender<ToolProgress>) -> Self {
+        self.progress = Some(sink);
+        self
+    }
+
+    /// 报告进度，没有接收方时忽略
+    pub fn progress(&self, message: impl Into<String>, fraction: Option<f32>) {
+        if let (Some(sink), Some(use_id)) = (&self.progress, self.use_id) {
+            let _ = sink.send(ToolProgress {
+                use_id,
+                message: message.into(),
+                fraction: fraction.map(|f| f.clamp(0.0, 1.0)),
+            });
+        }
+    }
+
+    /// 距离截止时间还剩多久，没有截止时间时为空
+    pub fn remaining(&self) -> Option<Duration> {
+        self.deadline
+            .map(|d| d.saturating_duration_since(Instant::now()))
+    }
+
+    /// 已被取消或超时时返回错误，供耗时的循环检查
+    pub fn check(&self) -> Result<(), Error> {
+        if self.cancel.is_cancelled() {
+            anyhow::bail!("Tool call cancelled");
+        }
+        if self.remaining() == Some(Duration::ZERO) {
+            anyhow::bail!("Tool call timed out");
+        }
+        Ok(())
+    }
+
+    /// 执行`fut`，被取消或超时时不再等待它
+    pub async fn run<F: Future>(&self, fut: F) -> Result<F::Output, Error> {
+        let deadline = async {
+            match self.deadline {
+                Some(deadline) => tokio::time::sleep_until(deadline).await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            output = fut => Ok(output),
+            _ = self.cancel.cancelled() => anyhow::bail!("Tool call cancelled"),
+            _ = deadline => anyhow::bail!("Tool call timed out"),
+        }
+    }
+}
+
+/// 工具调用`origin`的结果消息
+pub(crate) fn tool_result(origin: Uuid, content: Vec<MessageContent>) -> Message {
+    Message {
+        id: Uuid::new_v4(),
+        owner: Role::Tools(origin),
+        content,
+        reasoning: vec![],
+        tool_use: vec![],
+        interrupted: false,
+        parent: None,
+    }
+}
+
 #[async_trait::async_trait]
 pub trait Tool {
     fn name(&self) -> String;
     fn description(&self) -> ToolDescription;
-    async fn call(&self, args: &str) -> Result<Vec<MessageContent>, Error>;
+    async fn call(&self, ctx: &ToolContext, args: &str) -> Result<Vec<MessageContent>, Error>;
 
     fn visible_to_human(&self) -> bool {
         true
@@ -136,6 +261,8 @@ pub trait Tool {
     fn visible_to_model(&self) -> bool {
         true
     }
+    /// 会话被删除时释放工具为它保留的状态
+    fn close_chat(&self, _chat_id: Uuid) {}
 
     fn get_function_description(&self) -> String {
         let mut desc = self.description();
@@ -211,7 +338,13 @@ impl ToolSet {
         self
     }
 
-    pub async fn use_tool_async(&self, tool_use: ToolUse) -> (ToolUse, Message) {
+    pub fn close_chat(&self, chat_id: Uuid) {
+        for tool in self.tools.values() {
+            tool.close_chat(chat_id);
+        }
+    }
+
+    pub async fn use_tool_async(&self, ctx: &ToolContext, tool_use: ToolUse) -> (ToolUse, Message) {
         let result_content = match self
             .tools
             .get(&tool_use.function_name.trim().to_lowercase())
@@ -220,29 +353,37 @@ impl ToolSet {
                 let error_msg = format!("错误：未找到名为 '{}' 的工具。", tool_use.function_name);
                 vec![MessageContent::Text(error_msg)]
             }
-            Some(tool) => match tool.call(&tool_use.args).await {
-                Ok(content) => content,
-                Err(e) => {
-                    let error_msg = format!("工具 '{}' 执行失败：{}", tool_use.function_name, e);
-                    vec![MessageContent::Text(error_msg)]
+            Some(tool) => {
+                let ctx = ToolContext {
+                    use_id: Some(tool_use.use_id),
+                    ..ctx.clone()
+                };
+                // 被取消或超时的工具调用同样作为失败返回给模型
+                match ctx
+                    .run(tool.call(&ctx, &tool_use.args))
+                    .await
+                    .and_then(|r| r)
+                {
+                    Ok(content) => content,
+                    Err(e) => {
+                        let error_msg =
+                            format!("工具 '{}' 执行失败：{}", tool_use.function_name, e);
+                        vec![MessageContent::Text(error_msg)]
+                    }
                 }
-            },
+            }
         };
 
-        let origin = tool_use.use_id.clone();
-        (
-            tool_use,
-            Message {
-                id: Uuid::new_v4(),
-                owner: Role::Tools(origin),
-                content: result_content,
-                reasoning: vec![],
-                tool_use: vec![],
-            },
-        )
+        let result = tool_result(tool_use.use_id, result_content);
+        (tool_use, result)
     }
 
-    pub fn system_prompt(&self, lang: whatlang::Lang, parallel_function_calls: bool) -> String {
+    pub fn system_prompt(
+        &self,
+        lang: whatlang::Lang,
+        parallel_function_calls: bool,
+        format: &dyn ToolCallFormat,
+    ) -> String {
         let tool_descs = self
             .tools
             .values()
@@ -264,34 +405,71 @@ impl ToolSet {
             .tool_info_template
             .replace("{tool_descs}", &tool_descs);
 
-        let tool_fmt_string = if parallel_function_calls {
-            templates.parallel_call_template
-        } else {
-            templates.single_call_template
-        };
-        let tool_fmt = tool_fmt_string
-            .replace("{tool_names}", &tool_names)
-            .replace("{FN_NAME}", FN_NAME)
-            .replace("{FN_ARGS}", FN_ARGS)
-            .replace("{FN_RESULT}", FN_RESULT)
-            .replace("{FN_EXIT}", FN_EXIT);
-        let assistant_prompt = templates.assistant_desc_template.replace(
-            "{CURRENT_DATE}",
-            &chrono::Local::now().format("%Y-%m-%d").to_string(),
-        );
+        let tool_fmt = format.prompt_section(lang, &tool_names, parallel_function_calls);
+        let assistant_prompt = Self::assistant_prompt(lang);
 
         format!(r##"{}\n{}\n\n{}"##, assistant_prompt, tool_info, tool_fmt)
     }
+
+    /// 原生`tool_calls`模式使用的system prompt
+    /// 工具的描述通过请求中的`tools`传递，这里只保留助手描述
+    pub fn native_system_prompt(&self, lang: whatlang::Lang) -> String {
+        Self::assistant_prompt(lang)
+    }
+
+    /// 达到调用上限后，要求模型直接回答的提示
+    pub fn loop_limit_prompt(&self, lang: whatlang::Lang, limit: &str) -> String {
+        prompt_template::get_templates(lang)
+            .loop_limit_template
+            .replace("{limit}", limit)
+    }
+
+    /// 要求模型总结旧对话的提示
+    pub fn context_summary_prompt(&self, lang: whatlang::Lang) -> String {
+        prompt_template::get_templates(lang)
+            .context_summary_template
+            .to_string()
+    }
+
+    fn assistant_prompt(lang: whatlang::Lang) -> String {
+        prompt_template::get_templates(lang)
+            .assistant_desc_template
+            .replace(
+                "{CURRENT_DATE}",
+                &chrono::Local::now().format("%Y-%m-%d").to_string(),
+            )
+    }
+
+    /// 以OpenAI `tools`数组的形式导出对模型可见的工具
+    pub fn openai_tools(&self) -> Vec<ChatCompletionTools> {
+        self.tools
+            .values()
+            .filter(|t| t.visible_to_model())
+            .map(|tool| {
+                let mut desc = tool.description();
+                compact_schema(&mut desc.parameters);
+                ChatCompletionTools::Function(ChatCompletionTool {
+                    function: FunctionObject {
+                        name: tool.name(),
+                        description: Some(desc.description_for_model),
+                        parameters: Some(desc.parameters),
+                        strict: None,
+                    },
+                })
+            })
+            .collect()
+    }
 }
 
 pub const FN_TAG: &str = "✿";
+#[deprecated(note = "use `ToolCallFormat::max_marker_len` of the format in use")]
 pub const FN_MAX_LEN: usize = FN_NAME.len() * 2 + 6;
 pub const FN_NAME: &str = "✿FUNCTION✿";
 pub const FN_ARGS: &str = "✿ARGS✿";
 pub const FN_RESULT: &str = "✿RESULT✿";
 pub const FN_EXIT: &str = "✿RETURN✿";
-pub const FN_RAWHTML: &str = "✿RAWHTML✿";
-pub const FN_RAWSVG: &str = "✿RAWSVG✿";
+pub use js_prelude::{FN_RAWHTML, FN_RAWSVG};
+#[deprecated(note = "use `ToolCallFormat::start_marker`/`end_markers` of the format in use")]
 pub const FN_STOP_WORDS: [&str; 4] = [FN_NAME, FN_ARGS, FN_RESULT, FN_EXIT];
 
 #[test]
@@ -315,5 +493,40 @@ fn test_builder() {
         .add_tool(curl_tool)
         .add_tool(mem_tool)
         .build();
-    println!("{}", toolset.system_prompt(whatlang::Lang::Cmn, false))
+    println!(
+        "{}",
+        toolset.system_prompt(whatlang::Lang::Cmn, false, ToolFormatKind::Qwen.formatter())
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn context_deadline_and_progress() {
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let ctx = ToolContext {
+            use_id: Some(Uuid::nil()),
+            ..ToolContext::default()
+        }
+        .with_timeout(Some(Duration::from_millis(20)))
+        .with_progress(tx);
+
+        ctx.progress("half", Some(1.5));
+        let progress = rx.try_recv().unwrap();
+        assert_eq!(progress.message, "half");
+        assert_eq!(progress.fraction, Some(1.0));
+
+        assert_eq!(ctx.run(async { 1 }).await.unwrap(), 1);
+        let err = ctx.run(std::future::pending::<()>()).await.unwrap_err();
+        assert!(err.to_string().contains("timed out"));
+        assert!(ctx.check().is_err());
+
+        let ctx = ToolContext::default().with_timeout(None);
+        assert!(ctx.deadline.is_none());
+        assert!(ctx.check().is_ok());
+        ctx.cancel.cancel();
+        assert!(ctx.run(std::future::pending::<()>()).await.is_err());
+    }
 }
@@ -5,6 +5,8 @@ pub struct SystemPromptTemplates {
     pub tool_info_template: &'static str,
     pub parallel_call_template: &'static str,
     pub single_call_template: &'static str,
+    pub loop_limit_template: &'static str,
+    pub context_summary_template: &'static str,
 }
 
 pub fn get_templates(lang: Lang) -> SystemPromptTemplates {
@@ -74,6 +76,10 @@ pub fn get_templates(lang: Lang) -> SystemPromptTemplates {
         ### 结果处理：
         {FN_RESULT} ...
         收到结果后：检查错误 -> 基于结果行动。"###,
+            loop_limit_template: r###"工具调用已达到上限（{limit}），不能再调用任何工具。请根据目前已有的信息直接回答用户。"###,
+            context_summary_template: r###"请把下面这段较早的对话压缩成一份简洁的摘要，供之后的对话参考。
+保留：用户的目标和要求、已经确认的事实和结论、图片编号(image_idx)/资源编号(asset_idx)及其内容、尚未完成的事项。
+省略寒暄和重复内容。只输出摘要本身。"###,
         },
         Lang::Jpn => SystemPromptTemplates {
             assistant_desc_template: r###"あなたは**ネイティブな視覚**を持つAIです。
@@ -141,6 +147,10 @@ pub fn get_templates(lang: Lang) -> SystemPromptTemplates {
         ### 結果処理：
         {FN_RESULT} ...
         受信後：エラー確認 -> 結果に基づき行動。"###,
+            loop_limit_template: r###"ツール呼び出しが上限（{limit}）に達しました。これ以上ツールは使えません。現在の情報だけで直接ユーザーに回答してください。"###,
+            context_summary_template: r###"以下の過去の会話を、今後の会話で参照するための簡潔な要約にまとめてください。
+ユーザーの目的と要望、確定した事実と結論、画像番号(image_idx)/リソース番号(asset_idx)とその内容、未完了の事項を残してください。
+挨拶や重複は省略し、要約だけを出力してください。"###,
         },
 
         // -----------------------------------------------------------------
@@ -212,6 +222,10 @@ pub fn get_templates(lang: Lang) -> SystemPromptTemplates {
         ### 결과 처리:
         {FN_RESULT} ...
         수신 후: 오류 확인 -> 결과 기반 행동."###,
+            loop_limit_template: r###"도구 호출이 한도({limit})에 도달했습니다. 더 이상 도구를 사용할 수 없습니다. 지금까지의 정보만으로 사용자에게 바로 답변하세요."###,
+            context_summary_template: r###"아래의 이전 대화를 이후 대화에서 참고할 수 있도록 간결한 요약으로 정리하세요.
+사용자의 목표와 요구, 확인된 사실과 결론, 이미지 번호(image_idx)/리소스 번호(asset_idx)와 그 내용, 아직 끝나지 않은 작업을 남기세요.
+인사와 중복은 생략하고 요약만 출력하세요."###,
         },
 
         // -----------------------------------------------------------------
@@ -283,6 +297,42 @@ pub fn get_templates(lang: Lang) -> SystemPromptTemplates {
         ### Result Handling:
         {FN_RESULT} ...
         On Receive: Check Errors -> Act on Result."###,
+            loop_limit_template: r###"Tool call limit reached ({limit}). No more tools are available. Answer the user directly with the information gathered so far."###,
+            context_summary_template: r###"Condense the earlier conversation below into a concise summary for reference in the rest of the conversation.
+Keep: the user's goals and requirements, confirmed facts and conclusions, image_idx/asset_idx values and what they contain, unfinished work.
+Skip greetings and repetition. Output only the summary."###,
         },
     }
 }
+
+/// Hermes格式的模型只在英文的工具调用模板上训练过，不区分语言
+pub const HERMES_CALL_TEMPLATE: &str = r###"## Tool Calling Mode
+
+### Core Rules:
+1. **Real Params**: Quote user/tool outputs only. **NO Fabrication**.
+2. **Step-by-Step**: If a param depends on another tool's result, call that tool first and wait.
+
+### Format:
+For each call, return a JSON object with function name (one of: {tool_names}) and arguments within <tool_call></tool_call> XML tags:
+<tool_call>
+{"name": <function-name>, "arguments": <args-json-object>}
+</tool_call>
+
+### Result Handling:
+Results are returned within <tool_response></tool_response> tags.
+On Receive: Check Errors -> Act on Result."###;
+
+/// Llama-3 内置的工具调用格式
+pub const LLAMA3_CALL_TEMPLATE: &str = r###"## Tool Calling Mode
+
+### Core Rules:
+1. **Real Params**: Quote user/tool outputs only. **NO Fabrication**.
+2. **Step-by-Step**: If a param depends on another tool's result, call that tool first and wait.
+
+### Format:
+To call a function (one of: {tool_names}), respond with <|python_tag|> followed by a JSON object:
+<|python_tag|>{"name": <function-name>, "parameters": <args-json-object>}
+Do not add any other text to a function call.
+
+### Result Handling:
+On Receive: Check Errors -> Act on Result."###;
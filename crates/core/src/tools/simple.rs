@@ -3,7 +3,7 @@ use std::{str::FromStr, sync::Arc};
 use schemars::{JsonSchema, schema_for};
 use serde::Deserialize;
 
-use crate::{MessageContent, Tool, ToolDescription, AssetId, blob::BlobStorage};
+use crate::{MessageContent, Tool, ToolContext, ToolDescription, AssetId, blob::BlobStorage};
 
 fn bytes_preview(b: &[u8]) -> String {
     b.iter()
@@ -29,7 +29,11 @@ impl ImageTool {
 
 #[async_trait::async_trait]
 impl Tool for ImageTool {
-    async fn call(&self, args: &str) -> Result<Vec<MessageContent>, anyhow::Error> {
+    async fn call(
+        &self,
+        _ctx: &ToolContext,
+        args: &str,
+    ) -> Result<Vec<MessageContent>, anyhow::Error> {
         let args: ImageArgs = serde_json::from_str(args)?;
         let uuid = AssetId::from_str(&args.img_idx)?;
         Ok(match self.0.get(uuid)? {
@@ -85,7 +89,11 @@ impl AssetTool {
 
 #[async_trait::async_trait]
 impl Tool for AssetTool {
-    async fn call(&self, args: &str) -> Result<Vec<MessageContent>, anyhow::Error> {
+    async fn call(
+        &self,
+        _ctx: &ToolContext,
+        args: &str,
+    ) -> Result<Vec<MessageContent>, anyhow::Error> {
         let args: AssetArgs = serde_json::from_str(args)?;
         let uuid = AssetId::from_str(&args.asset_idx)?;
         Ok(match self.0.get(uuid)? {
@@ -94,10 +102,21 @@ impl Tool for AssetTool {
             }
             Some(v) => {
                 self.0.retain(uuid)?;
-                vec![MessageContent::AssetRef(
-                    uuid,
-                    format!("FileSize:{},Preview:{}", v.len(), bytes_preview(&v)),
-                )]
+                let mut label = String::new();
+                if let Some(meta) = self.0.get_meta(uuid)? {
+                    if let Some(name) = meta.filename {
+                        label.push_str(&format!("FileName:{},", name));
+                    }
+                    if let Some(mime) = meta.mime {
+                        label.push_str(&format!("Mime:{},", mime));
+                    }
+                }
+                label.push_str(&format!(
+                    "FileSize:{},Preview:{}",
+                    v.len(),
+                    bytes_preview(&v)
+                ));
+                vec![MessageContent::AssetRef(uuid, label)]
             }
         })
     }
@@ -197,7 +216,11 @@ impl Tool for ResourceInspector {
         }
     }
 
-    async fn call(&self, args: &str) -> Result<Vec<MessageContent>, anyhow::Error> {
+    async fn call(
+        &self,
+        _ctx: &ToolContext,
+        args: &str,
+    ) -> Result<Vec<MessageContent>, anyhow::Error> {
         let args: InspectArgs = serde_json::from_str(args)?;
         let uuid = AssetId::from_str(&args.uuid)?;
 
@@ -206,8 +229,13 @@ impl Tool for ResourceInspector {
             (None, _) => return Ok(vec![MessageContent::Text("Resource not found.".into())]),
         };
 
+        let meta = match ty {
+            ResourceType::Image => self.image.get_meta(uuid)?,
+            ResourceType::Asset => self.asset.get_meta(uuid)?,
+        };
         let mime = infer::get(&data)
             .map(|t| t.mime_type())
+            .or(meta.as_ref().and_then(|m| m.mime.as_deref()))
             .unwrap_or("application/octet-stream");
 
         let mut details = String::new();
@@ -249,9 +277,20 @@ impl Tool for ResourceInspector {
             details = format!("Hex Head: {}", hex);
         }
 
+        let mut origin = String::new();
+        if let Some(ref meta) = meta {
+            if let Some(ref name) = meta.filename {
+                origin.push_str(&format!("- FileName: {}\n", name));
+            }
+            origin.push_str(&format!(
+                "- Source: {}\n- Created: {}\n",
+                meta.tool.as_deref().unwrap_or("upload"),
+                meta.created_at.to_rfc3339()
+            ));
+        }
         let info = format!(
-            "Resource Info:\n- UUID: {}\n- Size: {} bytes\n- Mime: {}\n{}",
-            uuid, size, mime, details
+            "Resource Info:\n- UUID: {}\n- Size: {} bytes\n- Mime: {}\n{}{}",
+            uuid, size, mime, origin, details
         );
         v.push(MessageContent::Text(info));
 
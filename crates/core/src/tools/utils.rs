@@ -1,9 +1,10 @@
-use std::{io::Cursor, sync::{Arc, OnceLock}};
+use std::{borrow::Cow, io::Cursor, sync::{Arc, OnceLock}};
 
 use anyhow::anyhow;
-use image::ImageFormat;
+use image::{DynamicImage, ImageFormat};
 use resvg::{tiny_skia, usvg};
-use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use strum::{Display, EnumString};
 
 use crate::tools::code_interpreter::JsInterpreterArgs;
 
@@ -99,6 +100,87 @@ pub fn convert_to_png(input_data: Vec<u8>) -> Result<Vec<u8>, anyhow::Error> {
         }
     }
 }
+/// 保存图片时使用的格式
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Default, EnumString, Display, Serialize, Deserialize,
+)]
+#[strum(serialize_all = "snake_case")]
+pub enum ImageFormatPolicy {
+    /// 保留JPEG和PNG，其它格式转换成PNG
+    #[default]
+    #[strum(serialize = "keep")]
+    Keep,
+    /// 全部转换成PNG
+    #[strum(serialize = "png")]
+    Png,
+    /// 全部转换成JPEG，透明通道会被丢弃
+    #[strum(serialize = "jpeg")]
+    Jpeg,
+}
+
+/// 按`policy`转换保存的图片格式
+pub fn normalize_image(
+    input_data: Vec<u8>,
+    policy: ImageFormatPolicy,
+) -> Result<Vec<u8>, anyhow::Error> {
+    let target = match (policy, image::guess_format(&input_data)?) {
+        (ImageFormatPolicy::Keep, _) => return convert_to_png(input_data),
+        (ImageFormatPolicy::Png, ImageFormat::Png) => return Ok(input_data),
+        (ImageFormatPolicy::Jpeg, ImageFormat::Jpeg) => return Ok(input_data),
+        (ImageFormatPolicy::Png, _) => ImageFormat::Png,
+        (ImageFormatPolicy::Jpeg, _) => ImageFormat::Jpeg,
+    };
+    encode_image(image::load_from_memory(&input_data)?, target)
+}
+
+/// 根据文件头判断图片的MIME类型
+pub fn image_mime_type(data: &[u8]) -> Option<&'static str> {
+    image::guess_format(data).ok().map(|f| f.to_mime_type())
+}
+
+fn encode_image(img: DynamicImage, format: ImageFormat) -> Result<Vec<u8>, anyhow::Error> {
+    let mut buf = Vec::new();
+    match format {
+        // JPEG不支持alpha通道
+        ImageFormat::Jpeg => img
+            .into_rgb8()
+            .write_to(&mut Cursor::new(&mut buf), format)?,
+        _ => img.write_to(&mut Cursor::new(&mut buf), format)?,
+    }
+    Ok(buf)
+}
+
+/// 只读取图片头部获取 (宽, 高)，不解码整张图片
+pub fn image_dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    image::ImageReader::new(Cursor::new(data))
+        .with_guessed_format()
+        .ok()?
+        .into_dimensions()
+        .ok()
+}
+
+/// 按`resizer`缩小发送给模型的图片，只缩小不放大
+/// 尺寸不需要变化时原样返回，JPEG保持JPEG，其它格式输出PNG
+pub fn resize_for_model<'a>(
+    data: &'a [u8],
+    resizer: &ImageResizer,
+) -> Result<Cow<'a, [u8]>, anyhow::Error> {
+    let Some((width, height)) = image_dimensions(data) else {
+        return Ok(Cow::Borrowed(data));
+    };
+    let (new_h, new_w) = resizer.smart_resize(height, width);
+    if (new_w as u64) * (new_h as u64) >= (width as u64) * (height as u64) {
+        return Ok(Cow::Borrowed(data));
+    }
+    let format = match image::guess_format(data)? {
+        ImageFormat::Jpeg => ImageFormat::Jpeg,
+        _ => ImageFormat::Png,
+    };
+    let img = image::load_from_memory(data)?;
+    let resized = img.resize_exact(new_w, new_h, image::imageops::FilterType::Lanczos3);
+    Ok(Cow::Owned(encode_image(resized, format)?))
+}
+
 static GLOBAL_USVG_OPTIONS: OnceLock<usvg::Options<'static>> = OnceLock::new();
 pub fn get_usvg_options() -> &'static usvg::Options<'static> {
     let options = GLOBAL_USVG_OPTIONS.get_or_init(|| {
@@ -217,3 +299,82 @@ impl ImageResizer {
         (h_bar as u32, w_bar as u32)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(width: u32, height: u32, format: ImageFormat) -> Vec<u8> {
+        encode_image(DynamicImage::new_rgb8(width, height), format).unwrap()
+    }
+
+    #[test]
+    fn resize_for_model_fits_pixel_budget() {
+        let max_pixels = 512 * 512;
+        let resizer = ImageResizer::new(32, 32 * 32, max_pixels);
+        for (format, mime) in [
+            (ImageFormat::Png, "image/png"),
+            (ImageFormat::Jpeg, "image/jpeg"),
+            // 其它格式输出PNG
+            (ImageFormat::Bmp, "image/png"),
+        ] {
+            let data = encode(2000, 1500, format);
+            let resized = resize_for_model(&data, &resizer).unwrap();
+            let (width, height) = image_dimensions(&resized).unwrap();
+            assert!((width * height) as u64 <= max_pixels, "{:?}", format);
+            assert_eq!((width % 32, height % 32), (0, 0));
+            assert!(width > height);
+            assert_eq!(image_mime_type(&resized), Some(mime), "{:?}", format);
+        }
+    }
+
+    #[test]
+    fn normalize_image_follows_policy() {
+        let (png, jpeg, bmp) = (
+            encode(8, 8, ImageFormat::Png),
+            encode(8, 8, ImageFormat::Jpeg),
+            encode(8, 8, ImageFormat::Bmp),
+        );
+        let mime = |data: &[u8], policy| {
+            image_mime_type(&normalize_image(data.to_vec(), policy).unwrap()).unwrap()
+        };
+        assert_eq!(mime(&jpeg, ImageFormatPolicy::Keep), "image/jpeg");
+        assert_eq!(mime(&png, ImageFormatPolicy::Keep), "image/png");
+        assert_eq!(mime(&bmp, ImageFormatPolicy::Keep), "image/png");
+        assert_eq!(mime(&jpeg, ImageFormatPolicy::Png), "image/png");
+        assert_eq!(mime(&png, ImageFormatPolicy::Jpeg), "image/jpeg");
+        assert_eq!(mime(&bmp, ImageFormatPolicy::Jpeg), "image/jpeg");
+        // 格式已经一致时不重新编码
+        assert_eq!(
+            normalize_image(jpeg.clone(), ImageFormatPolicy::Jpeg).unwrap(),
+            jpeg
+        );
+        assert_eq!(
+            normalize_image(png.clone(), ImageFormatPolicy::Png).unwrap(),
+            png
+        );
+
+        // 透明通道在转换成JPEG时被丢弃
+        let mut rgba = Vec::new();
+        DynamicImage::new_rgba8(8, 8)
+            .write_to(&mut Cursor::new(&mut rgba), ImageFormat::Png)
+            .unwrap();
+        assert_eq!(mime(&rgba, ImageFormatPolicy::Jpeg), "image/jpeg");
+    }
+
+    #[test]
+    fn resize_for_model_never_upscales() {
+        // min_pixels大于原图时smart_resize会放大，发送给模型时保持原图
+        let resizer = ImageResizer::new(32, 1024 * 1024, 2048 * 2048);
+        for format in [ImageFormat::Png, ImageFormat::Jpeg] {
+            let data = encode(100, 80, format);
+            let resized = resize_for_model(&data, &resizer).unwrap();
+            assert!(matches!(resized, Cow::Borrowed(_)));
+            assert_eq!(&*resized, data.as_slice());
+        }
+
+        // 不是图片时原样返回
+        let resized = resize_for_model(b"not an image", &resizer).unwrap();
+        assert_eq!(&*resized, b"not an image");
+    }
+}
@@ -6,9 +6,9 @@ use std::str::FromStr;
 use std::sync::Arc;
 
 use crate::AssetId;
-use crate::blob::BlobStorage;
+use crate::blob::{BlobMeta, BlobStorage};
 use crate::schema::MessageContent;
-use crate::tools::{Tool, ToolDescription};
+use crate::tools::{Tool, ToolContext, ToolDescription, ToolKind};
 use crate::{ImageResizer, parse_tool_args};
 use anyhow::{Error, anyhow};
 use schemars::{JsonSchema, schema_for};
@@ -56,7 +56,7 @@ impl Tool for ZoomInTool {
             args_format: "JSON. Img must be UUID.".to_string(),
         }
     }
-    async fn call(&self, args: &str) -> Result<Vec<MessageContent>, Error> {
+    async fn call(&self, _ctx: &ToolContext, args: &str) -> Result<Vec<MessageContent>, Error> {
         let args: ZoomArgs = parse_tool_args(args)?;
         let id = AssetId::from_str(&args.img_idx)?;
         let mut v = Vec::new();
@@ -77,7 +77,9 @@ impl Tool for ZoomInTool {
                     y2: b.bbox_2d[3],
                 };
                 let cropped_img = image_zoom_in(&image, bbox)?;
-                let uuid = self.db.save(&cropped_img)?;
+                let uuid = self
+                    .db
+                    .save_with_meta(&cropped_img, BlobMeta::from_tool(ToolKind::ZoomIn))?;
                 v.push(MessageContent::ImageRef(
                     uuid,
                     b.label.unwrap_or("".to_string()),
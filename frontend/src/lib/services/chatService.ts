@@ -18,6 +18,7 @@ import type {
 	StreamPacket,
 	UploadImageResponse,
 	PreviewFile,
+	SearchResult,
 	ToolDescription,
   PendingFile
 } from '../types';
@@ -33,6 +34,9 @@ const maxReconnectInterval = 30000;
 let wsReconnecting = false;
 // 记录 chat_id -> request_id 的映射，用于发送中止请求
 const activeRequestIds = new Map<string, string>();
+// 记录 request_id -> 已收到的最后一个 seq，用于断线重连后恢复
+const lastSeqs = new Map<string, number>();
+const NIL_UUID = '00000000-0000-0000-0000-000000000000';
 
 export async function init() {
 	await loadHistorySidebar();
@@ -81,6 +85,32 @@ export async function regenerateMessage(messageId: string) {
 	}
 }
 
+export async function continueMessage() {
+	const curr = get(currentChat);
+	if (!curr || !ws) return;
+
+	const requestId = crypto.randomUUID();
+	processingChatIds.add(curr.id);
+	activeRequestIds.set(curr.id, requestId);
+
+	try {
+		if (!(await waitForConnection())) throw new Error('Connection timeout');
+
+		const payload: ClientRequest = {
+			type: 'Continue',
+			payload: {
+				request_id: requestId,
+				chat_id: curr.id,
+				config: settings.getLLMConfig()
+			}
+		};
+		ws.send(JSON.stringify(payload));
+	} catch (e: any) {
+		toasts.show(e.message);
+		processingChatIds.delete(curr.id);
+	}
+}
+
 export async function editMessage(messageId: string, newText: string) {
 	const curr = get(currentChat);
 	const currentSettings = get(settings); // 获取当前配置
@@ -142,6 +172,17 @@ function connectWebSocket() {
 		wsReconnecting = false;
 		reconnectInterval = 1000;
 
+		// 服务器上的生成任务不会因为断线中止，继续接收断线之后的事件
+		const processing = get(processingChatIds);
+		for (const [chatId, requestId] of activeRequestIds) {
+			if (!processing.has(chatId)) continue;
+			const payload: ClientRequest = {
+				type: 'Resume',
+				payload: { request_id: requestId, last_seq: lastSeqs.get(requestId) ?? 0 }
+			};
+			ws?.send(JSON.stringify(payload));
+		}
+
 		// 如果当前有打开的对话，且没在生成中，尝试重新加载以同步最新状态
 		const curr = get(currentChat);
 		if (curr && !get(processingChatIds).has(curr.id)) {
@@ -199,9 +240,17 @@ function handleWsMessage(event: MessageEvent) {
 		return;
 	}
 
-	const { chat_id } = packet;
+	// 恢复失败时服务器不知道 chat_id
+	const chat_id =
+		packet.chat_id === NIL_UUID ? chatIdOfRequest(packet.request_id) : packet.chat_id;
 	if (!chat_id) return;
 
+	// 重连后补发的事件可能与已收到的重复
+	if (packet.seq > 0) {
+		if (packet.seq <= (lastSeqs.get(packet.request_id) ?? 0)) return;
+		lastSeqs.set(packet.request_id, packet.seq);
+	}
+
 	if (packet.Error) {
 		console.error('Server Error:', packet.Error);
 		toasts.show(`Server Error: ${packet.Error}`, 'error', 5000);
@@ -214,10 +263,20 @@ function handleWsMessage(event: MessageEvent) {
 		return;
 	}
 
+	if (packet.LoopLimitReached) {
+		const [limit, value] = Object.entries(packet.LoopLimitReached)[0];
+		toasts.show(`Tool loop limit reached (${limit}: ${value}), answering without tools`, 'info', 5000);
+		return;
+	}
+
 	// 1. 处理流结束信号
 	if (packet.StreamEnd) {
 		console.log(`StreamEnd received for: ${chat_id}`);
 		processingChatIds.delete(chat_id);
+		lastSeqs.delete(packet.request_id);
+		if (activeRequestIds.get(chat_id) === packet.request_id) {
+			activeRequestIds.delete(chat_id);
+		}
 
 		// 清理增量缓存
 		wipDeltaStore.update((map) => {
@@ -257,6 +316,13 @@ function handleWsMessage(event: MessageEvent) {
 	}
 }
 
+function chatIdOfRequest(requestId: string): string | undefined {
+	for (const [chatId, reqId] of activeRequestIds) {
+		if (reqId === requestId) return chatId;
+	}
+	return undefined;
+}
+
 // --- API 操作 ---
 
 export async function loadHistorySidebar() {
@@ -274,6 +340,17 @@ export async function loadHistorySidebar() {
 	}
 }
 
+export async function searchHistory(query: string): Promise<SearchResult[]> {
+	try {
+		const res = await fetch(`${getApiBase()}/api/history/search?q=${encodeURIComponent(query)}`);
+		if (!res.ok) throw new Error(await res.text());
+		return await res.json();
+	} catch (e: any) {
+		toasts.show(e.message);
+		return [];
+	}
+}
+
 export async function loadChat(id: string) {
 	isLoading.set(true);
 	// 更新 URL 但不跳转页面
@@ -355,6 +432,34 @@ export async function deleteChat(id: string) {
 	}
 }
 
+export async function switchBranch(messageId: string) {
+	const curr = get(currentChat);
+	if (!curr) return;
+	try {
+		const res = await fetch(`${getApiBase()}/api/history/${curr.id}/branches/${messageId}`, {
+			method: 'POST'
+		});
+		if (!res.ok) throw new Error(await res.text());
+		currentChat.set(await res.json());
+	} catch (e: any) {
+		toasts.show(e.message);
+	}
+}
+
+export async function pruneBranch(messageId: string) {
+	const curr = get(currentChat);
+	if (!curr) return;
+	try {
+		const res = await fetch(`${getApiBase()}/api/history/${curr.id}/branches/${messageId}`, {
+			method: 'DELETE'
+		});
+		if (!res.ok) throw new Error(await res.text());
+		currentChat.set(await res.json());
+	} catch (e: any) {
+		toasts.show(e.message);
+	}
+}
+
 export async function abortGeneration(chatId: string) {
 	const reqId = activeRequestIds.get(chatId);
 	if (!reqId || !ws) return;
@@ -470,6 +575,14 @@ function appendTextDelta(contentArray: MessageContent[], delta: string) {
 	}
 }
 
+// 工具结果到达前后面可能已经有别的结果，按 use_id 往前找发起调用的消息
+function findToolOwner(messages: Message[], useId: string): Message | undefined {
+	for (let i = messages.length - 1; i >= 0; i--) {
+		if (messages[i].tool_use.some((t) => t.use_id === useId)) return messages[i];
+	}
+	return undefined;
+}
+
 function applyPacketToMessages(messages: Message[], packet: StreamPacket): Message[] {
 	// 辅助：获取或创建当前正在生成的 Assistant 消息
 	const getOrCreateWipAssistant = (): Message => {
@@ -498,7 +611,16 @@ function applyPacketToMessages(messages: Message[], packet: StreamPacket): Messa
 		const msg = getOrCreateWipAssistant();
 		msg.tool_use.push(packet.ToolCall);
 		msg.tool_deltas = ''; // Reset deltas after a full call is parsed
+	} else if (packet.ToolProgress) {
+		const { use_id, message, fraction } = packet.ToolProgress;
+		const owner = findToolOwner(messages, use_id);
+		if (owner) {
+			const percent = fraction === null ? '' : ` ${Math.round(fraction * 100)}%`;
+			owner.tool_progress = { ...owner.tool_progress, [use_id]: message + percent };
+		}
 	} else if (packet.ToolResult) {
+		const owner = findToolOwner(messages, packet.ToolResult.tool_use.use_id);
+		if (owner?.tool_progress) delete owner.tool_progress[packet.ToolResult.tool_use.use_id];
 		messages.push(packet.ToolResult.result);
 	} else if (packet.ContentDelta) {
 		appendTextDelta(getOrCreateWipAssistant().content, packet.ContentDelta);
@@ -29,6 +29,8 @@ export type ToolUse = {
     use_id: string;
     function_name: string;
     args: string;
+    // 原生模式下服务端给出的 tool_call id
+    call_id?: string;
 };
 
 export type Role =
@@ -44,11 +46,42 @@ export type Message = {
     content: MessageContent[];
     tool_use: ToolUse[];
     tool_deltas?: string;
+    // 正在执行的工具报告的进度，key 为 use_id，结果到达后删除
+    tool_progress?: Record<string, string>;
+    // 生成被中止或出错时保存的部分输出
+    interrupted?: boolean;
+    // 上一条消息，为空时是根消息
+    parent?: string | null;
+};
+
+// 对应 Rust struct SearchResult
+export type SearchHit = {
+    message_id: string;
+    score: number;
+    snippet: string;
+    offset: number;
+    positions: [number, number][];
+};
+
+export type SearchResult = {
+    chat: ChatMeta;
+    score: number;
+    hits: SearchHit[];
+};
+
+// 对应 Rust struct BranchInfo
+export type BranchInfo = {
+    id: string;
+    owner: Role;
+    preview: string;
+    active: boolean;
 };
 
 export type ClientRequest =
     | { type: 'Chat'; payload: { request_id: string; chat_id: string; content: MessageContent[], config?: any } }
     | { type: 'Abort'; payload: { request_id: string; chat_id: string } }
+    | { type: 'Continue'; payload: { request_id: string; chat_id: string; config?: any } }
+    | { type: 'Resume'; payload: { request_id: string; last_seq: number } }
     | { type: 'Regenerate'; payload: { request_id: string; chat_id: string; message_id: string, config?: any } }
     | { type: 'Edit'; payload: { request_id: string; chat_id: string; message_id: string; new_content: MessageContent[]; config?: any } };
 
@@ -57,6 +90,9 @@ export type ChatEntry = {
     date: string;
     summary: string;
     messages: Message[];
+    context_summary?: { until: string; content: string } | null;
+    // 不活跃分支上的消息
+    inactive?: Message[];
 };
 
 // WebSocket 消息类型
@@ -65,17 +101,34 @@ export type ClientWSMessage = {
     content: MessageContent[];
 };
 
+// 对应 Rust struct ToolProgress
+export type ToolProgress = {
+    use_id: string;
+    message: string;
+    fraction: number | null;
+};
+
+// 对应 Rust enum LoopLimit
+export type LoopLimit =
+    | { ToolRounds: number }
+    | { ToolCalls: number }
+    | { Duration: number };
+
 export interface StreamPacket {
     chat_id: string;
     request_id: string;
+    // 同一个 request 内单调递增，用于断线重连后补发
+    seq: number;
     // 这里的 key 对应 Rust ChatEvent 的字段
     ReasoningDelta?: string;
     ToolDelta?: string;
     ToolCall?: ToolUse;
     ToolResult?: { tool_use: ToolUse; result: Message };
+    ToolProgress?: ToolProgress;
     ContentDelta?: string;
     StreamEnd?: boolean;
     Usage?: CompletionUsage;
+    LoopLimitReached?: LoopLimit;
     Error?: string;
 }
 